@@ -81,6 +81,7 @@ impl Game {
             match request {
                 GgrsRequest::LoadGameState { cell, .. } => self.load_game_state(cell),
                 GgrsRequest::SaveGameState { cell, frame } => self.save_game_state(cell, frame),
+                GgrsRequest::ReportChecksum { cell, frame } => self.report_checksum(cell, frame),
                 GgrsRequest::AdvanceFrame { inputs } => self.advance_frame(inputs),
             }
         }
@@ -95,6 +96,14 @@ impl Game {
         cell.save(frame, Some(self.game_state.clone()), Some(checksum));
     }
 
+    // create a checksum without saving the gamestate, only requested in lockstep mode
+    fn report_checksum(&mut self, cell: GameStateCell<State>, frame: Frame) {
+        assert_eq!(self.game_state.frame, frame);
+        let buffer = bincode::serialize(&self.game_state).unwrap();
+        let checksum = fletcher16(&buffer) as u128;
+        cell.save(frame, None, Some(checksum));
+    }
+
     // load gamestate and overwrite
     fn load_game_state(&mut self, cell: GameStateCell<State>) {
         self.game_state = cell.load().expect("No data found.");
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use instant::{Duration, Instant};
+use parking_lot::Mutex;
+
+/// The source of time for all timers of a session, like timeouts, keep-alives, quality reports and
+/// ping measurements. Sessions use the [`SystemClock`] by default. Replace it with
+/// [`SessionBuilder::with_clock()`], e.g. with a [`ManualClock`] to step time explicitly in tests
+/// and simulations.
+///
+/// [`SessionBuilder::with_clock()`]: crate::SessionBuilder::with_clock
+#[cfg(feature = "sync-send")]
+pub trait Clock: Send + Sync {
+    /// Returns the current point in time. Successive calls must never go backwards.
+    fn now(&self) -> Instant;
+}
+
+/// The source of time for all timers of a session, like timeouts, keep-alives, quality reports and
+/// ping measurements. Sessions use the [`SystemClock`] by default. Replace it with
+/// [`SessionBuilder::with_clock()`], e.g. with a [`ManualClock`] to step time explicitly in tests
+/// and simulations.
+///
+/// [`SessionBuilder::with_clock()`]: crate::SessionBuilder::with_clock
+#[cfg(not(feature = "sync-send"))]
+pub trait Clock {
+    /// Returns the current point in time. Successive calls must never go backwards.
+    fn now(&self) -> Instant;
+}
+
+impl std::fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Clock").finish_non_exhaustive()
+    }
+}
+
+/// A [`Clock`] that follows the real, monotonic time of the system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A [`Clock`] that only moves forward when you [`advance()`](Self::advance) it. Clones share the
+/// same time, so you can keep a clone to control the clock after handing it to a session.
+///
+/// ```
+/// # use ggrs::{Clock, ManualClock};
+/// # use instant::Duration;
+/// let clock = ManualClock::new();
+/// let start = clock.now();
+/// clock.advance(Duration::from_millis(500));
+/// assert_eq!(clock.now() - start, Duration::from_millis(500));
+/// ```
+///
+/// Keep in mind that anything waiting for time to pass, like
+/// [`P2PSession::advance_frame_with_wait_timeout()`], waits until the clock is advanced.
+///
+/// [`P2PSession::advance_frame_with_wait_timeout()`]: crate::P2PSession::advance_frame_with_wait_timeout
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    /// Creates a clock that stands still until it is advanced.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+
+    /// Moves the clock and all its clones forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock() += duration;
+    }
+
+    /// Returns how far the clock has been advanced since it was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+// #########
+// # TESTS #
+// #########
+
+#[cfg(test)]
+mod clock_tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_stands_still() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_manual_clock_clones_share_time() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let handle = clock.clone();
+
+        handle.advance(Duration::from_millis(30));
+        handle.advance(Duration::from_millis(20));
+
+        assert_eq!(clock.now(), start + Duration::from_millis(50));
+        assert_eq!(clock.elapsed(), Duration::from_millis(50));
+    }
+}
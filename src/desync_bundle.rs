@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::replay::{into_io_error, ReplayFrame, ReplayHeader};
+use crate::{Frame, GgrsError};
+
+/// Magic bytes at the very beginning of every desync bundle.
+const DESYNC_BUNDLE_MAGIC: [u8; 4] = *b"GGDB";
+/// The version of the desync bundle format written by this version of GGRS.
+pub const DESYNC_BUNDLE_FORMAT_VERSION: u32 = 1;
+/// How many frames a [`DesyncBundleRecorder`] keeps at most while it has no keyframe to start
+/// bundles from, e.g. in lockstep mode. Ten minutes at 60 FPS.
+const MAX_FRAMES_WITHOUT_KEYFRAME: usize = 36_000;
+
+/// Everything needed to re-simulate a desync offline: the settings of the session, a starting
+/// state, all confirmed inputs from that state up to the desynced frame and the checksums of both
+/// sides. Created by [`P2PSession::desync_bundle()`].
+///
+/// To reproduce the desync, load `start_state` (or create the initial game state if it is `None`),
+/// advance it with the inputs of all `frames` in order and compare the checksum of the state at the
+/// beginning of each frame with the recorded one.
+///
+/// [`P2PSession::desync_bundle()`]: crate::P2PSession::desync_bundle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DesyncBundle<I> {
+    /// The settings of the session that detected the desync.
+    pub header: ReplayHeader,
+    /// The frame whose checksums differ.
+    pub desync_frame: Frame,
+    /// Our checksum of the desynced frame.
+    pub local_checksum: u128,
+    /// The checksum of the desynced frame the remote peer reported.
+    pub remote_checksum: u128,
+    /// The frame of `start_state`, which is also the frame of the first entry in `frames`.
+    pub start_frame: Frame,
+    /// Our saved state of `start_frame`, serialized with `bincode`. `None` if the bundle starts
+    /// with the initial game state at frame 0, which is the case in lockstep mode.
+    pub start_state: Option<Vec<u8>>,
+    /// The confirmed inputs of all frames from `start_frame` up to and including `desync_frame`,
+    /// together with our checksums of these frames, as far as we saved states for them.
+    pub frames: Vec<ReplayFrame<I>>,
+}
+
+impl<I: Serialize> DesyncBundle<I> {
+    /// Writes the bundle, e.g. into a file that is attached to a crash report.
+    ///
+    /// # Errors
+    /// - Returns the error of the writer if writing fails.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&DESYNC_BUNDLE_MAGIC)?;
+        writer.write_all(&DESYNC_BUNDLE_FORMAT_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, self).map_err(|err| into_io_error(*err))?;
+        writer.flush()
+    }
+}
+
+impl<I: DeserializeOwned> DesyncBundle<I> {
+    /// Reads a bundle written by [`Self::write_to()`].
+    ///
+    /// # Errors
+    /// - Returns an error of kind [`InvalidData`] if the source is not a desync bundle or was
+    ///   written in an unsupported version of the bundle format.
+    ///
+    /// [`InvalidData`]: io::ErrorKind::InvalidData
+    pub fn read_from(mut reader: impl Read) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != DESYNC_BUNDLE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a GGRS desync bundle",
+            ));
+        }
+
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != DESYNC_BUNDLE_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported desync bundle format version {version}"),
+            ));
+        }
+
+        bincode::deserialize_from(reader).map_err(|err| into_io_error(*err))
+    }
+}
+
+/// Keeps the confirmed inputs, checksums and some serialized states ("keyframes") of the recent
+/// past, so a [`DesyncBundle`] can be created for every desync found by desync detection.
+///
+/// Frames are recorded right away, but their checksums and keyframes are only taken once their
+/// saved states can no longer change, see [`Self::flush()`].
+pub(crate) struct DesyncBundleRecorder<I> {
+    header: ReplayHeader,
+    keyframe_interval: usize,
+    frames: VecDeque<ReplayFrame<I>>,
+    next_frame: Frame,
+    /// The first frame whose checksum has not been taken yet.
+    next_flush_frame: Frame,
+    next_keyframe: Frame,
+    keyframes: VecDeque<(Frame, Vec<u8>)>,
+    /// Local and remote checksums of desynced frames.
+    desyncs: BTreeMap<Frame, (u128, u128)>,
+}
+
+impl<I: Clone> DesyncBundleRecorder<I> {
+    pub(crate) fn new(header: ReplayHeader, keyframe_interval: usize) -> Self {
+        Self {
+            header,
+            keyframe_interval,
+            frames: VecDeque::new(),
+            next_frame: 0,
+            next_flush_frame: 0,
+            next_keyframe: 0,
+            keyframes: VecDeque::new(),
+            desyncs: BTreeMap::new(),
+        }
+    }
+
+    /// The next frame the recorder expects.
+    pub(crate) fn next_frame(&self) -> Frame {
+        self.next_frame
+    }
+
+    pub(crate) fn record(&mut self, frame: ReplayFrame<I>) {
+        self.next_frame = frame.frame + 1;
+        self.frames.push_back(frame);
+    }
+
+    fn first_frame(&self) -> Frame {
+        self.next_frame - self.frames.len() as Frame
+    }
+
+    /// Takes the checksums of all recorded frames up to and including `up_to`. Every
+    /// `keyframe_interval` frames, the saved state is kept as a keyframe as well.
+    pub(crate) fn flush(
+        &mut self,
+        up_to: Frame,
+        checksum: impl Fn(Frame) -> Option<u128>,
+        state: impl Fn(Frame) -> Option<Vec<u8>>,
+    ) {
+        let first_frame = self.first_frame();
+        let up_to = std::cmp::min(up_to, self.next_frame - 1);
+        for frame in self.next_flush_frame..=up_to {
+            self.frames[(frame - first_frame) as usize].checksum = checksum(frame);
+            if frame >= self.next_keyframe {
+                if let Some(state) = state(frame) {
+                    self.keyframes.push_back((frame, state));
+                    self.next_keyframe = frame + self.keyframe_interval as Frame;
+                }
+            }
+        }
+        self.next_flush_frame = std::cmp::max(self.next_flush_frame, up_to + 1);
+    }
+
+    /// Remembers the checksums of a desynced frame. Only the first remote checksum of each frame is kept.
+    pub(crate) fn record_desync(
+        &mut self,
+        frame: Frame,
+        local_checksum: u128,
+        remote_checksum: u128,
+    ) {
+        self.desyncs
+            .entry(frame)
+            .or_insert((local_checksum, remote_checksum));
+    }
+
+    /// Forgets everything that is not needed to bundle desyncs from `oldest_frame_to_keep` on.
+    pub(crate) fn forget_before(&mut self, oldest_frame_to_keep: Frame) {
+        self.desyncs = self.desyncs.split_off(&oldest_frame_to_keep);
+        while self
+            .keyframes
+            .get(1)
+            .is_some_and(|(frame, _)| *frame <= oldest_frame_to_keep)
+        {
+            self.keyframes.pop_front();
+        }
+        match self.keyframes.front() {
+            Some(&(keyframe, _)) => {
+                while self.frames.front().is_some_and(|f| f.frame < keyframe) {
+                    self.frames.pop_front();
+                }
+            }
+            // without any keyframe, bundles start at frame 0, but we cannot keep all frames forever
+            None => {
+                while self.frames.len() > MAX_FRAMES_WITHOUT_KEYFRAME
+                    && self
+                        .frames
+                        .front()
+                        .is_some_and(|f| f.frame < oldest_frame_to_keep)
+                {
+                    self.frames.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Continues recording at the given frame after the session loaded a state it did not simulate
+    /// itself, e.g. a join snapshot or the state of the recovery authority. Older frames and
+    /// keyframes cannot reproduce the states from then on, so they are dropped. Recorded frames from
+    /// the given frame on stay, but their checksums are taken again.
+    pub(crate) fn restart_at(&mut self, frame: Frame) {
+        self.keyframes.clear();
+        self.desyncs.retain(|&f, _| f < frame);
+        self.frames.retain(|f| f.frame >= frame);
+        for recorded in &mut self.frames {
+            recorded.checksum = None;
+        }
+        self.next_frame = std::cmp::max(self.next_frame, frame);
+        self.next_flush_frame = self.first_frame();
+        self.next_keyframe = frame;
+    }
+
+    /// Creates the bundle of the desync at the given frame, starting at the latest keyframe before
+    /// it. A keyframe of the desynced frame itself would only show the desynced state.
+    pub(crate) fn bundle(&self, desync_frame: Frame) -> Result<DesyncBundle<I>, GgrsError> {
+        let Some(&(local_checksum, remote_checksum)) = self.desyncs.get(&desync_frame) else {
+            return Err(GgrsError::InvalidRequest {
+                info: format!("No desync has been detected at frame {desync_frame} recently."),
+            });
+        };
+
+        let (start_frame, start_state) = match self
+            .keyframes
+            .iter()
+            .rev()
+            .find(|(frame, _)| *frame < desync_frame)
+        {
+            Some((frame, state)) => (*frame, Some(state.clone())),
+            None => (0, None),
+        };
+        let mut frames: Vec<_> = self
+            .frames
+            .iter()
+            .filter(|f| f.frame >= start_frame && f.frame <= desync_frame)
+            .cloned()
+            .collect();
+        if frames.first().map(|f| f.frame) != Some(start_frame)
+            || frames.last().map(|f| f.frame) != Some(desync_frame)
+        {
+            return Err(GgrsError::InvalidRequest {
+                info: format!(
+                    "The inputs that led to frame {desync_frame} are not available anymore."
+                ),
+            });
+        }
+        // the checksum of the desynced frame is known even if the recorder did not take it yet
+        if let Some(last) = frames.last_mut() {
+            last.checksum = Some(local_checksum);
+        }
+
+        Ok(DesyncBundle {
+            header: self.header.clone(),
+            desync_frame,
+            local_checksum,
+            remote_checksum,
+            start_frame,
+            start_state,
+            frames,
+        })
+    }
+}
+
+impl<I> std::fmt::Debug for DesyncBundleRecorder<I> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DesyncBundleRecorder")
+            .field("keyframe_interval", &self.keyframe_interval)
+            .field("num_frames", &self.frames.len())
+            .field("num_keyframes", &self.keyframes.len())
+            .finish_non_exhaustive()
+    }
+}
+
+// #########
+// # TESTS #
+// #########
+
+#[cfg(test)]
+mod desync_bundle_tests {
+    use super::*;
+
+    use crate::{InputEncoding, ReplaySessionKind};
+
+    fn recorder() -> DesyncBundleRecorder<u32> {
+        let header = ReplayHeader {
+            session_kind: ReplaySessionKind::P2P,
+            num_players: 1,
+            input_encoding: InputEncoding::Bincode,
+            input_type: "u32".to_owned(),
+            max_prediction: 8,
+            input_delay: 0,
+            fps: 60,
+            sparse_saving: false,
+            desync_detection_interval: Some(10),
+        };
+        DesyncBundleRecorder::new(header, 10)
+    }
+
+    /// Records frames up to `up_to` with the frame as input, checksum and state.
+    fn record_up_to(recorder: &mut DesyncBundleRecorder<u32>, up_to: Frame) {
+        for frame in recorder.next_frame()..=up_to {
+            recorder.record(ReplayFrame {
+                frame,
+                inputs: vec![frame as u32],
+                disconnected: vec![false],
+                checksum: None,
+            });
+        }
+        recorder.flush(
+            up_to,
+            |frame| Some(frame as u128),
+            |frame| Some(vec![frame as u8]),
+        );
+    }
+
+    #[test]
+    fn test_bundle_starts_at_keyframe_before_desync() {
+        let mut recorder = recorder();
+        record_up_to(&mut recorder, 35);
+        recorder.record_desync(30, 30, 1234);
+
+        let bundle = recorder.bundle(30).unwrap();
+        assert_eq!(bundle.start_frame, 20);
+        assert_eq!(bundle.start_state, Some(vec![20]));
+        let frames: Vec<_> = bundle.frames.iter().map(|f| f.frame).collect();
+        assert_eq!(frames, (20..=30).collect::<Vec<_>>());
+        assert_eq!(bundle.remote_checksum, 1234);
+
+        let mut bytes = Vec::new();
+        bundle.write_to(&mut bytes).unwrap();
+        assert_eq!(DesyncBundle::read_from(bytes.as_slice()).unwrap(), bundle);
+        assert!(DesyncBundle::<u32>::read_from(&bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn test_forgotten_frames_cannot_be_bundled() {
+        let mut recorder = recorder();
+        record_up_to(&mut recorder, 45);
+        recorder.record_desync(15, 15, 1234);
+        recorder.record_desync(35, 35, 1234);
+        recorder.forget_before(25);
+
+        assert!(recorder.bundle(15).is_err());
+        assert_eq!(recorder.bundle(35).unwrap().start_frame, 30);
+        assert!(recorder.bundle(40).is_err());
+    }
+
+    #[test]
+    fn test_loaded_state_restarts_recording() {
+        let mut recorder = recorder();
+        record_up_to(&mut recorder, 25);
+        recorder.record_desync(15, 15, 1234);
+        recorder.restart_at(22);
+        record_up_to(&mut recorder, 40);
+        recorder.record_desync(35, 35, 1234);
+
+        // keyframes before the loaded state do not lead to the states after it
+        assert!(recorder.bundle(15).is_err());
+        let bundle = recorder.bundle(35).unwrap();
+        assert_eq!(bundle.start_frame, 32);
+        assert_eq!(bundle.frames[0].checksum, Some(32));
+    }
+
+    #[test]
+    fn test_frames_without_keyframe_are_bounded() {
+        let mut recorder = recorder();
+        let up_to = MAX_FRAMES_WITHOUT_KEYFRAME as Frame + 100;
+        for frame in 0..=up_to {
+            recorder.record(ReplayFrame {
+                frame,
+                inputs: vec![frame as u32],
+                disconnected: vec![false],
+                checksum: None,
+            });
+        }
+        // no saved states, as in lockstep mode
+        recorder.flush(up_to, |frame| Some(frame as u128), |_| None);
+        recorder.record_desync(up_to, up_to as u128, 1234);
+        recorder.forget_before(up_to - 50);
+
+        assert_eq!(recorder.frames.len(), MAX_FRAMES_WITHOUT_KEYFRAME);
+        assert!(recorder.bundle(up_to).is_err());
+    }
+}
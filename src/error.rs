@@ -24,10 +24,16 @@ pub enum GgrsError {
         current_frame: Frame,
         /// The frames with mismatched checksums (one or more)
         mismatched_frames: Vec<Frame>,
+        /// The names of the sub-checksums that differ in any of these frames, if the states were
+        /// saved with [`GameStateCell::save_with_sub_checksums()`].
+        ///
+        /// [`GameStateCell::save_with_sub_checksums()`]: crate::GameStateCell::save_with_sub_checksums
+        mismatched_sub_checksums: Vec<String>,
     },
     /// The Session is not synchronized yet. Please start the session and wait a few ms to let the clients synchronize.
     NotSynchronized,
-    /// The spectator got so far behind the host that catching up is impossible.
+    /// The spectator got so far behind the host that catching up is impossible. Spectators with
+    /// state transfer enabled skip ahead to a snapshot of the host instead.
     SpectatorTooFarBehind,
     /// Not enough data has been collected yet to compute the requested statistics.
     /// This is returned by [`network_stats`] when less than one second has elapsed since the
@@ -59,11 +65,19 @@ impl Display for GgrsError {
             Self::MismatchedChecksum {
                 current_frame,
                 mismatched_frames,
+                mismatched_sub_checksums,
             } => {
                 write!(
                     f,
                     "Detected checksum mismatch during rollback on frame {current_frame}, mismatched frames: {mismatched_frames:?}",
-                )
+                )?;
+                if !mismatched_sub_checksums.is_empty() {
+                    write!(
+                        f,
+                        ", mismatched sub-checksums: {mismatched_sub_checksums:?}"
+                    )?;
+                }
+                Ok(())
             }
             Self::SpectatorTooFarBehind => {
                 write!(
@@ -1,5 +1,8 @@
 use crate::{Frame, NULL_FRAME};
 
+/// Named checksums of parts of a gamestate, sorted by name.
+pub(crate) type SubChecksums = Vec<(String, u128)>;
+
 /// Represents the game state of your game for a single frame. The `data` holds the game state, `frame` indicates the associated frame number
 /// and `checksum` can additionally be provided for use during a `SyncTestSession`.
 #[derive(Debug, Clone)]
@@ -10,6 +13,8 @@ pub(crate) struct GameState<S> {
     pub data: Option<S>,
     /// The checksum of the gamestate.
     pub checksum: Option<u128>,
+    /// Checksums of named parts of the gamestate, sorted by name.
+    pub sub_checksums: SubChecksums,
 }
 
 impl<S> Default for GameState<S> {
@@ -18,6 +23,7 @@ impl<S> Default for GameState<S> {
             frame: NULL_FRAME,
             data: None,
             checksum: None,
+            sub_checksums: Vec::new(),
         }
     }
 }
@@ -0,0 +1,180 @@
+use crate::Frame;
+
+/// Settings for adjusting the input delay of local players automatically, see
+/// [`SessionBuilder::with_adaptive_input_delay()`].
+///
+/// Every `interval` frames, the session estimates how many frames it takes for inputs to reach the
+/// remote peers: half the highest round trip time, converted to frames, plus the frames the session
+/// is ahead of the remotes. Up to `rollback_frames` of those are left to rollbacks, the rest is
+/// covered with input delay. If the session had to stop at the prediction threshold since the last
+/// evaluation, it asks for one more frame of delay than it currently uses.
+///
+/// The delay moves by at most one frame per evaluation. It increases as soon as more delay is
+/// needed, but only decreases once the needed delay is more than `hysteresis` frames below the
+/// current one, so small ping fluctuations do not make the delay flip back and forth.
+///
+/// Every peer adjusts only the delay of its own local players, from its own measurements, so the
+/// delays of the peers may differ. Remote peers are not told about the changes and do not emit
+/// [`GgrsEvent::InputDelayChanged`]; they simply keep receiving an input for every frame.
+///
+/// [`GgrsEvent::InputDelayChanged`]: crate::GgrsEvent::InputDelayChanged
+/// [`SessionBuilder::with_adaptive_input_delay()`]: crate::SessionBuilder::with_adaptive_input_delay
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AdaptiveInputDelay {
+    /// The lowest input delay the session uses.
+    pub min_delay: usize,
+    /// The highest input delay the session uses.
+    pub max_delay: usize,
+    /// How many frames of latency are hidden by rollbacks instead of input delay.
+    pub rollback_frames: usize,
+    /// How far the needed delay has to drop below the current delay before it is decreased.
+    pub hysteresis: usize,
+    /// The number of frames between two evaluations. Must be higher than 0.
+    pub interval: usize,
+}
+
+impl Default for AdaptiveInputDelay {
+    fn default() -> Self {
+        Self {
+            min_delay: 0,
+            max_delay: 6,
+            rollback_frames: 2,
+            hysteresis: 1,
+            interval: 60,
+        }
+    }
+}
+
+/// Applies the [`AdaptiveInputDelay`] rules to the measurements of a session.
+#[derive(Debug)]
+pub(crate) struct InputDelayController {
+    settings: AdaptiveInputDelay,
+    delay: usize,
+    next_evaluation: Frame,
+    /// How often the session stopped at the prediction threshold since the last evaluation.
+    threshold_hits: usize,
+}
+
+impl InputDelayController {
+    pub(crate) fn new(settings: AdaptiveInputDelay, delay: usize) -> Self {
+        Self {
+            settings,
+            delay,
+            next_evaluation: settings.interval as Frame,
+            threshold_hits: 0,
+        }
+    }
+
+    pub(crate) fn on_prediction_threshold(&mut self) {
+        self.threshold_hits += 1;
+    }
+
+    /// Evaluates the measurements if the next evaluation is due. Returns the new delay if it changed.
+    pub(crate) fn update(
+        &mut self,
+        frame: Frame,
+        round_trip_time: u128,
+        frame_advantage: i32,
+        fps: usize,
+    ) -> Option<usize> {
+        if frame < self.next_evaluation {
+            return None;
+        }
+        self.next_evaluation = frame + self.settings.interval as Frame;
+        let threshold_hits = std::mem::take(&mut self.threshold_hits);
+
+        let latency_frames =
+            usize::try_from((round_trip_time * fps as u128).div_ceil(2000)).unwrap_or(usize::MAX);
+        let mut target = latency_frames
+            .saturating_add(frame_advantage.max(0) as usize)
+            .saturating_sub(self.settings.rollback_frames);
+        if threshold_hits > 0 {
+            target = target.max(self.delay + 1);
+        }
+        let target = target.clamp(self.settings.min_delay, self.settings.max_delay);
+
+        let delay = if target > self.delay {
+            self.delay + 1
+        } else if target + self.settings.hysteresis < self.delay {
+            self.delay - 1
+        } else {
+            self.delay
+        };
+        if delay == self.delay {
+            return None;
+        }
+        self.delay = delay;
+        Some(delay)
+    }
+}
+
+// #########
+// # TESTS #
+// #########
+
+#[cfg(test)]
+mod input_delay_tests {
+    use super::*;
+
+    const FPS: usize = 60;
+
+    fn controller(delay: usize) -> InputDelayController {
+        InputDelayController::new(
+            AdaptiveInputDelay {
+                min_delay: 1,
+                max_delay: 4,
+                rollback_frames: 2,
+                hysteresis: 1,
+                interval: 10,
+            },
+            delay,
+        )
+    }
+
+    #[test]
+    fn test_waits_for_the_interval() {
+        let mut controller = controller(1);
+        assert_eq!(controller.update(9, 500, 0, FPS), None);
+        assert_eq!(controller.update(10, 500, 0, FPS), Some(2));
+        assert_eq!(controller.update(19, 500, 0, FPS), None);
+        assert_eq!(controller.update(20, 500, 0, FPS), Some(3));
+    }
+
+    #[test]
+    fn test_increases_one_frame_at_a_time_up_to_the_maximum() {
+        let mut controller = controller(1);
+        let delays: Vec<_> = (1..=5)
+            .map(|i| controller.update(i * 10, 1000, 0, FPS))
+            .collect();
+        assert_eq!(delays, vec![Some(2), Some(3), Some(4), None, None]);
+    }
+
+    #[test]
+    fn test_latency_and_frame_advantage_count_beyond_rollback_frames() {
+        // 100ms round trip time is 3 frames one way, the rollback covers 2 of them
+        let mut controller = controller(1);
+        assert_eq!(controller.update(10, 100, 0, FPS), None);
+        // being 1 frame ahead of the remote adds a frame
+        assert_eq!(controller.update(20, 100, 1, FPS), Some(2));
+        assert_eq!(controller.update(30, 100, 1, FPS), None);
+    }
+
+    #[test]
+    fn test_decreases_only_beyond_hysteresis() {
+        let mut controller = controller(3);
+        // a target of 2 is within the hysteresis
+        assert_eq!(controller.update(10, 120, 0, FPS), None);
+        // a target of 1 is not
+        assert_eq!(controller.update(20, 0, 0, FPS), Some(2));
+        assert_eq!(controller.update(30, 0, 0, FPS), None);
+    }
+
+    #[test]
+    fn test_prediction_threshold_asks_for_more_delay() {
+        let mut controller = controller(1);
+        controller.on_prediction_threshold();
+        assert_eq!(controller.update(10, 0, 0, FPS), Some(2));
+        // the hits are counted per evaluation
+        assert_eq!(controller.update(20, 0, 0, FPS), None);
+    }
+}
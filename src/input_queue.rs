@@ -3,7 +3,7 @@ use crate::{Config, Frame, InputPredictor, InputStatus, NULL_FRAME};
 use std::cmp;
 
 /// The length of the input queue. This describes the number of inputs GGRS can hold at the same time per player.
-const INPUT_QUEUE_LENGTH: usize = 128;
+pub(crate) const INPUT_QUEUE_LENGTH: usize = 128;
 
 /// `InputQueue` handles inputs for a single player and saves them in a circular array. Valid Inputs are between `head` and `tail`.
 #[derive(Debug, Clone)]
@@ -87,6 +87,36 @@ impl<T: Config> InputQueue<T> {
             .collect()
     }
 
+    /// Discards all inputs and restarts the queue so that the next input added is expected for
+    /// `start_frame`. A blank input for `start_frame - 1` is kept as the base for predictions.
+    /// The frame delay is kept; like [`set_frame_delay()`], this returns the fill inputs for the
+    /// delayed frames that will be implicitly added with the next input.
+    ///
+    /// [`set_frame_delay()`]: Self::set_frame_delay
+    pub(crate) fn reset(&mut self, start_frame: Frame) -> Vec<PlayerInput<T::Input>> {
+        assert!(start_frame >= 0);
+        let frame_delay = self.frame_delay;
+        *self = Self::new();
+        self.frame_delay = frame_delay;
+
+        if start_frame > 0 {
+            // inputs are always stored at the position given by their frame
+            let base_frame = start_frame - 1;
+            let base_pos = base_frame as usize % INPUT_QUEUE_LENGTH;
+            self.inputs[base_pos] = PlayerInput::blank_input(base_frame);
+            self.tail = base_pos;
+            self.head = (base_pos + 1) % INPUT_QUEUE_LENGTH;
+            self.length = 1;
+            self.first_frame = false;
+            self.last_added_frame = base_frame;
+            self.last_user_frame = base_frame;
+        }
+
+        (0..frame_delay as i32)
+            .map(|i| PlayerInput::blank_input(start_frame + i))
+            .collect()
+    }
+
     pub(crate) fn reset_prediction(&mut self) {
         self.prediction.frame = NULL_FRAME;
         self.first_incorrect_frame = NULL_FRAME;
@@ -505,6 +535,58 @@ mod input_queue_tests {
         );
     }
 
+    #[test]
+    fn test_reset_accepts_inputs_from_start_frame() {
+        let mut queue = InputQueue::<TestConfig>::new();
+        for i in 0..5_i32 {
+            queue.add_input(PlayerInput::new(i, TestInput { inp: i as u8 }));
+        }
+
+        let fills = queue.reset(20);
+        assert!(fills.is_empty());
+
+        // inputs before the start frame are dropped, the start frame itself is accepted
+        assert_eq!(
+            queue.add_input(PlayerInput::new(5, TestInput { inp: 5 })),
+            NULL_FRAME
+        );
+        assert_eq!(
+            queue.add_input(PlayerInput::new(20, TestInput { inp: 20 })),
+            20
+        );
+        assert_eq!(queue.confirmed_input(20).input.inp, 20);
+    }
+
+    #[test]
+    fn test_reset_predicts_blank_input_at_start_frame() {
+        let mut queue = InputQueue::<TestConfig>::new();
+        for i in 0..5_i32 {
+            queue.add_input(PlayerInput::new(i, TestInput { inp: 9 }));
+        }
+
+        queue.reset(10);
+        let (predicted, status) = queue.input(10);
+        assert_eq!(status, InputStatus::Predicted);
+        assert_eq!(predicted.inp, 0);
+    }
+
+    #[test]
+    fn test_reset_keeps_frame_delay_and_returns_fills() {
+        let mut queue = InputQueue::<TestConfig>::new();
+        queue.set_frame_delay(2);
+
+        let fills = queue.reset(10);
+        assert_eq!(fills.len(), 2);
+        assert_eq!(fills[0].frame, 10);
+        assert_eq!(fills[1].frame, 11);
+
+        assert_eq!(
+            queue.add_input(PlayerInput::new(10, TestInput { inp: 1 })),
+            12
+        );
+        assert_eq!(queue.confirmed_input(11).input.inp, 0);
+    }
+
     #[test]
     fn test_queue_wraps_around_without_panic() {
         let mut queue = InputQueue::<TestConfig>::new();
@@ -88,6 +88,7 @@
 //! | [`P2PSession`] | Main multiplayer session; connects peers directly. |
 //! | [`SpectatorSession`] | Watch a game without contributing input. |
 //! | [`SyncTestSession`] | Local determinism testing; no network required. |
+//! | [`ReplaySession`] | Play back a replay recorded by any of the sessions above. |
 //!
 //! All session types are constructed with [`SessionBuilder`].
 //!
@@ -110,34 +111,54 @@ use std::{
     hash::Hash,
 };
 
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use desync_bundle::{DesyncBundle, DESYNC_BUNDLE_FORMAT_VERSION};
 pub use error::GgrsError;
-pub use network::messages::Message;
+pub use input_delay::AdaptiveInputDelay;
+pub use network::channel_socket::{ChannelNetwork, ChannelSocket};
+pub use network::messages::{ConfigFingerprint, Message};
+pub use network::network_simulator::{NetworkConditions, NetworkSimulator};
 pub use network::network_stats::NetworkStats;
 pub use network::udp_socket::UdpNonBlockingSocket;
+pub use replay::{
+    InputEncoding, ReplayFrame, ReplayHeader, ReplayReader, ReplaySessionKind, ReplayWriter,
+    REPLAY_FORMAT_VERSION,
+};
 use serde::{de::DeserializeOwned, Serialize};
 pub use sessions::builder::SessionBuilder;
 pub use sessions::p2p_session::P2PSession;
 pub use sessions::p2p_spectator_session::SpectatorSession;
+pub use sessions::replay_session::ReplaySession;
 pub use sessions::sync_test_session::SyncTestSession;
 pub use sync_layer::{GameStateAccessor, GameStateCell};
 
+pub(crate) mod clock;
+pub(crate) mod desync_bundle;
 pub(crate) mod error;
 pub(crate) mod frame_info;
+pub(crate) mod input_delay;
 pub(crate) mod input_queue;
+pub(crate) mod replay;
 pub(crate) mod sync_layer;
 pub(crate) mod time_sync;
 pub(crate) mod sessions {
     pub(crate) mod builder;
     pub(crate) mod p2p_session;
     pub(crate) mod p2p_spectator_session;
+    pub(crate) mod replay_session;
     pub(crate) mod sync_test_session;
 }
 pub(crate) mod network {
+    pub(crate) mod channel_socket;
     pub(crate) mod compression;
     pub(crate) mod messages;
+    pub(crate) mod network_simulator;
     pub(crate) mod network_stats;
     pub(crate) mod protocol;
+    pub(crate) mod security;
+    pub(crate) mod state_transfer;
     pub(crate) mod udp_socket;
+    pub(crate) mod user_messages;
 }
 
 // #############
@@ -150,6 +171,10 @@ pub const NULL_FRAME: i32 = -1;
 pub type Frame = i32;
 /// Each player is identified by a player handle.
 pub type PlayerHandle = usize;
+/// The largest payload in bytes that can be sent with [`P2PSession::send_user_message()`].
+pub const MAX_USER_MESSAGE_SIZE: usize = 512;
+/// The largest join token in bytes that can be set with [`SessionBuilder::with_join_token()`].
+pub const MAX_JOIN_TOKEN_SIZE: usize = 256;
 
 // #############
 // #   ENUMS   #
@@ -168,6 +193,57 @@ pub enum DesyncDetection {
     Off,
 }
 
+/// Which peer, if any, brings the other peers back in sync after a desync was detected. The state
+/// of the authority is considered correct; the other peers load it and resimulate from there.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum DesyncRecovery {
+    /// Desyncs are only reported, not recovered from.
+    #[default]
+    Off,
+    /// The peer hosting the lowest player handle that is still connected is the authority.
+    LowestHandle,
+    /// The peer hosting the given player is the authority. If that player disconnects, the peer
+    /// hosting the lowest connected player handle takes over, as with [`LowestHandle`].
+    ///
+    /// [`LowestHandle`]: DesyncRecovery::LowestHandle
+    Authority {
+        /// The handle of the player whose peer is the authority.
+        player_handle: PlayerHandle,
+    },
+}
+
+/// How packets between peers are protected. Both modes use a 32 byte key that all peers of a match
+/// share and that you have to hand out through your own, trusted channel, for example your
+/// matchmaking server. Packets that were forged, altered or replayed are dropped, and so is every
+/// packet of a peer that uses a different key or mode.
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
+pub enum PacketSecurity {
+    /// Packets are only identified by a random magic number.
+    #[default]
+    Off,
+    /// Packets carry a message authentication code, but their contents are readable.
+    Authenticated {
+        /// The key shared by all peers of the match.
+        key: [u8; 32],
+    },
+    /// Packets carry a message authentication code and their contents are encrypted.
+    Encrypted {
+        /// The key shared by all peers of the match.
+        key: [u8; 32],
+    },
+}
+
+impl Debug for PacketSecurity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the key
+        match self {
+            Self::Off => f.write_str("Off"),
+            Self::Authenticated { .. } => f.write_str("Authenticated"),
+            Self::Encrypted { .. } => f.write_str("Encrypted"),
+        }
+    }
+}
+
 /// Defines the three types of players that GGRS considers:
 /// - local players, who play on the local device,
 /// - remote players, who play on other devices and
@@ -218,6 +294,16 @@ impl fmt::Display for DesyncDetection {
     }
 }
 
+impl fmt::Display for DesyncRecovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Off => write!(f, "Off"),
+            Self::LowestHandle => write!(f, "LowestHandle"),
+            Self::Authority { player_handle } => write!(f, "Authority ({player_handle})"),
+        }
+    }
+}
+
 impl<A> fmt::Display for PlayerType<A>
 where
     A: Clone + PartialEq + Eq + Hash + fmt::Display,
@@ -251,7 +337,7 @@ impl fmt::Display for InputStatus {
 }
 
 /// Notifications that you can receive from the session. Handling them is up to the user.
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum GgrsEvent<T>
 where
     T: Config,
@@ -275,6 +361,17 @@ where
         /// The address of the endpoint.
         addr: T::Address,
     },
+    /// The remote client runs with settings that are incompatible with ours, so the session
+    /// rejected it while synchronizing. A [`GgrsEvent::Disconnected`] for the same address follows.
+    /// [`ConfigFingerprint::mismatches()`] names the settings that differ.
+    ConfigMismatch {
+        /// The address of the endpoint.
+        addr: T::Address,
+        /// Our settings.
+        local: ConfigFingerprint,
+        /// The settings of the remote client.
+        remote: ConfigFingerprint,
+    },
     /// The session has not received packets from the remote client for some time and will disconnect the remote in `disconnect_timeout` ms.
     NetworkInterrupted {
         /// The address of the endpoint.
@@ -292,6 +389,84 @@ where
         /// Amount of frames recommended to be skipped in order to let other clients catch up.
         skip_frames: u32,
     },
+    /// A client the session does not know yet wants to connect. Accept it as a player or spectator
+    /// with [`P2PSession::accept_connection_request()`] or turn it away with
+    /// [`P2PSession::reject_connection_request()`].
+    ConnectionRequest {
+        /// The address of the client.
+        addr: T::Address,
+        /// The token the client set with [`SessionBuilder::with_join_token()`], if any.
+        token: Option<Vec<u8>>,
+    },
+    /// A remote client asks to take over a reserved player slot of the running match. The session
+    /// admits the player on its own; use this event to show the join in your UI.
+    JoinRequested {
+        /// The address of the joining endpoint.
+        addr: T::Address,
+        /// The reserved player handle the client wants to play as.
+        player_handle: PlayerHandle,
+    },
+    /// A player joined the running match. From `frame` on, the inputs of this player are part of
+    /// the game. Before that frame, the player is reported as [`InputStatus::Disconnected`].
+    PlayerJoined {
+        /// The handle of the player that joined.
+        player_handle: PlayerHandle,
+        /// The first frame the player takes part in.
+        frame: Frame,
+    },
+    /// A player that was disconnected rejoined the running match. From `frame` on, the inputs of
+    /// this player are part of the game again.
+    PlayerRejoined {
+        /// The handle of the player that rejoined.
+        player_handle: PlayerHandle,
+        /// The first frame the player takes part in again.
+        frame: Frame,
+    },
+    /// A spectator synchronized with the session. From `frame` on, the session sends the confirmed
+    /// inputs of all players to the spectator.
+    SpectatorJoined {
+        /// The address of the spectator.
+        addr: T::Address,
+        /// The handle of the spectator.
+        player_handle: PlayerHandle,
+        /// The first frame the spectator receives inputs for.
+        frame: Frame,
+    },
+    /// A spectator left the session, either because it was removed with
+    /// [`P2PSession::remove_spectator()`] or because it disconnected. Its handle and address can be
+    /// used for another spectator.
+    SpectatorLeft {
+        /// The address of the spectator.
+        addr: T::Address,
+        /// The handle the spectator had.
+        player_handle: PlayerHandle,
+    },
+    /// The spectator fell so far behind the host that the inputs it needed were gone, so it
+    /// continues from a snapshot of the host instead. The frames from `from` up to `to` are skipped;
+    /// the next [`SpectatorSession::advance_frame()`] loads the state of frame `to`.
+    FramesSkipped {
+        /// The first frame the spectator skipped.
+        from: Frame,
+        /// The frame of the snapshot the spectator continues from.
+        to: Frame,
+    },
+    /// The session changed the input delay of its local players, because adaptive input delay is
+    /// enabled with [`SessionBuilder::with_adaptive_input_delay()`]. Only the session that changed
+    /// its delay emits this event; remote peers are not notified.
+    ///
+    /// [`SessionBuilder::with_adaptive_input_delay()`]: crate::SessionBuilder::with_adaptive_input_delay
+    InputDelayChanged {
+        /// The new input delay in frames.
+        delay: usize,
+    },
+    /// A remote peer sent a message with [`P2PSession::send_user_message()`]. Messages from the
+    /// same peer arrive in the order they were sent.
+    UserMessage {
+        /// The address of the endpoint that sent the message.
+        addr: T::Address,
+        /// The bytes the remote peer sent.
+        payload: Vec<u8>,
+    },
     /// Sent whenever GGRS locally detected a discrepancy between local and remote checksums
     DesyncDetected {
         /// Frame of the checksums
@@ -302,6 +477,63 @@ where
         remote_checksum: u128,
         /// remote address of the endpoint.
         addr: T::Address,
+        /// Whether both peers simulated the frame with the same confirmed inputs. If they did, the
+        /// game simulation is nondeterministic; if not, the inputs themselves diverged. `None` if
+        /// one of the peers does not know all inputs since the start of the match, e.g. after a
+        /// late join.
+        inputs_match: Option<bool>,
+        /// The names of the sub-checksums that differ, if the states were saved with
+        /// [`GameStateCell::save_with_sub_checksums()`].
+        mismatched_sub_checksums: Vec<String>,
+    },
+    /// The checksum of a frame could not be compared with the endpoint at `addr`, because its
+    /// checksum report did not arrive while we still remembered our own checksum of that frame.
+    /// A desync in that frame would go unnoticed.
+    ChecksumUnverified {
+        /// Frame of the checksum
+        frame: Frame,
+        /// remote address of the endpoint.
+        addr: T::Address,
+    },
+    /// The checksums of a frame differ between three or more peers, and most of the peers agree on
+    /// one checksum. Every peer reports its checksums to all other peers, so each session can tally
+    /// them. The players hosted by the peers that disagree with the majority are listed in
+    /// `outliers`, which includes our own local players if we are the ones who diverged.
+    DesyncAttributed {
+        /// Frame of the checksums
+        frame: Frame,
+        /// the checksum most peers agree on
+        consensus_checksum: u128,
+        /// handles of all players hosted by peers with a different checksum, in ascending order
+        outliers: Vec<PlayerHandle>,
+    },
+    /// A remote peer sent its saved state of a frame that caused a [`GgrsEvent::DesyncDetected`],
+    /// because desync state exchange is enabled with
+    /// [`SessionBuilder::with_desync_state_exchange()`]. Both states are serialized with `bincode`
+    /// and can be deserialized and compared to find the cause of the desync.
+    ///
+    /// [`SessionBuilder::with_desync_state_exchange()`]: crate::SessionBuilder::with_desync_state_exchange
+    DesyncStateReceived {
+        /// Frame of the states
+        frame: Frame,
+        /// remote address of the endpoint.
+        addr: T::Address,
+        /// our serialized state of the given frame
+        local_state: Vec<u8>,
+        /// the serialized state of the given frame of the remote peer
+        remote_state: Vec<u8>,
+    },
+    /// The session replaced its game state with the state of the recovery authority after a desync,
+    /// because desync recovery is enabled with [`SessionBuilder::with_desync_recovery()`]. The
+    /// requests to load that state and resimulate up to the current frame were returned by the
+    /// same call to [`P2PSession::advance_frame()`].
+    ///
+    /// [`SessionBuilder::with_desync_recovery()`]: crate::SessionBuilder::with_desync_recovery
+    DesyncRecovered {
+        /// Frame of the loaded state
+        frame: Frame,
+        /// remote address of the authority.
+        addr: T::Address,
     },
 }
 
@@ -324,6 +556,16 @@ where
         /// The given `frame` is a sanity check: The gamestate you load is from that frame.
         frame: Frame,
     },
+    /// You should compute the checksum of the current gamestate and store it in the `cell` provided
+    /// to you, without saving the gamestate itself. Only sessions that never save the gamestate
+    /// send this request when desync detection is enabled: P2P sessions in lockstep mode and
+    /// spectator sessions.
+    ReportChecksum {
+        /// Use `cell.save(frame, None, Some(checksum))` to report the checksum.
+        cell: GameStateCell<T::State>,
+        /// The given `frame` is a sanity check: The checksum you report should be from that frame.
+        frame: Frame,
+    },
     /// You should advance the gamestate with the `inputs` provided to you.
     /// Disconnected players are indicated by having [`NULL_FRAME`] instead of the correct current frame in their input.
     AdvanceFrame {
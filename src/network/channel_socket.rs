@@ -0,0 +1,213 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+use crate::{network::messages::Message, NonBlockingSocket};
+
+type Mailboxes<A> = Arc<Mutex<MailboxRegistry<A>>>;
+
+#[derive(Debug)]
+struct MailboxRegistry<A> {
+    next_socket_id: u64,
+    /// Received messages by address, together with the id of the socket owning the address.
+    mailboxes: HashMap<A, (u64, VecDeque<(A, Message)>)>,
+}
+
+/// A set of connected in-memory sockets. Every [`ChannelSocket`] created from the same network can
+/// send messages to all other sockets of that network, identified by their address.
+///
+/// Messages are delivered instantly, in order and without loss, so sessions wired up with channel
+/// sockets behave the same on every run. This makes them useful for tests and local tools that run
+/// several sessions in one process, without touching the network stack of the operating system.
+///
+/// ```
+/// # use ggrs::{ChannelNetwork, PlayerType, SessionBuilder};
+/// # struct GgrsConfig;
+/// # impl ggrs::Config for GgrsConfig { type Input = u8; type InputPredictor = ggrs::PredictRepeatLast; type State = u8; type Address = &'static str; }
+/// let network = ChannelNetwork::new();
+/// let session = SessionBuilder::<GgrsConfig>::new()
+///     .add_player(PlayerType::Local, 0)?
+///     .add_player(PlayerType::Remote("bob"), 1)?
+///     .start_p2p_session(network.socket("alice"))?;
+/// # Ok::<(), ggrs::GgrsError>(())
+/// ```
+#[derive(Debug)]
+pub struct ChannelNetwork<A> {
+    mailboxes: Mailboxes<A>,
+}
+
+impl<A: Clone + Eq + Hash> ChannelNetwork<A> {
+    /// Creates a network without any sockets.
+    pub fn new() -> Self {
+        Self {
+            mailboxes: Arc::new(Mutex::new(MailboxRegistry {
+                next_socket_id: 0,
+                mailboxes: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Creates a socket that receives all messages sent to `addr` on this network. Messages that were
+    /// sent to `addr` before the socket existed are lost, just like on a real network. Creating a
+    /// second socket for the same address replaces the first one, which stops receiving.
+    pub fn socket(&self, addr: A) -> ChannelSocket<A> {
+        let mut registry = self.mailboxes.lock();
+        let id = registry.next_socket_id;
+        registry.next_socket_id += 1;
+        registry
+            .mailboxes
+            .insert(addr.clone(), (id, VecDeque::new()));
+        ChannelSocket {
+            id,
+            addr,
+            mailboxes: self.mailboxes.clone(),
+        }
+    }
+}
+
+impl<A: Clone + Eq + Hash> Default for ChannelNetwork<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An in-memory socket created by a [`ChannelNetwork`]. Messages to addresses without a socket are
+/// dropped. When the socket is dropped, its address stops receiving messages.
+#[derive(Debug)]
+pub struct ChannelSocket<A: Clone + Eq + Hash> {
+    id: u64,
+    addr: A,
+    mailboxes: Mailboxes<A>,
+}
+
+impl<A: Clone + Eq + Hash> ChannelSocket<A> {
+    /// Returns the address of this socket.
+    pub fn addr(&self) -> &A {
+        &self.addr
+    }
+
+    fn send_message(&mut self, msg: &Message, addr: &A) {
+        if let Some((_, mailbox)) = self.mailboxes.lock().mailboxes.get_mut(addr) {
+            mailbox.push_back((self.addr.clone(), msg.clone()));
+        }
+    }
+
+    fn take_messages(&mut self) -> Vec<(A, Message)> {
+        match self.mailboxes.lock().mailboxes.get_mut(&self.addr) {
+            Some((owner, mailbox)) if *owner == self.id => mailbox.drain(..).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl<A: Clone + Eq + Hash> Drop for ChannelSocket<A> {
+    fn drop(&mut self) {
+        let mut registry = self.mailboxes.lock();
+        if registry
+            .mailboxes
+            .get(&self.addr)
+            .is_some_and(|(owner, _)| *owner == self.id)
+        {
+            registry.mailboxes.remove(&self.addr);
+        }
+    }
+}
+
+#[cfg(feature = "sync-send")]
+impl<A: Clone + Eq + Hash + Send + Sync> NonBlockingSocket<A> for ChannelSocket<A> {
+    fn send_to(&mut self, msg: &Message, addr: &A) {
+        self.send_message(msg, addr);
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(A, Message)> {
+        self.take_messages()
+    }
+}
+
+#[cfg(not(feature = "sync-send"))]
+impl<A: Clone + Eq + Hash> NonBlockingSocket<A> for ChannelSocket<A> {
+    fn send_to(&mut self, msg: &Message, addr: &A) {
+        self.send_message(msg, addr);
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(A, Message)> {
+        self.take_messages()
+    }
+}
+
+// #########
+// # TESTS #
+// #########
+
+#[cfg(test)]
+mod channel_socket_tests {
+    use super::*;
+    use crate::network::messages::{MessageBody, MessageHeader};
+
+    fn message(magic: u16) -> Message {
+        Message {
+            header: MessageHeader { magic },
+            body: MessageBody::KeepAlive,
+        }
+    }
+
+    #[test]
+    fn test_messages_arrive_in_order_with_sender_address() {
+        let network = ChannelNetwork::new();
+        let mut alice = network.socket(1);
+        let mut bob = network.socket(2);
+
+        alice.send_to(&message(10), &2);
+        alice.send_to(&message(11), &2);
+        bob.send_to(&message(20), &1);
+
+        assert_eq!(
+            bob.receive_all_messages(),
+            vec![(1, message(10)), (1, message(11))]
+        );
+        assert!(bob.receive_all_messages().is_empty());
+        assert_eq!(alice.receive_all_messages(), vec![(2, message(20))]);
+    }
+
+    #[test]
+    fn test_messages_to_unknown_address_are_dropped() {
+        let network = ChannelNetwork::new();
+        let mut alice = network.socket(1);
+        alice.send_to(&message(0), &2);
+
+        // a socket created afterwards does not see earlier messages
+        let mut bob = network.socket(2);
+        assert!(bob.receive_all_messages().is_empty());
+    }
+
+    #[test]
+    fn test_dropped_socket_stops_receiving() {
+        let network = ChannelNetwork::new();
+        let mut alice = network.socket(1);
+        let bob = network.socket(2);
+        drop(bob);
+
+        alice.send_to(&message(0), &2);
+        assert!(!network.mailboxes.lock().mailboxes.contains_key(&2));
+    }
+
+    #[test]
+    fn test_new_socket_replaces_old_one() {
+        let network = ChannelNetwork::new();
+        let mut alice = network.socket(1);
+        let mut old_bob = network.socket(2);
+        let mut bob = network.socket(2);
+
+        alice.send_to(&message(0), &2);
+        assert!(old_bob.receive_all_messages().is_empty());
+        drop(old_bob);
+
+        // dropping the old socket does not affect the new one
+        alice.send_to(&message(1), &2);
+        assert_eq!(bob.receive_all_messages().len(), 2);
+    }
+}
@@ -1,11 +1,23 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{Frame, NULL_FRAME};
+use crate::frame_info::SubChecksums;
+use crate::{Frame, PlayerHandle, NULL_FRAME};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct ConnectionStatus {
     pub disconnected: bool,
     pub last_frame: Frame,
+    /// The first frame this player takes part in. Players present at session start have a connect
+    /// frame of 0, players that join a running match have it set to their join frame.
+    pub connect_frame: Frame,
+}
+
+impl ConnectionStatus {
+    /// Returns true if the player does not contribute inputs to the given frame, either because
+    /// it disconnected before that frame or because it only joins after it.
+    pub fn disconnected_at(&self, frame: Frame) -> bool {
+        (self.disconnected && self.last_frame < frame) || frame < self.connect_frame
+    }
 }
 
 impl Default for ConnectionStatus {
@@ -13,18 +25,89 @@ impl Default for ConnectionStatus {
         Self {
             disconnected: false,
             last_frame: NULL_FRAME,
+            connect_frame: 0,
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+/// The settings of a session that peers need to agree on. Peers exchange their fingerprints while
+/// synchronizing and reject each other with a [`GgrsEvent::ConfigMismatch`] if they differ.
+///
+/// [`GgrsEvent::ConfigMismatch`]: crate::GgrsEvent::ConfigMismatch
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ConfigFingerprint {
+    /// The version of the GGRS network protocol.
+    pub protocol_version: u32,
+    /// Whether the fingerprint belongs to a [`SpectatorSession`](crate::SpectatorSession).
+    pub spectator: bool,
+    /// The number of players of the session.
+    pub num_players: usize,
+    /// The size of a single serialized input.
+    pub input_size: u64,
+    /// The maximum prediction window of the session. It is only informational, since peers can
+    /// use different windows, which [`P2PSession::set_max_prediction()`] may change at any time.
+    ///
+    /// [`P2PSession::set_max_prediction()`]: crate::P2PSession::set_max_prediction
+    pub max_prediction: usize,
+    /// The desync detection interval of the session, if desync detection is on.
+    pub desync_detection_interval: Option<u32>,
+    /// The expected update frequency of the session.
+    pub fps: usize,
+    /// The build hash of the game, if one was set with
+    /// [`SessionBuilder::with_build_hash()`](crate::SessionBuilder::with_build_hash).
+    pub build_hash: Option<u64>,
+    /// Whether the session can send and load game states, as enabled with
+    /// [`SessionBuilder::with_state_transfer()`](crate::SessionBuilder::with_state_transfer). It
+    /// is only informational: spectators only ask hosts that have it enabled for snapshots.
+    pub state_transfer: bool,
+}
+
+impl ConfigFingerprint {
+    /// Returns the names of all settings that keep the owners of the two fingerprints from playing
+    /// together. A spectator only needs to agree with its host on the protocol version, the number
+    /// of players, the input size and the build hash.
+    pub fn mismatches(&self, other: &Self) -> Vec<&'static str> {
+        let mut mismatches = Vec::new();
+        if self.protocol_version != other.protocol_version {
+            mismatches.push("protocol_version");
+        }
+        if self.num_players != other.num_players {
+            mismatches.push("num_players");
+        }
+        if self.input_size != other.input_size {
+            mismatches.push("input_size");
+        }
+        if self.build_hash != other.build_hash {
+            mismatches.push("build_hash");
+        }
+        if self.spectator || other.spectator {
+            return mismatches;
+        }
+        if self.desync_detection_interval != other.desync_detection_interval {
+            mismatches.push("desync_detection_interval");
+        }
+        if self.fps != other.fps {
+            mismatches.push("fps");
+        }
+        mismatches
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct SyncRequest {
     pub random_request: u32, // please reply back with this random data
+    pub fingerprint: ConfigFingerprint,
+    /// The join token of the sender, which a session that does not know the sender yet passes on
+    /// to the user in a [`GgrsEvent::ConnectionRequest`].
+    ///
+    /// [`GgrsEvent::ConnectionRequest`]: crate::GgrsEvent::ConnectionRequest
+    pub join_token: Option<Vec<u8>>,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct SyncReply {
     pub random_reply: u32, // here's your random data back
+    pub fingerprint: ConfigFingerprint,
 }
 
 #[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -107,10 +190,150 @@ pub(crate) struct QualityReply {
     pub pong: u128,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub(crate) struct ChecksumReport {
     pub checksum: u128,
     pub frame: Frame,
+    /// hash of all confirmed inputs before `frame`, if the sender knows all of them
+    pub input_hash: Option<u64>,
+    /// named checksums of parts of the state, sorted by name
+    pub sub_checksums: SubChecksums,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub(crate) struct ChecksumReportAck {
+    pub frame: Frame,
+}
+
+/// One piece of a [`StateTransfer`] that is too large to fit into a single packet.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub(crate) struct StateChunk {
+    pub transfer_id: u32,
+    pub index: u32,
+    pub count: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl std::fmt::Debug for StateChunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateChunk")
+            .field("transfer_id", &self.transfer_id)
+            .field("index", &self.index)
+            .field("count", &self.count)
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub(crate) struct StateChunkAck {
+    pub transfer_id: u32,
+    pub index: u32,
+}
+
+/// An application message that is delivered reliably and in order, see
+/// [`P2PSession::send_user_message()`].
+///
+/// [`P2PSession::send_user_message()`]: crate::P2PSession::send_user_message
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub(crate) struct UserMessage {
+    pub seq: u32,
+    pub payload: Vec<u8>,
+}
+
+impl std::fmt::Debug for UserMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserMessage")
+            .field("seq", &self.seq)
+            .field("len", &self.payload.len())
+            .finish()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub(crate) struct UserMessageAck {
+    pub seq: u32,
+}
+
+/// A spectator asks its host for a snapshot, because it joined a running match or fell too far
+/// behind. The host only answers if it has not yet sent a snapshot of a later frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub(crate) struct SnapshotRequest {
+    /// the next frame the spectator needs to simulate
+    pub frame: Frame,
+}
+
+/// A message body protected with the session key set through
+/// [`SessionBuilder::with_packet_security()`]. The payload holds the serialized [`MessageBody`],
+/// encrypted or in plain text, followed by its authentication tag. Every packet is sealed with its
+/// own random nonce. Together, `sender` and `counter` identify the packet, so receivers can drop
+/// replayed packets.
+///
+/// [`SessionBuilder::with_packet_security()`]: crate::SessionBuilder::with_packet_security
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub(crate) struct Sealed {
+    pub sender: u64,
+    pub counter: u64,
+    pub nonce: [u8; 24],
+    pub encrypted: bool,
+    pub payload: Vec<u8>,
+}
+
+impl std::fmt::Debug for Sealed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sealed")
+            .field("sender", &self.sender)
+            .field("counter", &self.counter)
+            .field("encrypted", &self.encrypted)
+            .field("len", &self.payload.len())
+            .finish()
+    }
+}
+
+/// Everything a late joining peer needs to enter a running match: a saved game state, the confirmed
+/// inputs from the frame of that state up to the join frame and the connection status of all
+/// players as seen by the peer sending the snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct JoinSnapshot {
+    pub join_frame: Frame,
+    pub player_handles: Vec<PlayerHandle>,
+    pub state_frame: Frame,
+    pub state: Vec<u8>,
+    /// serialized `Vec<T::Input>` for every frame in `state_frame..join_frame`
+    pub inputs: Vec<Vec<u8>>,
+    pub connect_status: Vec<ConnectionStatus>,
+}
+
+/// The serialized saved state of a frame for which the checksums of two peers did not match.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DesyncState {
+    pub frame: Frame,
+    pub state: Vec<u8>,
+}
+
+/// The serialized saved state of the desync recovery authority, which peers that desynced load to
+/// get back in sync.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RecoverySnapshot {
+    pub frame: Frame,
+    pub state: Vec<u8>,
+}
+
+/// The serialized confirmed state a host sends to a spectator that joined the running match, which
+/// the spectator loads before it follows the inputs from that frame on.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SpectatorSnapshot {
+    pub frame: Frame,
+    pub state: Vec<u8>,
+}
+
+/// Session-level payloads that are sent reliably in chunks through [`StateChunk`] messages.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum StateTransfer {
+    JoinSnapshot(JoinSnapshot),
+    DesyncState(DesyncState),
+    RecoverySnapshot(RecoverySnapshot),
+    SpectatorSnapshot(SpectatorSnapshot),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
@@ -127,7 +350,15 @@ pub(crate) enum MessageBody {
     QualityReport(QualityReport),
     QualityReply(QualityReply),
     ChecksumReport(ChecksumReport),
+    ChecksumReportAck(ChecksumReportAck),
+    JoinRequest,
+    StateChunk(StateChunk),
+    StateChunkAck(StateChunkAck),
+    UserMessage(UserMessage),
+    UserMessageAck(UserMessageAck),
     KeepAlive,
+    SnapshotRequest(SnapshotRequest),
+    Sealed(Sealed),
 }
 
 /// A messages that [`NonBlockingSocket`] sends and receives. When implementing [`NonBlockingSocket`],
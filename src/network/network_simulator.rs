@@ -0,0 +1,442 @@
+use std::{collections::BTreeMap, hash::Hash, marker::PhantomData, sync::Arc};
+
+use instant::{Duration, Instant};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{network::messages::Message, Clock, NonBlockingSocket, SystemClock};
+
+/// Describes how a [`NetworkSimulator`] degrades the packets it sends. The default conditions send
+/// every packet right away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkConditions {
+    /// Delay added to every packet.
+    pub latency: Duration,
+    /// Maximum random delay added on top of the latency. Packets with different delays can arrive
+    /// out of order.
+    pub jitter: Duration,
+    /// Probability between 0 and 1 that a single packet is lost.
+    pub packet_loss: f64,
+    /// Probability between 0 and 1 that a packet starts a burst of `burst_length` lost packets.
+    pub burst_loss: f64,
+    /// The number of packets lost in a row once a burst starts.
+    pub burst_length: usize,
+    /// Probability between 0 and 1 that a packet is sent twice. The copy gets its own delay.
+    pub duplication: f64,
+    /// Probability between 0 and 1 that a packet is held back by `reorder_delay`, so later packets
+    /// overtake it.
+    pub reordering: f64,
+    /// Extra delay of packets that are held back for reordering.
+    pub reorder_delay: Duration,
+    /// Maximum upload rate in bytes per second. Packets queue up behind each other if the rate is
+    /// exceeded. `None` means unlimited.
+    pub bandwidth: Option<usize>,
+}
+
+impl Default for NetworkConditions {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            packet_loss: 0.0,
+            burst_loss: 0.0,
+            burst_length: 0,
+            duplication: 0.0,
+            reordering: 0.0,
+            reorder_delay: Duration::ZERO,
+            bandwidth: None,
+        }
+    }
+}
+
+/// Wraps any [`NonBlockingSocket`] and simulates a bad network on the packets sent through it:
+/// latency, jitter, random and burst packet loss, duplication, reordering and limited bandwidth.
+///
+/// All random decisions come from an RNG seeded with the given seed, so a run can be repeated with
+/// the same packet fate. Delayed packets are handed to the wrapped socket the next time the session
+/// sends or receives messages, so the simulation is as precise as your polling frequency. Delays
+/// are measured with the [`SystemClock`] by default; use [`NetworkSimulator::with_clock()`] to
+/// share a [`ManualClock`] with the session and make whole runs reproducible.
+///
+/// Only outgoing packets are affected. To degrade both directions, wrap the sockets of both peers.
+///
+/// ```
+/// # use ggrs::{ChannelNetwork, NetworkConditions, NetworkSimulator};
+/// # use instant::Duration;
+/// let network = ChannelNetwork::<u16>::new();
+/// let conditions = NetworkConditions {
+///     latency: Duration::from_millis(40),
+///     jitter: Duration::from_millis(10),
+///     packet_loss: 0.05,
+///     ..Default::default()
+/// };
+/// let socket: NetworkSimulator<u16, _> = NetworkSimulator::new(network.socket(1), conditions, 42);
+/// ```
+///
+/// [`ManualClock`]: crate::ManualClock
+#[derive(Debug)]
+pub struct NetworkSimulator<A, S> {
+    socket: S,
+    conditions: NetworkConditions,
+    rng: StdRng,
+    /// Packets waiting for their send time, ordered by send time and then by the order they were sent.
+    delayed: BTreeMap<(Instant, u64), (A, Message)>,
+    next_sequence: u64,
+    /// How many more packets are lost in the current loss burst.
+    burst_remaining: usize,
+    /// When the simulated link is done with the packets queued so far, if bandwidth is limited.
+    link_busy_until: Option<Instant>,
+    clock: Arc<dyn Clock>,
+    _address: PhantomData<A>,
+}
+
+impl<A: Clone + PartialEq + Eq + Hash, S> NetworkSimulator<A, S> {
+    /// Wraps the given socket. The `seed` determines all random decisions of the simulation.
+    pub fn new(socket: S, conditions: NetworkConditions, seed: u64) -> Self {
+        Self {
+            socket,
+            conditions,
+            rng: StdRng::seed_from_u64(seed),
+            delayed: BTreeMap::new(),
+            next_sequence: 0,
+            burst_remaining: 0,
+            link_busy_until: None,
+            clock: Arc::new(SystemClock),
+            _address: PhantomData,
+        }
+    }
+
+    /// Sets the clock that measures the delays of packets. Default is the [`SystemClock`]. Pass
+    /// the same [`ManualClock`] as to the session to step the simulated network along with it.
+    ///
+    /// [`ManualClock`]: crate::ManualClock
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Returns the current network conditions.
+    pub fn conditions(&self) -> &NetworkConditions {
+        &self.conditions
+    }
+
+    /// Changes the network conditions, e.g. to simulate a connection that gets worse over time.
+    /// Packets that are already delayed keep their delay.
+    pub fn set_conditions(&mut self, conditions: NetworkConditions) {
+        self.conditions = conditions;
+    }
+
+    /// Returns the number of packets that have been sent but not handed to the wrapped socket yet.
+    pub fn packets_in_flight(&self) -> usize {
+        self.delayed.len()
+    }
+
+    /// Returns the wrapped socket.
+    pub fn inner(&self) -> &S {
+        &self.socket
+    }
+
+    /// Returns the wrapped socket mutably. Messages sent through it directly bypass the simulation.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    /// Queues the packet and hands all packets whose delay has passed to the wrapped socket with
+    /// `send`, which is its [`NonBlockingSocket::send_to()`].
+    fn send_with(&mut self, msg: &Message, addr: &A, send: fn(&mut S, &Message, &A)) {
+        let now = self.clock.now();
+        self.simulate_send(msg, addr, now);
+        self.send_due_packets(now, send);
+    }
+
+    /// Hands all packets whose delay has passed to the wrapped socket with `send`.
+    fn send_due_packets(&mut self, now: Instant, send: fn(&mut S, &Message, &A)) {
+        for (addr, msg) in self.take_due_packets(now) {
+            send(&mut self.socket, &msg, &addr);
+        }
+    }
+
+    /// Decides the fate of a packet and queues it.
+    fn simulate_send(&mut self, msg: &Message, addr: &A, now: Instant) {
+        if self.is_lost() {
+            return;
+        }
+
+        let copies = if self.chance(self.conditions.duplication) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let send_time = self.send_time(msg, now);
+            self.delayed
+                .insert((send_time, self.next_sequence), (addr.clone(), msg.clone()));
+            self.next_sequence += 1;
+        }
+    }
+
+    fn is_lost(&mut self) -> bool {
+        if self.burst_remaining > 0 {
+            self.burst_remaining -= 1;
+            return true;
+        }
+        if self.conditions.burst_length > 0 && self.chance(self.conditions.burst_loss) {
+            self.burst_remaining = self.conditions.burst_length - 1;
+            return true;
+        }
+        self.chance(self.conditions.packet_loss)
+    }
+
+    /// Returns when the packet should be handed to the wrapped socket.
+    fn send_time(&mut self, msg: &Message, now: Instant) -> Instant {
+        // the packet has to wait for the link to transmit all earlier packets
+        let mut departure = now;
+        if let Some(bandwidth) = self.conditions.bandwidth {
+            let size = bincode::serialized_size(msg).unwrap_or(0) as f64;
+            let start = self.link_busy_until.map_or(now, |busy| busy.max(now));
+            departure = start + Duration::from_secs_f64(size / bandwidth.max(1) as f64);
+            self.link_busy_until = Some(departure);
+        }
+
+        let mut delay = self.conditions.latency;
+        if !self.conditions.jitter.is_zero() {
+            delay += self.conditions.jitter.mul_f64(self.rng.gen::<f64>());
+        }
+        if self.chance(self.conditions.reordering) {
+            delay += self.conditions.reorder_delay;
+        }
+        departure + delay
+    }
+
+    /// Removes all packets whose delay has passed, in the order they should be sent.
+    fn take_due_packets(&mut self, now: Instant) -> Vec<(A, Message)> {
+        let mut due = Vec::new();
+        while let Some(entry) = self.delayed.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            due.push(entry.remove());
+        }
+        due
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen::<f64>() < probability
+    }
+}
+
+#[cfg(feature = "sync-send")]
+impl<A, S> NonBlockingSocket<A> for NetworkSimulator<A, S>
+where
+    A: Clone + PartialEq + Eq + Hash + Send + Sync,
+    S: NonBlockingSocket<A>,
+{
+    fn send_to(&mut self, msg: &Message, addr: &A) {
+        self.send_with(msg, addr, S::send_to);
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(A, Message)> {
+        self.send_due_packets(self.clock.now(), S::send_to);
+        self.socket.receive_all_messages()
+    }
+}
+
+#[cfg(not(feature = "sync-send"))]
+impl<A, S> NonBlockingSocket<A> for NetworkSimulator<A, S>
+where
+    A: Clone + PartialEq + Eq + Hash,
+    S: NonBlockingSocket<A>,
+{
+    fn send_to(&mut self, msg: &Message, addr: &A) {
+        self.send_with(msg, addr, S::send_to);
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(A, Message)> {
+        self.send_due_packets(self.clock.now(), S::send_to);
+        self.socket.receive_all_messages()
+    }
+}
+
+// #########
+// # TESTS #
+// #########
+
+#[cfg(test)]
+mod network_simulator_tests {
+    use super::*;
+    use crate::network::channel_socket::{ChannelNetwork, ChannelSocket};
+    use crate::network::messages::{MessageBody, MessageHeader};
+    use crate::ManualClock;
+
+    fn message(magic: u16) -> Message {
+        Message {
+            header: MessageHeader { magic },
+            body: MessageBody::KeepAlive,
+        }
+    }
+
+    fn simulator(
+        conditions: NetworkConditions,
+        seed: u64,
+    ) -> (
+        NetworkSimulator<u8, ChannelSocket<u8>>,
+        ChannelSocket<u8>,
+        ManualClock,
+        ChannelNetwork<u8>,
+    ) {
+        let network = ChannelNetwork::new();
+        let clock = ManualClock::new();
+        let sender =
+            NetworkSimulator::new(network.socket(1), conditions, seed).with_clock(clock.clone());
+        let receiver = network.socket(2);
+        (sender, receiver, clock, network)
+    }
+
+    /// Advances the clock and returns how many packets arrived in the meantime.
+    fn advance(
+        clock: &ManualClock,
+        millis: u64,
+        sender: &mut NetworkSimulator<u8, ChannelSocket<u8>>,
+        receiver: &mut ChannelSocket<u8>,
+    ) -> usize {
+        clock.advance(Duration::from_millis(millis));
+        sender.receive_all_messages();
+        receiver.receive_all_messages().len()
+    }
+
+    /// Sends `count` messages at the same point in time and returns the received magic numbers.
+    fn send_all(conditions: NetworkConditions, seed: u64, count: u16) -> Vec<u16> {
+        let (mut sender, mut receiver, clock, _network) = simulator(conditions, seed);
+        for magic in 0..count {
+            sender.send_to(&message(magic), &2);
+        }
+        clock.advance(Duration::from_secs(3600));
+        sender.receive_all_messages();
+        receiver
+            .receive_all_messages()
+            .into_iter()
+            .map(|(_, msg)| msg.header.magic)
+            .collect()
+    }
+
+    #[test]
+    fn test_default_conditions_pass_packets_through() {
+        let (mut sender, mut receiver, _clock, _network) =
+            simulator(NetworkConditions::default(), 0);
+        sender.send_to(&message(7), &2);
+        assert_eq!(receiver.receive_all_messages(), vec![(1, message(7))]);
+        assert_eq!(sender.packets_in_flight(), 0);
+    }
+
+    #[test]
+    fn test_latency_delays_packets() {
+        let conditions = NetworkConditions {
+            latency: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let (mut sender, mut receiver, clock, _network) = simulator(conditions, 0);
+        sender.send_to(&message(0), &2);
+
+        assert_eq!(advance(&clock, 49, &mut sender, &mut receiver), 0);
+        assert_eq!(sender.packets_in_flight(), 1);
+        assert_eq!(advance(&clock, 1, &mut sender, &mut receiver), 1);
+        assert_eq!(sender.packets_in_flight(), 0);
+    }
+
+    #[test]
+    fn test_delayed_packets_wait_for_the_clock() {
+        let conditions = NetworkConditions {
+            latency: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let (mut sender, mut receiver, clock, _network) = simulator(conditions, 0);
+        sender.send_to(&message(0), &2);
+        clock.advance(Duration::from_millis(10));
+        sender.send_to(&message(1), &2);
+
+        // no matter how often the socket is polled, packets only leave once the clock says so
+        for _ in 0..100 {
+            assert_eq!(advance(&clock, 0, &mut sender, &mut receiver), 0);
+        }
+        assert_eq!(advance(&clock, 10, &mut sender, &mut receiver), 1);
+        assert_eq!(advance(&clock, 10, &mut sender, &mut receiver), 1);
+    }
+
+    #[test]
+    fn test_packet_loss_and_duplication() {
+        let lossy = NetworkConditions {
+            packet_loss: 1.0,
+            ..Default::default()
+        };
+        assert!(send_all(lossy, 0, 20).is_empty());
+
+        let duplicating = NetworkConditions {
+            duplication: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(send_all(duplicating, 0, 3), vec![0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn test_burst_loss_drops_consecutive_packets() {
+        let conditions = NetworkConditions {
+            burst_loss: 0.05,
+            burst_length: 5,
+            ..Default::default()
+        };
+        let received = send_all(conditions, 3, 500);
+        assert!(received.len() < 500);
+
+        // every gap in the received sequence is a multiple of the burst length
+        let mut expected = 0;
+        for magic in received {
+            assert_eq!((magic - expected) % 5, 0);
+            expected = magic + 1;
+        }
+    }
+
+    #[test]
+    fn test_jitter_and_reordering_change_packet_order() {
+        let conditions = NetworkConditions {
+            jitter: Duration::from_millis(20),
+            reordering: 0.2,
+            reorder_delay: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let received = send_all(conditions, 1, 100);
+        let mut sorted = received.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..100).collect::<Vec<_>>());
+        assert_ne!(received, sorted);
+    }
+
+    #[test]
+    fn test_bandwidth_spaces_packets_out() {
+        let size = bincode::serialized_size(&message(0)).unwrap() as usize;
+        let conditions = NetworkConditions {
+            // one packet per 10ms
+            bandwidth: Some(size * 100),
+            ..Default::default()
+        };
+        let (mut sender, mut receiver, clock, _network) = simulator(conditions, 0);
+        for magic in 0..10 {
+            sender.send_to(&message(magic), &2);
+        }
+
+        assert_eq!(advance(&clock, 35, &mut sender, &mut receiver), 3);
+        assert_eq!(advance(&clock, 65, &mut sender, &mut receiver), 7);
+    }
+
+    #[test]
+    fn test_same_seed_reproduces_packet_fate() {
+        let conditions = NetworkConditions {
+            jitter: Duration::from_millis(30),
+            packet_loss: 0.2,
+            burst_loss: 0.05,
+            burst_length: 3,
+            duplication: 0.1,
+            ..Default::default()
+        };
+        assert_eq!(send_all(conditions, 9, 200), send_all(conditions, 9, 200));
+        assert_ne!(send_all(conditions, 9, 200), send_all(conditions, 10, 200));
+    }
+}
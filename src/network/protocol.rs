@@ -1,23 +1,33 @@
-use crate::frame_info::PlayerInput;
+use crate::frame_info::{PlayerInput, SubChecksums};
 use crate::network::compression::{decode, encode};
 use crate::network::messages::{
-    ChecksumReport, ConnectionStatus, Input, InputAck, Message, MessageBody, MessageHeader,
-    QualityReply, QualityReport, SyncReply, SyncRequest,
+    ChecksumReport, ChecksumReportAck, ConfigFingerprint, ConnectionStatus, Input, InputAck,
+    Message, MessageBody, MessageHeader, QualityReply, QualityReport, SnapshotRequest, StateChunk,
+    StateChunkAck, StateTransfer, SyncReply, SyncRequest, UserMessage, UserMessageAck,
 };
+use crate::network::security::PacketGuard;
+use crate::network::state_transfer::{
+    IncomingTransfer, OutgoingTransfer, MAX_TRANSFER_CHUNKS, STATE_CHUNK_SIZE,
+};
+use crate::network::user_messages::{IncomingUserMessages, OutgoingUserMessages};
 use crate::time_sync::TimeSync;
 use crate::{
-    Config, DesyncDetection, Frame, GgrsError, NonBlockingSocket, PlayerHandle, NULL_FRAME,
+    Clock, Config, DesyncDetection, Frame, GgrsError, NonBlockingSocket, PacketSecurity,
+    PlayerHandle, MAX_USER_MESSAGE_SIZE, NULL_FRAME,
 };
 use tracing::{trace, warn};
 
 use instant::{Duration, Instant};
 use std::collections::vec_deque::Drain;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::ops::Add;
+use std::sync::Arc;
 
 use super::network_stats::NetworkStats;
 
+/// The version of the network protocol. Peers with different versions cannot play together.
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
 const NUM_SYNC_PACKETS: u32 = 5;
 const UDP_SHUTDOWN_TIMER: u64 = 5000;
 const PENDING_OUTPUT_SIZE: usize = 128;
@@ -36,23 +46,15 @@ const KEEP_ALIVE_INTERVAL: Duration = Duration::from_millis(200);
 /// Drives the rolling RTT and frame-advantage estimates exposed by `network_stats()`;
 /// at 200 ms this gives ~5 stat updates per second.
 const QUALITY_REPORT_INTERVAL: Duration = Duration::from_millis(200);
+/// How long to wait for the acknowledgement of a state chunk before sending it again.
+const STATE_CHUNK_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+/// How long to wait for the acknowledgement of a user message before sending it again.
+const USER_MESSAGE_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+/// How long to wait for the acknowledgement of a checksum report before sending it again.
+const CHECKSUM_REPORT_RETRY_INTERVAL: Duration = Duration::from_millis(200);
 /// Number of old checksums to keep in memory
 pub const MAX_CHECKSUM_HISTORY_SIZE: usize = 32;
 
-fn millis_since_epoch() -> u128 {
-    #[cfg(not(target_arch = "wasm32"))]
-    {
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis()
-    }
-    #[cfg(target_arch = "wasm32")]
-    {
-        js_sys::Date::new_0().get_time() as u128
-    }
-}
-
 // byte-encoded data representing the inputs of a client, possibly for multiple players at the same time
 #[derive(Clone)]
 struct InputBytes {
@@ -142,6 +144,19 @@ where
     NetworkInterrupted { disconnect_timeout: u128 },
     /// Sent only after a `NetworkInterrupted` event, if communication has resumed.
     NetworkResumed,
+    /// The remote client asks to join the running match. This event will not be forwarded to the user.
+    JoinRequested,
+    /// The remote spectator asks for a snapshot to continue from, since it cannot simulate `frame`. This event will not be forwarded to the user.
+    SnapshotRequested { frame: Frame },
+    /// The remote client has sent us a complete state transfer. This event will not be forwarded to the user.
+    StateTransferReceived { transfer: StateTransfer },
+    /// The remote client has sent us a user message.
+    UserMessage { payload: Vec<u8> },
+    /// The remote client runs with incompatible settings, so we stopped synchronizing with it.
+    ConfigMismatch {
+        local: ConfigFingerprint,
+        remote: ConfigFingerprint,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -177,11 +192,23 @@ where
     shutdown_timeout: Instant,
     fps: usize,
     magic: u16,
+    /// The build hash of the game, which the remote needs to share.
+    build_hash: Option<u64>,
+    /// True if this endpoint belongs to a spectator session and connects to its host.
+    spectator: bool,
+    /// True if our session can send and load game states.
+    state_transfer: bool,
+    /// The token we send along with our sync requests, for remotes that do not know us yet.
+    join_token: Option<Vec<u8>>,
 
     // the other client
     peer_addr: T::Address,
     remote_magic: u16,
+    /// The settings the remote reported while we synchronized with it.
+    remote_fingerprint: Option<ConfigFingerprint>,
     peer_connect_status: Vec<ConnectionStatus>,
+    /// Seals the messages we send and drops forged or replayed messages we receive.
+    guard: PacketGuard,
 
     // input compression
     pending_output: VecDeque<InputBytes>,
@@ -195,15 +222,32 @@ where
     remote_frame_advantage: i32,
 
     // network
-    stats_start_time: u128,
+    clock: Arc<dyn Clock>,
+    /// Reference point of the ping timestamps we send, which the remote echoes back.
+    ping_origin: Instant,
+    stats_start_time: Instant,
     round_trip_time: u128,
     last_send_time: Instant,
     last_sync_request_time: Instant,
     last_recv_time: Instant,
 
     // debug desync
-    pub(crate) pending_checksums: HashMap<Frame, u128>,
+    pub(crate) pending_checksums: HashMap<Frame, ChecksumReport>,
     desync_detection: DesyncDetection,
+    /// our checksum reports the remote has not acknowledged yet, with the time they were last sent
+    outgoing_checksums: BTreeMap<Frame, (ChecksumReport, Instant)>,
+    /// frames of the latest checksum reports we received, to ignore reports that were sent again
+    received_checksum_frames: BTreeSet<Frame>,
+
+    // state transfers
+    next_transfer_id: u32,
+    outgoing_transfers: VecDeque<OutgoingTransfer>,
+    incoming_transfer: Option<IncomingTransfer>,
+    next_incoming_transfer_id: u32,
+
+    // user messages
+    outgoing_user_messages: OutgoingUserMessages,
+    incoming_user_messages: IncomingUserMessages,
 }
 
 impl<T: Config> PartialEq for UdpProtocol<T> {
@@ -224,6 +268,12 @@ impl<T: Config> UdpProtocol<T> {
         disconnect_notify_start: Duration,
         fps: usize,
         desync_detection: DesyncDetection,
+        build_hash: Option<u64>,
+        packet_security: PacketSecurity,
+        join_token: Option<Vec<u8>>,
+        spectator: bool,
+        state_transfer: bool,
+        clock: Arc<dyn Clock>,
     ) -> Self {
         let mut magic = rand::random::<u16>();
         while magic == 0 {
@@ -243,6 +293,7 @@ impl<T: Config> UdpProtocol<T> {
         let mut recv_inputs = HashMap::new();
         recv_inputs.insert(NULL_FRAME, InputBytes::zeroed::<T>(recv_player_num));
 
+        let now = clock.now();
         Self {
             num_players,
             handles,
@@ -253,22 +304,28 @@ impl<T: Config> UdpProtocol<T> {
             state: ProtocolState::Initializing,
             sync_remaining_roundtrips: NUM_SYNC_PACKETS,
             sync_random_requests: HashSet::new(),
-            running_last_quality_report: Instant::now(),
-            running_last_input_recv: Instant::now(),
+            running_last_quality_report: now,
+            running_last_input_recv: now,
             disconnect_notify_sent: false,
             disconnect_event_sent: false,
 
             // constants
             disconnect_timeout,
             disconnect_notify_start,
-            shutdown_timeout: Instant::now(),
+            shutdown_timeout: now,
             fps,
             magic,
+            build_hash,
+            spectator,
+            state_transfer,
+            join_token,
 
             // the other client
             peer_addr,
             remote_magic: 0,
+            remote_fingerprint: None,
             peer_connect_status,
+            guard: PacketGuard::new(packet_security),
 
             // input compression
             pending_output: VecDeque::with_capacity(PENDING_OUTPUT_SIZE),
@@ -282,15 +339,29 @@ impl<T: Config> UdpProtocol<T> {
             remote_frame_advantage: 0,
 
             // network
-            stats_start_time: 0,
+            clock,
+            ping_origin: now,
+            stats_start_time: now,
             round_trip_time: 0,
-            last_send_time: Instant::now(),
-            last_sync_request_time: Instant::now(),
-            last_recv_time: Instant::now(),
+            last_send_time: now,
+            last_sync_request_time: now,
+            last_recv_time: now,
 
             // debug desync
             pending_checksums: HashMap::new(),
             desync_detection,
+            outgoing_checksums: BTreeMap::new(),
+            received_checksum_frames: BTreeSet::new(),
+
+            // state transfers
+            next_transfer_id: 0,
+            outgoing_transfers: VecDeque::new(),
+            incoming_transfer: None,
+            next_incoming_transfer_id: 0,
+
+            // user messages
+            outgoing_user_messages: OutgoingUserMessages::new(),
+            incoming_user_messages: IncomingUserMessages::new(),
         }
     }
 
@@ -310,8 +381,12 @@ impl<T: Config> UdpProtocol<T> {
             return Err(GgrsError::NotSynchronized);
         }
 
-        let now = millis_since_epoch();
-        let seconds = (now - self.stats_start_time) / 1000;
+        let seconds = u128::from(
+            self.clock
+                .now()
+                .duration_since(self.stats_start_time)
+                .as_secs(),
+        );
         if seconds == 0 {
             return Err(GgrsError::NotEnoughData);
         }
@@ -338,10 +413,22 @@ impl<T: Config> UdpProtocol<T> {
         self.state == ProtocolState::Running
     }
 
+    pub(crate) fn is_disconnected(&self) -> bool {
+        self.state == ProtocolState::Disconnected || self.state == ProtocolState::Shutdown
+    }
+
     pub(crate) fn is_handling_message(&self, addr: &T::Address) -> bool {
         self.peer_addr == *addr
     }
 
+    /// Returns the frame of the newest input handed to this endpoint for sending, or [`NULL_FRAME`]
+    /// if there was none yet.
+    pub(crate) fn last_queued_input_frame(&self) -> Frame {
+        self.pending_output
+            .back()
+            .map_or(self.last_acked_input.frame, |input| input.frame)
+    }
+
     pub(crate) fn peer_connect_status(&self, handle: PlayerHandle) -> ConnectionStatus {
         self.peer_connect_status[handle]
     }
@@ -353,17 +440,34 @@ impl<T: Config> UdpProtocol<T> {
 
         self.state = ProtocolState::Disconnected;
         // schedule the timeout which will lead to shutdown
-        self.shutdown_timeout = Instant::now().add(Duration::from_millis(UDP_SHUTDOWN_TIMER));
+        self.shutdown_timeout = self
+            .clock
+            .now()
+            .add(Duration::from_millis(UDP_SHUTDOWN_TIMER));
     }
 
     pub(crate) fn synchronize(&mut self) {
         assert_eq!(self.state, ProtocolState::Initializing);
         self.state = ProtocolState::Synchronizing;
         self.sync_remaining_roundtrips = NUM_SYNC_PACKETS;
-        self.stats_start_time = millis_since_epoch();
+        self.stats_start_time = self.clock.now();
         self.send_sync_request();
     }
 
+    /// Returns true if the remote told us while synchronizing that it can send game states.
+    pub(crate) fn remote_has_state_transfer(&self) -> bool {
+        self.remote_fingerprint
+            .is_some_and(|fingerprint| fingerprint.state_transfer)
+    }
+
+    pub(crate) fn set_max_prediction(&mut self, max_prediction: usize) {
+        self.max_prediction = max_prediction;
+    }
+
+    pub(crate) fn round_trip_time(&self) -> u128 {
+        self.round_trip_time
+    }
+
     pub(crate) fn average_frame_advantage(&self) -> i32 {
         self.time_sync_layer.average_frame_advantage()
     }
@@ -373,7 +477,7 @@ impl<T: Config> UdpProtocol<T> {
     }
 
     pub(crate) fn poll(&mut self, connect_status: &[ConnectionStatus]) -> Drain<'_, Event<T>> {
-        let now = Instant::now();
+        let now = self.clock.now();
         match self.state {
             ProtocolState::Synchronizing => {
                 // some time has passed, let us send another sync request
@@ -385,9 +489,18 @@ impl<T: Config> UdpProtocol<T> {
                 // resend pending inputs, if some time has passed without sending or receiving inputs
                 if self.running_last_input_recv + RUNNING_RETRY_INTERVAL < now {
                     self.send_pending_output(connect_status);
-                    self.running_last_input_recv = Instant::now();
+                    self.running_last_input_recv = now;
                 }
 
+                // send state chunks that are due or have not been acknowledged in time
+                self.send_state_chunks(now);
+
+                // send user messages that are due or have not been acknowledged in time
+                self.send_user_messages(now);
+
+                // send checksum reports again that have not been acknowledged in time
+                self.resend_checksum_reports(now);
+
                 // periodically send a quality report
                 if self.running_last_quality_report + QUALITY_REPORT_INTERVAL < now {
                     self.send_quality_report();
@@ -420,7 +533,7 @@ impl<T: Config> UdpProtocol<T> {
                 }
             }
             ProtocolState::Disconnected => {
-                if self.shutdown_timeout < Instant::now() {
+                if self.shutdown_timeout < now {
                     self.state = ProtocolState::Shutdown;
                 }
             }
@@ -469,7 +582,7 @@ impl<T: Config> UdpProtocol<T> {
         let num_messages = self.send_queue.len();
         trace!("Sending {num_messages} messages over socket");
         for msg in self.send_queue.drain(..) {
-            socket.send_to(&msg, &self.peer_addr);
+            socket.send_to(&self.guard.seal(msg), &self.peer_addr);
         }
     }
 
@@ -538,6 +651,99 @@ impl<T: Config> UdpProtocol<T> {
         }
     }
 
+    /// Queues a session-level payload to be reliably sent to the remote in chunks. Transfers are
+    /// delivered in the order they were queued.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the payload needs more chunks than the remote accepts. It is
+    ///   not queued, since it would block all later transfers.
+    ///
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub(crate) fn send_state_transfer(
+        &mut self,
+        transfer: &StateTransfer,
+    ) -> Result<(), GgrsError> {
+        let bytes = bincode::serialize(transfer).expect("state transfer serialization failed");
+        let max_bytes = STATE_CHUNK_SIZE * MAX_TRANSFER_CHUNKS as usize;
+        if bytes.len() > max_bytes {
+            return Err(GgrsError::InvalidRequest {
+                info: format!(
+                    "State transfer of {} bytes exceeds the maximum of {max_bytes} bytes.",
+                    bytes.len()
+                ),
+            });
+        }
+        let id = self.next_transfer_id;
+        self.next_transfer_id += 1;
+        trace!(
+            "Queuing state transfer {id} of {} bytes to {:?}",
+            bytes.len(),
+            self.peer_addr
+        );
+        self.outgoing_transfers
+            .push_back(OutgoingTransfer::new(id, &bytes));
+
+        if self.state == ProtocolState::Running {
+            self.send_state_chunks(self.clock.now());
+        }
+        Ok(())
+    }
+
+    fn send_state_chunks(&mut self, now: Instant) {
+        let Some(transfer) = self.outgoing_transfers.front_mut() else {
+            return;
+        };
+        for chunk in transfer.chunks_to_send(now, STATE_CHUNK_RETRY_INTERVAL) {
+            self.queue_message(MessageBody::StateChunk(chunk));
+        }
+    }
+
+    /// Queues a user message to be reliably sent to the remote. Messages are delivered in the order
+    /// they were queued.
+    pub(crate) fn send_user_message(&mut self, payload: Vec<u8>) -> Result<(), GgrsError> {
+        if self.is_disconnected() {
+            return Err(GgrsError::InvalidRequest {
+                info: "Cannot send a user message to a disconnected endpoint.".to_owned(),
+            });
+        }
+        if payload.len() > MAX_USER_MESSAGE_SIZE {
+            return Err(GgrsError::InvalidRequest {
+                info: format!(
+                    "User message of {} bytes exceeds the maximum of {MAX_USER_MESSAGE_SIZE} bytes.",
+                    payload.len()
+                ),
+            });
+        }
+        if self.outgoing_user_messages.is_full() {
+            return Err(GgrsError::InvalidRequest {
+                info: "Too many user messages are waiting to be delivered.".to_owned(),
+            });
+        }
+        self.outgoing_user_messages.push(payload);
+
+        if self.state == ProtocolState::Running {
+            self.send_user_messages(self.clock.now());
+        }
+        Ok(())
+    }
+
+    fn send_user_messages(&mut self, now: Instant) {
+        for message in self
+            .outgoing_user_messages
+            .messages_to_send(now, USER_MESSAGE_RETRY_INTERVAL)
+        {
+            self.queue_message(MessageBody::UserMessage(message));
+        }
+    }
+
+    pub(crate) fn send_join_request(&mut self) {
+        self.queue_message(MessageBody::JoinRequest);
+    }
+
+    pub(crate) fn send_snapshot_request(&mut self, frame: Frame) {
+        self.queue_message(MessageBody::SnapshotRequest(SnapshotRequest { frame }));
+    }
+
     fn send_input_ack(&mut self) {
         let body = InputAck {
             ack_frame: self.last_recv_frame(),
@@ -551,24 +757,26 @@ impl<T: Config> UdpProtocol<T> {
     }
 
     fn send_sync_request(&mut self) {
-        self.last_sync_request_time = Instant::now();
+        self.last_sync_request_time = self.clock.now();
         let random_number = rand::random::<u32>();
         self.sync_random_requests.insert(random_number);
         let body = SyncRequest {
             random_request: random_number,
+            fingerprint: self.fingerprint(),
+            join_token: self.join_token.clone(),
         };
         self.queue_message(MessageBody::SyncRequest(body));
     }
 
     fn send_quality_report(&mut self) {
-        self.running_last_quality_report = Instant::now();
+        self.running_last_quality_report = self.clock.now();
         let body = QualityReport {
             frame_advantage: i16::try_from(
                 self.local_frame_advantage
                     .clamp(i32::from(i16::MIN), i32::from(i16::MAX)),
             )
             .expect("local_frame_advantage should have been clamped into the range of an i16"),
-            ping: millis_since_epoch(),
+            ping: self.millis_since_ping_origin(),
         };
 
         self.queue_message(MessageBody::QualityReport(body));
@@ -581,7 +789,7 @@ impl<T: Config> UdpProtocol<T> {
         let header = MessageHeader { magic: self.magic };
         let msg = Message { header, body };
 
-        self.last_send_time = Instant::now();
+        self.last_send_time = self.clock.now();
 
         // add the packet to the back of the send queue
         self.send_queue.push_back(msg);
@@ -591,12 +799,35 @@ impl<T: Config> UdpProtocol<T> {
      *  RECEIVING MESSAGES
      */
 
+    /// Handles a message received from the remote, unless it is forged or replayed.
+    pub(crate) fn receive_message(&mut self, msg: &Message) {
+        // until the remote is authorized, it may still restart its session with a new sender id
+        match self.guard.open(msg, self.remote_magic == 0) {
+            Some(msg) => self.handle_message(&msg),
+            None => trace!("Received message that failed verification; ignoring"),
+        }
+    }
+
+    /// Returns true if the message is an authentic request to start synchronizing that has not
+    /// been received before.
+    pub(crate) fn is_sync_request(&self, msg: &Message) -> bool {
+        self.guard
+            .check(msg, true)
+            .is_some_and(|msg| matches!(msg.body, MessageBody::SyncRequest(_)))
+    }
+
+    /// Hands the packet guard over to an endpoint that replaces this one, so packets this endpoint
+    /// received cannot be replayed to its successor.
+    pub(crate) fn pass_guard_to(self, successor: &mut Self) {
+        successor.guard = self.guard;
+    }
+
     pub(crate) fn handle_message(&mut self, msg: &Message) {
         trace!("Handling message from {:?}: {:?}", self.peer_addr, msg);
 
-        // don't handle messages if shutdown
-        if self.state == ProtocolState::Shutdown {
-            trace!("Protocol is shutting down; ignoring message");
+        // don't handle messages if disconnected, so the remote notices the disconnect as well
+        if self.state == ProtocolState::Disconnected || self.state == ProtocolState::Shutdown {
+            trace!("Protocol is disconnected; ignoring message");
             return;
         }
 
@@ -607,7 +838,7 @@ impl<T: Config> UdpProtocol<T> {
         }
 
         // update time when we last received packages
-        self.last_recv_time = Instant::now();
+        self.last_recv_time = self.clock.now();
 
         // if the connection has been marked as interrupted, send an event to signal we are receiving again
         if self.disconnect_notify_sent && self.state == ProtocolState::Running {
@@ -618,21 +849,42 @@ impl<T: Config> UdpProtocol<T> {
 
         // handle the message
         match &msg.body {
-            MessageBody::SyncRequest(body) => self.on_sync_request(*body),
+            MessageBody::SyncRequest(body) => self.on_sync_request(body),
             MessageBody::SyncReply(body) => self.on_sync_reply(msg.header, *body),
             MessageBody::Input(body) => self.on_input(body),
             MessageBody::InputAck(body) => self.on_input_ack(*body),
             MessageBody::QualityReport(body) => self.on_quality_report(body),
             MessageBody::QualityReply(body) => self.on_quality_reply(body),
             MessageBody::ChecksumReport(body) => self.on_checksum_report(body),
+            MessageBody::ChecksumReportAck(body) => self.on_checksum_report_ack(*body),
+            MessageBody::JoinRequest => self.event_queue.push_back(Event::JoinRequested),
+            MessageBody::StateChunk(body) => self.on_state_chunk(body),
+            MessageBody::StateChunkAck(body) => self.on_state_chunk_ack(*body),
+            MessageBody::UserMessage(body) => self.on_user_message(body),
+            MessageBody::UserMessageAck(body) => self.on_user_message_ack(*body),
             MessageBody::KeepAlive => (),
+            MessageBody::SnapshotRequest(body) => self
+                .event_queue
+                .push_back(Event::SnapshotRequested { frame: body.frame }),
+            // sealed messages are opened in `receive_message()`
+            MessageBody::Sealed(_) => trace!("Received sealed message; ignoring"),
         }
     }
 
     /// Upon receiving a `SyncRequest`, answer with a `SyncReply` with the proper data
-    fn on_sync_request(&mut self, body: SyncRequest) {
+    fn on_sync_request(&mut self, body: &SyncRequest) {
+        if self.state == ProtocolState::Running {
+            // we already agreed on the settings, so a mismatch cannot come from our remote
+            if !self.fingerprint().mismatches(&body.fingerprint).is_empty() {
+                trace!("Received sync request with incompatible settings while running; ignoring");
+                return;
+            }
+        } else if !self.accepts_fingerprint(body.fingerprint) {
+            return;
+        }
         let reply_body = SyncReply {
             random_reply: body.random_request,
+            fingerprint: self.fingerprint(),
         };
         self.queue_message(MessageBody::SyncReply(reply_body));
     }
@@ -647,6 +899,10 @@ impl<T: Config> UdpProtocol<T> {
         if !self.sync_random_requests.remove(&body.random_reply) {
             return;
         }
+        if !self.accepts_fingerprint(body.fingerprint) {
+            return;
+        }
+        self.remote_fingerprint = Some(body.fingerprint);
         // the sync reply is good, so we send a sync request again until we have finished the required roundtrips. Then, we can conclude the syncing process.
         self.sync_remaining_roundtrips -= 1;
         if self.sync_remaining_roundtrips > 0 {
@@ -668,7 +924,55 @@ impl<T: Config> UdpProtocol<T> {
         }
     }
 
+    /// The settings of our session that the remote has to agree with.
+    fn fingerprint(&self) -> ConfigFingerprint {
+        ConfigFingerprint {
+            protocol_version: PROTOCOL_VERSION,
+            spectator: self.spectator,
+            num_players: self.num_players,
+            input_size: bincode::serialized_size(&T::Input::default())
+                .expect("input serialization failed"),
+            max_prediction: self.max_prediction,
+            desync_detection_interval: match self.desync_detection {
+                DesyncDetection::On { interval } => Some(interval),
+                DesyncDetection::Off => None,
+            },
+            fps: self.fps,
+            build_hash: self.build_hash,
+            state_transfer: self.state_transfer,
+        }
+    }
+
+    /// Checks the settings of the remote. If they are incompatible with ours, we stop talking to
+    /// the remote and report the mismatch.
+    fn accepts_fingerprint(&mut self, remote: ConfigFingerprint) -> bool {
+        let local = self.fingerprint();
+        let mismatches = local.mismatches(&remote);
+        if mismatches.is_empty() {
+            return true;
+        }
+        warn!(
+            "Rejecting {:?} because of incompatible settings: {}",
+            self.peer_addr,
+            mismatches.join(", ")
+        );
+        self.event_queue
+            .push_back(Event::ConfigMismatch { local, remote });
+        self.disconnect();
+        false
+    }
+
     fn on_input(&mut self, body: &Input) {
+        // inputs are only accepted once the handshake is complete. The remote keeps resending them
+        // until we acknowledge, so nothing is lost.
+        if matches!(
+            self.state,
+            ProtocolState::Initializing | ProtocolState::Synchronizing
+        ) {
+            trace!("Ignoring input packet received before synchronization finished");
+            return;
+        }
+
         if !body.disconnect_requested && body.peer_connect_status.len() != self.num_players {
             warn!(
                 "Discarding input packet with {} connection statuses; expected {}",
@@ -698,13 +1002,18 @@ impl<T: Config> UdpProtocol<T> {
             }
         } else {
             // update the peer connection status
-            for i in 0..self.peer_connect_status.len() {
-                self.peer_connect_status[i].disconnected = body.peer_connect_status[i].disconnected
-                    || self.peer_connect_status[i].disconnected;
-                self.peer_connect_status[i].last_frame = std::cmp::max(
-                    self.peer_connect_status[i].last_frame,
-                    body.peer_connect_status[i].last_frame,
-                );
+            for (local, remote) in self
+                .peer_connect_status
+                .iter_mut()
+                .zip(&body.peer_connect_status)
+            {
+                if remote.connect_frame > local.connect_frame {
+                    // the player joined at a later frame, the previous status no longer applies
+                    *local = *remote;
+                } else if remote.connect_frame == local.connect_frame {
+                    local.disconnected = remote.disconnected || local.disconnected;
+                    local.last_frame = std::cmp::max(local.last_frame, remote.last_frame);
+                }
             }
         }
 
@@ -718,7 +1027,7 @@ impl<T: Config> UdpProtocol<T> {
 
         // if we have the necessary input saved, we decode
         if let Some(decode_inp) = self.recv_inputs.get(&decode_frame) {
-            self.running_last_input_recv = Instant::now();
+            self.running_last_input_recv = self.clock.now();
 
             let recv_inputs = match decode(&decode_inp.bytes, &body.bytes) {
                 Ok(inputs) => inputs,
@@ -781,32 +1090,157 @@ impl<T: Config> UdpProtocol<T> {
 
     /// Upon receiving a `QualityReply`, update network stats.
     fn on_quality_reply(&mut self, body: &QualityReply) {
-        let millis = millis_since_epoch();
-        // Use saturating_sub: if the clock went backward (NTP step) or the
-        // remote sent a tampered pong value, we get 0ms RTT rather than a panic.
+        let millis = self.millis_since_ping_origin();
+        // Use saturating_sub: if the remote sent a tampered pong value, we get 0ms RTT rather than
+        // a panic.
         self.round_trip_time = millis.saturating_sub(body.pong);
     }
 
-    /// Upon receiving a `ChecksumReport`, add it to the checksum history
+    /// Upon receiving a `ChecksumReport`, acknowledge it and add it to the checksum history
     fn on_checksum_report(&mut self, body: &ChecksumReport) {
-        let interval = if let DesyncDetection::On { interval } = self.desync_detection {
-            interval
-        } else {
-            debug_assert!(
-                false,
-                "Received checksum report, but desync detection is off. Check
-                that configuration is consistent between peers."
-            );
-            1
+        self.queue_message(MessageBody::ChecksumReportAck(ChecksumReportAck {
+            frame: body.frame,
+        }));
+
+        let DesyncDetection::On { interval } = self.desync_detection else {
+            // hosts send their checksums to all spectators, which only compare them if they
+            // enabled desync detection themselves
+            trace!("Ignoring checksum report, since desync detection is off");
+            return;
         };
 
+        // the remote sends reports again until our acknowledgement arrives
+        let oldest_received = self.received_checksum_frames.first().copied();
+        if self.received_checksum_frames.contains(&body.frame)
+            || (self.received_checksum_frames.len() >= MAX_CHECKSUM_HISTORY_SIZE
+                && oldest_received.is_some_and(|oldest| body.frame < oldest))
+        {
+            return;
+        }
+        self.received_checksum_frames.insert(body.frame);
+        if self.received_checksum_frames.len() > MAX_CHECKSUM_HISTORY_SIZE {
+            self.received_checksum_frames.pop_first();
+        }
+
         if self.pending_checksums.len() >= MAX_CHECKSUM_HISTORY_SIZE {
             let oldest_frame_to_keep =
                 body.frame - (MAX_CHECKSUM_HISTORY_SIZE as i32 - 1) * interval as i32;
             self.pending_checksums
                 .retain(|&frame, _| frame >= oldest_frame_to_keep);
         }
-        self.pending_checksums.insert(body.frame, body.checksum);
+        self.pending_checksums.insert(body.frame, body.clone());
+    }
+
+    /// Upon receiving a `StateChunk`, acknowledge it and assemble the transfer it belongs to.
+    fn on_state_chunk(&mut self, body: &StateChunk) {
+        if body.transfer_id < self.next_incoming_transfer_id {
+            // a chunk of an already completed transfer, our acknowledgement probably got lost
+            self.send_state_chunk_ack(body);
+            return;
+        }
+        if body.transfer_id > self.next_incoming_transfer_id
+            || body.count == 0
+            || body.count > MAX_TRANSFER_CHUNKS
+        {
+            warn!(
+                "Discarding state chunk {} of transfer {} ({} chunks)",
+                body.index, body.transfer_id, body.count
+            );
+            return;
+        }
+
+        let transfer = self
+            .incoming_transfer
+            .get_or_insert_with(|| IncomingTransfer::new(body.transfer_id, body.count));
+        if !transfer.insert(body) {
+            warn!(
+                "Discarding malformed state chunk {} of transfer {}",
+                body.index, body.transfer_id
+            );
+            return;
+        }
+        let complete = transfer.is_complete();
+        self.send_state_chunk_ack(body);
+
+        if complete {
+            let transfer = self
+                .incoming_transfer
+                .take()
+                .expect("Expected incoming transfer to exist");
+            self.next_incoming_transfer_id = transfer.id() + 1;
+            match bincode::deserialize::<StateTransfer>(&transfer.assemble()) {
+                Ok(transfer) => self
+                    .event_queue
+                    .push_back(Event::StateTransferReceived { transfer }),
+                Err(e) => warn!("Failed to decode state transfer, discarding: {e}"),
+            }
+        }
+    }
+
+    fn send_state_chunk_ack(&mut self, chunk: &StateChunk) {
+        let body = StateChunkAck {
+            transfer_id: chunk.transfer_id,
+            index: chunk.index,
+        };
+        self.queue_message(MessageBody::StateChunkAck(body));
+    }
+
+    /// Upon receiving a `StateChunkAck`, mark the chunk as delivered.
+    fn on_state_chunk_ack(&mut self, body: StateChunkAck) {
+        let Some(transfer) = self.outgoing_transfers.front_mut() else {
+            return;
+        };
+        if transfer.id() != body.transfer_id {
+            return;
+        }
+        transfer.ack(body.index);
+        if transfer.is_complete() {
+            self.outgoing_transfers.pop_front();
+            // start with the next transfer right away
+            self.send_state_chunks(self.clock.now());
+        }
+    }
+
+    /// Upon receiving a `UserMessage`, acknowledge it and deliver all messages that are now in order.
+    fn on_user_message(&mut self, body: &UserMessage) {
+        if body.payload.len() > MAX_USER_MESSAGE_SIZE {
+            warn!(
+                "Discarding user message {} of {} bytes",
+                body.seq,
+                body.payload.len()
+            );
+            return;
+        }
+        if !self.incoming_user_messages.insert(body) {
+            trace!(
+                "Discarding user message {} that arrived too early",
+                body.seq
+            );
+            return;
+        }
+        self.queue_message(MessageBody::UserMessageAck(UserMessageAck {
+            seq: body.seq,
+        }));
+        for payload in self.incoming_user_messages.drain_ready() {
+            self.event_queue.push_back(Event::UserMessage { payload });
+        }
+    }
+
+    /// Upon receiving a `UserMessageAck`, mark the message as delivered.
+    fn on_user_message_ack(&mut self, body: UserMessageAck) {
+        self.outgoing_user_messages.ack(body.seq);
+        // the acknowledgement might make room for more messages
+        if self.state == ProtocolState::Running {
+            self.send_user_messages(self.clock.now());
+        }
+    }
+
+    /// Returns the timestamp for ping measurements. Only we interpret it, the remote just echoes it.
+    fn millis_since_ping_origin(&self) -> u128 {
+        self.clock
+            .now()
+            .duration_since(self.ping_origin)
+            .as_millis()
     }
 
     /// Returns the frame of the last received input
@@ -817,20 +1251,53 @@ impl<T: Config> UdpProtocol<T> {
         }
     }
 
-    pub(crate) fn send_checksum_report(&mut self, frame_to_send: Frame, checksum: u128) {
+    pub(crate) fn send_checksum_report(
+        &mut self,
+        frame_to_send: Frame,
+        checksum: u128,
+        input_hash: Option<u64>,
+        sub_checksums: SubChecksums,
+    ) {
         let body = ChecksumReport {
             frame: frame_to_send,
             checksum,
+            input_hash,
+            sub_checksums,
         };
+        self.outgoing_checksums
+            .insert(frame_to_send, (body.clone(), self.clock.now()));
+        // give up on the oldest report, the remote reports its frame as unverified
+        if self.outgoing_checksums.len() > MAX_CHECKSUM_HISTORY_SIZE {
+            self.outgoing_checksums.pop_first();
+        }
         self.queue_message(MessageBody::ChecksumReport(body));
     }
+
+    fn resend_checksum_reports(&mut self, now: Instant) {
+        let mut to_send = Vec::new();
+        for (report, last_sent) in self.outgoing_checksums.values_mut() {
+            if *last_sent + CHECKSUM_REPORT_RETRY_INTERVAL < now {
+                *last_sent = now;
+                to_send.push(report.clone());
+            }
+        }
+        for report in to_send {
+            self.queue_message(MessageBody::ChecksumReport(report));
+        }
+    }
+
+    /// Upon receiving a `ChecksumReportAck`, stop sending the report again.
+    fn on_checksum_report_ack(&mut self, body: ChecksumReportAck) {
+        self.outgoing_checksums.remove(&body.frame);
+    }
 }
 
 #[cfg(test)]
 mod protocol_tests {
     use super::*;
     use crate::network::compression::encode;
-    use crate::PredictRepeatLast;
+    use crate::network::messages::DesyncState;
+    use crate::{ManualClock, PredictRepeatLast, SystemClock};
     use serde::{Deserialize, Serialize};
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
@@ -853,6 +1320,14 @@ mod protocol_tests {
     }
 
     fn running_protocol(handles: Vec<PlayerHandle>, num_players: usize) -> UdpProtocol<TestConfig> {
+        running_protocol_with_clock(handles, num_players, Arc::new(SystemClock))
+    }
+
+    fn running_protocol_with_clock(
+        handles: Vec<PlayerHandle>,
+        num_players: usize,
+        clock: Arc<dyn Clock>,
+    ) -> UdpProtocol<TestConfig> {
         let mut protocol = UdpProtocol::new(
             handles,
             localhost(9000),
@@ -863,6 +1338,12 @@ mod protocol_tests {
             Duration::from_millis(500),
             60,
             DesyncDetection::Off,
+            None,
+            PacketSecurity::Off,
+            None,
+            false,
+            false,
+            clock,
         );
         protocol.state = ProtocolState::Running;
         protocol
@@ -969,4 +1450,143 @@ mod protocol_tests {
         assert_eq!(protocol.last_recv_frame(), NULL_FRAME);
         assert!(protocol.event_queue.is_empty());
     }
+
+    #[test]
+    fn round_trip_time_is_measured_with_the_clock() {
+        let clock = ManualClock::new();
+        let mut protocol = running_protocol_with_clock(vec![0], 2, Arc::new(clock.clone()));
+
+        clock.advance(Duration::from_millis(250));
+        protocol.send_quality_report();
+        let ping = match protocol.send_queue.pop_back().map(|msg| msg.body) {
+            Some(MessageBody::QualityReport(report)) => report.ping,
+            other => panic!("expected a quality report, got {other:?}"),
+        };
+
+        clock.advance(Duration::from_millis(40));
+        protocol.on_quality_reply(&QualityReply { pong: ping });
+
+        assert_eq!(protocol.round_trip_time, 40);
+    }
+
+    #[test]
+    fn mismatched_sync_request_does_not_disconnect_running_endpoint() {
+        let mut protocol = running_protocol(vec![0], 2);
+        let mut fingerprint = protocol.fingerprint();
+        fingerprint.num_players += 1;
+        let msg = Message {
+            header: MessageHeader { magic: 0 },
+            body: MessageBody::SyncRequest(SyncRequest {
+                random_request: 7,
+                fingerprint,
+                join_token: None,
+            }),
+        };
+
+        protocol.handle_message(&msg);
+
+        assert!(protocol.is_running());
+        assert!(protocol.event_queue.is_empty());
+        assert!(protocol.send_queue.is_empty());
+    }
+
+    #[test]
+    fn oversized_state_transfer_is_not_queued() {
+        let mut protocol = running_protocol(vec![0], 2);
+        let state = vec![0; STATE_CHUNK_SIZE * MAX_TRANSFER_CHUNKS as usize];
+        let transfer = StateTransfer::DesyncState(DesyncState { frame: 0, state });
+
+        assert!(protocol.send_state_transfer(&transfer).is_err());
+        assert!(protocol.outgoing_transfers.is_empty());
+        assert!(protocol.send_queue.is_empty());
+    }
+
+    #[test]
+    fn lost_user_message_is_resent_and_delivered_in_order() {
+        let clock = ManualClock::new();
+        let mut sender = running_protocol_with_clock(vec![0], 2, Arc::new(clock.clone()));
+        let mut receiver = running_protocol(vec![1], 2);
+        for payload in [b"a", b"b", b"c"] {
+            sender.send_user_message(payload.to_vec()).unwrap();
+        }
+
+        // the first message is lost
+        let mut sent: Vec<Message> = sender.send_queue.drain(..).collect();
+        assert_eq!(sent.len(), 3);
+        sent.remove(0);
+        for msg in &sent {
+            receiver.handle_message(msg);
+        }
+        assert!(receiver.event_queue.is_empty());
+        for msg in receiver.send_queue.drain(..).collect::<Vec<_>>() {
+            sender.handle_message(&msg);
+        }
+
+        // only the unacknowledged message is resent
+        clock.advance(USER_MESSAGE_RETRY_INTERVAL * 2);
+        let _ = sender.poll(&[]);
+        let resent: Vec<Message> = sender
+            .send_queue
+            .drain(..)
+            .filter(|msg| matches!(msg.body, MessageBody::UserMessage(_)))
+            .collect();
+        assert_eq!(resent.len(), 1);
+        receiver.handle_message(&resent[0]);
+
+        let payloads: Vec<Vec<u8>> = receiver
+            .event_queue
+            .drain(..)
+            .map(|event| match event {
+                Event::UserMessage { payload } => payload,
+                _ => panic!("expected only user messages"),
+            })
+            .collect();
+        assert_eq!(payloads, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn lost_checksum_report_is_resent_until_acknowledged() {
+        let clock = ManualClock::new();
+        let mut sender = running_protocol_with_clock(vec![0], 2, Arc::new(clock.clone()));
+        let mut receiver = running_protocol(vec![1], 2);
+        receiver.desync_detection = DesyncDetection::On { interval: 10 };
+
+        // the first report is lost
+        sender.send_checksum_report(10, 1234, None, Vec::new());
+        sender.send_queue.clear();
+
+        clock.advance(CHECKSUM_REPORT_RETRY_INTERVAL * 2);
+        let _ = sender.poll(&[]);
+        let resent: Vec<Message> = sender
+            .send_queue
+            .drain(..)
+            .filter(|msg| matches!(msg.body, MessageBody::ChecksumReport(_)))
+            .collect();
+        assert_eq!(resent.len(), 1);
+
+        // a duplicate is acknowledged again, but only stored once
+        receiver.handle_message(&resent[0]);
+        receiver.pending_checksums.clear();
+        receiver.handle_message(&resent[0]);
+        assert!(receiver.pending_checksums.is_empty());
+        let acks: Vec<Message> = receiver.send_queue.drain(..).collect();
+        assert_eq!(acks.len(), 2);
+        sender.handle_message(&acks[0]);
+
+        // once acknowledged, the report is not sent again
+        clock.advance(CHECKSUM_REPORT_RETRY_INTERVAL * 2);
+        let _ = sender.poll(&[]);
+        assert!(!sender
+            .send_queue
+            .iter()
+            .any(|msg| matches!(msg.body, MessageBody::ChecksumReport(_))));
+    }
+
+    #[test]
+    fn oversized_user_message_is_rejected() {
+        let mut protocol = running_protocol(vec![0], 2);
+        let result = protocol.send_user_message(vec![0; MAX_USER_MESSAGE_SIZE + 1]);
+        assert!(matches!(result, Err(GgrsError::InvalidRequest { .. })));
+        assert!(protocol.send_queue.is_empty());
+    }
 }
@@ -0,0 +1,434 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+
+use crate::network::messages::{Message, MessageBody, MessageHeader, Sealed};
+use crate::PacketSecurity;
+
+/// Size of the authentication tag at the end of every sealed payload.
+const TAG_SIZE: usize = 16;
+/// How far a packet can fall behind the newest packet of the remote and still be accepted. Older
+/// packets are dropped, since we can no longer tell whether we have seen them before.
+const REPLAY_WINDOW_SIZE: u64 = 64;
+
+/// Remembers the counters of the packets we accepted from a sender, so replayed packets are
+/// dropped while packets that arrive out of order are still accepted.
+struct ReplayWindow {
+    newest: u64,
+    /// bit `i` is set if the packet with counter `newest - i` has been accepted
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn new(counter: u64) -> Self {
+        Self {
+            newest: counter,
+            seen: 1,
+        }
+    }
+
+    fn is_fresh(&self, counter: u64) -> bool {
+        if counter > self.newest {
+            return true;
+        }
+        let age = self.newest - counter;
+        age < REPLAY_WINDOW_SIZE && self.seen & (1 << age) == 0
+    }
+
+    fn accept(&mut self, counter: u64) {
+        if counter > self.newest {
+            let shift = counter - self.newest;
+            self.seen = if shift < REPLAY_WINDOW_SIZE {
+                self.seen << shift
+            } else {
+                0
+            };
+            self.seen |= 1;
+            self.newest = counter;
+        } else {
+            self.seen |= 1 << (self.newest - counter);
+        }
+    }
+}
+
+/// Seals the messages we send to a single remote and opens the messages we receive from it, as
+/// configured with [`PacketSecurity`]. Since all peers of a match share the key, every packet is
+/// sealed with a random 192-bit nonce, so nonces never repeat under the key. Every sealed message
+/// also carries a random id of its sender and a counter, which let the receiver drop replayed
+/// packets.
+pub(crate) struct PacketGuard {
+    cipher: Option<XChaCha20Poly1305>,
+    encrypt: bool,
+    sender: u64,
+    last_counter: u64,
+    /// replay windows of every sender we accepted a packet from
+    windows: HashMap<u64, ReplayWindow>,
+    /// the sender we currently accept packets from; `None` if packets of all senders are accepted
+    current: Option<u64>,
+    single_sender: bool,
+}
+
+impl PacketGuard {
+    pub(crate) fn new(security: PacketSecurity) -> Self {
+        let (cipher, encrypt) = match security {
+            PacketSecurity::Off => (None, false),
+            PacketSecurity::Authenticated { key } => {
+                (Some(XChaCha20Poly1305::new(Key::from_slice(&key))), false)
+            }
+            PacketSecurity::Encrypted { key } => {
+                (Some(XChaCha20Poly1305::new(Key::from_slice(&key))), true)
+            }
+        };
+        Self {
+            cipher,
+            encrypt,
+            sender: rand::random::<u64>(),
+            last_counter: 0,
+            windows: HashMap::new(),
+            current: None,
+            single_sender: true,
+        }
+    }
+
+    /// Creates a guard for messages from remotes we have no endpoint for. Unlike a guard for a
+    /// single remote, it accepts messages of any number of senders at once, but still drops
+    /// messages that it has received before.
+    pub(crate) fn for_unknown_remotes(security: PacketSecurity) -> Self {
+        Self {
+            single_sender: false,
+            ..Self::new(security)
+        }
+    }
+
+    /// Turns the message into one that only peers with the same key can read and verify. Without
+    /// a key, the message is returned as it is.
+    pub(crate) fn seal(&mut self, msg: Message) -> Message {
+        let Some(cipher) = &self.cipher else {
+            return msg;
+        };
+        self.last_counter += 1;
+        let nonce = rand::random::<[u8; 24]>();
+        let header = associated_data(msg.header, self.sender, self.last_counter);
+        let mut payload = bincode::serialize(&msg.body).expect("message serialization failed");
+        let tag = if self.encrypt {
+            cipher.encrypt_in_place_detached(XNonce::from_slice(&nonce), &header, &mut payload)
+        } else {
+            let aad = [&header[..], &payload].concat();
+            cipher.encrypt_in_place_detached(XNonce::from_slice(&nonce), &aad, &mut [])
+        }
+        .expect("message is too large to be sealed");
+        payload.extend_from_slice(&tag);
+
+        Message {
+            header: msg.header,
+            body: MessageBody::Sealed(Sealed {
+                sender: self.sender,
+                counter: self.last_counter,
+                nonce,
+                encrypted: self.encrypt,
+                payload,
+            }),
+        }
+    }
+
+    /// Verifies and unseals the message, dropping it if it has been received before. The remote
+    /// can only switch to a new sender id, for example after restarting its session, if
+    /// `accept_new_sender` is set. Once the remote switched, messages of the sender it used before
+    /// are dropped for good.
+    pub(crate) fn open<'a>(
+        &mut self,
+        msg: &'a Message,
+        accept_new_sender: bool,
+    ) -> Option<Cow<'a, Message>> {
+        let opened = self.check(msg, accept_new_sender)?;
+        if let MessageBody::Sealed(sealed) = &msg.body {
+            self.windows
+                .entry(sealed.sender)
+                .and_modify(|window| window.accept(sealed.counter))
+                .or_insert_with(|| ReplayWindow::new(sealed.counter));
+            if self.single_sender {
+                self.current = Some(sealed.sender);
+            }
+        }
+        Some(opened)
+    }
+
+    /// Verifies and unseals the message like [`PacketGuard::open()`] would, but without
+    /// remembering that it has been received.
+    pub(crate) fn check<'a>(
+        &self,
+        msg: &'a Message,
+        accept_new_sender: bool,
+    ) -> Option<Cow<'a, Message>> {
+        let Some(cipher) = &self.cipher else {
+            // without a key, sealed messages cannot be verified
+            return match msg.body {
+                MessageBody::Sealed(_) => None,
+                _ => Some(Cow::Borrowed(msg)),
+            };
+        };
+        let MessageBody::Sealed(sealed) = &msg.body else {
+            return None;
+        };
+        if sealed.encrypted != self.encrypt || !self.is_fresh(sealed, accept_new_sender) {
+            return None;
+        }
+        let body = unseal(cipher, msg.header, sealed)?;
+        Some(Cow::Owned(Message {
+            header: msg.header,
+            body,
+        }))
+    }
+
+    fn is_fresh(&self, sealed: &Sealed, accept_new_sender: bool) -> bool {
+        match self.windows.get(&sealed.sender) {
+            // senders the remote switched away from are never accepted again
+            Some(_) if self.single_sender && self.current != Some(sealed.sender) => false,
+            Some(window) => window.is_fresh(sealed.counter),
+            None => !self.single_sender || self.current.is_none() || accept_new_sender,
+        }
+    }
+}
+
+/// The unencrypted parts of a sealed message that are authenticated along with its body.
+fn associated_data(header: MessageHeader, sender: u64, counter: u64) -> Vec<u8> {
+    [
+        &header.magic.to_le_bytes()[..],
+        &sender.to_le_bytes(),
+        &counter.to_le_bytes(),
+    ]
+    .concat()
+}
+
+fn unseal(
+    cipher: &XChaCha20Poly1305,
+    header: MessageHeader,
+    sealed: &Sealed,
+) -> Option<MessageBody> {
+    let split = sealed.payload.len().checked_sub(TAG_SIZE)?;
+    let (bytes, tag) = sealed.payload.split_at(split);
+    let tag = Tag::from_slice(tag);
+    let nonce = XNonce::from_slice(&sealed.nonce);
+    let header = associated_data(header, sealed.sender, sealed.counter);
+    let mut bytes = bytes.to_vec();
+    if sealed.encrypted {
+        cipher
+            .decrypt_in_place_detached(nonce, &header, &mut bytes, tag)
+            .ok()?;
+    } else {
+        let aad = [&header[..], &bytes].concat();
+        cipher
+            .decrypt_in_place_detached(nonce, &aad, &mut [], tag)
+            .ok()?;
+    }
+
+    match bincode::deserialize(&bytes).ok()? {
+        MessageBody::Sealed(_) => None,
+        body => Some(body),
+    }
+}
+
+// #########
+// # TESTS #
+// #########
+
+#[cfg(test)]
+mod security_tests {
+    use super::*;
+    use crate::network::messages::UserMessage;
+    use std::collections::HashSet;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn message(payload: &[u8]) -> Message {
+        Message {
+            header: MessageHeader { magic: 1234 },
+            body: MessageBody::UserMessage(UserMessage {
+                seq: 0,
+                payload: payload.to_vec(),
+            }),
+        }
+    }
+
+    fn sealed_payload(msg: &mut Message) -> &mut Vec<u8> {
+        match &mut msg.body {
+            MessageBody::Sealed(sealed) => &mut sealed.payload,
+            _ => panic!("message is not sealed"),
+        }
+    }
+
+    #[test]
+    fn test_sealed_messages_open_with_the_same_key() {
+        for security in [
+            PacketSecurity::Authenticated { key: KEY },
+            PacketSecurity::Encrypted { key: KEY },
+        ] {
+            let mut sender = PacketGuard::new(security);
+            let mut receiver = PacketGuard::new(security);
+            let sealed = sender.seal(message(b"hello"));
+            assert_ne!(sealed, message(b"hello"));
+            let opened = receiver.open(&sealed, true).unwrap();
+            assert_eq!(opened.into_owned(), message(b"hello"));
+        }
+    }
+
+    #[test]
+    fn test_encrypted_payload_is_unreadable() {
+        let mut authenticated = PacketGuard::new(PacketSecurity::Authenticated { key: KEY });
+        let mut encrypted = PacketGuard::new(PacketSecurity::Encrypted { key: KEY });
+        let plain = b"a rather recognizable payload";
+        let contains_plain = |msg: &mut Message| {
+            sealed_payload(msg)
+                .windows(plain.len())
+                .any(|window| window == plain)
+        };
+        assert!(contains_plain(&mut authenticated.seal(message(plain))));
+        assert!(!contains_plain(&mut encrypted.seal(message(plain))));
+    }
+
+    #[test]
+    fn test_forged_messages_are_dropped() {
+        let security = PacketSecurity::Encrypted { key: KEY };
+        let mut sender = PacketGuard::new(security);
+        let mut receiver = PacketGuard::new(security);
+
+        let mut tampered = sender.seal(message(b"hello"));
+        sealed_payload(&mut tampered)[0] ^= 1;
+        assert!(receiver.open(&tampered, true).is_none());
+
+        let mut wrong_magic = sender.seal(message(b"hello"));
+        wrong_magic.header.magic += 1;
+        assert!(receiver.open(&wrong_magic, true).is_none());
+
+        let mut truncated = sender.seal(message(b"hello"));
+        sealed_payload(&mut truncated).truncate(3);
+        assert!(receiver.open(&truncated, true).is_none());
+
+        let mut other_key = PacketGuard::new(PacketSecurity::Encrypted { key: [8; 32] });
+        assert!(receiver
+            .open(&other_key.seal(message(b"hi")), true)
+            .is_none());
+
+        let mut other_mode = PacketGuard::new(PacketSecurity::Authenticated { key: KEY });
+        assert!(receiver
+            .open(&other_mode.seal(message(b"hi")), true)
+            .is_none());
+
+        // plain messages are dropped as well once a key is set
+        assert!(receiver.open(&message(b"hi"), true).is_none());
+    }
+
+    #[test]
+    fn test_sealed_messages_are_dropped_without_key() {
+        let mut sender = PacketGuard::new(PacketSecurity::Authenticated { key: KEY });
+        let mut receiver = PacketGuard::new(PacketSecurity::Off);
+        assert!(receiver.open(&sender.seal(message(b"hi")), true).is_none());
+        assert!(receiver.open(&message(b"hi"), true).is_some());
+    }
+
+    #[test]
+    fn test_every_message_has_its_own_nonce() {
+        let mut first = PacketGuard::new(PacketSecurity::Encrypted { key: KEY });
+        let mut second = PacketGuard::new(PacketSecurity::Encrypted { key: KEY });
+        // even guards that happen to share a sender id never reuse a nonce
+        second.sender = first.sender;
+        let nonce = |msg: Message| match msg.body {
+            MessageBody::Sealed(sealed) => sealed.nonce,
+            _ => panic!("message is not sealed"),
+        };
+        let nonces: HashSet<_> = (0..100)
+            .flat_map(|_| [first.seal(message(b"hi")), second.seal(message(b"hi"))])
+            .map(nonce)
+            .collect();
+        assert_eq!(nonces.len(), 200);
+    }
+
+    #[test]
+    fn test_sender_and_counter_are_authenticated() {
+        let security = PacketSecurity::Authenticated { key: KEY };
+        let mut sender = PacketGuard::new(security);
+        let mut receiver = PacketGuard::new(security);
+        let mut sealed = sender.seal(message(b"hi"));
+        if let MessageBody::Sealed(sealed) = &mut sealed.body {
+            sealed.counter += 1;
+        }
+        assert!(receiver.open(&sealed, true).is_none());
+    }
+
+    #[test]
+    fn test_replayed_messages_are_dropped() {
+        let security = PacketSecurity::Authenticated { key: KEY };
+        let mut sender = PacketGuard::new(security);
+        let mut receiver = PacketGuard::new(security);
+        let sealed: Vec<_> = (0..100).map(|_| sender.seal(message(b"hi"))).collect();
+
+        assert!(receiver.open(&sealed[50], true).is_some());
+        assert!(receiver.open(&sealed[50], true).is_none());
+        // older messages that arrive late are accepted once, unless they are too old
+        assert!(receiver.open(&sealed[20], true).is_some());
+        assert!(receiver.open(&sealed[20], true).is_none());
+        assert!(receiver.open(&sealed[99], true).is_some());
+        assert!(receiver.open(&sealed[0], true).is_none());
+        assert!(receiver.open(&sealed[50], true).is_none());
+        assert!(receiver.open(&sealed[49], true).is_some());
+    }
+
+    #[test]
+    fn test_new_sender_is_only_accepted_when_allowed() {
+        let security = PacketSecurity::Authenticated { key: KEY };
+        let mut receiver = PacketGuard::new(security);
+        let mut first = PacketGuard::new(security);
+        let mut second = PacketGuard::new(security);
+
+        assert!(receiver.open(&first.seal(message(b"hi")), false).is_some());
+        assert!(receiver.open(&second.seal(message(b"hi")), false).is_none());
+        assert!(receiver.open(&second.seal(message(b"hi")), true).is_some());
+        assert!(receiver.open(&first.seal(message(b"hi")), false).is_none());
+    }
+
+    #[test]
+    fn test_previous_senders_are_never_accepted_again() {
+        let security = PacketSecurity::Authenticated { key: KEY };
+        let mut receiver = PacketGuard::new(security);
+        let mut first = PacketGuard::new(security);
+        let mut second = PacketGuard::new(security);
+        let old = first.seal(message(b"hi"));
+
+        // alternating between senders does not open a way to replay messages
+        assert!(receiver.open(&old, true).is_some());
+        assert!(receiver.open(&second.seal(message(b"hi")), true).is_some());
+        assert!(receiver.open(&old, true).is_none());
+        assert!(receiver.open(&first.seal(message(b"hi")), true).is_none());
+    }
+
+    #[test]
+    fn test_check_does_not_remember_messages() {
+        let security = PacketSecurity::Encrypted { key: KEY };
+        let mut sender = PacketGuard::new(security);
+        let mut receiver = PacketGuard::new(security);
+        let sealed = sender.seal(message(b"hi"));
+
+        assert!(receiver.check(&sealed, true).is_some());
+        assert!(receiver.check(&sealed, true).is_some());
+        assert!(receiver.open(&sealed, true).is_some());
+        assert!(receiver.check(&sealed, true).is_none());
+    }
+
+    #[test]
+    fn test_guard_for_unknown_remotes_accepts_many_senders() {
+        let security = PacketSecurity::Encrypted { key: KEY };
+        let mut receiver = PacketGuard::for_unknown_remotes(security);
+        let mut first = PacketGuard::new(security);
+        let mut second = PacketGuard::new(security);
+        let from_first = first.seal(message(b"hi"));
+        let from_second = second.seal(message(b"hi"));
+
+        assert!(receiver.open(&from_first, false).is_some());
+        assert!(receiver.open(&from_second, false).is_some());
+        assert!(receiver.open(&first.seal(message(b"hi")), false).is_some());
+        assert!(receiver.open(&from_first, false).is_none());
+        assert!(receiver.open(&from_second, false).is_none());
+    }
+}
@@ -0,0 +1,223 @@
+use instant::{Duration, Instant};
+
+use crate::network::messages::StateChunk;
+
+/// Maximum number of payload bytes per chunk. Together with the message overhead, this keeps
+/// chunk packets below the usual safe UDP payload size.
+pub(crate) const STATE_CHUNK_SIZE: usize = 400;
+/// How many chunks can be sent but not yet acknowledged at the same time.
+const MAX_CHUNKS_IN_FLIGHT: usize = 32;
+/// Upper bound on the number of chunks of a single transfer (~26 MB). Larger announced transfers are
+/// rejected by the receiver.
+pub(crate) const MAX_TRANSFER_CHUNKS: u32 = 1 << 16;
+
+/// A payload that is being sent to the remote in chunks. Chunks are resent until acknowledged.
+pub(crate) struct OutgoingTransfer {
+    id: u32,
+    chunks: Vec<Vec<u8>>,
+    acked: Vec<bool>,
+    last_sent: Vec<Option<Instant>>,
+    num_acked: usize,
+}
+
+impl OutgoingTransfer {
+    pub(crate) fn new(id: u32, bytes: &[u8]) -> Self {
+        let mut chunks: Vec<Vec<u8>> = bytes.chunks(STATE_CHUNK_SIZE).map(<[u8]>::to_vec).collect();
+        // an empty payload is still sent as a single empty chunk
+        if chunks.is_empty() {
+            chunks.push(Vec::new());
+        }
+        let count = chunks.len();
+        Self {
+            id,
+            chunks,
+            acked: vec![false; count],
+            last_sent: vec![None; count],
+            num_acked: 0,
+        }
+    }
+
+    pub(crate) fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.num_acked == self.chunks.len()
+    }
+
+    pub(crate) fn ack(&mut self, index: u32) {
+        if let Some(acked) = self.acked.get_mut(index as usize) {
+            if !*acked {
+                *acked = true;
+                self.num_acked += 1;
+            }
+        }
+    }
+
+    /// Returns all chunks that should be sent now: chunks that have never been sent or have not been
+    /// acknowledged within `retry_interval`, limited by the number of chunks in flight.
+    pub(crate) fn chunks_to_send(
+        &mut self,
+        now: Instant,
+        retry_interval: Duration,
+    ) -> Vec<StateChunk> {
+        let is_in_flight = |last_sent: &Option<Instant>| {
+            last_sent.is_some_and(|sent_at| sent_at + retry_interval >= now)
+        };
+        let in_flight = (0..self.chunks.len())
+            .filter(|&i| !self.acked[i] && is_in_flight(&self.last_sent[i]))
+            .count();
+
+        let mut to_send = Vec::new();
+        for i in 0..self.chunks.len() {
+            if in_flight + to_send.len() >= MAX_CHUNKS_IN_FLIGHT {
+                break;
+            }
+            if self.acked[i] || is_in_flight(&self.last_sent[i]) {
+                continue;
+            }
+            self.last_sent[i] = Some(now);
+            to_send.push(StateChunk {
+                transfer_id: self.id,
+                index: i as u32,
+                count: self.chunks.len() as u32,
+                bytes: self.chunks[i].clone(),
+            });
+        }
+        to_send
+    }
+}
+
+/// A payload that is being received from the remote in chunks.
+pub(crate) struct IncomingTransfer {
+    id: u32,
+    chunks: Vec<Option<Vec<u8>>>,
+    num_received: usize,
+}
+
+impl IncomingTransfer {
+    pub(crate) fn new(id: u32, count: u32) -> Self {
+        Self {
+            id,
+            chunks: vec![None; count as usize],
+            num_received: 0,
+        }
+    }
+
+    pub(crate) fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Stores a received chunk. Returns false if the chunk does not belong to this transfer.
+    pub(crate) fn insert(&mut self, chunk: &StateChunk) -> bool {
+        if chunk.transfer_id != self.id || chunk.count as usize != self.chunks.len() {
+            return false;
+        }
+        match self.chunks.get_mut(chunk.index as usize) {
+            Some(slot) => {
+                if slot.is_none() {
+                    *slot = Some(chunk.bytes.clone());
+                    self.num_received += 1;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.num_received == self.chunks.len()
+    }
+
+    /// Concatenates all chunks into the transferred payload.
+    pub(crate) fn assemble(self) -> Vec<u8> {
+        assert!(self.is_complete());
+        self.chunks.into_iter().flatten().flatten().collect()
+    }
+}
+
+// #########
+// # TESTS #
+// #########
+
+#[cfg(test)]
+mod state_transfer_tests {
+    use super::*;
+
+    const RETRY: Duration = Duration::from_millis(200);
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_empty_payload_is_sent_as_single_chunk() {
+        let mut transfer = OutgoingTransfer::new(0, &[]);
+        let chunks = transfer.chunks_to_send(Instant::now(), RETRY);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].count, 1);
+        assert!(chunks[0].bytes.is_empty());
+    }
+
+    #[test]
+    fn test_chunks_reassemble_to_payload() {
+        let bytes = payload(STATE_CHUNK_SIZE * 3 + 17);
+        let mut outgoing = OutgoingTransfer::new(4, &bytes);
+        let chunks = outgoing.chunks_to_send(Instant::now(), RETRY);
+        assert_eq!(chunks.len(), 4);
+
+        let mut incoming = IncomingTransfer::new(4, chunks[0].count);
+        // deliver out of order and with a duplicate
+        for index in [2, 0, 3, 0, 1] {
+            assert!(incoming.insert(&chunks[index]));
+        }
+        assert!(incoming.is_complete());
+        assert_eq!(incoming.assemble(), bytes);
+    }
+
+    #[test]
+    fn test_chunk_of_other_transfer_is_rejected() {
+        let mut outgoing = OutgoingTransfer::new(1, &payload(10));
+        let chunks = outgoing.chunks_to_send(Instant::now(), RETRY);
+        let mut incoming = IncomingTransfer::new(2, 1);
+        assert!(!incoming.insert(&chunks[0]));
+        assert!(!incoming.is_complete());
+    }
+
+    #[test]
+    fn test_unacked_chunks_are_resent_after_retry_interval() {
+        let mut transfer = OutgoingTransfer::new(0, &payload(STATE_CHUNK_SIZE * 2));
+        let start = Instant::now();
+        assert_eq!(transfer.chunks_to_send(start, RETRY).len(), 2);
+        transfer.ack(0);
+
+        // nothing to do while the second chunk is still in flight
+        assert!(transfer.chunks_to_send(start, RETRY).is_empty());
+
+        let resent = transfer.chunks_to_send(start + RETRY * 2, RETRY);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].index, 1);
+
+        transfer.ack(1);
+        assert!(transfer.is_complete());
+    }
+
+    #[test]
+    fn test_chunks_in_flight_are_limited() {
+        let num_chunks = MAX_CHUNKS_IN_FLIGHT + 8;
+        let mut transfer = OutgoingTransfer::new(0, &payload(STATE_CHUNK_SIZE * num_chunks));
+        let now = Instant::now();
+        assert_eq!(
+            transfer.chunks_to_send(now, RETRY).len(),
+            MAX_CHUNKS_IN_FLIGHT
+        );
+
+        // acknowledging chunks frees up room for the remaining ones
+        for index in 0..8 {
+            transfer.ack(index);
+        }
+        let rest = transfer.chunks_to_send(now, RETRY);
+        assert_eq!(rest.len(), 8);
+        assert_eq!(rest[0].index as usize, MAX_CHUNKS_IN_FLIGHT);
+    }
+}
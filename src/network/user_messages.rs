@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use instant::{Duration, Instant};
+
+use crate::network::messages::UserMessage;
+
+/// How many user messages can be sent but not yet acknowledged at the same time. Together with
+/// [`MAX_USER_MESSAGE_SIZE`], this caps the bandwidth user messages take away from inputs.
+///
+/// [`MAX_USER_MESSAGE_SIZE`]: crate::MAX_USER_MESSAGE_SIZE
+pub(crate) const MAX_USER_MESSAGES_IN_FLIGHT: usize = 4;
+/// How many user messages can wait for delivery to a single peer, including the ones in flight.
+pub(crate) const MAX_QUEUED_USER_MESSAGES: usize = 64;
+
+struct PendingUserMessage {
+    seq: u32,
+    payload: Vec<u8>,
+    last_sent: Option<Instant>,
+}
+
+/// User messages that are being sent to the remote. Messages are resent until acknowledged.
+pub(crate) struct OutgoingUserMessages {
+    next_seq: u32,
+    pending: VecDeque<PendingUserMessage>,
+}
+
+impl OutgoingUserMessages {
+    pub(crate) fn new() -> Self {
+        Self {
+            next_seq: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.pending.len() >= MAX_QUEUED_USER_MESSAGES
+    }
+
+    pub(crate) fn push(&mut self, payload: Vec<u8>) {
+        self.pending.push_back(PendingUserMessage {
+            seq: self.next_seq,
+            payload,
+            last_sent: None,
+        });
+        self.next_seq += 1;
+    }
+
+    pub(crate) fn ack(&mut self, seq: u32) {
+        self.pending.retain(|message| message.seq != seq);
+    }
+
+    /// Returns all messages that should be sent now: messages that have never been sent or have not
+    /// been acknowledged within `retry_interval`. Only messages within
+    /// [`MAX_USER_MESSAGES_IN_FLIGHT`] of the oldest unacknowledged one are sent, so the remote never
+    /// has to buffer more messages than that out of order.
+    pub(crate) fn messages_to_send(
+        &mut self,
+        now: Instant,
+        retry_interval: Duration,
+    ) -> Vec<UserMessage> {
+        let Some(oldest) = self.pending.front() else {
+            return Vec::new();
+        };
+        let window_end = oldest.seq + MAX_USER_MESSAGES_IN_FLIGHT as u32;
+
+        let mut to_send = Vec::new();
+        for message in self
+            .pending
+            .iter_mut()
+            .take_while(|message| message.seq < window_end)
+        {
+            if message
+                .last_sent
+                .is_some_and(|sent_at| sent_at + retry_interval >= now)
+            {
+                continue;
+            }
+            message.last_sent = Some(now);
+            to_send.push(UserMessage {
+                seq: message.seq,
+                payload: message.payload.clone(),
+            });
+        }
+        to_send
+    }
+}
+
+/// User messages received from the remote, reordered to the order they were sent in.
+pub(crate) struct IncomingUserMessages {
+    next_seq: u32,
+    early: BTreeMap<u32, Vec<u8>>,
+}
+
+impl IncomingUserMessages {
+    pub(crate) fn new() -> Self {
+        Self {
+            next_seq: 0,
+            early: BTreeMap::new(),
+        }
+    }
+
+    /// Stores a received message. Returns false if the message should not be acknowledged, because
+    /// it is too far ahead of the messages delivered so far.
+    pub(crate) fn insert(&mut self, message: &UserMessage) -> bool {
+        if message.seq < self.next_seq {
+            // a duplicate, our acknowledgement probably got lost
+            return true;
+        }
+        if message.seq - self.next_seq >= MAX_USER_MESSAGES_IN_FLIGHT as u32 {
+            return false;
+        }
+        self.early
+            .entry(message.seq)
+            .or_insert_with(|| message.payload.clone());
+        true
+    }
+
+    /// Returns the payloads of all messages that can be delivered in order.
+    pub(crate) fn drain_ready(&mut self) -> Vec<Vec<u8>> {
+        let mut ready = Vec::new();
+        while let Some(payload) = self.early.remove(&self.next_seq) {
+            ready.push(payload);
+            self.next_seq += 1;
+        }
+        ready
+    }
+}
+
+// #########
+// # TESTS #
+// #########
+
+#[cfg(test)]
+mod user_messages_tests {
+    use super::*;
+
+    const RETRY: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn test_messages_in_flight_are_limited() {
+        let mut outgoing = OutgoingUserMessages::new();
+        for i in 0..MAX_USER_MESSAGES_IN_FLIGHT + 2 {
+            outgoing.push(vec![i as u8]);
+        }
+        let now = Instant::now();
+        let sent = outgoing.messages_to_send(now, RETRY);
+        assert_eq!(sent.len(), MAX_USER_MESSAGES_IN_FLIGHT);
+        assert!(outgoing.messages_to_send(now, RETRY).is_empty());
+
+        // acknowledging a newer message does not move the window
+        outgoing.ack(1);
+        assert!(outgoing.messages_to_send(now, RETRY).is_empty());
+
+        // acknowledging the oldest message moves the window past both acknowledged messages
+        outgoing.ack(0);
+        let sent: Vec<u32> = outgoing
+            .messages_to_send(now, RETRY)
+            .iter()
+            .map(|message| message.seq)
+            .collect();
+        let next = MAX_USER_MESSAGES_IN_FLIGHT as u32;
+        assert_eq!(sent, vec![next, next + 1]);
+    }
+
+    #[test]
+    fn test_unacked_messages_are_resent_after_retry_interval() {
+        let mut outgoing = OutgoingUserMessages::new();
+        outgoing.push(vec![1]);
+        outgoing.push(vec![2]);
+        let start = Instant::now();
+        assert_eq!(outgoing.messages_to_send(start, RETRY).len(), 2);
+        outgoing.ack(1);
+
+        let resent = outgoing.messages_to_send(start + RETRY * 2, RETRY);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].seq, 0);
+    }
+
+    #[test]
+    fn test_queue_is_full_at_limit() {
+        let mut outgoing = OutgoingUserMessages::new();
+        for _ in 0..MAX_QUEUED_USER_MESSAGES {
+            assert!(!outgoing.is_full());
+            outgoing.push(Vec::new());
+        }
+        assert!(outgoing.is_full());
+    }
+
+    #[test]
+    fn test_incoming_messages_are_delivered_in_order_once() {
+        let message = |seq: u32| UserMessage {
+            seq,
+            payload: vec![seq as u8],
+        };
+        let mut incoming = IncomingUserMessages::new();
+        assert!(incoming.insert(&message(1)));
+        assert!(incoming.drain_ready().is_empty());
+
+        assert!(incoming.insert(&message(0)));
+        assert!(incoming.insert(&message(1)));
+        assert_eq!(incoming.drain_ready(), vec![vec![0], vec![1]]);
+
+        // duplicates are acknowledged again, but not delivered
+        assert!(incoming.insert(&message(0)));
+        assert!(incoming.drain_ready().is_empty());
+    }
+
+    #[test]
+    fn test_incoming_messages_too_far_ahead_are_rejected() {
+        let mut incoming = IncomingUserMessages::new();
+        let message = UserMessage {
+            seq: MAX_USER_MESSAGES_IN_FLIGHT as u32,
+            payload: Vec::new(),
+        };
+        assert!(!incoming.insert(&message));
+        assert!(incoming.drain_ready().is_empty());
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{Frame, InputStatus};
+
+/// Magic bytes at the very beginning of every replay.
+const REPLAY_MAGIC: [u8; 4] = *b"GGRP";
+/// The version of the replay format written by this version of GGRS.
+pub const REPLAY_FORMAT_VERSION: u32 = 1;
+
+/// A destination for recorded replays, e.g. a [`std::fs::File`] or a [`Vec<u8>`].
+/// Every [`Write`] implementation can be used; wrap files in a [`std::io::BufWriter`] to avoid
+/// a system call per recorded frame.
+#[cfg(feature = "sync-send")]
+pub trait ReplayWriter: Write + Send + Sync {}
+#[cfg(feature = "sync-send")]
+impl<W: Write + Send + Sync> ReplayWriter for W {}
+
+/// A destination for recorded replays, e.g. a [`std::fs::File`] or a [`Vec<u8>`].
+/// Every [`Write`] implementation can be used; wrap files in a [`std::io::BufWriter`] to avoid
+/// a system call per recorded frame.
+#[cfg(not(feature = "sync-send"))]
+pub trait ReplayWriter: Write {}
+#[cfg(not(feature = "sync-send"))]
+impl<W: Write> ReplayWriter for W {}
+
+impl std::fmt::Debug for dyn ReplayWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplayWriter").finish_non_exhaustive()
+    }
+}
+
+/// How the inputs of a replay are encoded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputEncoding {
+    /// Inputs are serialized with `bincode`, just like on the network.
+    Bincode,
+}
+
+/// The kind of session a replay was recorded from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplaySessionKind {
+    /// Recorded by a [`P2PSession`](crate::P2PSession).
+    P2P,
+    /// Recorded by a [`SyncTestSession`](crate::SyncTestSession).
+    SyncTest,
+    /// Recorded by a [`SpectatorSession`](crate::SpectatorSession).
+    Spectator,
+}
+
+/// Describes the recorded session. Written once at the start of every replay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayHeader {
+    /// The kind of session that recorded the replay.
+    pub session_kind: ReplaySessionKind,
+    /// The number of players of the recorded session.
+    pub num_players: usize,
+    /// How the inputs are encoded.
+    pub input_encoding: InputEncoding,
+    /// The type name of the recorded input type. Only meant as a hint for tooling, since type names
+    /// are not guaranteed to be stable between compiler versions.
+    pub input_type: String,
+    /// The maximum prediction window of the recorded session.
+    pub max_prediction: usize,
+    /// The input delay of the local players of the recorded session.
+    pub input_delay: usize,
+    /// The expected update frequency of the recorded session.
+    pub fps: usize,
+    /// Whether the recorded session used sparse saving.
+    pub sparse_saving: bool,
+    /// The desync detection interval of the recorded session, if desync detection was on.
+    pub desync_detection_interval: Option<u32>,
+}
+
+/// The confirmed inputs of all players for a single frame of a replay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayFrame<I> {
+    /// The frame these inputs belong to.
+    pub frame: Frame,
+    /// The inputs of all players, indexed by player handle. Disconnected players have a default input.
+    pub inputs: Vec<I>,
+    /// For each player, whether the player was disconnected in this frame.
+    pub disconnected: Vec<bool>,
+    /// The checksum of the game state at the beginning of this frame, if the recording session had
+    /// a saved state with a checksum for it.
+    pub checksum: Option<u128>,
+}
+
+impl<I: Copy> ReplayFrame<I> {
+    /// Returns the inputs in the form they are handed to the game in [`GgrsRequest::AdvanceFrame`].
+    ///
+    /// [`GgrsRequest::AdvanceFrame`]: crate::GgrsRequest::AdvanceFrame
+    pub fn input_statuses(&self) -> Vec<(I, InputStatus)> {
+        self.inputs
+            .iter()
+            .zip(&self.disconnected)
+            .map(|(input, &disconnected)| {
+                if disconnected {
+                    (*input, InputStatus::Disconnected)
+                } else {
+                    (*input, InputStatus::Confirmed)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reads a replay written by a session with a replay recorder. Frames are read lazily by iterating
+/// over the reader.
+///
+/// A truncated last frame, as left behind by a crashed recording, ends the replay.
+pub struct ReplayReader<I, R> {
+    reader: R,
+    header: ReplayHeader,
+    finished: bool,
+    _input: PhantomData<I>,
+}
+
+impl<I: DeserializeOwned, R: Read> ReplayReader<I, R> {
+    /// Reads the replay header from the given source.
+    ///
+    /// # Errors
+    /// - Returns an error of kind [`InvalidData`] if the source is not a replay or was written in an
+    ///   unsupported version of the replay format.
+    ///
+    /// [`InvalidData`]: io::ErrorKind::InvalidData
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != REPLAY_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a GGRS replay",
+            ));
+        }
+
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != REPLAY_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported replay format version {version}"),
+            ));
+        }
+
+        let header = bincode::deserialize_from(&mut reader).map_err(|err| into_io_error(*err))?;
+        Ok(Self {
+            reader,
+            header,
+            finished: false,
+            _input: PhantomData,
+        })
+    }
+
+    /// Returns the header of the replay.
+    pub fn header(&self) -> &ReplayHeader {
+        &self.header
+    }
+}
+
+impl<I: DeserializeOwned, R: Read> Iterator for ReplayReader<I, R> {
+    type Item = io::Result<ReplayFrame<I>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        match bincode::deserialize_from(&mut self.reader) {
+            Ok(frame) => Some(Ok(frame)),
+            Err(err) => {
+                self.finished = true;
+                match *err {
+                    bincode::ErrorKind::Io(ref io_err)
+                        if io_err.kind() == io::ErrorKind::UnexpectedEof =>
+                    {
+                        None
+                    }
+                    _ => Some(Err(into_io_error(*err))),
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn into_io_error(err: bincode::ErrorKind) -> io::Error {
+    match err {
+        bincode::ErrorKind::Io(io_err) => io_err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+/// Streams confirmed frames into a [`ReplayWriter`]. Frames can be recorded right away or be kept
+/// pending until the checksum of their game state is known.
+///
+/// Writing is best effort: if the writer fails, a warning is logged and recording stops, but the
+/// session keeps running.
+pub(crate) struct ReplayRecorder<I: Serialize> {
+    writer: Option<Box<dyn ReplayWriter>>,
+    next_frame: Frame,
+    pending: VecDeque<ReplayFrame<I>>,
+}
+
+impl<I: Serialize> ReplayRecorder<I> {
+    /// Creates a recorder and writes the replay header.
+    pub(crate) fn new(writer: Box<dyn ReplayWriter>, header: &ReplayHeader) -> Self {
+        let mut recorder = Self {
+            writer: Some(writer),
+            next_frame: 0,
+            pending: VecDeque::new(),
+        };
+        recorder.write_with(|writer| {
+            writer.write_all(&REPLAY_MAGIC)?;
+            writer.write_all(&REPLAY_FORMAT_VERSION.to_le_bytes())?;
+            bincode::serialize_into(writer, header).map_err(|err| into_io_error(*err))
+        });
+        recorder
+    }
+
+    /// The next frame the recorder expects.
+    pub(crate) fn next_frame(&self) -> Frame {
+        self.next_frame
+    }
+
+    /// Continues recording at the given frame. Pending frames from before are written without
+    /// checksums. Used when the session skips frames, e.g. after receiving a join snapshot.
+    pub(crate) fn skip_to(&mut self, frame: Frame) {
+        self.flush_pending(Frame::MAX, |_| None);
+        self.next_frame = frame;
+    }
+
+    /// Writes the frame right away.
+    pub(crate) fn record(&mut self, frame: ReplayFrame<I>) {
+        self.next_frame = frame.frame + 1;
+        self.write_frame(&frame);
+    }
+
+    /// Keeps the frame until its checksum is known, see [`Self::flush_pending()`].
+    pub(crate) fn record_pending(&mut self, frame: ReplayFrame<I>) {
+        self.next_frame = frame.frame + 1;
+        self.pending.push_back(frame);
+    }
+
+    /// Writes all pending frames up to and including `up_to`, taking their checksum from `checksum`.
+    pub(crate) fn flush_pending(&mut self, up_to: Frame, checksum: impl Fn(Frame) -> Option<u128>) {
+        while self.pending.front().is_some_and(|f| f.frame <= up_to) {
+            let mut frame = self.pending.pop_front().expect("pending frame");
+            frame.checksum = checksum(frame.frame);
+            self.write_frame(&frame);
+        }
+    }
+
+    fn write_frame(&mut self, frame: &ReplayFrame<I>) {
+        self.write_with(|writer| {
+            bincode::serialize_into(writer, frame).map_err(|err| into_io_error(*err))
+        });
+    }
+
+    fn write_with(&mut self, write: impl FnOnce(&mut dyn ReplayWriter) -> io::Result<()>) {
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+        if let Err(err) = write(writer.as_mut()) {
+            warn!("Writing the replay failed, recording stops: {err}");
+            self.writer = None;
+        }
+    }
+}
+
+impl<I: Serialize> Drop for ReplayRecorder<I> {
+    fn drop(&mut self) {
+        self.flush_pending(Frame::MAX, |_| None);
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(err) = writer.flush() {
+                warn!("Flushing the replay failed: {err}");
+            }
+        }
+    }
+}
+
+impl<I: Serialize> std::fmt::Debug for ReplayRecorder<I> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplayRecorder")
+            .field("next_frame", &self.next_frame)
+            .finish_non_exhaustive()
+    }
+}
+
+// #########
+// # TESTS #
+// #########
+
+#[cfg(test)]
+mod replay_tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    /// A writer whose buffer stays accessible after the recorder took ownership of it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn header() -> ReplayHeader {
+        ReplayHeader {
+            session_kind: ReplaySessionKind::P2P,
+            num_players: 2,
+            input_encoding: InputEncoding::Bincode,
+            input_type: "u32".to_owned(),
+            max_prediction: 8,
+            input_delay: 2,
+            fps: 60,
+            sparse_saving: false,
+            desync_detection_interval: Some(10),
+        }
+    }
+
+    fn frame(frame: Frame) -> ReplayFrame<u32> {
+        ReplayFrame {
+            frame,
+            inputs: vec![frame as u32, 7],
+            disconnected: vec![false, frame > 1],
+            checksum: None,
+        }
+    }
+
+    fn read(buffer: &SharedBuffer) -> (ReplayHeader, Vec<ReplayFrame<u32>>) {
+        let bytes = buffer.0.lock().clone();
+        let reader = ReplayReader::<u32, _>::new(bytes.as_slice()).unwrap();
+        let header = reader.header().clone();
+        (header, reader.map(Result::unwrap).collect())
+    }
+
+    #[test]
+    fn test_recorded_frames_read_back() {
+        let buffer = SharedBuffer::default();
+        let mut recorder = ReplayRecorder::new(Box::new(buffer.clone()), &header());
+        for f in 0..3 {
+            recorder.record(frame(f));
+        }
+        assert_eq!(recorder.next_frame(), 3);
+        drop(recorder);
+
+        let (read_header, frames) = read(&buffer);
+        assert_eq!(read_header, header());
+        assert_eq!(frames, vec![frame(0), frame(1), frame(2)]);
+        assert_eq!(
+            frames[2].input_statuses(),
+            vec![(2, InputStatus::Confirmed), (7, InputStatus::Disconnected)]
+        );
+    }
+
+    #[test]
+    fn test_pending_frames_get_checksums_when_flushed() {
+        let buffer = SharedBuffer::default();
+        let mut recorder = ReplayRecorder::new(Box::new(buffer.clone()), &header());
+        for f in 0..3 {
+            recorder.record_pending(frame(f));
+        }
+        recorder.flush_pending(1, |f| Some(f as u128 + 100));
+        // the last frame is flushed without checksum when the recorder is dropped
+        drop(recorder);
+
+        let (_, frames) = read(&buffer);
+        let checksums: Vec<_> = frames.iter().map(|f| f.checksum).collect();
+        assert_eq!(checksums, vec![Some(100), Some(101), None]);
+    }
+
+    #[test]
+    fn test_truncated_replay_ends_after_last_complete_frame() {
+        let buffer = SharedBuffer::default();
+        let mut recorder = ReplayRecorder::new(Box::new(buffer.clone()), &header());
+        recorder.record(frame(0));
+        recorder.record(frame(1));
+        drop(recorder);
+
+        let mut bytes = buffer.0.lock().clone();
+        bytes.truncate(bytes.len() - 3);
+        let frames: Vec<_> = ReplayReader::<u32, _>::new(bytes.as_slice())
+            .unwrap()
+            .collect();
+        assert_eq!(frames.len(), 1);
+    }
+
+    #[test]
+    fn test_unknown_version_is_rejected() {
+        let mut bytes = REPLAY_MAGIC.to_vec();
+        bytes.extend_from_slice(&(REPLAY_FORMAT_VERSION + 1).to_le_bytes());
+        let err = ReplayReader::<u32, _>::new(bytes.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(ReplayReader::<u32, _>::new(&b"not a replay"[..]).is_err());
+    }
+
+    #[test]
+    fn test_failing_writer_stops_recording() {
+        let mut recorder = ReplayRecorder::<u32>::new(Box::new(FailingWriter), &header());
+        assert!(recorder.writer.is_none());
+        // recording keeps working without a writer
+        recorder.record(frame(0));
+        assert_eq!(recorder.next_frame(), 1);
+    }
+}
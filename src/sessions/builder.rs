@@ -1,11 +1,22 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Read;
+use std::sync::Arc;
 
 use instant::Duration;
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::warn;
 
 use crate::{
-    network::protocol::UdpProtocol, sessions::p2p_session::PlayerRegistry, Config, DesyncDetection,
-    GgrsError, NonBlockingSocket, P2PSession, PlayerHandle, PlayerType, SpectatorSession,
-    SyncTestSession,
+    desync_bundle::DesyncBundleRecorder,
+    input_delay::InputDelayController,
+    network::protocol::UdpProtocol,
+    replay::{ReplayRecorder, ReplayWriter},
+    sessions::p2p_session::PlayerRegistry,
+    sync_layer::StateCodec,
+    AdaptiveInputDelay, Clock, Config, DesyncDetection, DesyncRecovery, GgrsError, InputEncoding,
+    NonBlockingSocket, P2PSession, PacketSecurity, PlayerHandle, PlayerType, ReplayHeader,
+    ReplayReader, ReplaySession, ReplaySessionKind, SpectatorSession, SyncTestSession, SystemClock,
+    MAX_JOIN_TOKEN_SIZE,
 };
 
 // The amount of inputs a spectator can buffer (a second worth of inputs at 60 FPS)
@@ -24,6 +35,8 @@ const DEFAULT_CHECK_DISTANCE: usize = 2;
 const DEFAULT_MAX_FRAMES_BEHIND: usize = 10;
 // The amount of frames the spectator advances in a single step if too far behind
 const DEFAULT_CATCHUP_SPEED: usize = 1;
+// The amount of frames between two saved states of a replay session
+const DEFAULT_KEYFRAME_INTERVAL: usize = 60;
 // The amount of events a spectator can buffer; should never be an issue if the user polls the events at every step
 pub(crate) const MAX_EVENT_QUEUE_SIZE: usize = 100;
 
@@ -51,6 +64,35 @@ where
     check_dist: usize,
     max_frames_behind: usize,
     catchup_speed: usize,
+    /// Player handles that are left free at session start for players joining later.
+    late_join_slots: BTreeSet<PlayerHandle>,
+    /// If true, the session joins a match that is already running.
+    late_join: bool,
+    reconnect: bool,
+    /// If true, the session sends and loads game states for late joins, rejoins and spectators.
+    state_transfer: bool,
+    state_codec: Option<StateCodec<T::State>>,
+    /// If true, peers exchange their saved states of frames with mismatching checksums.
+    desync_state_exchange: bool,
+    /// If true, remote peers whose checksums disagree with the majority are disconnected.
+    disconnect_desync_outliers: bool,
+    /// Which peer, if any, sends its state to peers that desynced.
+    desync_recovery: DesyncRecovery,
+    /// If true, the session keeps what is needed to create a desync bundle for detected desyncs.
+    desync_bundles: bool,
+    /// Destination for a replay of the session, if the user wants one.
+    replay_writer: Option<Box<dyn ReplayWriter>>,
+    /// The amount of frames between two saved states of a replay session.
+    keyframe_interval: usize,
+    /// The build hash of the game, which all peers need to share.
+    build_hash: Option<u64>,
+    /// How packets between peers are protected.
+    packet_security: PacketSecurity,
+    /// The token we send to peers that do not know us yet.
+    join_token: Option<Vec<u8>>,
+    /// The source of time for all timers of the session.
+    clock: Arc<dyn Clock>,
+    adaptive_input_delay: Option<AdaptiveInputDelay>,
 }
 
 impl<T: Config> Default for SessionBuilder<T> {
@@ -76,6 +118,22 @@ impl<T: Config> SessionBuilder<T> {
             check_dist: DEFAULT_CHECK_DISTANCE,
             max_frames_behind: DEFAULT_MAX_FRAMES_BEHIND,
             catchup_speed: DEFAULT_CATCHUP_SPEED,
+            late_join_slots: BTreeSet::new(),
+            late_join: false,
+            reconnect: false,
+            state_transfer: false,
+            state_codec: None,
+            desync_state_exchange: false,
+            disconnect_desync_outliers: false,
+            desync_recovery: DesyncRecovery::Off,
+            desync_bundles: false,
+            replay_writer: None,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+            build_hash: None,
+            packet_security: PacketSecurity::Off,
+            join_token: None,
+            clock: Arc::new(SystemClock),
+            adaptive_input_delay: None,
         }
     }
 
@@ -95,7 +153,9 @@ impl<T: Config> SessionBuilder<T> {
         player_handle: PlayerHandle,
     ) -> Result<Self, GgrsError> {
         // check if the player handle is already in use
-        if self.player_reg.handles.contains_key(&player_handle) {
+        if self.player_reg.handles.contains_key(&player_handle)
+            || self.late_join_slots.contains(&player_handle)
+        {
             return Err(GgrsError::InvalidRequest {
                 info: "Player handle already in use.".to_owned(),
             });
@@ -109,6 +169,70 @@ impl<T: Config> SessionBuilder<T> {
         Ok(self)
     }
 
+    /// Reserves a player handle for a player that joins after the session has started. Until then,
+    /// the player is reported as [`InputStatus::Disconnected`]. Once the joining peer is known,
+    /// register it with [`P2PSession::add_remote_player()`]. Reserved handles count towards the
+    /// players that need to be registered before starting the session.
+    ///
+    /// Late joins require [`with_state_transfer()`] on every peer.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the handle is already in use or not in `0..num_players`.
+    ///
+    /// [`InputStatus::Disconnected`]: crate::InputStatus::Disconnected
+    /// [`with_state_transfer()`]: Self::with_state_transfer
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn add_late_join_slot(mut self, player_handle: PlayerHandle) -> Result<Self, GgrsError> {
+        if self.player_reg.handles.contains_key(&player_handle)
+            || self.late_join_slots.contains(&player_handle)
+        {
+            return Err(GgrsError::InvalidRequest {
+                info: "Player handle already in use.".to_owned(),
+            });
+        }
+        if player_handle >= self.num_players {
+            return Err(GgrsError::InvalidRequest {
+                info: "The player handle you provided is invalid. For a late join slot, the handle should be between 0 and num_players".to_owned(),
+            });
+        }
+        self.late_join_slots.insert(player_handle);
+        Ok(self)
+    }
+
+    /// Makes this session join a match that is already in progress. Register the local player with
+    /// the handle reserved for it via [`add_late_join_slot()`] on the other peers, register all
+    /// other peers as remote players and all other free handles as late join slots.
+    ///
+    /// After synchronizing, the session asks the other peers to be admitted and stays in
+    /// [`SessionState::Synchronizing`] until it received a snapshot of the game. The first
+    /// [`advance_frame()`] afterwards loads the snapshot and fast-forwards to the join frame.
+    ///
+    /// [`add_late_join_slot()`]: Self::add_late_join_slot
+    /// [`SessionState::Synchronizing`]: crate::SessionState::Synchronizing
+    /// [`advance_frame()`]: P2PSession::advance_frame
+    pub fn with_late_join(mut self, late_join: bool) -> Self {
+        self.late_join = late_join;
+        self
+    }
+
+    /// Lets remote players that were disconnected rejoin the match under their old handle. Every
+    /// peer of the session needs to enable this.
+    ///
+    /// A peer that lost the connection to all remote players keeps running on its own while it
+    /// tries to synchronize with them again. Once it reaches the remaining peers, it is admitted
+    /// like a late joining player: it receives a snapshot of the match and continues from the
+    /// rejoin frame, and all peers emit [`GgrsEvent::PlayerRejoined`]. If all peers lost each
+    /// other, the peer with the lowest player handle continues the match and the others rejoin it.
+    ///
+    /// Requires [`with_state_transfer()`]. Peers that host spectators never rejoin by themselves.
+    ///
+    /// [`GgrsEvent::PlayerRejoined`]: crate::GgrsEvent::PlayerRejoined
+    /// [`with_state_transfer()`]: Self::with_state_transfer
+    pub fn with_reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
     fn validate_player_handle(
         player_type: &PlayerType<T::Address>,
         player_handle: PlayerHandle,
@@ -178,6 +302,41 @@ impl<T: Config> SessionBuilder<T> {
         self
     }
 
+    /// Lets a [`P2PSession`] adjust the input delay of its local players on its own, based on the
+    /// measured round trip time, the frame advantage and how often the session stops at the
+    /// prediction threshold. The delay stays within the bounds of the given settings; the delay set
+    /// with [`with_input_delay()`] is the starting point. Whenever the delay changes, the session
+    /// emits a [`GgrsEvent::InputDelayChanged`]. See [`AdaptiveInputDelay`] for the rules.
+    ///
+    /// Changes are applied like [`P2PSession::set_input_delay()`], so remote peers keep receiving
+    /// an input for every frame. Delay you set manually is overridden by the next adjustment. Each
+    /// peer adjusts its own delay, so peers may end up with different delays.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if `min_delay` is higher than `max_delay` or `interval` is 0.
+    ///
+    /// [`with_input_delay()`]: Self::with_input_delay
+    /// [`GgrsEvent::InputDelayChanged`]: crate::GgrsEvent::InputDelayChanged
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn with_adaptive_input_delay(
+        mut self,
+        settings: AdaptiveInputDelay,
+    ) -> Result<Self, GgrsError> {
+        if settings.min_delay > settings.max_delay {
+            return Err(GgrsError::InvalidRequest {
+                info: "The minimum input delay must not be higher than the maximum input delay."
+                    .to_owned(),
+            });
+        }
+        if settings.interval == 0 {
+            return Err(GgrsError::InvalidRequest {
+                info: "The adaptive input delay interval must be higher than 0.".to_owned(),
+            });
+        }
+        self.adaptive_input_delay = Some(settings);
+        Ok(self)
+    }
+
     /// Change number of total players. Default is 2.
     ///
     /// Must be at least 1. This value determines valid player handle ranges: local and remote
@@ -201,6 +360,16 @@ impl<T: Config> SessionBuilder<T> {
         for (&player_handle, player_type) in &self.player_reg.handles {
             Self::validate_player_handle(player_type, player_handle, num_players)?;
         }
+        if self
+            .late_join_slots
+            .iter()
+            .any(|&player_handle| player_handle >= num_players)
+        {
+            return Err(GgrsError::InvalidRequest {
+                info: "The late join slots you reserved are invalid for the new number of players."
+                    .to_owned(),
+            });
+        }
         self.num_players = num_players;
         Ok(self)
     }
@@ -217,11 +386,23 @@ impl<T: Config> SessionBuilder<T> {
 
     /// Sets the desync detection mode. With desync detection, the session compares checksums for all peers to detect desyncs.
     /// If a desync is found, the session sends a `DesyncDetected` event. `DesyncDetection::On` requires an interval higher than 0 when starting a P2P session.
+    /// Spectators with desync detection compare their checksums with the checksums of the host.
     pub fn with_desync_detection_mode(mut self, desync_detection: DesyncDetection) -> Self {
         self.desync_detection = desync_detection;
         self
     }
 
+    /// With three or more peers, desync detection tallies the checksums of all peers and names the
+    /// peers that disagree with the majority in a [`GgrsEvent::DesyncAttributed`]. If `disconnect`
+    /// is true, the session also disconnects the players of those peers. If we are the ones who
+    /// diverged, the other peers disconnect us instead. Requires desync detection.
+    ///
+    /// [`GgrsEvent::DesyncAttributed`]: crate::GgrsEvent::DesyncAttributed
+    pub fn with_desync_outlier_disconnect(mut self, disconnect: bool) -> Self {
+        self.disconnect_desync_outliers = disconnect;
+        self
+    }
+
     /// Sets the disconnect timeout. The session will automatically disconnect from a remote peer if it has not received a packet in the timeout window.
     pub fn with_disconnect_timeout(mut self, timeout: Duration) -> Self {
         self.disconnect_timeout = timeout;
@@ -249,6 +430,96 @@ impl<T: Config> SessionBuilder<T> {
         Ok(self)
     }
 
+    /// Records a replay of the session into the given writer. The replay starts with a
+    /// [`ReplayHeader`] describing the session, followed by the inputs and disconnect status of all
+    /// players for every confirmed frame, plus the checksum of the game state for that frame if it
+    /// was saved with one. Use [`ReplayReader`] to read it back.
+    ///
+    /// Recording works for all session types. Frames are written as soon as they are confirmed, so
+    /// they trail the current frame of a [`P2PSession`] by a few frames. Recording stops with a
+    /// warning if the writer returns an error.
+    ///
+    /// [`ReplayReader`]: crate::ReplayReader
+    pub fn with_replay_recorder(mut self, writer: impl ReplayWriter + 'static) -> Self {
+        self.replay_writer = Some(Box::new(writer));
+        self
+    }
+
+    /// Sets a hash of your game build, e.g. of the version or the commit it was built from. Peers and
+    /// spectators compare their build hashes while synchronizing and reject each other with a
+    /// [`GgrsEvent::ConfigMismatch`] if they differ, just like they do for incompatible session
+    /// settings. Default is no build hash, which only matches peers without a build hash either.
+    ///
+    /// [`GgrsEvent::ConfigMismatch`]: crate::GgrsEvent::ConfigMismatch
+    pub fn with_build_hash(mut self, build_hash: u64) -> Self {
+        self.build_hash = Some(build_hash);
+        self
+    }
+
+    /// Protects all packets of the session with a key that is shared by all peers and spectators of
+    /// the match. Every packet is authenticated and carries a counter, so forged, altered and replayed
+    /// packets are dropped before they are handled. With [`PacketSecurity::Encrypted`], the contents
+    /// of the packets are encrypted as well. Peers using a different key or mode cannot read each
+    /// other's packets and never finish synchronizing. Default is [`PacketSecurity::Off`].
+    pub fn with_packet_security(mut self, packet_security: PacketSecurity) -> Self {
+        self.packet_security = packet_security;
+        self
+    }
+
+    /// Sets a token that is sent along while synchronizing, e.g. a ticket from your matchmaking
+    /// server. A [`P2PSession`] that does not know this client yet hands the token to the user in a
+    /// [`GgrsEvent::ConnectionRequest`], who can then decide whether to let the client in. Default is
+    /// no token.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the token is larger than [`MAX_JOIN_TOKEN_SIZE`].
+    ///
+    /// [`GgrsEvent::ConnectionRequest`]: crate::GgrsEvent::ConnectionRequest
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    /// [`MAX_JOIN_TOKEN_SIZE`]: crate::MAX_JOIN_TOKEN_SIZE
+    pub fn with_join_token(mut self, token: impl Into<Vec<u8>>) -> Result<Self, GgrsError> {
+        let token = token.into();
+        if token.len() > MAX_JOIN_TOKEN_SIZE {
+            return Err(GgrsError::InvalidRequest {
+                info: format!("The join token cannot be larger than {MAX_JOIN_TOKEN_SIZE} bytes."),
+            });
+        }
+        self.join_token = Some(token);
+        Ok(self)
+    }
+
+    /// Sets the source of time for all timers of the session, like timeouts, keep-alives and ping
+    /// measurements. Default is the [`SystemClock`]. Use a [`ManualClock`] to step time explicitly,
+    /// e.g. to test timeouts without waiting for them.
+    ///
+    /// [`ManualClock`]: crate::ManualClock
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets how many frames apart a [`ReplaySession`] saves the game state. Seeking backwards loads
+    /// the closest saved state and fast-forwards from there, so smaller intervals make seeking
+    /// faster at the cost of memory. Default is 60.
+    ///
+    /// With [`with_desync_bundles()`], this is also the interval in which a [`P2PSession`] keeps a
+    /// serialized state to start desync bundles from.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the interval is 0.
+    ///
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    /// [`with_desync_bundles()`]: Self::with_desync_bundles
+    pub fn with_keyframe_interval(mut self, interval: usize) -> Result<Self, GgrsError> {
+        if interval == 0 {
+            return Err(GgrsError::InvalidRequest {
+                info: "Keyframe interval cannot be smaller than 1.".to_owned(),
+            });
+        }
+        self.keyframe_interval = interval;
+        Ok(self)
+    }
+
     /// Change the check distance for [`SyncTestSession`]. Default is 2.
     ///
     /// The check distance is the number of frames that will be rolled back and re-simulated each
@@ -329,13 +600,103 @@ impl<T: Config> SessionBuilder<T> {
 
         // check if all players are added
         for player_handle in 0..self.num_players {
-            if !self.player_reg.handles.contains_key(&player_handle) {
+            if !self.player_reg.handles.contains_key(&player_handle)
+                && !self.late_join_slots.contains(&player_handle)
+            {
                 return Err(GgrsError::InvalidRequest{
                     info: "Not enough players have been added. Keep registering players up to the defined player number.".to_owned(),
                 });
             }
         }
 
+        if (self.late_join || !self.late_join_slots.is_empty()) && !self.state_transfer {
+            return Err(GgrsError::InvalidRequest {
+                info:
+                    "Late joins require state transfers to be enabled with with_state_transfer()."
+                        .to_owned(),
+            });
+        }
+
+        if self.desync_state_exchange && self.desync_detection == DesyncDetection::Off {
+            return Err(GgrsError::InvalidRequest {
+                info: "Desync state exchange requires desync detection to be enabled with with_desync_detection_mode()."
+                    .to_owned(),
+            });
+        }
+
+        if self.disconnect_desync_outliers && self.desync_detection == DesyncDetection::Off {
+            return Err(GgrsError::InvalidRequest {
+                info: "Disconnecting desync outliers requires desync detection to be enabled with with_desync_detection_mode()."
+                    .to_owned(),
+            });
+        }
+
+        if self.desync_recovery != DesyncRecovery::Off
+            && self.desync_detection == DesyncDetection::Off
+        {
+            return Err(GgrsError::InvalidRequest {
+                info: "Desync recovery requires desync detection to be enabled with with_desync_detection_mode()."
+                    .to_owned(),
+            });
+        }
+
+        if self.desync_bundles && self.desync_detection == DesyncDetection::Off {
+            return Err(GgrsError::InvalidRequest {
+                info: "Desync bundles require desync detection to be enabled with with_desync_detection_mode()."
+                    .to_owned(),
+            });
+        }
+
+        if let DesyncRecovery::Authority { player_handle } = self.desync_recovery {
+            if player_handle >= self.num_players {
+                return Err(GgrsError::InvalidRequest {
+                    info: "The desync recovery authority must be a player of the session."
+                        .to_owned(),
+                });
+            }
+        }
+
+        if self.reconnect && !self.state_transfer {
+            return Err(GgrsError::InvalidRequest {
+                info: "Reconnecting requires state transfers to be enabled with with_state_transfer()."
+                    .to_owned(),
+            });
+        }
+
+        if self.late_join {
+            if self.local_players != 1 {
+                return Err(GgrsError::InvalidRequest {
+                    info: "A late joining session must have exactly one local player.".to_owned(),
+                });
+            }
+            if self.player_reg.remote_player_handles().is_empty() {
+                return Err(GgrsError::InvalidRequest {
+                    info: "A late joining session needs remote players to join.".to_owned(),
+                });
+            }
+            if self.player_reg.num_spectators() > 0 {
+                return Err(GgrsError::InvalidRequest {
+                    info: "A late joining session cannot have spectators.".to_owned(),
+                });
+            }
+        }
+
+        let replay_recorder = self.replay_recorder(ReplaySessionKind::P2P);
+        let desync_bundle_recorder = self.desync_bundles.then(|| {
+            DesyncBundleRecorder::new(
+                self.replay_header(ReplaySessionKind::P2P),
+                self.keyframe_interval,
+            )
+        });
+
+        // adaptive input delay starts from the configured delay, moved into its bounds
+        let input_delay_controller = self.adaptive_input_delay.map(|settings| {
+            self.input_delay = self
+                .input_delay
+                .clamp(settings.min_delay, settings.max_delay);
+            InputDelayController::new(settings, self.input_delay)
+        });
+
         // count the number of players per address
         let mut addr_count = HashMap::<PlayerType<T::Address>, Vec<PlayerHandle>>::new();
         for (handle, player_type) in &self.player_reg.handles {
@@ -376,6 +737,23 @@ impl<T: Config> SessionBuilder<T> {
             self.desync_detection,
             self.input_delay,
             self.fps,
+            self.disconnect_timeout,
+            self.disconnect_notify_start,
+            self.late_join_slots,
+            self.late_join,
+            self.reconnect,
+            self.state_transfer,
+            self.state_codec,
+            self.desync_state_exchange,
+            self.disconnect_desync_outliers,
+            self.desync_recovery,
+            desync_bundle_recorder,
+            replay_recorder,
+            self.build_hash,
+            self.packet_security,
+            self.join_token,
+            self.clock,
+            input_delay_controller,
         ))
     }
 
@@ -384,7 +762,7 @@ impl<T: Config> SessionBuilder<T> {
     /// The host will broadcast all confirmed inputs to this session.
     /// This session can be used to spectate a session without contributing to the game input.
     pub fn start_spectator_session(
-        self,
+        mut self,
         host_addr: T::Address,
         socket: impl NonBlockingSocket<T::Address> + 'static,
     ) -> SpectatorSession<T> {
@@ -398,15 +776,27 @@ impl<T: Config> SessionBuilder<T> {
             self.disconnect_timeout,
             self.disconnect_notify_start,
             self.fps,
-            DesyncDetection::Off,
+            self.desync_detection,
+            self.build_hash,
+            self.packet_security,
+            self.join_token.clone(),
+            true,
+            self.state_transfer,
+            self.clock.clone(),
         );
         host.synchronize();
+        let replay_recorder = self.replay_recorder(ReplaySessionKind::Spectator);
         SpectatorSession::new(
             self.num_players,
             Box::new(socket),
             host,
             self.max_frames_behind,
             self.catchup_speed,
+            replay_recorder,
+            self.desync_detection,
+            // spectators only need the codec for snapshots
+            self.state_codec.filter(|_| self.state_transfer),
+            self.clock,
         )
     }
 
@@ -424,7 +814,7 @@ impl<T: Config> SessionBuilder<T> {
     ///   to compare checksums across the full check window, so sparse saving is incompatible.
     ///
     /// [`InvalidRequest`]: GgrsError::InvalidRequest
-    pub fn start_synctest_session(self) -> Result<SyncTestSession<T>, GgrsError> {
+    pub fn start_synctest_session(mut self) -> Result<SyncTestSession<T>, GgrsError> {
         if self.check_dist >= self.max_prediction {
             return Err(GgrsError::InvalidRequest {
                 info: "Check distance too big.".to_owned(),
@@ -435,14 +825,102 @@ impl<T: Config> SessionBuilder<T> {
                 info: "Sparse saving is not supported for synctest sessions.".to_owned(),
             });
         }
+        let replay_recorder = self.replay_recorder(ReplaySessionKind::SyncTest);
         Ok(SyncTestSession::new(
             self.num_players,
             self.max_prediction,
             self.check_dist,
             self.input_delay,
+            replay_recorder,
+        ))
+    }
+
+    /// Consumes the builder to construct a [`ReplaySession`] that plays back the given replay, as
+    /// recorded with [`with_replay_recorder()`]. The number of players is taken from the replay.
+    /// The whole replay is read before the session starts.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the replay cannot be read, or if it does not start at frame 0.
+    ///
+    /// [`with_replay_recorder()`]: Self::with_replay_recorder
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn start_replay_session(self, replay: impl Read) -> Result<ReplaySession<T>, GgrsError> {
+        let invalid_replay = |err: std::io::Error| GgrsError::InvalidRequest {
+            info: format!("Could not read the replay: {err}"),
+        };
+
+        let reader = ReplayReader::<T::Input, _>::new(replay).map_err(invalid_replay)?;
+        let header = reader.header().clone();
+        if header.input_type != std::any::type_name::<T::Input>() {
+            warn!(
+                "The replay was recorded with input type {}, but is played back with {}",
+                header.input_type,
+                std::any::type_name::<T::Input>()
+            );
+        }
+
+        let mut frames = Vec::new();
+        for frame in reader {
+            let frame = frame.map_err(invalid_replay)?;
+            if frame.inputs.len() != header.num_players
+                || frame.disconnected.len() != header.num_players
+            {
+                return Err(GgrsError::InvalidRequest {
+                    info: format!(
+                        "Frame {} of the replay does not contain inputs for all players.",
+                        frame.frame
+                    ),
+                });
+            }
+            // playback stops at the first frame missing from the recording
+            if frame.frame != frames.len() as i32 {
+                if frames.is_empty() {
+                    return Err(GgrsError::InvalidRequest {
+                        info: "The replay does not start at frame 0.".to_owned(),
+                    });
+                }
+                warn!(
+                    "The replay skips from frame {} to frame {}, playback ends there",
+                    frames.len() - 1,
+                    frame.frame
+                );
+                break;
+            }
+            frames.push(frame);
+        }
+
+        Ok(ReplaySession::new(header, frames, self.keyframe_interval))
+    }
+
+    /// Creates the replay recorder and writes the replay header, if the user asked for a replay.
+    fn replay_recorder(
+        &mut self,
+        session_kind: ReplaySessionKind,
+    ) -> Option<ReplayRecorder<T::Input>> {
+        let writer = self.replay_writer.take()?;
+        Some(ReplayRecorder::new(
+            writer,
+            &self.replay_header(session_kind),
         ))
     }
 
+    fn replay_header(&self, session_kind: ReplaySessionKind) -> ReplayHeader {
+        ReplayHeader {
+            session_kind,
+            num_players: self.num_players,
+            input_encoding: InputEncoding::Bincode,
+            input_type: std::any::type_name::<T::Input>().to_owned(),
+            max_prediction: self.max_prediction,
+            input_delay: self.input_delay,
+            fps: self.fps,
+            sparse_saving: self.sparse_saving,
+            desync_detection_interval: match self.desync_detection {
+                DesyncDetection::On { interval } => Some(interval),
+                DesyncDetection::Off => None,
+            },
+        }
+    }
+
     fn create_endpoint(
         &self,
         handles: Vec<PlayerHandle>,
@@ -460,9 +938,80 @@ impl<T: Config> SessionBuilder<T> {
             self.disconnect_notify_start,
             self.fps,
             self.desync_detection,
+            self.build_hash,
+            self.packet_security,
+            self.join_token.clone(),
+            false,
+            self.state_transfer,
+            self.clock.clone(),
         );
         // start the synchronization
         endpoint.synchronize();
         endpoint
     }
 }
+
+impl<T: Config> SessionBuilder<T>
+where
+    T::State: Serialize + DeserializeOwned,
+{
+    /// Allows the session to send and receive serialized game states, which is required for late
+    /// joins and for spectators that join a running match. States are serialized with `bincode`,
+    /// so the serialized state needs to be deterministic for all peers just like your checksums.
+    pub fn with_state_transfer(mut self) -> Self {
+        self.state_codec = Some(StateCodec::new());
+        self.state_transfer = true;
+        self
+    }
+
+    /// Lets peers exchange their saved states of a frame when desync detection finds that their
+    /// checksums of that frame differ. Both peers then receive a [`GgrsEvent::DesyncStateReceived`]
+    /// with the serialized states of both sides, so tools can diff them. The session keeps the
+    /// serialized states of the last 32 checked frames, which costs a serialization per checksum
+    /// interval. Requires desync detection on every peer. States are serialized with `bincode` as
+    /// with [`with_state_transfer()`], but this does not enable state transfers.
+    ///
+    /// [`GgrsEvent::DesyncStateReceived`]: crate::GgrsEvent::DesyncStateReceived
+    /// [`with_state_transfer()`]: Self::with_state_transfer
+    pub fn with_desync_state_exchange(mut self) -> Self {
+        self.state_codec = Some(StateCodec::new());
+        self.desync_state_exchange = true;
+        self
+    }
+
+    /// Lets the session recover from desyncs found by desync detection. The peer hosting the
+    /// recovery authority sends its latest confirmed state to every peer whose checksums differ
+    /// from its own. Those peers load the state with a [`GgrsRequest::LoadGameState`] and
+    /// resimulate up to their current frame, after which they emit a
+    /// [`GgrsEvent::DesyncRecovered`]. All peers need the same setting, and desync detection has
+    /// to be enabled. Recovery needs saved states, so it does nothing in lockstep mode. The
+    /// recovery states are serialized with `bincode`, but unlike [`with_state_transfer()`], this
+    /// does not let the session send states to late joining peers or spectators.
+    ///
+    /// [`GgrsRequest::LoadGameState`]: crate::GgrsRequest::LoadGameState
+    /// [`GgrsEvent::DesyncRecovered`]: crate::GgrsEvent::DesyncRecovered
+    /// [`with_state_transfer()`]: Self::with_state_transfer
+    pub fn with_desync_recovery(mut self, recovery: DesyncRecovery) -> Self {
+        self.state_codec = Some(StateCodec::new());
+        self.desync_recovery = recovery;
+        self
+    }
+
+    /// Lets a [`P2PSession`] create a [`DesyncBundle`] for every desync found by desync detection,
+    /// see [`P2PSession::desync_bundle()`]. The session keeps the confirmed inputs of the recently
+    /// checked frames and, every [`with_keyframe_interval()`] frames, a serialized saved state to
+    /// start the bundles from. In lockstep mode, or if the saved states hold no data, there are no
+    /// states to start from, so bundles start at frame 0 instead. The session then keeps the inputs
+    /// of at most 36000 frames (ten minutes at 60 FPS), so only desyncs within those frames can be
+    /// bundled. Requires desync detection to be enabled. Keyframes are serialized with `bincode`;
+    /// this does not enable [`with_state_transfer()`].
+    ///
+    /// [`DesyncBundle`]: crate::DesyncBundle
+    /// [`with_keyframe_interval()`]: Self::with_keyframe_interval
+    /// [`with_state_transfer()`]: Self::with_state_transfer
+    pub fn with_desync_bundles(mut self) -> Self {
+        self.state_codec = Some(StateCodec::new());
+        self.desync_bundles = true;
+        self
+    }
+}
@@ -1,25 +1,43 @@
+use crate::desync_bundle::DesyncBundleRecorder;
 use crate::error::GgrsError;
-use crate::frame_info::PlayerInput;
-use crate::network::messages::ConnectionStatus;
+use crate::frame_info::{PlayerInput, SubChecksums};
+use crate::input_delay::InputDelayController;
+use crate::input_queue::INPUT_QUEUE_LENGTH;
+use crate::network::messages::{
+    ConnectionStatus, DesyncState, JoinSnapshot, Message, MessageBody, RecoverySnapshot,
+    SpectatorSnapshot, StateTransfer,
+};
 use crate::network::network_stats::NetworkStats;
 use crate::network::protocol::{UdpProtocol, MAX_CHECKSUM_HISTORY_SIZE};
+use crate::network::security::PacketGuard;
+use crate::replay::{ReplayFrame, ReplayRecorder};
 use crate::sessions::builder::MAX_EVENT_QUEUE_SIZE;
-use crate::sync_layer::SyncLayer;
-use crate::DesyncDetection;
+use crate::sync_layer::{mismatched_sub_checksums, StateCodec, SyncLayer};
 use crate::{
-    network::protocol::Event, Config, Frame, GgrsEvent, GgrsRequest, InputStatus,
+    network::protocol::Event, Clock, Config, Frame, GgrsEvent, GgrsRequest, InputStatus,
     NonBlockingSocket, PlayerHandle, PlayerType, SessionState, NULL_FRAME,
 };
-use tracing::{debug, trace, warn};
+use crate::{DesyncBundle, DesyncDetection, DesyncRecovery, PacketSecurity};
+use tracing::{debug, info, trace, warn};
 
 use instant::{Duration, Instant};
 use std::collections::vec_deque::Drain;
 use std::collections::VecDeque;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
+use std::sync::Arc;
 
 const RECOMMENDATION_INTERVAL: Frame = 60;
 const MIN_RECOMMENDATION: u32 = 3;
+/// How often a late joining session repeats its request to be admitted until it receives a snapshot.
+pub(crate) const JOIN_REQUEST_INTERVAL: Duration = Duration::from_millis(200);
+/// How many frames of confirmed inputs are kept to resimulate from the state of the recovery authority.
+const MAX_RECOVERY_INPUT_HISTORY: usize = 128;
+/// How many connection requests from unknown addresses can wait for an answer of the user at once.
+const MAX_CONNECTION_REQUESTS: usize = 16;
+/// How many rejected addresses are remembered at most. The address we heard from least recently is
+/// forgotten first.
+const MAX_REJECTED_CONNECTIONS: usize = 64;
 
 pub(crate) struct PlayerRegistry<T>
 where
@@ -120,15 +138,20 @@ where
     /// The sync layer handles player input queues and provides predictions.
     sync_layer: SyncLayer<T>,
     /// With sparse saving, the session will only request to save the minimum confirmed frame.
+    /// Sparse saving is inactive in lockstep mode.
     sparse_saving: bool,
 
-    /// If we receive a disconnect from another client, we have to rollback from that frame on in order to prevent wrong predictions
+    /// If we receive a disconnect from another client or a player joins at a frame we already simulated, we have to rollback from that frame on in order to prevent wrong predictions
     disconnect_frame: Frame,
 
     /// Internal State of the Session.
     state: SessionState,
     /// Expected update frequency. Used to bound the optional lockstep wait helper.
     fps: usize,
+    /// The time until a remote player gets disconnected. Used for endpoints added at runtime.
+    disconnect_timeout: Duration,
+    /// The time until the client will get a notification that a remote player is about to be disconnected. Used for endpoints added at runtime.
+    disconnect_notify_start: Duration,
 
     /// The [`P2PSession`] uses this socket to send and receive all messages for remote players.
     socket: Box<dyn NonBlockingSocket<T::Address>>,
@@ -157,8 +180,85 @@ where
     desync_detection: DesyncDetection,
     /// Desync detection over the network
     local_checksum_history: HashMap<Frame, u128>,
+    /// Hashes of the confirmed inputs that led to the frames in `local_checksum_history`.
+    local_input_hashes: HashMap<Frame, u64>,
+    /// Named sub-checksums of the frames in `local_checksum_history`, if the user saved any.
+    local_sub_checksums: HashMap<Frame, SubChecksums>,
+    /// The frames in `local_checksum_history`, with the remote peers whose checksums of these frames
+    /// we have not compared yet.
+    awaited_checksums: BTreeMap<Frame, HashSet<T::Address>>,
     /// The last frame we sent a checksum for
     last_sent_checksum_frame: Frame,
+    /// If true, we send our saved state of a frame to peers whose checksum of that frame differs.
+    desync_state_exchange: bool,
+    /// Serialized saved states of the frames in `local_checksum_history`, if desync state exchange is enabled.
+    local_desync_states: HashMap<Frame, Vec<u8>>,
+    /// Checksums received from remote peers, by frame and address, until the checksums of all
+    /// peers for that frame are known. Only used with at least two remote peers.
+    checksum_votes: BTreeMap<Frame, HashMap<T::Address, u128>>,
+    /// If true, remote peers whose checksums disagree with the majority are disconnected.
+    disconnect_desync_outliers: bool,
+    /// Decides which peer sends its state to desynced peers.
+    desync_recovery: DesyncRecovery,
+    /// Confirmed inputs of the frames before `next_recovery_input_frame`, if desync recovery is
+    /// enabled. Desynced peers resimulate with them from the state of the recovery authority.
+    recovery_inputs: VecDeque<Vec<PlayerInput<T::Input>>>,
+    /// The next frame whose confirmed inputs are added to `recovery_inputs`.
+    next_recovery_input_frame: Frame,
+    /// The state of the recovery authority, together with its frame and the address of the
+    /// authority. It is loaded once all inputs before its frame are confirmed.
+    pending_recovery: Option<(Frame, T::State, T::Address)>,
+    /// The frame of the last recovery snapshot we sent to each peer as recovery authority.
+    sent_recovery_frames: HashMap<T::Address, Frame>,
+
+    /// Reserved player handles that no player has joined yet.
+    late_join_slots: BTreeSet<PlayerHandle>,
+    /// If true, we send game states to late joining peers and spectators.
+    state_transfer: bool,
+    /// Serializes game states for state transfers and the desync tools that need them.
+    state_codec: Option<StateCodec<T::State>>,
+    /// Addresses of late joining peers we have already notified the user about.
+    join_requests: HashSet<T::Address>,
+    /// Joins we admitted as sponsor, by address of the joining peer, together with the join frame.
+    /// The snapshot is sent as soon as all inputs before the join frame are confirmed.
+    pending_join_snapshots: HashMap<T::Address, Frame>,
+    /// True while this session joins a running match and has not received the snapshot yet.
+    awaiting_join_snapshot: bool,
+    /// Spectators that asked for a snapshot of the running match, by address, together with the
+    /// earliest frame the snapshot can have. The spectator has received all inputs from that frame on.
+    pending_spectator_snapshots: HashMap<T::Address, Frame>,
+    /// The frame of the last snapshot we sent to each spectator.
+    sent_spectator_snapshots: HashMap<T::Address, Frame>,
+    /// If true, disconnected players can rejoin the match.
+    reconnect: bool,
+    /// When we last asked the other peers to be admitted.
+    last_join_request: Option<Instant>,
+    /// Remote inputs that arrived before the sending player was admitted. They are replayed once the join has been applied.
+    early_inputs: Vec<(PlayerHandle, PlayerInput<T::Input>)>,
+    /// Requests to load and fast-forward a received snapshot. They are handed to the user with the next call to `advance_frame()`.
+    pending_requests: Vec<GgrsRequest<T>>,
+    /// Keeps recent confirmed inputs and states for desync bundles, if the user enabled them.
+    desync_bundle_recorder: Option<DesyncBundleRecorder<T::Input>>,
+    /// Records all confirmed inputs into a replay, if the user asked for one.
+    replay_recorder: Option<ReplayRecorder<T::Input>>,
+    /// The build hash of the game, which all peers need to share.
+    build_hash: Option<u64>,
+    /// How packets between peers are protected.
+    packet_security: PacketSecurity,
+    /// Verifies packets from addresses that have no endpoint yet.
+    connection_guard: PacketGuard,
+    /// The token we send to peers that do not know us yet.
+    join_token: Option<Vec<u8>>,
+    /// Connection requests of unknown addresses the user has not answered yet, with their join
+    /// token and the time we last heard from them.
+    connection_requests: HashMap<T::Address, (Option<Vec<u8>>, Instant)>,
+    /// Addresses whose connection requests the user rejected, with the time we last heard from
+    /// them. They are ignored until they have been silent for the disconnect timeout.
+    rejected_connections: HashMap<T::Address, Instant>,
+    /// The source of time for all timers of the session and its endpoints.
+    clock: Arc<dyn Clock>,
+    /// Adjusts the input delay of local players, if the user enabled adaptive input delay.
+    input_delay_controller: Option<InputDelayController>,
 }
 
 impl<T: Config> P2PSession<T> {
@@ -174,11 +274,31 @@ impl<T: Config> P2PSession<T> {
         desync_detection: DesyncDetection,
         input_delay: usize,
         fps: usize,
+        disconnect_timeout: Duration,
+        disconnect_notify_start: Duration,
+        late_join_slots: BTreeSet<PlayerHandle>,
+        late_join: bool,
+        reconnect: bool,
+        state_transfer: bool,
+        state_codec: Option<StateCodec<T::State>>,
+        desync_state_exchange: bool,
+        disconnect_desync_outliers: bool,
+        desync_recovery: DesyncRecovery,
+        desync_bundle_recorder: Option<DesyncBundleRecorder<T::Input>>,
+        replay_recorder: Option<ReplayRecorder<T::Input>>,
+        build_hash: Option<u64>,
+        packet_security: PacketSecurity,
+        join_token: Option<Vec<u8>>,
+        clock: Arc<dyn Clock>,
+        input_delay_controller: Option<InputDelayController>,
     ) -> Self {
-        // local connection status
+        // local connection status; reserved slots count as disconnected until someone joins
         let mut local_connect_status = Vec::new();
-        for _ in 0..num_players {
-            local_connect_status.push(ConnectionStatus::default());
+        for handle in 0..num_players {
+            local_connect_status.push(ConnectionStatus {
+                disconnected: late_join_slots.contains(&handle),
+                ..Default::default()
+            });
         }
 
         // sync layer & set input delay
@@ -196,24 +316,23 @@ impl<T: Config> P2PSession<T> {
             SessionState::Synchronizing
         };
 
-        let sparse_saving = if max_prediction == 0 && sparse_saving {
-            // in lockstep mode, saving will never happen, but we use the last saved frame to mark
-            // control marking frames confirmed, so we need to turn off sparse saving to ensure that
-            // frames are marked as confirmed - otherwise we will never advance the game state.
+        if max_prediction == 0 && sparse_saving {
+            // in lockstep mode, saving will never happen, but sparse saving uses the last saved
+            // frame to control marking frames confirmed, so sparse saving stays inactive while in
+            // lockstep mode - otherwise we will never advance the game state.
             warn!(
                 "Sparse saving setting is ignored because lockstep mode is on \
                 (max_prediction set to 0), so no saving will take place"
             );
-            false
-        } else {
-            sparse_saving
-        };
+        }
 
         Self {
             state,
             num_players,
             max_prediction,
             fps,
+            disconnect_timeout,
+            disconnect_notify_start,
             sparse_saving,
             socket,
             local_connect_status,
@@ -229,7 +348,41 @@ impl<T: Config> P2PSession<T> {
             last_sent_outgoing_input_frame: NULL_FRAME,
             desync_detection,
             local_checksum_history: HashMap::new(),
+            awaited_checksums: BTreeMap::new(),
+            local_input_hashes: HashMap::new(),
+            local_sub_checksums: HashMap::new(),
             last_sent_checksum_frame: NULL_FRAME,
+            desync_state_exchange,
+            local_desync_states: HashMap::new(),
+            checksum_votes: BTreeMap::new(),
+            disconnect_desync_outliers,
+            desync_recovery,
+            recovery_inputs: VecDeque::new(),
+            next_recovery_input_frame: 0,
+            pending_recovery: None,
+            sent_recovery_frames: HashMap::new(),
+            late_join_slots,
+            reconnect,
+            state_transfer,
+            state_codec,
+            join_requests: HashSet::new(),
+            pending_join_snapshots: HashMap::new(),
+            awaiting_join_snapshot: late_join,
+            pending_spectator_snapshots: HashMap::new(),
+            sent_spectator_snapshots: HashMap::new(),
+            last_join_request: None,
+            early_inputs: Vec::new(),
+            pending_requests: Vec::new(),
+            desync_bundle_recorder,
+            replay_recorder,
+            build_hash,
+            packet_security,
+            connection_guard: PacketGuard::for_unknown_remotes(packet_security),
+            join_token,
+            connection_requests: HashMap::new(),
+            rejected_connections: HashMap::new(),
+            clock,
+            input_delay_controller,
         }
     }
 
@@ -312,9 +465,11 @@ impl<T: Config> P2PSession<T> {
     ///
     /// This helper makes the lockstep wait explicit. It may spin-poll for up to `timeout`, so use
     /// it only when a short bounded wait is appropriate for your platform and game loop. Passing a
-    /// zero timeout is equivalent to [`advance_frame`].
+    /// zero timeout is equivalent to [`advance_frame`]. The timeout is measured with the clock of the
+    /// session, see [`SessionBuilder::with_clock()`].
     ///
     /// [`advance_frame`]: Self::advance_frame
+    /// [`SessionBuilder::with_clock()`]: crate::SessionBuilder::with_clock
     pub fn advance_frame_with_wait_timeout(
         &mut self,
         timeout: Duration,
@@ -326,8 +481,8 @@ impl<T: Config> P2PSession<T> {
             return Ok(requests);
         }
 
-        let deadline = Instant::now() + timeout;
-        while Instant::now() < deadline {
+        let deadline = self.clock.now() + timeout;
+        while self.clock.now() < deadline {
             self.poll_remote_clients();
             if self.lockstep_current_frame_confirmed() {
                 return self.advance_frame_after_poll();
@@ -370,8 +525,14 @@ impl<T: Config> P2PSession<T> {
             self.compare_local_checksums_against_peers();
         }
 
-        // This list of requests will be returned to the user
-        let mut requests = Vec::new();
+        // This list of requests will be returned to the user. It starts with loading and
+        // fast-forwarding a snapshot we received while joining, if there is one.
+        let mut requests = std::mem::take(&mut self.pending_requests);
+
+        // replace our state with the state of the recovery authority, if we received one
+        if self.pending_recovery.is_some() {
+            self.apply_desync_recovery(&mut requests);
+        }
 
         /*
          * ROLLBACKS AND GAME STATE MANAGEMENT
@@ -388,9 +549,19 @@ impl<T: Config> P2PSession<T> {
             requests.push(self.sync_layer.save_current_state());
         }
 
-        // propagate disconnects to multiple players
+        // adopt players that joined on other peers, then propagate disconnects to multiple players
+        self.update_player_joins();
         self.update_player_disconnects();
 
+        // send snapshots to players we admitted, once all inputs before their join frame are known
+        if !self.pending_join_snapshots.is_empty() {
+            self.send_join_snapshots(&mut requests);
+        }
+        // send snapshots to spectators that joined the running match
+        if !self.pending_spectator_snapshots.is_empty() {
+            self.send_spectator_snapshots(&mut requests);
+        }
+
         if lockstep {
             self.advance_lockstep_frame(&mut requests);
         } else {
@@ -404,6 +575,14 @@ impl<T: Config> P2PSession<T> {
         // check time sync between clients and send wait recommendation, if appropriate
         self.check_wait_recommendation();
 
+        /*
+         *  ADAPTIVE INPUT DELAY
+         */
+
+        if self.input_delay_controller.is_some() {
+            self.update_input_delay();
+        }
+
         Ok(requests)
     }
 
@@ -413,14 +592,39 @@ impl<T: Config> P2PSession<T> {
         // Get all packets and distribute them to associated endpoints.
         // The endpoints will handle their packets, which will trigger both events and UPD replies.
         for (from_addr, msg) in &self.socket.receive_all_messages() {
+            // a disconnected peer that starts a new handshake wants to rejoin
+            if self.reconnect
+                && self
+                    .player_reg
+                    .remotes
+                    .get(from_addr)
+                    .is_some_and(|endpoint| {
+                        endpoint.is_disconnected() && endpoint.is_sync_request(msg)
+                    })
+            {
+                self.recreate_disconnected_endpoint(from_addr);
+            }
             if let Some(endpoint) = self.player_reg.remotes.get_mut(from_addr) {
-                endpoint.handle_message(msg);
+                endpoint.receive_message(msg);
             }
             if let Some(endpoint) = self.player_reg.spectators.get_mut(from_addr) {
-                endpoint.handle_message(msg);
+                endpoint.receive_message(msg);
+            }
+            if !self.player_reg.remotes.contains_key(from_addr)
+                && !self.player_reg.spectators.contains_key(from_addr)
+            {
+                self.on_unknown_message(from_addr, msg);
             }
         }
 
+        // forget connection requests of clients that gave up
+        let now = self.clock.now();
+        let timeout = self.disconnect_timeout;
+        self.connection_requests
+            .retain(|_, (_, last_seen)| now.duration_since(*last_seen) < timeout);
+        self.rejected_connections
+            .retain(|_, last_seen| now.duration_since(*last_seen) < timeout);
+
         // update frame information between remote players
         for remote_endpoint in self.player_reg.remotes.values_mut() {
             if remote_endpoint.is_running() {
@@ -448,6 +652,14 @@ impl<T: Config> P2PSession<T> {
             self.handle_event(event, handles, addr);
         }
 
+        // keep asking to be admitted until we receive a snapshot
+        if self.awaiting_join_snapshot {
+            self.send_join_requests();
+        } else if self.is_isolated() {
+            self.reconnect_to_remotes();
+            self.send_join_requests();
+        }
+
         // send all queued packets
         for endpoint in self.player_reg.remotes.values_mut() {
             endpoint.send_all_messages(&mut self.socket);
@@ -490,6 +702,152 @@ impl<T: Config> P2PSession<T> {
         }
     }
 
+    /// Registers the peer of a player that joins the running match in a slot reserved with
+    /// [`SessionBuilder::add_late_join_slot()`]. Every peer of the session needs to register the
+    /// joining player. The session then synchronizes with the new peer and admits the player once it
+    /// asks to join, which is signaled through [`GgrsEvent::JoinRequested`] and
+    /// [`GgrsEvent::PlayerJoined`].
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the handle is not a reserved slot or the address is already in use.
+    ///
+    /// [`SessionBuilder::add_late_join_slot()`]: crate::SessionBuilder::add_late_join_slot
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn add_remote_player(
+        &mut self,
+        player_handle: PlayerHandle,
+        addr: T::Address,
+    ) -> Result<(), GgrsError> {
+        // all handles that are not registered as players are reserved slots
+        if player_handle >= self.num_players || self.player_reg.handles.contains_key(&player_handle)
+        {
+            return Err(GgrsError::InvalidRequest {
+                info: "The player handle you provided is not a reserved late join slot.".to_owned(),
+            });
+        }
+        if self.player_reg.remotes.contains_key(&addr)
+            || self.player_reg.spectators.contains_key(&addr)
+        {
+            return Err(GgrsError::InvalidRequest {
+                info: "Address already in use.".to_owned(),
+            });
+        }
+
+        let local_players = self.player_reg.local_player_handles().len();
+        let endpoint = self.create_endpoint(vec![player_handle], addr.clone(), local_players);
+        self.rejected_connections.remove(&addr);
+        self.player_reg.remotes.insert(addr.clone(), endpoint);
+        self.player_reg
+            .handles
+            .insert(player_handle, PlayerType::Remote(addr));
+        Ok(())
+    }
+
+    /// Accepts the connection request of a client that announced itself with
+    /// [`GgrsEvent::ConnectionRequest`]. A `player_handle` below the number of players lets the
+    /// client take over that reserved slot, just like [`add_remote_player()`] does. A higher
+    /// `player_handle` adds the client as a spectator, just like [`add_spectator()`] does. The
+    /// session then synchronizes with the client.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if there is no pending connection request from the address, or
+    ///   the handle is neither a reserved slot nor a free spectator handle.
+    ///
+    /// [`add_remote_player()`]: Self::add_remote_player
+    /// [`add_spectator()`]: Self::add_spectator
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn accept_connection_request(
+        &mut self,
+        addr: T::Address,
+        player_handle: PlayerHandle,
+    ) -> Result<(), GgrsError> {
+        if !self.connection_requests.contains_key(&addr) {
+            return Err(GgrsError::InvalidRequest {
+                info: "No pending connection request from this address.".to_owned(),
+            });
+        }
+        if player_handle < self.num_players {
+            self.add_remote_player(player_handle, addr.clone())?;
+        } else {
+            self.add_spectator_endpoint(player_handle, addr.clone())?;
+        }
+        self.connection_requests.remove(&addr);
+        Ok(())
+    }
+
+    /// Adds a spectator to the session, which can also be done while the match is running. The
+    /// session synchronizes with the spectator and then sends it the confirmed inputs of all players,
+    /// starting with the frame reported through [`GgrsEvent::SpectatorJoined`]. Returns the handle of
+    /// the spectator, which is the lowest free handle `>= num_players`.
+    ///
+    /// A spectator that joins a running match needs the game state of that frame to follow the
+    /// match.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the address is already in use, or the session is a late
+    ///   joining session that has not joined the match yet.
+    ///
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn add_spectator(&mut self, addr: T::Address) -> Result<PlayerHandle, GgrsError> {
+        let player_handle = (self.num_players..)
+            .find(|handle| !self.player_reg.handles.contains_key(handle))
+            .expect("there is always a free spectator handle");
+        self.add_spectator_endpoint(player_handle, addr)?;
+        Ok(player_handle)
+    }
+
+    /// Removes a spectator from the session. The session stops sending inputs to the spectator and
+    /// emits [`GgrsEvent::SpectatorLeft`]. The handle and address of the spectator can be used again.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the handle is not referring to a spectator.
+    ///
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn remove_spectator(&mut self, player_handle: PlayerHandle) -> Result<(), GgrsError> {
+        match self.player_reg.handles.get(&player_handle) {
+            Some(PlayerType::Spectator(addr)) => {
+                let addr = addr.clone();
+                self.remove_spectator_endpoint(player_handle, addr);
+                Ok(())
+            }
+            _ => Err(GgrsError::InvalidRequest {
+                info: "Given player handle not referring to a spectator".to_owned(),
+            }),
+        }
+    }
+
+    /// Rejects the connection request of a client that announced itself with
+    /// [`GgrsEvent::ConnectionRequest`]. All further packets from the address are ignored, until
+    /// the client has been silent for the disconnect timeout or the address is added with
+    /// [`add_remote_player()`] or [`add_spectator()`].
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if there is no pending connection request from the address.
+    ///
+    /// [`add_remote_player()`]: Self::add_remote_player
+    /// [`add_spectator()`]: Self::add_spectator
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn reject_connection_request(&mut self, addr: T::Address) -> Result<(), GgrsError> {
+        if self.connection_requests.remove(&addr).is_none() {
+            return Err(GgrsError::InvalidRequest {
+                info: "No pending connection request from this address.".to_owned(),
+            });
+        }
+        debug!("Rejecting connection request from {addr:?}");
+        if self.rejected_connections.len() >= MAX_REJECTED_CONNECTIONS {
+            let oldest = self
+                .rejected_connections
+                .iter()
+                .min_by_key(|(_, last_seen)| **last_seen)
+                .map(|(addr, _)| addr.clone());
+            if let Some(oldest) = oldest {
+                self.rejected_connections.remove(&oldest);
+            }
+        }
+        self.rejected_connections.insert(addr, self.clock.now());
+        Ok(())
+    }
+
     /// Changes the input delay for a local player. This can be called at any point during a session.
     ///
     /// When decreasing delay, inputs that fall inside the now-removed frames are dropped.
@@ -563,6 +921,69 @@ impl<T: Config> P2PSession<T> {
         }
     }
 
+    /// Sends an application message, like a chat line or a ready flag, to the peer of a remote player
+    /// or spectator. Messages travel through the socket of the session and are delivered reliably
+    /// and in order, where the peer receives them as [`GgrsEvent::UserMessage`]. Messages sent
+    /// before the peer is synchronized are delivered once it is.
+    ///
+    /// Only a few messages are in flight at the same time, so they never crowd out inputs. Each
+    /// message can hold up to [`MAX_USER_MESSAGE_SIZE`] bytes.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the handle is not referring to a remote player or spectator,
+    ///   the peer is disconnected, the payload is too large or too many messages to the peer are
+    ///   still waiting to be delivered.
+    ///
+    /// [`MAX_USER_MESSAGE_SIZE`]: crate::MAX_USER_MESSAGE_SIZE
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn send_user_message(
+        &mut self,
+        player_handle: PlayerHandle,
+        payload: &[u8],
+    ) -> Result<(), GgrsError> {
+        let endpoint = match self.player_reg.handles.get(&player_handle) {
+            Some(PlayerType::Remote(addr)) => self.player_reg.remotes.get_mut(addr),
+            Some(PlayerType::Spectator(addr)) => self.player_reg.spectators.get_mut(addr),
+            _ => None,
+        };
+        let Some(endpoint) = endpoint else {
+            return Err(GgrsError::InvalidRequest {
+                info: "Given player handle not referring to a remote player or spectator"
+                    .to_owned(),
+            });
+        };
+        endpoint.send_user_message(payload.to_vec())?;
+        endpoint.send_all_messages(&mut self.socket);
+        Ok(())
+    }
+
+    /// Returns a [`DesyncBundle`] with everything needed to re-simulate the desync at the given
+    /// frame offline: the session settings, a saved state before the desync, all confirmed inputs
+    /// since that state and the checksums of both sides. Call this after receiving a
+    /// [`GgrsEvent::DesyncDetected`] for the frame, e.g. from your crash reporter, and write the
+    /// bundle with [`DesyncBundle::write_to()`].
+    ///
+    /// The session keeps what is needed for the desyncs of the last 32 checked frames.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if desync bundles have not been enabled with
+    ///   [`SessionBuilder::with_desync_bundles()`].
+    /// - Returns [`InvalidRequest`] if no desync of the given frame was detected recently, or if the
+    ///   inputs before that frame are not available anymore, e.g. after joining a running match.
+    ///
+    /// [`DesyncBundle`]: crate::DesyncBundle
+    /// [`DesyncBundle::write_to()`]: crate::DesyncBundle::write_to
+    /// [`SessionBuilder::with_desync_bundles()`]: crate::SessionBuilder::with_desync_bundles
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn desync_bundle(&self, frame: Frame) -> Result<DesyncBundle<T::Input>, GgrsError> {
+        let Some(recorder) = &self.desync_bundle_recorder else {
+            return Err(GgrsError::InvalidRequest {
+                info: "Desync bundles have not been enabled with with_desync_bundles().".to_owned(),
+            });
+        };
+        recorder.bundle(frame)
+    }
+
     /// Returns the highest confirmed frame. We have received all input for this frame and it is thus correct.
     pub fn confirmed_frame(&self) -> Frame {
         let mut confirmed_frame = i32::MAX;
@@ -604,6 +1025,58 @@ impl<T: Config> P2PSession<T> {
         self.max_prediction == 0
     }
 
+    /// Changes the maximum prediction window of the session. A larger window lets the session
+    /// predict further ahead instead of stalling with [`PredictionThreshold`] when latency spikes;
+    /// a smaller window saves memory and limits how far rollbacks go. Setting the window to 0
+    /// switches to lockstep mode, setting it above 0 switches back to rollback mode.
+    ///
+    /// The window can only shrink as far as the frames the session may still need to roll back to.
+    /// In particular, switching to lockstep mode requires all inputs up to the current frame to be
+    /// confirmed. Other peers are not affected and can use different windows.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the session has predicted more frames than the new window
+    ///   allows. Try again once more inputs are confirmed.
+    ///
+    /// [`PredictionThreshold`]: GgrsError::PredictionThreshold
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn set_max_prediction(&mut self, max_prediction: usize) -> Result<(), GgrsError> {
+        let current_frame = self.sync_layer.current_frame();
+        let mut oldest_frame = self.oldest_frame_to_correct();
+        // with sparse saving, rollbacks go back to the last saved frame, which lockstep never loads
+        let last_saved = self.sync_layer.last_saved_frame();
+        if max_prediction > 0 && self.saves_sparsely() && last_saved != NULL_FRAME {
+            oldest_frame = std::cmp::min(oldest_frame, last_saved);
+        }
+        let rollback_depth = current_frame - oldest_frame;
+        if rollback_depth > max_prediction as Frame {
+            return Err(GgrsError::InvalidRequest {
+                info: format!(
+                    "Cannot shrink the prediction window to {max_prediction} frames while the session may roll back {rollback_depth} frames."
+                ),
+            });
+        }
+
+        let leaves_lockstep = self.in_lockstep_mode() && max_prediction > 0;
+        self.max_prediction = max_prediction;
+        self.sync_layer.set_max_prediction(max_prediction);
+        for endpoint in self
+            .player_reg
+            .remotes
+            .values_mut()
+            .chain(self.player_reg.spectators.values_mut())
+        {
+            endpoint.set_max_prediction(max_prediction);
+        }
+
+        // lockstep mode saves no states, but sparse saving needs one to roll back to
+        if leaves_lockstep && self.saves_sparsely() && current_frame > 0 {
+            self.pending_requests
+                .push(self.sync_layer.save_current_state());
+        }
+        Ok(())
+    }
+
     /// Returns the current [`SessionState`] of a session.
     pub fn current_state(&self) -> SessionState {
         self.state
@@ -666,6 +1139,7 @@ impl<T: Config> P2PSession<T> {
             return;
         }
 
+        let local_join_frame = self.join_frame_of(&local_handles);
         while let Some(frame_to_send) = self.next_complete_outgoing_input_frame(&local_handles) {
             let inputs = self
                 .outgoing_local_inputs
@@ -673,12 +1147,105 @@ impl<T: Config> P2PSession<T> {
                 .expect("complete outgoing input frame should still be queued");
 
             for endpoint in self.player_reg.remotes.values_mut() {
+                // endpoints of players that joined later are caught up separately below
+                if local_join_frame > 0
+                    || endpoint
+                        .handles()
+                        .iter()
+                        .any(|&h| self.local_connect_status[h].connect_frame > 0)
+                {
+                    continue;
+                }
+                // peers that try to rejoin must not receive inputs before they have been admitted
+                if endpoint
+                    .handles()
+                    .iter()
+                    .all(|&h| self.local_connect_status[h].disconnected)
+                {
+                    continue;
+                }
                 endpoint.send_input(&inputs, &self.local_connect_status);
                 endpoint.send_all_messages(&mut self.socket);
             }
 
             self.last_sent_outgoing_input_frame = frame_to_send;
         }
+
+        let joined_addrs: Vec<_> = self
+            .player_reg
+            .remotes
+            .iter()
+            .filter(|(_, endpoint)| {
+                local_join_frame > 0
+                    || endpoint
+                        .handles()
+                        .iter()
+                        .any(|&h| self.local_connect_status[h].connect_frame > 0)
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in joined_addrs {
+            self.send_missed_inputs(&addr);
+        }
+    }
+
+    /// Returns the latest frame any of the given players joined at, or 0 if all of them played from the start.
+    fn join_frame_of(&self, handles: &[PlayerHandle]) -> Frame {
+        handles
+            .iter()
+            .map(|&h| self.local_connect_status[h].connect_frame)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Sends all local inputs from the join frame on that the endpoint of a late joining player
+    /// has not received yet. These inputs are taken from the input queues, so this works even if
+    /// the endpoint was not synchronized when the inputs were registered.
+    fn send_missed_inputs(&mut self, addr: &T::Address) {
+        let local_handles = self.player_reg.local_player_handles();
+        let Some(endpoint) = self.player_reg.remotes.get_mut(addr) else {
+            return;
+        };
+        if !endpoint.is_running() {
+            return;
+        }
+        // don't send anything before the players of the endpoint have been admitted
+        if endpoint.handles().iter().any(|&h| {
+            self.late_join_slots.contains(&h) || self.local_connect_status[h].disconnected
+        }) {
+            return;
+        }
+
+        // the first frame both we and the players of the endpoint take part in
+        let join_frame = endpoint
+            .handles()
+            .iter()
+            .chain(local_handles.iter())
+            .map(|&h| self.local_connect_status[h].connect_frame)
+            .max()
+            .unwrap_or(0);
+        if join_frame == 0 {
+            // everyone played from the start, so inputs are sent as they come in
+            return;
+        }
+        let first_frame = match endpoint.last_queued_input_frame() {
+            NULL_FRAME => join_frame,
+            frame => frame + 1,
+        };
+        let last_frame = local_handles
+            .iter()
+            .map(|&h| self.local_connect_status[h].last_frame)
+            .min()
+            .unwrap_or(NULL_FRAME);
+
+        for frame in first_frame..=last_frame {
+            let inputs = local_handles
+                .iter()
+                .map(|&h| (h, self.sync_layer.confirmed_input(h, frame)))
+                .collect();
+            endpoint.send_input(&inputs, &self.local_connect_status);
+        }
+        endpoint.send_all_messages(&mut self.socket);
     }
 
     fn next_complete_outgoing_input_frame(&self, local_handles: &[PlayerHandle]) -> Option<Frame> {
@@ -754,10 +1321,12 @@ impl<T: Config> P2PSession<T> {
                     .get_mut(addr)
                     .expect("There should be no address without registered endpoint");
 
-                // mark the affected players as disconnected
+                // mark the affected players as disconnected and forget inputs they sent early
                 for &handle in endpoint.handles() {
                     self.local_connect_status[handle].disconnected = true;
                 }
+                self.early_inputs
+                    .retain(|(player, _)| !endpoint.handles().contains(player));
                 endpoint.disconnect();
 
                 if self.sync_layer.current_frame() > last_frame + 1 {
@@ -788,6 +1357,11 @@ impl<T: Config> P2PSession<T> {
             return;
         }
 
+        // a late joining session can only start once it received the snapshot of the match
+        if self.awaiting_join_snapshot {
+            return;
+        }
+
         // if any endpoint is not synchronized, we continue synchronizing
         for endpoint in self.player_reg.remotes.values_mut() {
             if !endpoint.is_synchronized() {
@@ -820,8 +1394,7 @@ impl<T: Config> P2PSession<T> {
                 .map(|(handle, pi)| {
                     debug_assert_eq!(
                         pi.frame == NULL_FRAME,
-                        self.local_connect_status[handle].disconnected
-                            && self.local_connect_status[handle].last_frame < game_frame,
+                        self.local_connect_status[handle].disconnected_at(game_frame),
                         "confirmed_inputs returned NULL_FRAME for a connected player or \
                          a real frame for a disconnected player (handle {handle})"
                     );
@@ -832,6 +1405,14 @@ impl<T: Config> P2PSession<T> {
                     }
                 })
                 .collect();
+            // lockstep sessions don't save states, so we ask for the checksum separately
+            if let DesyncDetection::On { interval } = self.desync_detection {
+                if game_frame == self.next_checksum_frame(interval)
+                    && self.sync_layer.last_saved_frame() != game_frame
+                {
+                    requests.push(self.sync_layer.report_current_checksum());
+                }
+            }
             self.sync_layer.advance_frame();
             self.pending_local_inputs.clear();
             requests.push(GgrsRequest::AdvanceFrame { inputs });
@@ -845,9 +1426,13 @@ impl<T: Config> P2PSession<T> {
 
         let consumed_frame = self.sync_layer.current_frame() - 1;
         let bookkeeping_frame = std::cmp::min(self.confirmed_frame(), consumed_frame);
+        self.record_confirmed_inputs(bookkeeping_frame);
+        self.record_desync_bundle_inputs(bookkeeping_frame);
+        self.record_recovery_inputs(bookkeeping_frame);
+        self.hash_confirmed_inputs(bookkeeping_frame);
         self.send_confirmed_inputs_to_spectators(bookkeeping_frame);
         self.sync_layer
-            .set_last_confirmed_frame(bookkeeping_frame, self.sparse_saving);
+            .set_last_confirmed_frame(bookkeeping_frame, self.saves_sparsely());
     }
 
     /// Rollback advance: inputs are predicted and corrected on mismatch. The session may run
@@ -861,12 +1446,16 @@ impl<T: Config> P2PSession<T> {
         // check game consistency and roll back, if necessary
         self.handle_rollback_and_save(confirmed_frame, requests);
 
-        // send confirmed inputs to spectators before throwing them away
+        // record and send confirmed inputs to spectators before throwing them away
+        self.record_confirmed_inputs(confirmed_frame);
+        self.record_desync_bundle_inputs(confirmed_frame);
+        self.record_recovery_inputs(confirmed_frame);
+        self.hash_confirmed_inputs(confirmed_frame);
         self.send_confirmed_inputs_to_spectators(confirmed_frame);
 
         // set the last confirmed frame and discard all saved inputs before that frame
         self.sync_layer
-            .set_last_confirmed_frame(confirmed_frame, self.sparse_saving);
+            .set_last_confirmed_frame(confirmed_frame, self.saves_sparsely());
 
         self.register_local_inputs();
 
@@ -887,6 +1476,9 @@ impl<T: Config> P2PSession<T> {
                 "Prediction Threshold reached. Skipping on frame {}",
                 self.sync_layer.current_frame()
             );
+            if let Some(controller) = &mut self.input_delay_controller {
+                controller.on_prediction_threshold();
+            }
         }
     }
 
@@ -905,7 +1497,7 @@ impl<T: Config> P2PSession<T> {
         }
 
         let last_saved = self.sync_layer.last_saved_frame();
-        if self.sparse_saving {
+        if self.saves_sparsely() {
             self.check_last_saved_state(last_saved, confirmed_frame, requests);
         } else {
             requests.push(self.sync_layer.save_current_state());
@@ -920,7 +1512,7 @@ impl<T: Config> P2PSession<T> {
     ) {
         let current_frame = self.sync_layer.current_frame();
         // determine the frame to load
-        let frame_to_load = if self.sparse_saving {
+        let frame_to_load = if self.saves_sparsely() {
             // if sparse saving is turned on, we will rollback to the last saved state
             self.sync_layer.last_saved_frame()
         } else {
@@ -950,7 +1542,7 @@ impl<T: Config> P2PSession<T> {
                 .synchronized_inputs(&self.local_connect_status);
 
             // decide whether to request a state save
-            if self.sparse_saving {
+            if self.saves_sparsely() {
                 // with sparse saving, we only save exactly the min_confirmed frame
                 if self.sync_layer.current_frame() == min_confirmed {
                     requests.push(self.sync_layer.save_current_state());
@@ -970,9 +1562,113 @@ impl<T: Config> P2PSession<T> {
         assert_eq!(self.sync_layer.current_frame(), current_frame);
     }
 
+    /// Hands all confirmed inputs up until the given frame to the replay recorder. The frames are
+    /// written once their saved state can no longer change, so their checksums can be recorded too.
+    fn record_confirmed_inputs(&mut self, confirmed_frame: Frame) {
+        let Some(recorder) = self.replay_recorder.as_mut() else {
+            return;
+        };
+
+        // states up to the last confirmed frame were saved in earlier steps and will not be rolled back
+        let sync_layer = &self.sync_layer;
+        recorder.flush_pending(sync_layer.last_confirmed_frame(), |frame| {
+            sync_layer
+                .saved_state_by_frame(frame)
+                .and_then(|cell| cell.checksum())
+        });
+
+        for frame in recorder.next_frame()..=confirmed_frame {
+            let inputs = sync_layer.confirmed_inputs(frame, &self.local_connect_status);
+            recorder.record_pending(ReplayFrame {
+                frame,
+                inputs: inputs.iter().map(|input| input.input).collect(),
+                disconnected: inputs
+                    .iter()
+                    .map(|input| input.frame == NULL_FRAME)
+                    .collect(),
+                checksum: None,
+            });
+        }
+    }
+
+    /// Hands all confirmed inputs up until the given frame to the desync bundle recorder.
+    fn record_desync_bundle_inputs(&mut self, confirmed_frame: Frame) {
+        self.flush_desync_bundle_frames();
+        let Some(recorder) = self.desync_bundle_recorder.as_mut() else {
+            return;
+        };
+        for frame in recorder.next_frame()..=confirmed_frame {
+            let inputs = self
+                .sync_layer
+                .confirmed_inputs(frame, &self.local_connect_status);
+            recorder.record(ReplayFrame {
+                frame,
+                inputs: inputs.iter().map(|input| input.input).collect(),
+                disconnected: inputs
+                    .iter()
+                    .map(|input| input.frame == NULL_FRAME)
+                    .collect(),
+                checksum: None,
+            });
+        }
+    }
+
+    /// Hands the checksums and keyframes of all frames up to the last confirmed frame to the desync
+    /// bundle recorder, since their saved states will not be rolled back anymore.
+    fn flush_desync_bundle_frames(&mut self) {
+        let (Some(recorder), Some(codec)) =
+            (self.desync_bundle_recorder.as_mut(), self.state_codec)
+        else {
+            return;
+        };
+        let sync_layer = &self.sync_layer;
+        recorder.flush(
+            sync_layer.last_confirmed_frame(),
+            |frame| {
+                sync_layer
+                    .saved_state_by_frame(frame)
+                    .and_then(|cell| cell.checksum())
+            },
+            |frame| {
+                sync_layer
+                    .saved_state_by_frame(frame)
+                    .and_then(|cell| cell.data().map(|data| (codec.encode)(&data)))
+            },
+        );
+    }
+
+    /// Keeps the confirmed inputs up until the given frame, so the session can resimulate from an
+    /// older state of the recovery authority after a desync.
+    fn record_recovery_inputs(&mut self, confirmed_frame: Frame) {
+        if self.desync_recovery == DesyncRecovery::Off {
+            return;
+        }
+
+        while self.next_recovery_input_frame <= confirmed_frame {
+            let inputs = self
+                .sync_layer
+                .confirmed_inputs(self.next_recovery_input_frame, &self.local_connect_status);
+            self.recovery_inputs.push_back(inputs);
+            if self.recovery_inputs.len() > MAX_RECOVERY_INPUT_HISTORY {
+                self.recovery_inputs.pop_front();
+            }
+            self.next_recovery_input_frame += 1;
+        }
+    }
+
+    /// Extends the hash chain of confirmed inputs that is sent along with our checksums.
+    fn hash_confirmed_inputs(&mut self, confirmed_frame: Frame) {
+        if self.desync_detection != DesyncDetection::Off {
+            self.sync_layer
+                .hash_confirmed_inputs(confirmed_frame, &self.local_connect_status);
+        }
+    }
+
     /// For each spectator, send all confirmed input up until the minimum confirmed frame.
     fn send_confirmed_inputs_to_spectators(&mut self, confirmed_frame: Frame) {
         if self.num_spectators() == 0 {
+            // spectators that are added later start with the next confirmed frame
+            self.next_spectator_frame = self.next_spectator_frame.max(confirmed_frame + 1);
             return;
         }
 
@@ -1010,10 +1706,14 @@ impl<T: Config> P2PSession<T> {
 
             // check all player connection status for every remote player
             for endpoint in self.player_reg.remotes.values() {
-                if !endpoint.is_running() {
+                if !endpoint.is_running() || !self.takes_part(endpoint) {
                     continue;
                 }
                 let con_status = endpoint.peer_connect_status(handle);
+                // the peer has not learned about the latest join of this player yet
+                if con_status.connect_frame != self.local_connect_status[handle].connect_frame {
+                    continue;
+                }
                 let connected = !con_status.disconnected;
                 let min_confirmed = con_status.last_frame;
 
@@ -1074,6 +1774,65 @@ impl<T: Config> P2PSession<T> {
         }
     }
 
+    /// Feeds the controller with the current measurements and applies the delay it asks for to all
+    /// local players.
+    fn update_input_delay(&mut self) {
+        let round_trip_time = self
+            .player_reg
+            .remotes
+            .values()
+            .filter(|endpoint| {
+                endpoint.is_running()
+                    && endpoint
+                        .handles()
+                        .iter()
+                        .any(|&handle| !self.local_connect_status[handle].disconnected)
+            })
+            .map(UdpProtocol::round_trip_time)
+            .max()
+            .unwrap_or(0);
+
+        let Some(controller) = &mut self.input_delay_controller else {
+            return;
+        };
+        let Some(delay) = controller.update(
+            self.sync_layer.current_frame(),
+            round_trip_time,
+            self.frames_ahead,
+            self.fps,
+        ) else {
+            return;
+        };
+
+        debug!("Adaptive input delay changes the input delay to {delay}");
+        for handle in self.player_reg.local_player_handles() {
+            self.set_input_delay(handle, delay)
+                .expect("local player handles are valid");
+        }
+        self.event_queue
+            .push_back(GgrsEvent::InputDelayChanged { delay });
+    }
+
+    /// Returns true if sparse saving is enabled and active. Lockstep mode saves no states, so sparse
+    /// saving is inactive while the session is in lockstep mode.
+    fn saves_sparsely(&self) -> bool {
+        self.sparse_saving && !self.in_lockstep_mode()
+    }
+
+    /// Returns the oldest frame the session may still have to correct with a rollback.
+    fn oldest_frame_to_correct(&self) -> Frame {
+        let current_frame = self.sync_layer.current_frame();
+        // every frame that is not confirmed yet may have been mispredicted
+        let mut oldest = std::cmp::min(self.confirmed_frame() + 1, current_frame);
+        let first_incorrect = self
+            .sync_layer
+            .check_simulation_consistency(self.disconnect_frame);
+        if first_incorrect != NULL_FRAME {
+            oldest = std::cmp::min(oldest, first_incorrect);
+        }
+        oldest
+    }
+
     fn check_last_saved_state(
         &mut self,
         last_saved: Frame,
@@ -1128,39 +1887,106 @@ impl<T: Config> P2PSession<T> {
             // check if all remotes are synced, then forward to user
             Event::Synchronized => {
                 self.check_initial_sync();
-                self.event_queue.push_back(GgrsEvent::Synchronized { addr });
+                self.event_queue
+                    .push_back(GgrsEvent::Synchronized { addr: addr.clone() });
+                // the spectator receives all inputs we send from now on
+                if self.player_reg.spectators.contains_key(&addr) {
+                    for &player_handle in &player_handles {
+                        self.event_queue.push_back(GgrsEvent::SpectatorJoined {
+                            addr: addr.clone(),
+                            player_handle,
+                            frame: self.next_spectator_frame,
+                        });
+                    }
+                }
+                // a late joining player might have been admitted before we synchronized with it
+                self.send_missed_inputs(&addr);
+            }
+            // forward to user, then treat the rejected peer as disconnected
+            Event::ConfigMismatch { local, remote } => {
+                self.event_queue.push_back(GgrsEvent::ConfigMismatch {
+                    addr: addr.clone(),
+                    local,
+                    remote,
+                });
+                self.handle_event(Event::Disconnected, player_handles, addr);
             }
             // disconnect the player, then forward to user
             Event::Disconnected => {
-                for handle in player_handles {
-                    let last_frame = if handle < self.num_players as PlayerHandle {
-                        self.local_connect_status[handle].last_frame
-                    } else {
-                        NULL_FRAME // spectator
-                    };
-
-                    self.disconnect_player_at_frame(handle, last_frame);
+                self.early_inputs
+                    .retain(|(player, _)| !player_handles.contains(player));
+                let spectator = self.player_reg.spectators.contains_key(&addr);
+                if spectator {
+                    // spectators are removed below
+                } else if !player_handles.is_empty()
+                    && player_handles
+                        .iter()
+                        .all(|h| self.late_join_slots.contains(h))
+                {
+                    // a peer that has not been admitted yet just frees its reserved slots again
+                    self.player_reg.remotes.remove(&addr);
+                    for handle in &player_handles {
+                        self.player_reg.handles.remove(handle);
+                    }
+                    self.join_requests.remove(&addr);
+                } else if player_handles
+                    .iter()
+                    .all(|&h| h < self.num_players && self.local_connect_status[h].disconnected)
+                {
+                    // the players already were disconnected, e.g. a failed attempt to rejoin
+                    if let Some(endpoint) = self.player_reg.remotes.get_mut(&addr) {
+                        endpoint.disconnect();
+                    }
+                } else {
+                    for &handle in &player_handles {
+                        let last_frame = self.local_connect_status[handle].last_frame;
+                        self.disconnect_player_at_frame(handle, last_frame);
+                    }
                 }
 
-                self.event_queue.push_back(GgrsEvent::Disconnected { addr });
+                self.event_queue
+                    .push_back(GgrsEvent::Disconnected { addr: addr.clone() });
+                if spectator {
+                    for handle in player_handles {
+                        self.remove_spectator_endpoint(handle, addr.clone());
+                    }
+                }
             }
             // add the input and all associated information
             Event::Input { input, player } => {
                 // input only comes from remote players, not spectators
                 assert!(player < self.num_players as PlayerHandle);
-                if !self.local_connect_status[player].disconnected {
-                    // check if the input comes in the correct sequence
-                    let current_remote_frame = self.local_connect_status[player].last_frame;
-                    assert!(
-                        current_remote_frame == NULL_FRAME
-                            || current_remote_frame + 1 == input.frame
-                    );
-                    // update our info
-                    self.local_connect_status[player].last_frame = input.frame;
-                    // add the remote input
-                    self.sync_layer.add_remote_input(player, input);
+                if self.awaiting_join_snapshot
+                    || self.late_join_slots.contains(&player)
+                    || (self.reconnect && self.local_connect_status[player].disconnected)
+                {
+                    // we cannot use the input before the join has been applied, so keep it until then
+                    if !self.buffer_early_input(player, input) {
+                        warn!("Player {player} sent more inputs than fit before its join; disconnecting {addr:?}");
+                        self.handle_event(Event::Disconnected, player_handles, addr);
+                    }
+                } else {
+                    self.add_remote_input(player, input);
                 }
             }
+            // admit the player, if we are responsible for it
+            Event::JoinRequested => self.on_join_request(player_handles, addr),
+            // send the spectator a snapshot, if we have not sent a newer one already
+            Event::SnapshotRequested { frame } => self.on_spectator_snapshot_request(addr, frame),
+            Event::StateTransferReceived { transfer } => match transfer {
+                StateTransfer::JoinSnapshot(snapshot) => self.on_join_snapshot(snapshot, addr),
+                StateTransfer::DesyncState(state) => self.on_desync_state(state, addr),
+                StateTransfer::RecoverySnapshot(snapshot) => {
+                    self.on_recovery_snapshot(snapshot, addr)
+                }
+                // only spectators receive these
+                StateTransfer::SpectatorSnapshot(_) => (),
+            },
+            // forward to user
+            Event::UserMessage { payload } => {
+                self.event_queue
+                    .push_back(GgrsEvent::UserMessage { addr, payload });
+            }
         }
 
         // check event queue size and discard oldest events if too big
@@ -1169,13 +1995,932 @@ impl<T: Config> P2PSession<T> {
         }
     }
 
+    fn add_remote_input(&mut self, player: PlayerHandle, input: PlayerInput<T::Input>) {
+        if !self.local_connect_status[player].disconnected {
+            // check if the input comes in the correct sequence
+            let current_remote_frame = self.local_connect_status[player].last_frame;
+            assert!(current_remote_frame == NULL_FRAME || current_remote_frame + 1 == input.frame);
+            // update our info
+            self.local_connect_status[player].last_frame = input.frame;
+            // add the remote input
+            self.sync_layer.add_remote_input(player, input);
+        }
+    }
+
+    /// Keeps an input that arrived before its player was admitted. Inputs we already have are
+    /// dropped. Returns false if the player sent more inputs than it could have played before the
+    /// join is applied, which no well-behaved peer does.
+    fn buffer_early_input(&mut self, player: PlayerHandle, input: PlayerInput<T::Input>) -> bool {
+        if input.frame <= self.local_connect_status[player].last_frame {
+            return true;
+        }
+        let buffered = self
+            .early_inputs
+            .iter()
+            .filter(|(p, _)| *p == player)
+            .count();
+        if buffered >= self.max_prediction + INPUT_QUEUE_LENGTH {
+            return false;
+        }
+        self.early_inputs.push((player, input));
+        true
+    }
+
+    /// Adds the inputs that arrived before their players were admitted, dropping those that lie
+    /// before the join.
+    fn replay_early_inputs(&mut self) {
+        let early_inputs = std::mem::take(&mut self.early_inputs);
+        for (player, input) in early_inputs {
+            if self.awaiting_join_snapshot
+                || self.late_join_slots.contains(&player)
+                || (self.reconnect && self.local_connect_status[player].disconnected)
+            {
+                self.early_inputs.push((player, input));
+            } else if input.frame > self.local_connect_status[player].last_frame {
+                self.add_remote_input(player, input);
+            }
+        }
+    }
+
+    /// Returns true if this session is responsible for admitting late joining players, which is the
+    /// case if it owns the lowest player handle of all connected players.
+    fn is_join_sponsor(&self) -> bool {
+        (0..self.num_players)
+            .find(|handle| {
+                !self.late_join_slots.contains(handle)
+                    && !self.local_connect_status[*handle].disconnected
+            })
+            .is_some_and(|handle| {
+                matches!(
+                    self.player_reg.handles.get(&handle),
+                    Some(PlayerType::Local)
+                )
+            })
+    }
+
+    /// Handles a message from an address without endpoint. Clients that want to synchronize with us
+    /// are passed on to the user as connection requests.
+    fn on_unknown_message(&mut self, addr: &T::Address, msg: &Message) {
+        if let Some(last_seen) = self.rejected_connections.get_mut(addr) {
+            *last_seen = self.clock.now();
+            return;
+        }
+        let Some(msg) = self.connection_guard.open(msg, true) else {
+            return;
+        };
+        let MessageBody::SyncRequest(request) = &msg.body else {
+            return;
+        };
+
+        let now = self.clock.now();
+        if let Some((_, last_seen)) = self.connection_requests.get_mut(addr) {
+            // the client keeps asking until we answer
+            *last_seen = now;
+            return;
+        }
+        if self.connection_requests.len() >= MAX_CONNECTION_REQUESTS {
+            trace!("Too many pending connection requests; ignoring request from {addr:?}");
+            return;
+        }
+        debug!("Connection request from unknown address {addr:?}");
+        self.connection_requests
+            .insert(addr.clone(), (request.join_token.clone(), now));
+        self.event_queue.push_back(GgrsEvent::ConnectionRequest {
+            addr: addr.clone(),
+            token: request.join_token.clone(),
+        });
+    }
+
+    /// Registers a spectator under the given handle.
+    fn add_spectator_endpoint(
+        &mut self,
+        player_handle: PlayerHandle,
+        addr: T::Address,
+    ) -> Result<(), GgrsError> {
+        if self.awaiting_join_snapshot {
+            return Err(GgrsError::InvalidRequest {
+                info: "Spectators can only be added once the session joined the match.".to_owned(),
+            });
+        }
+        if self.player_reg.handles.contains_key(&player_handle) {
+            return Err(GgrsError::InvalidRequest {
+                info: "Player handle already in use.".to_owned(),
+            });
+        }
+        if self.player_reg.remotes.contains_key(&addr)
+            || self.player_reg.spectators.contains_key(&addr)
+        {
+            return Err(GgrsError::InvalidRequest {
+                info: "Address already in use.".to_owned(),
+            });
+        }
+
+        // we send the inputs of all players to the spectator
+        let endpoint = self.create_endpoint(vec![player_handle], addr.clone(), self.num_players);
+        self.rejected_connections.remove(&addr);
+        self.player_reg.spectators.insert(addr.clone(), endpoint);
+        self.player_reg
+            .handles
+            .insert(player_handle, PlayerType::Spectator(addr));
+        Ok(())
+    }
+
+    /// Forgets the spectator, so its handle and address are free again.
+    fn remove_spectator_endpoint(&mut self, player_handle: PlayerHandle, addr: T::Address) {
+        self.player_reg.spectators.remove(&addr);
+        self.player_reg.handles.remove(&player_handle);
+        self.pending_spectator_snapshots.remove(&addr);
+        self.sent_spectator_snapshots.remove(&addr);
+        self.event_queue.push_back(GgrsEvent::SpectatorLeft {
+            addr,
+            player_handle,
+        });
+        // the session might only have been waiting for this spectator
+        self.check_initial_sync();
+    }
+
+    /// Upon receiving a join request, notify the user and admit the player if we are responsible.
+    fn on_join_request(&mut self, player_handles: Vec<PlayerHandle>, addr: T::Address) {
+        // only peers that take over reserved slots or disconnected players can join
+        if player_handles.is_empty()
+            || !player_handles.iter().all(|&h| {
+                self.late_join_slots.contains(&h)
+                    || (self.reconnect
+                        && h < self.num_players
+                        && self.local_connect_status[h].disconnected)
+            })
+        {
+            return;
+        }
+        // if we lost everyone ourselves, only the peer with the lowest handle continues the match
+        if self.is_isolated() && !self.has_lowest_player_handle() {
+            return;
+        }
+
+        if self.join_requests.insert(addr.clone()) {
+            for &player_handle in &player_handles {
+                self.event_queue.push_back(GgrsEvent::JoinRequested {
+                    addr: addr.clone(),
+                    player_handle,
+                });
+            }
+        }
+
+        if self.state != SessionState::Running || !self.is_join_sponsor() {
+            return;
+        }
+        // we need to be able to send to the joining peer; it keeps asking until we are
+        if !self
+            .player_reg
+            .remotes
+            .get(&addr)
+            .is_some_and(|endpoint| endpoint.is_running())
+        {
+            return;
+        }
+
+        // The join frame has to come after all inputs we have sent so far. That way, every peer
+        // learns about the join through our connection status before it can confirm the join frame.
+        let last_local_frame = self
+            .player_reg
+            .local_player_handles()
+            .iter()
+            .map(|&h| self.local_connect_status[h].last_frame)
+            .max()
+            .unwrap_or(NULL_FRAME);
+        let join_frame = std::cmp::max(self.sync_layer.current_frame(), last_local_frame) + 1;
+        info!("Admitting player(s) {player_handles:?} at {addr:?} from frame {join_frame} on");
+
+        for handle in player_handles {
+            self.admit_player(handle, join_frame);
+        }
+        self.pending_join_snapshots.insert(addr, join_frame);
+    }
+
+    /// Check if other peers admitted players we don't know of yet and admit them as well.
+    fn update_player_joins(&mut self) {
+        for handle in 0..self.num_players {
+            if matches!(
+                self.player_reg.handles.get(&handle),
+                Some(PlayerType::Local)
+            ) {
+                continue;
+            }
+            let join_frame = self
+                .player_reg
+                .remotes
+                .values()
+                .filter(|endpoint| endpoint.is_running() && self.takes_part(endpoint))
+                .map(|endpoint| endpoint.peer_connect_status(handle).connect_frame)
+                .max()
+                .unwrap_or(0);
+            if join_frame > self.local_connect_status[handle].connect_frame {
+                self.admit_player(handle, join_frame);
+            }
+        }
+    }
+
+    /// Lets a remote player take part in the game from `join_frame` on.
+    fn admit_player(&mut self, player_handle: PlayerHandle, join_frame: Frame) {
+        debug!("Player {player_handle} joins at frame {join_frame}");
+        let rejoined = !self.late_join_slots.remove(&player_handle);
+        self.local_connect_status[player_handle] = ConnectionStatus {
+            disconnected: false,
+            last_frame: join_frame - 1,
+            connect_frame: join_frame,
+        };
+        self.sync_layer.reset_input_queue(player_handle, join_frame);
+
+        if self.sync_layer.current_frame() > join_frame {
+            // we already simulated frames without the new player, so we have to resimulate them
+            self.disconnect_frame = if self.disconnect_frame == NULL_FRAME {
+                join_frame
+            } else {
+                std::cmp::min(self.disconnect_frame, join_frame)
+            };
+        }
+
+        self.event_queue.push_back(if rejoined {
+            GgrsEvent::PlayerRejoined {
+                player_handle,
+                frame: join_frame,
+            }
+        } else {
+            GgrsEvent::PlayerJoined {
+                player_handle,
+                frame: join_frame,
+            }
+        });
+
+        self.replay_early_inputs();
+        if let Some(PlayerType::Remote(addr)) = self.player_reg.handles.get(&player_handle).cloned()
+        {
+            self.join_requests.remove(&addr);
+            self.send_missed_inputs(&addr);
+        }
+    }
+
+    /// Sends the snapshots for players we admitted as soon as all inputs before the join frame are
+    /// confirmed.
+    fn send_join_snapshots(&mut self, requests: &mut Vec<GgrsRequest<T>>) {
+        let pending: Vec<_> = self
+            .pending_join_snapshots
+            .iter()
+            .map(|(addr, &join_frame)| (addr.clone(), join_frame))
+            .collect();
+
+        for (addr, join_frame) in pending {
+            let handles = self.player_reg.handles_by_address(addr.clone());
+            if handles.is_empty()
+                || handles
+                    .iter()
+                    .any(|&h| self.local_connect_status[h].disconnected)
+            {
+                // the joining peer left before we could send the snapshot
+                self.pending_join_snapshots.remove(&addr);
+                continue;
+            }
+
+            // lockstep sessions don't save states by themselves, so we ask for the state at the join frame
+            if self.in_lockstep_mode()
+                && self.sync_layer.current_frame() == join_frame
+                && self.sync_layer.saved_state_by_frame(join_frame).is_none()
+            {
+                requests.push(self.sync_layer.save_current_state());
+            }
+
+            if self.sync_layer.last_confirmed_frame() < join_frame - 1 {
+                continue;
+            }
+            let Some(snapshot) = self.create_join_snapshot(handles, join_frame) else {
+                continue;
+            };
+
+            debug!(
+                "Sending snapshot of frame {} to {addr:?} joining at frame {join_frame}",
+                snapshot.state_frame
+            );
+            if let Some(endpoint) = self.player_reg.remotes.get_mut(&addr) {
+                if let Err(e) = endpoint.send_state_transfer(&StateTransfer::JoinSnapshot(snapshot))
+                {
+                    warn!("Cannot send join snapshot to {addr:?}: {e}");
+                }
+                endpoint.send_all_messages(&mut self.socket);
+            }
+            self.pending_join_snapshots.remove(&addr);
+        }
+    }
+
+    /// Upon a spectator asking for a snapshot to continue from `frame`, remember to send it one. The
+    /// spectator keeps asking until the snapshot arrives, so we ignore the request if we already
+    /// sent a snapshot of a later frame.
+    fn on_spectator_snapshot_request(&mut self, addr: T::Address, frame: Frame) {
+        if !self.state_transfer {
+            debug!("Ignoring snapshot request from spectator {addr:?}; state transfer is disabled");
+            return;
+        }
+        if !self.player_reg.spectators.contains_key(&addr)
+            || self
+                .sent_spectator_snapshots
+                .get(&addr)
+                .is_some_and(|&sent_frame| sent_frame > frame)
+        {
+            return;
+        }
+        // the spectator has received all inputs we sent so far, so any later frame works
+        let next_spectator_frame = self.next_spectator_frame;
+        self.pending_spectator_snapshots
+            .entry(addr)
+            .or_insert(next_spectator_frame);
+    }
+
+    /// Sends the spectators that asked for a snapshot our latest correct saved state, as soon as
+    /// there is one they receive all later inputs for.
+    fn send_spectator_snapshots(&mut self, requests: &mut Vec<GgrsRequest<T>>) {
+        let pending: Vec<_> = self
+            .pending_spectator_snapshots
+            .iter()
+            .map(|(addr, &min_frame)| (addr.clone(), min_frame))
+            .collect();
+
+        for (addr, min_frame) in pending {
+            // lockstep sessions don't save states by themselves, so we ask for the current state
+            let current_frame = self.sync_layer.current_frame();
+            if self.in_lockstep_mode()
+                && current_frame >= min_frame
+                && self
+                    .sync_layer
+                    .saved_state_by_frame(current_frame)
+                    .is_none()
+            {
+                requests.push(self.sync_layer.save_current_state());
+            }
+
+            let Some(snapshot) = self.create_spectator_snapshot(min_frame) else {
+                continue;
+            };
+            debug!(
+                "Sending snapshot of frame {} to spectator {addr:?}",
+                snapshot.frame
+            );
+            let frame = snapshot.frame;
+            if let Some(endpoint) = self.player_reg.spectators.get_mut(&addr) {
+                let transfer = StateTransfer::SpectatorSnapshot(snapshot);
+                if let Err(e) = endpoint.send_state_transfer(&transfer) {
+                    warn!("Cannot send snapshot to spectator {addr:?}: {e}");
+                }
+                endpoint.send_all_messages(&mut self.socket);
+            }
+            self.pending_spectator_snapshots.remove(&addr);
+            self.sent_spectator_snapshots.insert(addr, frame);
+        }
+    }
+
+    /// Creates a snapshot from the latest correct saved state, if there is one from `min_frame` on.
+    fn create_spectator_snapshot(&self, min_frame: Frame) -> Option<SpectatorSnapshot> {
+        let codec = self.state_codec?;
+        // all saved states up to one frame after the last confirmed frame are correct
+        let last_confirmed = self.sync_layer.last_confirmed_frame();
+        let cell = self.sync_layer.latest_saved_state_in_range(
+            std::cmp::max(min_frame, last_confirmed - 1),
+            last_confirmed + 1,
+        )?;
+        let frame = cell.frame();
+        let state = (codec.encode)(&*cell.data()?);
+        Some(SpectatorSnapshot { frame, state })
+    }
+
+    /// Creates a snapshot from the latest correct saved state up to the join frame, if there is one.
+    fn create_join_snapshot(
+        &self,
+        player_handles: Vec<PlayerHandle>,
+        join_frame: Frame,
+    ) -> Option<JoinSnapshot> {
+        let codec = self.state_codec?;
+        // all saved states up to one frame after the last confirmed frame are correct
+        let last_confirmed = self.sync_layer.last_confirmed_frame();
+        let cell = self
+            .sync_layer
+            .latest_saved_state_in_range(std::cmp::max(last_confirmed - 1, 0), join_frame)?;
+        let state_frame = cell.frame();
+        let state = (codec.encode)(&*cell.data()?);
+
+        // players that disconnected after the join frame have inputs the joining peer cannot get
+        // from anyone else, so they are included as well
+        let last_input_frame = self
+            .local_connect_status
+            .iter()
+            .filter(|status| status.disconnected)
+            .map(|status| status.last_frame)
+            .fold(join_frame - 1, std::cmp::max);
+
+        let mut inputs = Vec::new();
+        for frame in state_frame..=last_input_frame {
+            let frame_inputs: Vec<T::Input> = self
+                .local_connect_status
+                .iter()
+                .enumerate()
+                .map(|(handle, status)| {
+                    if !status.disconnected_at(frame) && (frame < join_frame || status.disconnected)
+                    {
+                        self.sync_layer.confirmed_input(handle, frame).input
+                    } else {
+                        T::Input::default()
+                    }
+                })
+                .collect();
+            inputs.push(bincode::serialize(&frame_inputs).expect("input serialization failed"));
+        }
+
+        Some(JoinSnapshot {
+            join_frame,
+            player_handles,
+            state_frame,
+            state,
+            inputs,
+            connect_status: self.local_connect_status.clone(),
+        })
+    }
+
+    /// Upon receiving the state of a remote peer for a frame with mismatching checksums, hand both
+    /// states to the user.
+    fn on_desync_state(&mut self, remote_state: DesyncState, addr: T::Address) {
+        if !self.desync_state_exchange {
+            debug!("Ignoring desync state from {addr:?}; desync state exchange is disabled");
+            return;
+        }
+        let frame = remote_state.frame;
+        let Some(local_state) = self.local_desync_states.get(&frame) else {
+            warn!("Received desync state of frame {frame} from {addr:?}, but have no state of that frame");
+            return;
+        };
+        self.event_queue.push_back(GgrsEvent::DesyncStateReceived {
+            frame,
+            addr,
+            local_state: local_state.clone(),
+            remote_state: remote_state.state,
+        });
+    }
+
+    /// Returns the handle of the player whose peer is the desync recovery authority. Once the
+    /// configured authority disconnected, the connected player with the lowest handle takes over.
+    fn recovery_authority(&self) -> Option<PlayerHandle> {
+        let lowest_connected = || {
+            (0..self.num_players).find(|&handle| !self.local_connect_status[handle].disconnected)
+        };
+        match self.desync_recovery {
+            DesyncRecovery::Off => None,
+            DesyncRecovery::LowestHandle => lowest_connected(),
+            DesyncRecovery::Authority { player_handle }
+                if self.local_connect_status[player_handle].disconnected =>
+            {
+                lowest_connected()
+            }
+            DesyncRecovery::Authority { player_handle } => Some(player_handle),
+        }
+    }
+
+    /// Returns true if we host the desync recovery authority.
+    fn is_recovery_authority(&self) -> bool {
+        self.recovery_authority().is_some_and(|handle| {
+            matches!(
+                self.player_reg.handles.get(&handle),
+                Some(PlayerType::Local)
+            )
+        })
+    }
+
+    /// Creates a snapshot of our latest state that can no longer change, for desynced peers.
+    fn create_recovery_snapshot(&self) -> Option<RecoverySnapshot> {
+        let codec = self.state_codec?;
+        // all saved states up to the last confirmed frame are correct
+        let last_confirmed = self.sync_layer.last_confirmed_frame();
+        let cell = self
+            .sync_layer
+            .latest_saved_state_in_range(std::cmp::max(last_confirmed - 1, 0), last_confirmed)?;
+        let frame = cell.frame();
+        let state = (codec.encode)(&*cell.data()?);
+        Some(RecoverySnapshot { frame, state })
+    }
+
+    /// Upon receiving the state of the recovery authority, keep it until we can load it.
+    fn on_recovery_snapshot(&mut self, snapshot: RecoverySnapshot, addr: T::Address) {
+        let from_authority = self.recovery_authority().is_some_and(|handle| {
+            self.player_reg
+                .remotes
+                .get(&addr)
+                .is_some_and(|endpoint| endpoint.handles().contains(&handle))
+        });
+        if !from_authority {
+            debug!("Ignoring recovery snapshot from {addr:?}; it is not the recovery authority");
+            return;
+        }
+        if self
+            .pending_recovery
+            .as_ref()
+            .is_some_and(|(frame, ..)| *frame > snapshot.frame)
+        {
+            return;
+        }
+        let Some(state) = self
+            .state_codec
+            .and_then(|codec| (codec.decode)(&snapshot.state))
+        else {
+            warn!("Failed to decode recovery snapshot from {addr:?}, discarding");
+            return;
+        };
+        self.pending_recovery = Some((snapshot.frame, state, addr));
+    }
+
+    /// Loads the state of the recovery authority and resimulates up to the current frame, as soon
+    /// as we have simulated up to the frame of that state and all inputs before it are confirmed.
+    fn apply_desync_recovery(&mut self, requests: &mut Vec<GgrsRequest<T>>) {
+        let current_frame = self.sync_layer.current_frame();
+        let confirmed_frame = self.confirmed_frame();
+        if self
+            .pending_recovery
+            .as_ref()
+            .is_some_and(|(frame, ..)| *frame > current_frame || *frame - 1 > confirmed_frame)
+        {
+            return;
+        }
+        let Some((frame, state, addr)) = self.pending_recovery.take() else {
+            return;
+        };
+
+        let history_start = self.next_recovery_input_frame - self.recovery_inputs.len() as Frame;
+        if frame < history_start {
+            warn!("Discarding recovery snapshot of frame {frame} from {addr:?}; the inputs since that frame are gone");
+            return;
+        }
+        info!("Recovering from desync with state of frame {frame} from {addr:?}");
+
+        // load the snapshot and resimulate up to the frame we were at
+        let last_confirmed = self.sync_layer.last_confirmed_frame();
+        let save_every_frame = !self.in_lockstep_mode() && !self.saves_sparsely();
+        requests.push(self.sync_layer.load_snapshot(frame, state));
+        while self.sync_layer.current_frame() < current_frame {
+            let replay_frame = self.sync_layer.current_frame();
+            let inputs = match self
+                .recovery_inputs
+                .get((replay_frame - history_start) as usize)
+            {
+                // the input queues may not hold inputs this old anymore
+                Some(confirmed) => confirmed
+                    .iter()
+                    .map(|input| {
+                        if input.frame == NULL_FRAME {
+                            (input.input, InputStatus::Disconnected)
+                        } else {
+                            (input.input, InputStatus::Confirmed)
+                        }
+                    })
+                    .collect(),
+                None => self
+                    .sync_layer
+                    .synchronized_inputs(&self.local_connect_status),
+            };
+            if replay_frame > frame
+                && (save_every_frame || (self.saves_sparsely() && replay_frame == last_confirmed))
+            {
+                requests.push(self.sync_layer.save_current_state());
+            }
+            self.sync_layer.advance_frame();
+            requests.push(GgrsRequest::AdvanceFrame { inputs });
+        }
+        self.sync_layer.set_last_confirmed_frame(
+            std::cmp::max(last_confirmed, frame - 1),
+            self.saves_sparsely(),
+        );
+
+        // our checksums from the frame of the snapshot on are computed and sent again
+        self.local_checksum_history.retain(|&f, _| f < frame);
+        self.awaited_checksums.retain(|&f, _| f < frame);
+        self.local_input_hashes.retain(|&f, _| f < frame);
+        self.local_sub_checksums.retain(|&f, _| f < frame);
+        self.local_desync_states.retain(|&f, _| f < frame);
+        if let Some(recorder) = self.desync_bundle_recorder.as_mut() {
+            recorder.restart_at(frame);
+        }
+        if let DesyncDetection::On { interval } = self.desync_detection {
+            let interval = interval as i32;
+            self.last_sent_checksum_frame = std::cmp::min(
+                self.last_sent_checksum_frame,
+                ((frame - 1) / interval) * interval,
+            );
+        }
+
+        self.event_queue
+            .push_back(GgrsEvent::DesyncRecovered { frame, addr });
+    }
+
+    /// Asks all peers we are synchronized with to admit us into the running match.
+    fn send_join_requests(&mut self) {
+        let now = self.clock.now();
+        if self
+            .last_join_request
+            .is_some_and(|last_request| last_request + JOIN_REQUEST_INTERVAL > now)
+        {
+            return;
+        }
+        self.last_join_request = Some(now);
+
+        for endpoint in self.player_reg.remotes.values_mut() {
+            if endpoint.is_running() {
+                endpoint.send_join_request();
+            }
+        }
+    }
+
+    /// Upon receiving the snapshot we asked for, set up the session to continue from the join frame.
+    fn on_join_snapshot(&mut self, snapshot: JoinSnapshot, addr: T::Address) {
+        let rejoined = !self.awaiting_join_snapshot;
+        if !self.awaiting_join_snapshot && !self.is_isolated() {
+            debug!("Ignoring join snapshot from {addr:?}; not waiting for one");
+            return;
+        }
+
+        let mut local_handles = self.player_reg.local_player_handles();
+        local_handles.sort_unstable();
+        let mut snapshot_handles = snapshot.player_handles.clone();
+        snapshot_handles.sort_unstable();
+        if local_handles != snapshot_handles {
+            warn!("Ignoring join snapshot from {addr:?} for players {snapshot_handles:?}");
+            return;
+        }
+
+        let decoded = self
+            .state_codec
+            .and_then(|codec| (codec.decode)(&snapshot.state));
+        let (Some(state), Some(inputs)) = (decoded, self.decode_join_inputs(&snapshot)) else {
+            warn!("Failed to decode join snapshot from {addr:?}, discarding");
+            return;
+        };
+
+        let join_frame = snapshot.join_frame;
+        let state_frame = snapshot.state_frame;
+        info!("Joining at frame {join_frame} with state of frame {state_frame} from {addr:?}");
+
+        // take over the connection status and inputs of all other players
+        for handle in 0..self.num_players {
+            if local_handles.contains(&handle) {
+                continue;
+            }
+            let mut status = snapshot.connect_status[handle];
+            let start_frame = std::cmp::max(state_frame, status.connect_frame);
+            let last_known_frame = if status.disconnected {
+                status.last_frame
+            } else {
+                join_frame - 1
+            };
+
+            self.sync_layer.reset_input_queue(handle, start_frame);
+            let mut last_frame = start_frame - 1;
+            for (frame, frame_inputs) in (state_frame..).zip(&inputs) {
+                if frame >= start_frame && frame <= last_known_frame {
+                    self.sync_layer
+                        .add_remote_input(handle, PlayerInput::new(frame, frame_inputs[handle]));
+                    last_frame = frame;
+                }
+            }
+            if !status.disconnected {
+                status.last_frame = last_frame;
+            }
+            self.local_connect_status[handle] = status;
+            if status.connect_frame > 0 {
+                // the player joined before us
+                self.late_join_slots.remove(&handle);
+            }
+        }
+
+        // our own players take part from the join frame on; everything we simulated or sent on our
+        // own is discarded
+        self.disconnect_frame = NULL_FRAME;
+        self.outgoing_local_inputs.clear();
+        self.last_sent_outgoing_input_frame = NULL_FRAME;
+        for &handle in &local_handles {
+            self.local_connect_status[handle] = ConnectionStatus {
+                disconnected: false,
+                last_frame: join_frame - 1,
+                connect_frame: join_frame,
+            };
+            let fills = self.sync_layer.reset_input_queue(handle, join_frame);
+            for fill_input in fills {
+                self.local_connect_status[handle].last_frame = fill_input.frame;
+                self.queue_outgoing_local_input(handle, fill_input);
+            }
+        }
+
+        // load the snapshot and simulate up to the join frame
+        let mut requests = vec![self.sync_layer.load_snapshot(state_frame, state)];
+        let save_every_frame = !self.in_lockstep_mode() && !self.saves_sparsely();
+        while self.sync_layer.current_frame() < join_frame {
+            let inputs = self
+                .sync_layer
+                .synchronized_inputs(&self.local_connect_status);
+            if save_every_frame && self.sync_layer.current_frame() > state_frame {
+                requests.push(self.sync_layer.save_current_state());
+            }
+            self.sync_layer.advance_frame();
+            requests.push(GgrsRequest::AdvanceFrame { inputs });
+        }
+        if self.saves_sparsely() && state_frame < join_frame {
+            // all inputs before the join frame are confirmed, so this state is correct
+            requests.push(self.sync_layer.save_current_state());
+        }
+        self.sync_layer.reset_prediction();
+        self.sync_layer
+            .set_last_confirmed_frame(join_frame - 1, self.saves_sparsely());
+        self.pending_requests = requests;
+
+        // continue the bookkeeping from the join frame on
+        self.next_spectator_frame = join_frame;
+        self.recovery_inputs.clear();
+        self.next_recovery_input_frame = join_frame;
+        self.sync_layer.discard_input_hashes();
+        if let Some(recorder) = self.replay_recorder.as_mut() {
+            recorder.skip_to(join_frame);
+        }
+        if let Some(recorder) = self.desync_bundle_recorder.as_mut() {
+            recorder.restart_at(join_frame);
+        }
+        if let DesyncDetection::On { interval } = self.desync_detection {
+            let interval = interval as i32;
+            self.last_sent_checksum_frame = ((join_frame - 1) / interval) * interval;
+        }
+        // the user may already have given us inputs for the frame we started at
+        for input in self.pending_local_inputs.values_mut() {
+            input.frame = join_frame;
+        }
+
+        // peers that are not part of the match anymore should not hold up our start
+        for endpoint in self.player_reg.remotes.values_mut() {
+            if !endpoint.is_running()
+                && endpoint
+                    .handles()
+                    .iter()
+                    .all(|&h| self.local_connect_status[h].disconnected)
+            {
+                endpoint.disconnect();
+            }
+        }
+
+        self.awaiting_join_snapshot = false;
+        for &player_handle in &local_handles {
+            self.event_queue.push_back(if rejoined {
+                GgrsEvent::PlayerRejoined {
+                    player_handle,
+                    frame: join_frame,
+                }
+            } else {
+                GgrsEvent::PlayerJoined {
+                    player_handle,
+                    frame: join_frame,
+                }
+            });
+        }
+        self.replay_early_inputs();
+        self.check_initial_sync();
+    }
+
+    /// Decodes the inputs of a snapshot and checks that they are consistent with the snapshot.
+    fn decode_join_inputs(&self, snapshot: &JoinSnapshot) -> Option<Vec<Vec<T::Input>>> {
+        if snapshot.connect_status.len() != self.num_players
+            || snapshot.state_frame < 0
+            || snapshot.join_frame < snapshot.state_frame
+            || snapshot.inputs.len() < (snapshot.join_frame - snapshot.state_frame) as usize
+        {
+            return None;
+        }
+
+        let mut inputs = Vec::with_capacity(snapshot.inputs.len());
+        for bytes in &snapshot.inputs {
+            let frame_inputs: Vec<T::Input> = bincode::deserialize(bytes).ok()?;
+            if frame_inputs.len() != self.num_players {
+                return None;
+            }
+            inputs.push(frame_inputs);
+        }
+        Some(inputs)
+    }
+
+    /// Returns true if the players of the endpoint are part of the match. Peers that are trying to
+    /// (re)join don't know the state of the match, so their connection status is meaningless.
+    fn takes_part(&self, endpoint: &UdpProtocol<T>) -> bool {
+        endpoint
+            .handles()
+            .iter()
+            .any(|&h| !self.local_connect_status[h].disconnected)
+    }
+
+    /// Returns true if we lost the connection to all remote players and should try to rejoin them.
+    fn is_isolated(&self) -> bool {
+        if !self.reconnect
+            || self.state != SessionState::Running
+            || self.awaiting_join_snapshot
+            || !self.player_reg.spectators.is_empty()
+        {
+            return false;
+        }
+        let mut remote_players = self
+            .player_reg
+            .remote_player_handles()
+            .into_iter()
+            .filter(|h| !self.late_join_slots.contains(h))
+            .peekable();
+        remote_players.peek().is_some()
+            && remote_players.all(|h| self.local_connect_status[h].disconnected)
+    }
+
+    /// Returns true if we own the lowest handle of all players that took part in the match.
+    fn has_lowest_player_handle(&self) -> bool {
+        (0..self.num_players)
+            .find(|handle| !self.late_join_slots.contains(handle))
+            .is_some_and(|handle| {
+                matches!(
+                    self.player_reg.handles.get(&handle),
+                    Some(PlayerType::Local)
+                )
+            })
+    }
+
+    fn create_endpoint(
+        &self,
+        handles: Vec<PlayerHandle>,
+        addr: T::Address,
+        local_players: usize,
+    ) -> UdpProtocol<T> {
+        let mut endpoint = UdpProtocol::new(
+            handles,
+            addr,
+            self.num_players,
+            local_players,
+            self.max_prediction,
+            self.disconnect_timeout,
+            self.disconnect_notify_start,
+            self.fps,
+            self.desync_detection,
+            self.build_hash,
+            self.packet_security,
+            self.join_token.clone(),
+            false,
+            self.state_transfer,
+            self.clock.clone(),
+        );
+        endpoint.synchronize();
+        endpoint
+    }
+
+    /// Replaces the endpoint of a disconnected peer with a fresh one that synchronizes again.
+    fn recreate_disconnected_endpoint(&mut self, addr: &T::Address) {
+        let Some(endpoint) = self.player_reg.remotes.get(addr) else {
+            return;
+        };
+        if !endpoint.is_disconnected() {
+            return;
+        }
+        debug!("Synchronizing again with disconnected peer {addr:?}");
+        let local_players = self.player_reg.local_player_handles().len();
+        let mut successor =
+            self.create_endpoint(endpoint.handles().clone(), addr.clone(), local_players);
+        // keep the replay history, so packets of the old connection cannot be replayed
+        if let Some(endpoint) = self.player_reg.remotes.remove(addr) {
+            endpoint.pass_guard_to(&mut successor);
+        }
+        self.player_reg.remotes.insert(addr.clone(), successor);
+    }
+
+    /// Starts synchronizing again with all remote players we lost the connection to.
+    fn reconnect_to_remotes(&mut self) {
+        let addrs: Vec<_> = self
+            .player_reg
+            .remotes
+            .iter()
+            .filter(|(_, endpoint)| {
+                endpoint
+                    .handles()
+                    .iter()
+                    .all(|h| !self.late_join_slots.contains(h))
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in addrs {
+            self.recreate_disconnected_endpoint(&addr);
+        }
+    }
+
     fn compare_local_checksums_against_peers(&mut self) {
         match self.desync_detection {
             DesyncDetection::On { .. } => {
+                let is_recovery_authority = self.is_recovery_authority();
+                let tally_votes = self.player_reg.remotes.len() >= 2;
+                let mut desynced_peers = Vec::new();
+                let mut desyncs = Vec::new();
                 for remote in self.player_reg.remotes.values_mut() {
                     let mut checked_frames = Vec::new();
+                    let mut mismatched_frames = Vec::new();
 
-                    for (&remote_frame, &remote_checksum) in &remote.pending_checksums {
+                    for (&remote_frame, report) in &remote.pending_checksums {
                         if remote_frame >= self.sync_layer.last_confirmed_frame() {
                             // we're still waiting for inputs for this frame
                             continue;
@@ -1183,13 +2928,39 @@ impl<T: Config> P2PSession<T> {
                         if let Some(&local_checksum) =
                             self.local_checksum_history.get(&remote_frame)
                         {
-                            if local_checksum != remote_checksum {
+                            let remote_checksum = report.checksum;
+                            let mismatched_sub_checksums = mismatched_sub_checksums(
+                                self.local_sub_checksums
+                                    .get(&remote_frame)
+                                    .map_or(&[], Vec::as_slice),
+                                &report.sub_checksums,
+                            );
+                            if local_checksum != remote_checksum
+                                || !mismatched_sub_checksums.is_empty()
+                            {
+                                let local_input_hash = self.local_input_hashes.get(&remote_frame);
+                                let inputs_match = local_input_hash
+                                    .zip(report.input_hash)
+                                    .map(|(&local, remote)| local == remote);
                                 self.event_queue.push_back(GgrsEvent::DesyncDetected {
                                     frame: remote_frame,
                                     local_checksum,
                                     remote_checksum,
                                     addr: remote.peer_addr(),
+                                    inputs_match,
+                                    mismatched_sub_checksums,
                                 });
+                                desyncs.push((remote_frame, local_checksum, remote_checksum));
+                                mismatched_frames.push(remote_frame);
+                            }
+                            if tally_votes {
+                                self.checksum_votes
+                                    .entry(remote_frame)
+                                    .or_default()
+                                    .insert(remote.peer_addr(), remote_checksum);
+                            }
+                            if let Some(awaited) = self.awaited_checksums.get_mut(&remote_frame) {
+                                awaited.remove(&remote.peer_addr());
                             }
                             checked_frames.push(remote_frame);
                         }
@@ -1198,20 +2969,198 @@ impl<T: Config> P2PSession<T> {
                     for frame in checked_frames {
                         remote.pending_checksums.remove_entry(&frame);
                     }
+
+                    // as recovery authority, send our state to the remote unless it desynced before
+                    // the state we sent last
+                    let addr = remote.peer_addr();
+                    let last_recovery_frame = self.sent_recovery_frames.get(&addr);
+                    if is_recovery_authority
+                        && mismatched_frames
+                            .iter()
+                            .any(|frame| last_recovery_frame.is_none_or(|last| frame > last))
+                    {
+                        desynced_peers.push(addr);
+                    }
+
+                    // let the remote compare our states with its own
+                    for frame in mismatched_frames {
+                        if let Some(state) = self.local_desync_states.get(&frame) {
+                            let transfer = StateTransfer::DesyncState(DesyncState {
+                                frame,
+                                state: state.clone(),
+                            });
+                            if let Err(e) = remote.send_state_transfer(&transfer) {
+                                warn!(
+                                    "Cannot send desync state of frame {frame} to {:?}: {e}",
+                                    remote.peer_addr()
+                                );
+                            }
+                        }
+                    }
+                }
+
+                if !desyncs.is_empty() {
+                    self.record_desyncs(desyncs);
+                }
+
+                if tally_votes {
+                    self.tally_checksum_votes();
+                }
+
+                if desynced_peers.is_empty() {
+                    return;
+                }
+                let Some(snapshot) = self.create_recovery_snapshot() else {
+                    debug!("No confirmed state to send to desynced peers");
+                    return;
+                };
+                let frame = snapshot.frame;
+                let transfer = StateTransfer::RecoverySnapshot(snapshot);
+                for addr in desynced_peers {
+                    if let Some(remote) = self.player_reg.remotes.get_mut(&addr) {
+                        info!("Sending state of frame {frame} to desynced peer {addr:?}");
+                        if let Err(e) = remote.send_state_transfer(&transfer) {
+                            warn!("Cannot send state of frame {frame} to {addr:?}: {e}");
+                        }
+                    }
+                    self.sent_recovery_frames.insert(addr, frame);
                 }
             }
             DesyncDetection::Off => (),
         }
     }
 
+    /// For every frame all peers reported a checksum for, finds the checksum most peers agree on and
+    /// names the players of the peers that disagree with it.
+    fn tally_checksum_votes(&mut self) {
+        let voters: Vec<T::Address> = self
+            .player_reg
+            .remotes
+            .iter()
+            .filter(|(_, endpoint)| endpoint.is_running() && self.takes_part(endpoint))
+            .map(|(addr, _)| addr.clone())
+            .collect();
+
+        let complete_frames: Vec<Frame> = self
+            .checksum_votes
+            .iter()
+            .filter(|(_, votes)| voters.iter().all(|addr| votes.contains_key(addr)))
+            .map(|(&frame, _)| frame)
+            .collect();
+        for frame in complete_frames {
+            let Some(votes) = self.checksum_votes.remove(&frame) else {
+                continue;
+            };
+            let Some(&local_checksum) = self.local_checksum_history.get(&frame) else {
+                continue;
+            };
+
+            let mut counts = HashMap::<u128, usize>::new();
+            *counts.entry(local_checksum).or_default() += 1;
+            for addr in &voters {
+                *counts.entry(votes[addr]).or_default() += 1;
+            }
+            let num_peers = voters.len() + 1;
+            let Some((&consensus_checksum, &agreeing)) =
+                counts.iter().max_by_key(|(_, &count)| count)
+            else {
+                continue;
+            };
+            // without a strict majority, we can't tell who diverged
+            if agreeing == num_peers || agreeing * 2 <= num_peers {
+                continue;
+            }
+
+            let mut outliers = Vec::new();
+            if local_checksum != consensus_checksum {
+                outliers.extend(self.player_reg.local_player_handles());
+            }
+            for addr in &voters {
+                if votes[addr] != consensus_checksum {
+                    outliers.extend(self.player_reg.remotes[addr].handles().iter().copied());
+                }
+            }
+            outliers.sort_unstable();
+            warn!("Players {outliers:?} disagree with the checksum most peers agree on at frame {frame}");
+
+            let mut disconnected = Vec::new();
+            if self.disconnect_desync_outliers {
+                for &handle in &outliers {
+                    let Some(PlayerType::Remote(addr)) = self.player_reg.handles.get(&handle)
+                    else {
+                        continue;
+                    };
+                    if !self.local_connect_status[handle].disconnected {
+                        disconnected.push(addr.clone());
+                        let last_frame = self.local_connect_status[handle].last_frame;
+                        self.disconnect_player_at_frame(handle, last_frame);
+                    }
+                }
+            }
+            self.event_queue.push_back(GgrsEvent::DesyncAttributed {
+                frame,
+                consensus_checksum,
+                outliers,
+            });
+            for addr in disconnected {
+                self.event_queue.push_back(GgrsEvent::Disconnected { addr });
+            }
+        }
+
+        // frames we no longer have a checksum for can't be tallied anymore
+        let local_checksum_history = &self.local_checksum_history;
+        self.checksum_votes
+            .retain(|frame, _| local_checksum_history.contains_key(frame));
+    }
+
+    /// Returns the next frame whose checksum is sent to the other peers.
+    fn next_checksum_frame(&self, interval: u32) -> Frame {
+        if self.last_sent_checksum_frame == NULL_FRAME {
+            interval as i32
+        } else {
+            self.last_sent_checksum_frame + interval as i32
+        }
+    }
+
+    /// Remembers the checksums of desynced frames for desync bundles.
+    fn record_desyncs(&mut self, desyncs: Vec<(Frame, u128, u128)>) {
+        if self.desync_bundle_recorder.is_none() {
+            return;
+        }
+        // the desynced frames are confirmed, so their checksums can be taken right away
+        self.flush_desync_bundle_frames();
+        if let Some(recorder) = self.desync_bundle_recorder.as_mut() {
+            for (frame, local_checksum, remote_checksum) in desyncs {
+                recorder.record_desync(frame, local_checksum, remote_checksum);
+            }
+        }
+    }
+
+    /// Forgets the frames before `oldest_frame_to_keep` and sends a `ChecksumUnverified` event for
+    /// each connected peer whose checksum of such a frame never arrived.
+    fn report_unverified_checksums(&mut self, oldest_frame_to_keep: Frame) {
+        let kept = self.awaited_checksums.split_off(&oldest_frame_to_keep);
+        let forgotten = std::mem::replace(&mut self.awaited_checksums, kept);
+        for (frame, addrs) in forgotten {
+            for addr in addrs {
+                if self
+                    .player_reg
+                    .remotes
+                    .get(&addr)
+                    .is_some_and(|remote| remote.is_running())
+                {
+                    warn!("Checksum of frame {frame} was never compared with {addr:?}");
+                    self.event_queue
+                        .push_back(GgrsEvent::ChecksumUnverified { frame, addr });
+                }
+            }
+        }
+    }
+
     fn check_checksum_send_interval(&mut self) {
         match self.desync_detection {
             DesyncDetection::On { interval } => {
-                let frame_to_send = if self.last_sent_checksum_frame == NULL_FRAME {
-                    interval as i32
-                } else {
-                    self.last_sent_checksum_frame + interval as i32
-                };
+                let frame_to_send = self.next_checksum_frame(interval);
 
                 if frame_to_send <= self.sync_layer.last_confirmed_frame() {
                     let Some(cell) =
@@ -1229,18 +3178,62 @@ impl<T: Config> P2PSession<T> {
 
                     if let Some(checksum) = cell.checksum() {
                         let checksum_frame = cell.frame();
-                        for remote in self.player_reg.remotes.values_mut() {
-                            remote.send_checksum_report(checksum_frame, checksum);
+                        let input_hash = self.sync_layer.input_hash(checksum_frame);
+                        let sub_checksums = cell.sub_checksums();
+                        // spectators verify that they show the same game as the players
+                        for remote in self
+                            .player_reg
+                            .remotes
+                            .values_mut()
+                            .chain(self.player_reg.spectators.values_mut())
+                        {
+                            remote.send_checksum_report(
+                                checksum_frame,
+                                checksum,
+                                input_hash,
+                                sub_checksums.clone(),
+                            );
+                        }
+                        if !sub_checksums.is_empty() {
+                            self.local_sub_checksums
+                                .insert(checksum_frame, sub_checksums);
                         }
                         self.last_sent_checksum_frame = checksum_frame;
                         // collect locally for later comparison
                         self.local_checksum_history.insert(checksum_frame, checksum);
+                        let awaited = self
+                            .player_reg
+                            .remotes
+                            .values()
+                            .filter(|remote| remote.is_running())
+                            .map(|remote| remote.peer_addr())
+                            .collect();
+                        self.awaited_checksums.insert(checksum_frame, awaited);
+                        if let Some(input_hash) = input_hash {
+                            self.local_input_hashes.insert(checksum_frame, input_hash);
+                        }
+                        if self.desync_state_exchange {
+                            if let (Some(codec), Some(data)) = (self.state_codec, cell.data()) {
+                                self.local_desync_states
+                                    .insert(checksum_frame, (codec.encode)(&data));
+                            }
+                        }
 
                         if self.local_checksum_history.len() > MAX_CHECKSUM_HISTORY_SIZE {
                             let oldest_frame_to_keep = checksum_frame
                                 - (MAX_CHECKSUM_HISTORY_SIZE as i32 - 1) * interval as i32;
                             self.local_checksum_history
                                 .retain(|&frame, _| frame >= oldest_frame_to_keep);
+                            self.local_input_hashes
+                                .retain(|&frame, _| frame >= oldest_frame_to_keep);
+                            self.local_sub_checksums
+                                .retain(|&frame, _| frame >= oldest_frame_to_keep);
+                            self.local_desync_states
+                                .retain(|&frame, _| frame >= oldest_frame_to_keep);
+                            self.report_unverified_checksums(oldest_frame_to_keep);
+                            if let Some(recorder) = self.desync_bundle_recorder.as_mut() {
+                                recorder.forget_before(oldest_frame_to_keep);
+                            }
                         }
                     }
                 }
@@ -1,14 +1,21 @@
-use std::collections::{vec_deque::Drain, VecDeque};
+use std::collections::{vec_deque::Drain, BTreeMap, VecDeque};
+use std::sync::Arc;
+
+use instant::Instant;
+use tracing::{debug, warn};
 
 use crate::{
     frame_info::PlayerInput,
     network::{
-        messages::ConnectionStatus,
-        protocol::{Event, UdpProtocol},
+        messages::{ConnectionStatus, SpectatorSnapshot, StateTransfer},
+        protocol::{Event, UdpProtocol, MAX_CHECKSUM_HISTORY_SIZE},
     },
+    replay::{ReplayFrame, ReplayRecorder},
     sessions::builder::{MAX_EVENT_QUEUE_SIZE, SPECTATOR_BUFFER_SIZE},
-    Config, Frame, GgrsError, GgrsEvent, GgrsRequest, InputStatus, NetworkStats, NonBlockingSocket,
-    SessionState, NULL_FRAME,
+    sessions::p2p_session::JOIN_REQUEST_INTERVAL,
+    sync_layer::{hash_frame_inputs, mismatched_sub_checksums, StateCodec, FNV_OFFSET_BASIS},
+    Clock, Config, DesyncDetection, Frame, GameStateCell, GgrsError, GgrsEvent, GgrsRequest,
+    InputStatus, NetworkStats, NonBlockingSocket, SessionState, NULL_FRAME,
 };
 
 // The amount of frames the spectator advances in a normal step.
@@ -33,18 +40,44 @@ where
     last_recv_frame: Frame,
     max_frames_behind: usize,
     catchup_speed: usize,
+    replay_recorder: Option<ReplayRecorder<T::Input>>,
+    desync_detection: DesyncDetection,
+    /// hash chain of all inputs the spectator has advanced with; unknown if the spectator started
+    /// from a snapshot
+    input_hash: Option<u64>,
+    /// checksums the user reported for frames to compare with the host, and the input hash of
+    /// these frames
+    local_checksums: BTreeMap<Frame, (GameStateCell<T::State>, Option<u64>)>,
+    /// Deserializes the snapshot of a running match, if the user enabled state transfers.
+    state_codec: Option<StateCodec<T::State>>,
+    /// True while the spectator joins a running match, or fell too far behind, and has not received
+    /// a snapshot yet.
+    awaiting_snapshot: bool,
+    /// The first frame we could not simulate because its inputs were gone, if we are waiting for a
+    /// snapshot to skip ahead.
+    skipped_from: Option<Frame>,
+    /// When we last asked the host for a snapshot.
+    last_snapshot_request: Option<Instant>,
+    /// The request to load a received snapshot. It is handed to the user with the next call to `advance_frame()`.
+    pending_load: Option<GgrsRequest<T>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl<T: Config> SpectatorSession<T> {
     /// Creates a new [`SpectatorSession`] for a spectator.
     /// The session will receive inputs from all players from the given host directly.
     /// The session will use the provided socket.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         num_players: usize,
         socket: Box<dyn NonBlockingSocket<T::Address>>,
         host: UdpProtocol<T>,
         max_frames_behind: usize,
         catchup_speed: usize,
+        replay_recorder: Option<ReplayRecorder<T::Input>>,
+        desync_detection: DesyncDetection,
+        state_codec: Option<StateCodec<T::State>>,
+        clock: Arc<dyn Clock>,
     ) -> Self {
         // host connection status
         let mut host_connect_status = Vec::new();
@@ -67,6 +100,16 @@ impl<T: Config> SpectatorSession<T> {
             last_recv_frame: NULL_FRAME,
             max_frames_behind,
             catchup_speed,
+            replay_recorder,
+            desync_detection,
+            input_hash: Some(FNV_OFFSET_BASIS),
+            local_checksums: BTreeMap::new(),
+            state_codec,
+            awaiting_snapshot: false,
+            skipped_from: None,
+            last_snapshot_request: None,
+            pending_load: None,
+            clock,
         }
     }
 
@@ -79,13 +122,12 @@ impl<T: Config> SpectatorSession<T> {
     ///
     /// Both `last_recv_frame` and `current_frame` are initialised to `NULL_FRAME` (-1),
     /// so this returns `0` before any input has arrived from the host, which is correct:
-    /// neither side has advanced yet.  Once the session is running, the invariant
-    /// `last_recv_frame >= current_frame` is maintained by the session, so the result
-    /// is always non-negative.
+    /// neither side has advanced yet.  Once the session is running, the spectator never
+    /// advances past `last_recv_frame`. Only after loading a snapshot of the host, the inputs
+    /// of the snapshot frame may still be on their way, in which case this returns `0` as well.
     pub fn frames_behind_host(&self) -> usize {
         let diff = self.last_recv_frame - self.current_frame;
-        assert!(diff >= 0);
-        diff as usize
+        diff.max(0) as usize
     }
 
     /// Used to fetch some statistics about the quality of the network connection.
@@ -109,12 +151,24 @@ impl<T: Config> SpectatorSession<T> {
     /// You should call this to notify GGRS that you are ready to advance your gamestate by a single frame.
     /// Returns an order-sensitive [`Vec<GgrsRequest>`]. You should fulfill all requests in the exact order they are provided.
     /// Failure to do so will cause panics later.
+    ///
+    /// With desync detection enabled, the requests include a [`GgrsRequest::ReportChecksum`] for
+    /// frames whose checksums are compared with the checksums of the host. A spectator that joined a
+    /// running match starts with a [`GgrsRequest::LoadGameState`] of the snapshot the host sent.
     /// # Errors
     /// - Returns [`NotSynchronized`] if the session is not yet ready to accept input.
     ///   In this case, you either need to start the session or wait for synchronization between clients.
+    /// - Returns [`PredictionThreshold`] if the inputs of the next frame, or the snapshot of a
+    ///   running match, have not arrived yet.
+    /// - Returns [`SpectatorTooFarBehind`] if the inputs of the next frame are gone, because the
+    ///   spectator fell more than the spectator buffer behind the host. With state transfer
+    ///   enabled, the spectator asks for a snapshot instead and skips ahead, see
+    ///   [`GgrsEvent::FramesSkipped`].
     ///
     /// [`Vec<GgrsRequest>`]: GgrsRequest
     /// [`NotSynchronized`]: GgrsError::NotSynchronized
+    /// [`PredictionThreshold`]: GgrsError::PredictionThreshold
+    /// [`SpectatorTooFarBehind`]: GgrsError::SpectatorTooFarBehind
     pub fn advance_frame(&mut self) -> Result<Vec<GgrsRequest<T>>, GgrsError> {
         // receive info from host, trigger events and send messages
         self.poll_remote_clients();
@@ -123,7 +177,16 @@ impl<T: Config> SpectatorSession<T> {
             return Err(GgrsError::NotSynchronized);
         }
 
-        let mut requests = Vec::new();
+        // we cannot follow a running match before we have its state
+        if self.awaiting_snapshot {
+            return Err(GgrsError::PredictionThreshold);
+        }
+
+        if self.desync_detection != DesyncDetection::Off {
+            self.compare_checksums_against_host();
+        }
+
+        let mut requests: Vec<_> = self.pending_load.take().into_iter().collect();
 
         let frames_behind = self.frames_behind_host();
         let frames_to_advance = if frames_behind > self.max_frames_behind {
@@ -137,7 +200,56 @@ impl<T: Config> SpectatorSession<T> {
         for _ in 0..frames_to_advance {
             // get inputs for the next frame
             let frame_to_grab = self.current_frame + 1;
-            let synced_inputs = self.inputs_at_frame(frame_to_grab)?;
+            let synced_inputs = match self.inputs_at_frame(frame_to_grab) {
+                // the inputs are gone, so we continue from a snapshot of the host
+                Err(GgrsError::SpectatorTooFarBehind) if self.can_request_snapshots() => {
+                    debug!(
+                        "Inputs of frame {frame_to_grab} are gone, asking the host for a snapshot"
+                    );
+                    self.skipped_from = Some(frame_to_grab);
+                    self.awaiting_snapshot = true;
+                    self.last_snapshot_request = None;
+                    self.send_snapshot_request();
+                    self.host.send_all_messages(&mut self.socket);
+                    return Err(GgrsError::PredictionThreshold);
+                }
+                result => result?,
+            };
+
+            // ask for the checksum of the state before these inputs, which is the state of
+            // `frame_to_grab` from the point of view of the players
+            if self.is_checksum_frame(frame_to_grab) {
+                let cell = GameStateCell::default();
+                self.local_checksums
+                    .insert(frame_to_grab, (cell.clone(), self.input_hash));
+                if self.local_checksums.len() > MAX_CHECKSUM_HISTORY_SIZE {
+                    self.forget_oldest_checksum();
+                }
+                requests.push(GgrsRequest::ReportChecksum {
+                    cell,
+                    frame: frame_to_grab,
+                });
+            }
+            self.input_hash = self.input_hash.map(|input_hash| {
+                hash_frame_inputs(
+                    input_hash,
+                    synced_inputs
+                        .iter()
+                        .map(|(input, status)| (input, *status == InputStatus::Disconnected)),
+                )
+            });
+
+            if let Some(recorder) = self.replay_recorder.as_mut() {
+                recorder.record(ReplayFrame {
+                    frame: frame_to_grab,
+                    inputs: synced_inputs.iter().map(|(input, _)| *input).collect(),
+                    disconnected: synced_inputs
+                        .iter()
+                        .map(|(_, status)| *status == InputStatus::Disconnected)
+                        .collect(),
+                    checksum: None,
+                });
+            }
 
             requests.push(GgrsRequest::AdvanceFrame {
                 inputs: synced_inputs,
@@ -157,7 +269,7 @@ impl<T: Config> SpectatorSession<T> {
         // The endpoints will handle their packets, which will trigger both events and UPD replies.
         for (from, msg) in &self.socket.receive_all_messages() {
             if self.host.is_handling_message(from) {
-                self.host.handle_message(msg);
+                self.host.receive_message(msg);
             }
         }
 
@@ -173,6 +285,11 @@ impl<T: Config> SpectatorSession<T> {
             self.handle_event(event, addr);
         }
 
+        // keep asking for the state of the running match until we receive it
+        if self.awaiting_snapshot {
+            self.send_snapshot_request();
+        }
+
         // send out all pending UDP messages
         self.host.send_all_messages(&mut self.socket);
     }
@@ -207,9 +324,7 @@ impl<T: Config> SpectatorSession<T> {
             .iter()
             .enumerate()
             .map(|(handle, player_input)| {
-                if self.host_connect_status[handle].disconnected
-                    && self.host_connect_status[handle].last_frame < frame_to_grab
-                {
+                if self.host_connect_status[handle].disconnected_at(frame_to_grab) {
                     (player_input.input, InputStatus::Disconnected)
                 } else {
                     (player_input.input, InputStatus::Confirmed)
@@ -218,6 +333,134 @@ impl<T: Config> SpectatorSession<T> {
             .collect())
     }
 
+    /// Returns true if the checksum of the given frame should be compared with the host. These are
+    /// the frames the host sends its checksums for, as long as its reports arrive before the
+    /// spectator passes the frame.
+    fn is_checksum_frame(&self, frame: Frame) -> bool {
+        match self.desync_detection {
+            DesyncDetection::On { interval } => {
+                (interval > 0 && frame > 0 && frame % interval as i32 == 0)
+                    || self.host.pending_checksums.contains_key(&frame)
+            }
+            DesyncDetection::Off => false,
+        }
+    }
+
+    /// Forgets the oldest checksum reported by the user. If the checksum of the host for that frame
+    /// never arrived, a `ChecksumUnverified` event is sent.
+    fn forget_oldest_checksum(&mut self) {
+        if let Some((frame, (cell, _))) = self.local_checksums.pop_first() {
+            if cell.checksum().is_some() {
+                self.event_queue.push_back(GgrsEvent::ChecksumUnverified {
+                    frame,
+                    addr: self.host.peer_addr(),
+                });
+            }
+        }
+    }
+
+    /// Compares the checksums the host sent us with the checksums reported for the same frames.
+    fn compare_checksums_against_host(&mut self) {
+        let mut checked_frames = Vec::new();
+        for (&frame, report) in &self.host.pending_checksums {
+            let Some((cell, input_hash)) = self.local_checksums.get(&frame) else {
+                continue;
+            };
+            let Some(local_checksum) = cell.checksum() else {
+                continue;
+            };
+            let mismatched_sub_checksums =
+                mismatched_sub_checksums(&cell.sub_checksums(), &report.sub_checksums);
+            if local_checksum != report.checksum || !mismatched_sub_checksums.is_empty() {
+                self.event_queue.push_back(GgrsEvent::DesyncDetected {
+                    frame,
+                    local_checksum,
+                    remote_checksum: report.checksum,
+                    addr: self.host.peer_addr(),
+                    inputs_match: report
+                        .input_hash
+                        .zip(*input_hash)
+                        .map(|(remote, local)| remote == local),
+                    mismatched_sub_checksums,
+                });
+            }
+            checked_frames.push(frame);
+        }
+
+        for frame in checked_frames {
+            self.host.pending_checksums.remove(&frame);
+            self.local_checksums.remove(&frame);
+        }
+        // checksums of frames we already passed without reporting our own can't be compared
+        let next_frame = self.current_frame + 1;
+        let local_checksums = &self.local_checksums;
+        self.host
+            .pending_checksums
+            .retain(|frame, _| *frame >= next_frame || local_checksums.contains_key(frame));
+    }
+
+    /// Returns true if both we and the host have state transfer enabled, so the host answers our
+    /// snapshot requests.
+    fn can_request_snapshots(&self) -> bool {
+        self.state_codec.is_some() && self.host.remote_has_state_transfer()
+    }
+
+    /// Asks the host for a snapshot of the running match, at most every [`JOIN_REQUEST_INTERVAL`].
+    fn send_snapshot_request(&mut self) {
+        let now = self.clock.now();
+        if self
+            .last_snapshot_request
+            .is_some_and(|last_request| last_request + JOIN_REQUEST_INTERVAL > now)
+        {
+            return;
+        }
+        self.last_snapshot_request = Some(now);
+        if self.host.is_running() {
+            self.host.send_snapshot_request(self.current_frame + 1);
+        }
+    }
+
+    /// Upon receiving the snapshot we asked for, continue from its frame on.
+    fn on_snapshot(&mut self, snapshot: SpectatorSnapshot) {
+        if !self.awaiting_snapshot {
+            debug!(
+                "Ignoring snapshot of frame {}; not waiting for one",
+                snapshot.frame
+            );
+            return;
+        }
+        let decoded = self
+            .state_codec
+            .and_then(|codec| (codec.decode)(&snapshot.state));
+        let Some(state) = decoded.filter(|_| snapshot.frame >= 0) else {
+            warn!(
+                "Failed to decode snapshot of frame {}, discarding",
+                snapshot.frame
+            );
+            return;
+        };
+
+        let cell = GameStateCell::default();
+        cell.save(snapshot.frame, Some(state), None);
+        self.pending_load = Some(GgrsRequest::LoadGameState {
+            cell,
+            frame: snapshot.frame,
+        });
+        if let Some(from) = self.skipped_from.take() {
+            self.event_queue.push_back(GgrsEvent::FramesSkipped {
+                from,
+                to: snapshot.frame,
+            });
+        }
+        self.current_frame = snapshot.frame - 1;
+        self.awaiting_snapshot = false;
+        // we don't know the inputs before the snapshot, so we cannot tell whether they match
+        self.input_hash = None;
+        if let Some(recorder) = self.replay_recorder.as_mut() {
+            recorder.skip_to(snapshot.frame);
+        }
+    }
+
     fn handle_event(&mut self, event: Event<T>, addr: T::Address) {
         match event {
             // forward to user
@@ -242,12 +485,34 @@ impl<T: Config> SpectatorSession<T> {
                 self.state = SessionState::Running;
                 self.event_queue.push_back(GgrsEvent::Synchronized { addr });
             }
+            // forward to user, then treat the rejected host as disconnected
+            Event::ConfigMismatch { local, remote } => {
+                self.event_queue.push_back(GgrsEvent::ConfigMismatch {
+                    addr: addr.clone(),
+                    local,
+                    remote,
+                });
+                self.event_queue.push_back(GgrsEvent::Disconnected { addr });
+            }
             // disconnect the player, then forward to user
             Event::Disconnected => {
                 self.event_queue.push_back(GgrsEvent::Disconnected { addr });
             }
             // add the input and all associated information
             Event::Input { input, player } => {
+                // the first input of a running match we joined; we need its state to follow it
+                if self.last_recv_frame == NULL_FRAME
+                    && self.current_frame == NULL_FRAME
+                    && input.frame > 0
+                    && self.can_request_snapshots()
+                {
+                    debug!(
+                        "Joined the match at frame {}, asking the host for a snapshot",
+                        input.frame
+                    );
+                    self.awaiting_snapshot = true;
+                }
+
                 // save the input
                 self.inputs[input.frame as usize % SPECTATOR_BUFFER_SIZE][player] = input;
                 assert!(input.frame >= self.last_recv_frame);
@@ -261,6 +526,18 @@ impl<T: Config> SpectatorSession<T> {
                     self.host_connect_status[i] = self.host.peer_connect_status(i);
                 }
             }
+            // forward to user
+            Event::UserMessage { payload } => {
+                self.event_queue
+                    .push_back(GgrsEvent::UserMessage { addr, payload });
+            }
+            Event::StateTransferReceived {
+                transfer: StateTransfer::SpectatorSnapshot(snapshot),
+            } => self.on_snapshot(snapshot),
+            // spectators neither admit players, send snapshots nor receive other state transfers
+            Event::JoinRequested
+            | Event::SnapshotRequested { .. }
+            | Event::StateTransferReceived { .. } => (),
         }
 
         // check event queue size and discard oldest events if too big
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    replay::{ReplayFrame, ReplayHeader},
+    Config, Frame, GameStateCell, GgrsError, GgrsRequest,
+};
+
+/// Plays back a replay recorded with [`SessionBuilder::with_replay_recorder()`].
+///
+/// Like a [`SpectatorSession`], a [`ReplaySession`] hands you [`GgrsRequest::AdvanceFrame`]
+/// requests with the recorded inputs, marked as [`InputStatus::Confirmed`] or
+/// [`InputStatus::Disconnected`]. On top of that, playback can be paused, stepped, sped up or slowed
+/// down, and you can seek to any recorded frame.
+///
+/// # Keyframes
+///
+/// To jump backwards, the session requests to save the game state every `keyframe_interval` frames
+/// (see [`SessionBuilder::with_keyframe_interval()`]). Seeking to an earlier frame loads the closest
+/// keyframe before it and fast-forwards from there. Keyframes are kept for the whole lifetime of the
+/// session.
+///
+/// # Recorded frames
+///
+/// Playback starts at frame 0 with the initial state of your game, so the replay has to be recorded
+/// from the start of a match. Playback ends at the last recorded frame or at the first frame that
+/// is missing from the replay, e.g. because the recording session rejoined the match.
+///
+/// [`SessionBuilder::with_replay_recorder()`]: crate::SessionBuilder::with_replay_recorder
+/// [`SessionBuilder::with_keyframe_interval()`]: crate::SessionBuilder::with_keyframe_interval
+/// [`SpectatorSession`]: crate::SpectatorSession
+/// [`InputStatus::Confirmed`]: crate::InputStatus::Confirmed
+/// [`InputStatus::Disconnected`]: crate::InputStatus::Disconnected
+pub struct ReplaySession<T>
+where
+    T: Config,
+{
+    header: ReplayHeader,
+    frames: Vec<ReplayFrame<T::Input>>,
+    /// The frame whose inputs are played next. Equals the frame of the game state the user holds.
+    current_frame: Frame,
+    keyframe_interval: usize,
+    /// Saved game states, by frame.
+    keyframes: BTreeMap<Frame, GameStateCell<T::State>>,
+    paused: bool,
+    /// How many frames are played per call to `advance_frame()`.
+    speed: f32,
+    /// Fractional frames that have not been played yet, so speeds below 1 play a frame every few calls.
+    speed_progress: f32,
+}
+
+impl<T: Config> ReplaySession<T> {
+    pub(crate) fn new(
+        header: ReplayHeader,
+        frames: Vec<ReplayFrame<T::Input>>,
+        keyframe_interval: usize,
+    ) -> Self {
+        Self {
+            header,
+            frames,
+            current_frame: 0,
+            keyframe_interval,
+            keyframes: BTreeMap::new(),
+            paused: false,
+            speed: 1.0,
+            speed_progress: 0.0,
+        }
+    }
+
+    /// Plays the replay according to the current speed. Returns an order-sensitive
+    /// [`Vec<GgrsRequest>`]. You should fulfill all requests in the exact order they are provided.
+    ///
+    /// While the session is paused or after the last recorded frame has been played, the returned
+    /// list is empty.
+    ///
+    /// [`Vec<GgrsRequest>`]: GgrsRequest
+    pub fn advance_frame(&mut self) -> Vec<GgrsRequest<T>> {
+        let mut requests = Vec::new();
+        if self.paused {
+            return requests;
+        }
+
+        self.speed_progress += self.speed;
+        while self.speed_progress >= 1.0 && !self.is_finished() {
+            self.play_frame(&mut requests);
+            self.speed_progress -= 1.0;
+        }
+        if self.is_finished() {
+            self.speed_progress = 0.0;
+        }
+        requests
+    }
+
+    /// Plays exactly one frame, no matter whether the session is paused. Returns an order-sensitive
+    /// [`Vec<GgrsRequest>`], which is empty after the last recorded frame has been played.
+    ///
+    /// [`Vec<GgrsRequest>`]: GgrsRequest
+    pub fn step(&mut self) -> Vec<GgrsRequest<T>> {
+        let mut requests = Vec::new();
+        if !self.is_finished() {
+            self.play_frame(&mut requests);
+        }
+        requests
+    }
+
+    /// Jumps to the given frame, so the next played frame will be `frame`. Jumping backwards loads the
+    /// closest keyframe and fast-forwards from there; jumping forwards plays all frames in between.
+    /// Returns an order-sensitive [`Vec<GgrsRequest>`]. You should fulfill all requests in the exact
+    /// order they are provided.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the frame is negative or beyond [`end_frame()`].
+    ///
+    /// [`Vec<GgrsRequest>`]: GgrsRequest
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    /// [`end_frame()`]: Self::end_frame
+    pub fn seek(&mut self, frame: Frame) -> Result<Vec<GgrsRequest<T>>, GgrsError> {
+        if frame < 0 || frame > self.end_frame() {
+            return Err(GgrsError::InvalidRequest {
+                info: format!(
+                    "Cannot seek to frame {frame}, the replay covers frames 0 to {}.",
+                    self.end_frame()
+                ),
+            });
+        }
+
+        let mut requests = Vec::new();
+        if frame < self.current_frame {
+            let (&keyframe, cell) = self
+                .keyframes
+                .range(..=frame)
+                .next_back()
+                .expect("the first frame is always saved before it is played");
+            requests.push(GgrsRequest::LoadGameState {
+                cell: cell.clone(),
+                frame: keyframe,
+            });
+            self.current_frame = keyframe;
+        }
+        while self.current_frame < frame {
+            self.play_frame(&mut requests);
+        }
+        self.speed_progress = 0.0;
+        Ok(requests)
+    }
+
+    /// Pauses playback. [`advance_frame()`] returns no requests until playback is resumed, while
+    /// [`step()`] and [`seek()`] still work.
+    ///
+    /// [`advance_frame()`]: Self::advance_frame
+    /// [`step()`]: Self::step
+    /// [`seek()`]: Self::seek
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resumes playback after a [`pause()`](Self::pause).
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Returns true if playback is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Sets how many frames are played per call to [`advance_frame()`]. A speed of 2 plays two frames
+    /// per call, a speed of 0.5 plays a frame every second call. The default is 1.
+    ///
+    /// # Errors
+    /// - Returns [`InvalidRequest`] if the speed is not a positive number.
+    ///
+    /// [`advance_frame()`]: Self::advance_frame
+    /// [`InvalidRequest`]: GgrsError::InvalidRequest
+    pub fn set_speed(&mut self, speed: f32) -> Result<(), GgrsError> {
+        if !speed.is_finite() || speed <= 0.0 {
+            return Err(GgrsError::InvalidRequest {
+                info: "Replay speed must be a positive number.".to_owned(),
+            });
+        }
+        self.speed = speed;
+        Ok(())
+    }
+
+    /// Returns the playback speed.
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Returns the current frame of a session, which is the next frame to be played.
+    pub fn current_frame(&self) -> Frame {
+        self.current_frame
+    }
+
+    /// Returns the frame at which playback ends, i.e. the number of playable frames.
+    pub fn end_frame(&self) -> Frame {
+        self.frames.len() as Frame
+    }
+
+    /// Returns true if all recorded frames have been played.
+    pub fn is_finished(&self) -> bool {
+        self.current_frame >= self.end_frame()
+    }
+
+    /// Returns the number of players of the recorded session.
+    pub fn num_players(&self) -> usize {
+        self.header.num_players
+    }
+
+    /// Returns the header of the played replay.
+    pub fn header(&self) -> &ReplayHeader {
+        &self.header
+    }
+
+    /// Returns the checksum that was recorded for the game state at the beginning of the given frame,
+    /// if there is one. You can compare it with your own checksum to verify the playback.
+    pub fn recorded_checksum(&self, frame: Frame) -> Option<u128> {
+        let index = usize::try_from(frame).ok()?;
+        self.frames.get(index)?.checksum
+    }
+
+    /// Plays the current frame, saving a keyframe before if necessary.
+    fn play_frame(&mut self, requests: &mut Vec<GgrsRequest<T>>) {
+        let frame = self.current_frame;
+        if (frame as usize).is_multiple_of(self.keyframe_interval)
+            && !self.keyframes.contains_key(&frame)
+        {
+            let cell = GameStateCell::default();
+            self.keyframes.insert(frame, cell.clone());
+            requests.push(GgrsRequest::SaveGameState { cell, frame });
+        }
+
+        let inputs = self.frames[frame as usize].input_statuses();
+        requests.push(GgrsRequest::AdvanceFrame { inputs });
+        self.current_frame += 1;
+    }
+}
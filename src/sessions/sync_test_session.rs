@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 
 use crate::error::GgrsError;
-use crate::frame_info::PlayerInput;
+use crate::frame_info::{PlayerInput, SubChecksums};
 use crate::network::messages::ConnectionStatus;
-use crate::sync_layer::SyncLayer;
-use crate::{Config, Frame, GgrsRequest, PlayerHandle};
+use crate::replay::{ReplayFrame, ReplayRecorder};
+use crate::sync_layer::{mismatched_sub_checksums, SyncLayer};
+use crate::{Config, Frame, GgrsRequest, InputStatus, PlayerHandle};
 
 /// A session for verifying that your game logic is deterministic, without any network involvement.
 ///
@@ -48,8 +49,9 @@ where
     check_distance: usize,
     sync_layer: SyncLayer<T>,
     dummy_connect_status: Vec<ConnectionStatus>,
-    checksum_history: HashMap<Frame, Option<u128>>,
+    checksum_history: HashMap<Frame, (Option<u128>, SubChecksums)>,
     local_inputs: HashMap<PlayerHandle, PlayerInput<T::Input>>,
+    replay_recorder: Option<ReplayRecorder<T::Input>>,
 }
 
 impl<T: Config> SyncTestSession<T> {
@@ -58,6 +60,7 @@ impl<T: Config> SyncTestSession<T> {
         max_prediction: usize,
         check_distance: usize,
         input_delay: usize,
+        replay_recorder: Option<ReplayRecorder<T::Input>>,
     ) -> Self {
         let mut dummy_connect_status = Vec::new();
         for _ in 0..num_players {
@@ -77,6 +80,7 @@ impl<T: Config> SyncTestSession<T> {
             dummy_connect_status,
             checksum_history: HashMap::new(),
             local_inputs: HashMap::new(),
+            replay_recorder,
         }
     }
 
@@ -116,19 +120,37 @@ impl<T: Config> SyncTestSession<T> {
     pub fn advance_frame(&mut self) -> Result<Vec<GgrsRequest<T>>, GgrsError> {
         let mut requests = Vec::new();
 
-        // if we advanced far enough into the game do comparisons and rollbacks
+        // the states of all previous frames have been saved by now, so their checksums are known
         let current_frame = self.sync_layer.current_frame();
+        if let Some(recorder) = self.replay_recorder.as_mut() {
+            let sync_layer = &self.sync_layer;
+            recorder.flush_pending(current_frame - 1, |frame| {
+                sync_layer
+                    .saved_state_by_frame(frame)
+                    .and_then(|cell| cell.checksum())
+            });
+        }
+
+        // if we advanced far enough into the game do comparisons and rollbacks
         if self.check_distance > 0 && current_frame > self.check_distance as i32 {
             // compare checksums of older frames to our checksum history (where only the first version of any checksum is recorded)
             let oldest_frame_to_check = current_frame - self.check_distance as Frame;
-            let mismatched_frames: Vec<_> = (oldest_frame_to_check..=current_frame)
-                .filter(|frame_to_check| !self.checksums_consistent(*frame_to_check))
-                .collect();
+            let mut mismatched_frames = Vec::new();
+            let mut mismatched_sub_checksums = Vec::new();
+            for frame_to_check in oldest_frame_to_check..=current_frame {
+                if let Err(names) = self.checksums_consistent(frame_to_check) {
+                    mismatched_frames.push(frame_to_check);
+                    mismatched_sub_checksums.extend(names);
+                }
+            }
 
             if !mismatched_frames.is_empty() {
+                mismatched_sub_checksums.sort_unstable();
+                mismatched_sub_checksums.dedup();
                 return Err(GgrsError::MismatchedChecksum {
                     current_frame,
                     mismatched_frames,
+                    mismatched_sub_checksums,
                 });
             }
 
@@ -162,6 +184,19 @@ impl<T: Config> SyncTestSession<T> {
             .sync_layer
             .synchronized_inputs(&self.dummy_connect_status);
 
+        // the inputs of the original run are the ones to record
+        if let Some(recorder) = self.replay_recorder.as_mut() {
+            recorder.record_pending(ReplayFrame {
+                frame: self.sync_layer.current_frame(),
+                inputs: inputs.iter().map(|(input, _)| *input).collect(),
+                disconnected: inputs
+                    .iter()
+                    .map(|(_, status)| *status == InputStatus::Disconnected)
+                    .collect(),
+                checksum: None,
+            });
+        }
+
         // advance the frame
         requests.push(GgrsRequest::AdvanceFrame { inputs });
         self.sync_layer.advance_frame();
@@ -200,22 +235,29 @@ impl<T: Config> SyncTestSession<T> {
         self.check_distance
     }
 
-    /// Updates the `checksum_history` and checks if the checksum is identical if it already has been recorded once
-    fn checksums_consistent(&mut self, frame_to_check: Frame) -> bool {
+    /// Updates the `checksum_history` and checks if the checksum is identical if it already has been recorded once.
+    /// On a mismatch, returns the names of the sub-checksums that differ.
+    fn checksums_consistent(&mut self, frame_to_check: Frame) -> Result<(), Vec<String>> {
         // remove entries older than the `check_distance`
         let oldest_allowed_frame = self.sync_layer.current_frame() - self.check_distance as i32;
         self.checksum_history
             .retain(|&k, _| k >= oldest_allowed_frame);
 
         let Some(latest_cell) = self.sync_layer.saved_state_by_frame(frame_to_check) else {
-            return true;
+            return Ok(());
         };
-        if let Some(&cs) = self.checksum_history.get(&latest_cell.frame()) {
-            cs == latest_cell.checksum()
+        let sub_checksums = latest_cell.sub_checksums();
+        if let Some((cs, first_sub_checksums)) = self.checksum_history.get(&latest_cell.frame()) {
+            let mismatched = mismatched_sub_checksums(first_sub_checksums, &sub_checksums);
+            if *cs == latest_cell.checksum() && mismatched.is_empty() {
+                Ok(())
+            } else {
+                Err(mismatched)
+            }
         } else {
             self.checksum_history
-                .insert(latest_cell.frame(), latest_cell.checksum());
-            true
+                .insert(latest_cell.frame(), (latest_cell.checksum(), sub_checksums));
+            Ok(())
         }
     }
 
@@ -1,12 +1,43 @@
 use parking_lot::{MappedMutexGuard, Mutex};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeMap;
 use std::ops::Deref;
 use std::sync::Arc;
 
-use crate::frame_info::{GameState, PlayerInput};
+use crate::frame_info::{GameState, PlayerInput, SubChecksums};
 use crate::input_queue::InputQueue;
 use crate::network::messages::ConnectionStatus;
 use crate::{Config, Frame, GgrsRequest, InputStatus, PlayerHandle, NULL_FRAME};
 
+/// How many frames of input hashes the sync layer keeps.
+const MAX_INPUT_HASH_HISTORY: usize = 128;
+pub(crate) const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Feeds `bytes` into a 64 bit FNV-1a hash. Unlike the hashers of the standard library, the result
+/// is the same on every platform and version.
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Extends a hash chain of confirmed inputs by one frame, hashing the inputs and disconnect status
+/// of all players in that frame.
+pub(crate) fn hash_frame_inputs<'a, I: Serialize + 'a>(
+    mut hash: u64,
+    inputs: impl IntoIterator<Item = (&'a I, bool)>,
+) -> u64 {
+    for (input, disconnected) in inputs {
+        hash = fnv1a(hash, &[u8::from(disconnected)]);
+        let bytes = bincode::serialize(input).expect("input serialization failed");
+        hash = fnv1a(hash, &bytes);
+    }
+    hash
+}
+
 /// An [`Arc<Mutex>`] that you can [`save()`]/[`load()`] a `T` to/from. These will be handed to the user as part of a [`GgrsRequest`].
 ///
 /// [`save()`]: GameStateCell#method.save
@@ -16,11 +47,30 @@ pub struct GameStateCell<T>(Arc<Mutex<GameState<T>>>);
 impl<T> GameStateCell<T> {
     /// Saves a `T` the user creates into the cell.
     pub fn save(&self, frame: Frame, data: Option<T>, checksum: Option<u128>) {
+        self.save_with_sub_checksums(frame, data, checksum, &[]);
+    }
+
+    /// Saves a `T` the user creates into the cell, together with checksums of named parts of the
+    /// state, like `"physics"` or `"rng"`. If the checksums of a frame differ, sync tests and desync
+    /// detection also report the names of the sub-checksums that differ, so you know where to look.
+    /// Keep the set small, since the sub-checksums are sent to other peers with every checksum.
+    pub fn save_with_sub_checksums(
+        &self,
+        frame: Frame,
+        data: Option<T>,
+        checksum: Option<u128>,
+        sub_checksums: &[(&str, u128)],
+    ) {
         let mut state = self.0.lock();
         assert!(frame != NULL_FRAME);
         state.frame = frame;
         state.data = data;
         state.checksum = checksum;
+        state.sub_checksums = sub_checksums
+            .iter()
+            .map(|&(name, checksum)| (name.to_owned(), checksum))
+            .collect();
+        state.sub_checksums.sort_unstable();
     }
 
     /// Provides direct access to the `T` that the user previously saved into the cell (if there was
@@ -72,6 +122,34 @@ impl<T> GameStateCell<T> {
     pub(crate) fn checksum(&self) -> Option<u128> {
         self.0.lock().checksum
     }
+
+    pub(crate) fn sub_checksums(&self) -> SubChecksums {
+        self.0.lock().sub_checksums.clone()
+    }
+}
+
+/// Returns the names of all sub-checksums that differ between two saved states, including the ones
+/// only one of the states has.
+pub(crate) fn mismatched_sub_checksums(
+    local: &[(String, u128)],
+    remote: &[(String, u128)],
+) -> Vec<String> {
+    let local: BTreeMap<&str, u128> = local
+        .iter()
+        .map(|(name, cs)| (name.as_str(), *cs))
+        .collect();
+    let remote: BTreeMap<&str, u128> = remote
+        .iter()
+        .map(|(name, cs)| (name.as_str(), *cs))
+        .collect();
+    let mut names: Vec<&str> = local.keys().chain(remote.keys()).copied().collect();
+    names.sort_unstable();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| local.get(name) != remote.get(name))
+        .map(str::to_owned)
+        .collect()
 }
 
 impl<T: Clone> GameStateCell<T> {
@@ -137,6 +215,38 @@ impl<T> GameStateAccessor<'_, T> {
     }
 }
 
+/// Serializes game states so they can be transferred to other peers, e.g. for late joins.
+/// Only available if the user opted in with [`SessionBuilder::with_state_transfer()`].
+///
+/// [`SessionBuilder::with_state_transfer()`]: crate::SessionBuilder::with_state_transfer
+pub(crate) struct StateCodec<S> {
+    pub encode: fn(&S) -> Vec<u8>,
+    pub decode: fn(&[u8]) -> Option<S>,
+}
+
+impl<S: Serialize + DeserializeOwned> StateCodec<S> {
+    pub(crate) fn new() -> Self {
+        Self {
+            encode: |state| bincode::serialize(state).expect("state serialization failed"),
+            decode: |bytes| bincode::deserialize(bytes).ok(),
+        }
+    }
+}
+
+impl<S> Clone for StateCodec<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S> Copy for StateCodec<S> {}
+
+impl<S> std::fmt::Debug for StateCodec<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateCodec").finish_non_exhaustive()
+    }
+}
+
 pub(crate) struct SavedStates<T> {
     pub states: Vec<GameStateCell<T>>,
 }
@@ -154,6 +264,20 @@ impl<T> SavedStates<T> {
         Self { states }
     }
 
+    /// Changes the number of stored states, keeping the newest saved state of every position in the
+    /// new ring.
+    fn resize(&mut self, max_pred: usize) {
+        let mut old_states = std::mem::replace(&mut self.states, Self::new(max_pred).states);
+        old_states.sort_by_key(GameStateCell::frame);
+        for cell in old_states {
+            let frame = cell.frame();
+            if frame != NULL_FRAME {
+                let pos = frame as usize % self.states.len();
+                self.states[pos] = cell;
+            }
+        }
+    }
+
     fn get_cell(&self, frame: Frame) -> GameStateCell<T> {
         assert!(frame >= 0);
         let pos = frame as usize % self.states.len();
@@ -172,6 +296,9 @@ where
     last_saved_frame: Frame,
     current_frame: Frame,
     input_queues: Vec<InputQueue<T>>,
+    /// Hashes of the confirmed inputs of all frames before the frame they are stored with. Empty if
+    /// we don't know the inputs since the start of the match, e.g. after a late join.
+    input_hashes: BTreeMap<Frame, u64>,
 }
 
 impl<T: Config> SyncLayer<T> {
@@ -190,9 +317,17 @@ impl<T: Config> SyncLayer<T> {
             current_frame: 0,
             saved_states: SavedStates::new(max_prediction),
             input_queues,
+            input_hashes: BTreeMap::from([(0, FNV_OFFSET_BASIS)]),
         }
     }
 
+    /// Changes the prediction window. The caller has to make sure that every frame that may still be
+    /// loaded stays inside the new window.
+    pub(crate) fn set_max_prediction(&mut self, max_prediction: usize) {
+        self.max_prediction = max_prediction;
+        self.saved_states.resize(max_prediction);
+    }
+
     pub(crate) fn current_frame(&self) -> Frame {
         self.current_frame
     }
@@ -210,6 +345,16 @@ impl<T: Config> SyncLayer<T> {
         }
     }
 
+    /// Asks the user for the checksum of the current frame, stored in the cell that would hold its
+    /// saved state. Unlike [`SyncLayer::save_current_state()`], this doesn't count as a save.
+    pub(crate) fn report_current_checksum(&self) -> GgrsRequest<T> {
+        let cell = self.saved_states.get_cell(self.current_frame);
+        GgrsRequest::ReportChecksum {
+            cell,
+            frame: self.current_frame,
+        }
+    }
+
     pub(crate) fn set_frame_delay(
         &mut self,
         player_handle: PlayerHandle,
@@ -219,6 +364,17 @@ impl<T: Config> SyncLayer<T> {
         self.input_queues[player_handle].set_frame_delay(delay)
     }
 
+    /// Discards all inputs of a player and restarts its input queue at `start_frame`. Returns the
+    /// fill inputs created by the frame delay of that player, see [`InputQueue::reset()`].
+    pub(crate) fn reset_input_queue(
+        &mut self,
+        player_handle: PlayerHandle,
+        start_frame: Frame,
+    ) -> Vec<PlayerInput<T::Input>> {
+        assert!(player_handle < self.num_players as PlayerHandle);
+        self.input_queues[player_handle].reset(start_frame)
+    }
+
     pub(crate) fn reset_prediction(&mut self) {
         for i in 0..self.num_players {
             self.input_queues[i].reset_prediction();
@@ -254,6 +410,20 @@ impl<T: Config> SyncLayer<T> {
         }
     }
 
+    /// Moves the sync layer to `frame` and stores the given state for that frame, as received from
+    /// another peer. Returns the request for the user to load that state.
+    pub(crate) fn load_snapshot(&mut self, frame: Frame, state: T::State) -> GgrsRequest<T> {
+        assert!(frame >= 0, "cannot load a snapshot for a negative frame");
+        let cell = self.saved_states.get_cell(frame);
+        cell.save(frame, Some(state), None);
+        self.current_frame = frame;
+        self.last_saved_frame = frame;
+        self.last_confirmed_frame = frame - 1;
+        self.reset_prediction();
+
+        GgrsRequest::LoadGameState { cell, frame }
+    }
+
     /// Adds local input to the corresponding input queue. Checks if the prediction threshold has been reached. Returns the frame number where the input is actually added to.
     /// This number will only be different if the input delay was set to a number higher than 0.
     pub(crate) fn add_local_input(
@@ -283,7 +453,7 @@ impl<T: Config> SyncLayer<T> {
     ) -> Vec<(T::Input, InputStatus)> {
         let mut inputs = Vec::new();
         for (i, con_stat) in connect_status.iter().enumerate() {
-            if con_stat.disconnected && con_stat.last_frame < self.current_frame {
+            if con_stat.disconnected_at(self.current_frame) {
                 inputs.push((T::Input::default(), InputStatus::Disconnected));
             } else {
                 inputs.push(self.input_queues[i].input(self.current_frame));
@@ -300,7 +470,7 @@ impl<T: Config> SyncLayer<T> {
     ) -> Vec<PlayerInput<T::Input>> {
         let mut inputs = Vec::new();
         for (i, con_stat) in connect_status.iter().enumerate() {
-            if con_stat.disconnected && con_stat.last_frame < frame {
+            if con_stat.disconnected_at(frame) {
                 inputs.push(PlayerInput::blank_input(NULL_FRAME));
             } else {
                 inputs.push(self.input_queues[i].confirmed_input(frame));
@@ -309,6 +479,53 @@ impl<T: Config> SyncLayer<T> {
         inputs
     }
 
+    /// Returns the confirmed input of a single player for the given frame.
+    pub(crate) fn confirmed_input(
+        &self,
+        player_handle: PlayerHandle,
+        frame: Frame,
+    ) -> PlayerInput<T::Input> {
+        self.input_queues[player_handle].confirmed_input(frame)
+    }
+
+    /// Extends the hash chain of confirmed inputs up until the given frame. Each link hashes the
+    /// previous one together with the inputs and disconnect status of all players in that frame.
+    pub(crate) fn hash_confirmed_inputs(
+        &mut self,
+        confirmed_frame: Frame,
+        connect_status: &[ConnectionStatus],
+    ) {
+        let Some((&next_frame, &last_hash)) = self.input_hashes.last_key_value() else {
+            return;
+        };
+
+        let mut hash = last_hash;
+        for frame in next_frame..=confirmed_frame {
+            let inputs = self.confirmed_inputs(frame, connect_status);
+            hash = hash_frame_inputs(
+                hash,
+                inputs
+                    .iter()
+                    .map(|input| (&input.input, input.frame == NULL_FRAME)),
+            );
+            self.input_hashes.insert(frame + 1, hash);
+            if self.input_hashes.len() > MAX_INPUT_HASH_HISTORY {
+                self.input_hashes.pop_first();
+            }
+        }
+    }
+
+    /// Returns the hash of all confirmed inputs before the given frame, which led to the state of
+    /// that frame.
+    pub(crate) fn input_hash(&self, frame: Frame) -> Option<u64> {
+        self.input_hashes.get(&frame).copied()
+    }
+
+    /// Stops hashing inputs, because the inputs before `frame` are not known to us.
+    pub(crate) fn discard_input_hashes(&mut self) {
+        self.input_hashes.clear();
+    }
+
     /// Sets the last confirmed frame to a given frame. By raising the last confirmed frame, we can discard all previous frames, as they are no longer necessary.
     pub(crate) fn set_last_confirmed_frame(&mut self, mut frame: Frame, sparse_saving: bool) {
         // don't set the last confirmed frame after the first incorrect frame before a rollback has happened
@@ -424,6 +641,29 @@ mod sync_layer_tests {
 
     // GameStateCell tests
 
+    #[test]
+    fn test_mismatched_sub_checksums() {
+        let cell = GameStateCell::<u8>::default();
+        cell.save_with_sub_checksums(0, None, Some(1), &[("rng", 2), ("physics", 3), ("ai", 4)]);
+        let local = cell.sub_checksums();
+        assert_eq!(local[0].0, "ai");
+
+        let remote = vec![
+            ("ai".to_owned(), 4),
+            ("physics".to_owned(), 5),
+            ("sound".to_owned(), 6),
+        ];
+        assert_eq!(
+            mismatched_sub_checksums(&local, &remote),
+            vec!["physics", "rng", "sound"]
+        );
+        assert!(mismatched_sub_checksums(&local, &local).is_empty());
+
+        // saving without sub-checksums clears the old ones
+        cell.save(1, None, Some(1));
+        assert!(cell.sub_checksums().is_empty());
+    }
+
     #[test]
     fn test_cell_default_frame_is_null() {
         let cell = GameStateCell::<u8>::default();
@@ -590,6 +830,68 @@ mod sync_layer_tests {
         assert_eq!(sync_layer.current_frame(), 0);
     }
 
+    #[test]
+    fn test_set_max_prediction_keeps_recent_saved_states() {
+        let mut sync_layer = SyncLayer::<TestConfig>::new(1, 4);
+        for _ in 0..=6 {
+            let req = sync_layer.save_current_state();
+            if let GgrsRequest::SaveGameState { cell, frame } = req {
+                cell.save(frame, Some(frame as u8), None);
+            }
+            sync_layer.advance_frame();
+        }
+        // the ring of 5 cells holds frames 2 to 6
+        assert!(sync_layer.saved_state_by_frame(1).is_none());
+
+        sync_layer.set_max_prediction(8);
+        for frame in 2..=6 {
+            assert_eq!(
+                sync_layer.saved_state_by_frame(frame).unwrap().frame(),
+                frame
+            );
+        }
+
+        sync_layer.set_max_prediction(2);
+        assert!(sync_layer.saved_state_by_frame(3).is_none());
+        for frame in 4..=6 {
+            assert_eq!(
+                sync_layer.saved_state_by_frame(frame).unwrap().frame(),
+                frame
+            );
+        }
+    }
+
+    #[test]
+    fn test_input_hashes_depend_on_input_history() {
+        let connect_status = make_connect_status(1);
+        let mut sync_layers: Vec<_> = (0..3).map(|_| SyncLayer::<TestConfig>::new(1, 8)).collect();
+        for i in 0..6 {
+            for (j, sync_layer) in sync_layers.iter_mut().enumerate() {
+                // the last sync layer sees a different input in frame 2
+                let inp = if j == 2 && i == 2 { 99 } else { i as u8 };
+                sync_layer.add_remote_input(0, PlayerInput::new(i, TestInput { inp }));
+                sync_layer.hash_confirmed_inputs(i, &connect_status);
+            }
+        }
+
+        for frame in 0..=6 {
+            assert!(sync_layers[0].input_hash(frame).is_some());
+            assert_eq!(
+                sync_layers[0].input_hash(frame),
+                sync_layers[1].input_hash(frame)
+            );
+            // the hashes differ from the frame after the diverging input on
+            assert_eq!(
+                sync_layers[0].input_hash(frame) == sync_layers[2].input_hash(frame),
+                frame <= 2
+            );
+        }
+
+        sync_layers[0].discard_input_hashes();
+        sync_layers[0].hash_confirmed_inputs(6, &connect_status);
+        assert!(sync_layers[0].input_hash(6).is_none());
+    }
+
     #[test]
     fn test_check_simulation_consistency_no_mismatch() {
         let mut sync_layer = SyncLayer::<TestConfig>::new(2, 8);
@@ -2,13 +2,16 @@ use rand::{prelude::ThreadRng, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use ggrs::{
     Config, Frame, GameStateCell, GgrsError, GgrsRequest, InputStatus, P2PSession, PlayerType,
-    PredictRepeatLast, SessionBuilder, SessionState, SpectatorSession, UdpNonBlockingSocket,
+    PredictRepeatLast, ReplayFrame, ReplayHeader, ReplayReader, SessionBuilder, SessionState,
+    SpectatorSession, UdpNonBlockingSocket,
 };
 
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
@@ -56,6 +59,7 @@ impl GameStub {
             match request {
                 GgrsRequest::LoadGameState { cell, .. } => self.load_game_state(cell),
                 GgrsRequest::SaveGameState { cell, frame } => self.save_game_state(cell, frame),
+                GgrsRequest::ReportChecksum { cell, frame } => self.report_checksum(cell, frame),
                 GgrsRequest::AdvanceFrame { inputs } => self.advance_frame(inputs),
             }
         }
@@ -67,6 +71,12 @@ impl GameStub {
         cell.save(frame, Some(self.gs), Some(checksum as u128));
     }
 
+    fn report_checksum(&mut self, cell: GameStateCell<StateStub>, frame: Frame) {
+        assert_eq!(self.gs.frame, frame);
+        let checksum = calculate_hash(&self.gs);
+        cell.save(frame, None, Some(checksum as u128));
+    }
+
     fn load_game_state(&mut self, cell: GameStateCell<StateStub>) {
         self.gs = cell.load().unwrap();
     }
@@ -102,6 +112,7 @@ impl RandomChecksumGameStub {
             match request {
                 GgrsRequest::LoadGameState { cell, .. } => self.load_game_state(cell),
                 GgrsRequest::SaveGameState { cell, frame } => self.save_game_state(cell, frame),
+                GgrsRequest::ReportChecksum { cell, frame } => self.report_checksum(cell, frame),
                 GgrsRequest::AdvanceFrame { inputs } => self.advance_frame(inputs),
             }
         }
@@ -114,6 +125,11 @@ impl RandomChecksumGameStub {
         cell.save(frame, Some(self.gs), Some(random_checksum));
     }
 
+    fn report_checksum(&mut self, cell: GameStateCell<StateStub>, frame: Frame) {
+        assert_eq!(self.gs.frame, frame);
+        cell.save(frame, None, Some(self.rng.gen()));
+    }
+
     fn load_game_state(&mut self, cell: GameStateCell<StateStub>) {
         self.gs = cell.load().expect("No data found.");
     }
@@ -123,6 +139,67 @@ impl RandomChecksumGameStub {
     }
 }
 
+/// A game stub that reports the frame counter and the game state as separate sub-checksums.
+/// With `random_state_checksum()`, the `state` sub-checksum is randomized on every save.
+pub struct SubChecksumGameStub {
+    pub gs: StateStub,
+    rng: Option<ThreadRng>,
+}
+
+impl Default for SubChecksumGameStub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SubChecksumGameStub {
+    #[allow(dead_code)]
+    pub fn new() -> SubChecksumGameStub {
+        SubChecksumGameStub {
+            gs: StateStub { frame: 0, state: 0 },
+            rng: None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn random_state_checksum() -> SubChecksumGameStub {
+        SubChecksumGameStub {
+            gs: StateStub { frame: 0, state: 0 },
+            rng: Some(thread_rng()),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn handle_requests(&mut self, requests: Vec<GgrsRequest<StubConfig>>) {
+        for request in requests {
+            match request {
+                GgrsRequest::LoadGameState { cell, .. } => self.gs = cell.load().unwrap(),
+                GgrsRequest::SaveGameState { cell, frame } => self.save_game_state(cell, frame),
+                GgrsRequest::ReportChecksum { cell, frame } => {
+                    assert_eq!(self.gs.frame, frame);
+                    cell.save(frame, None, Some(calculate_hash(&self.gs) as u128));
+                }
+                GgrsRequest::AdvanceFrame { inputs } => self.gs.advance_frame(inputs),
+            }
+        }
+    }
+
+    fn save_game_state(&mut self, cell: GameStateCell<StateStub>, frame: Frame) {
+        assert_eq!(self.gs.frame, frame);
+        let frame_checksum = calculate_hash(&self.gs.frame) as u128;
+        let state_checksum = match self.rng.as_mut() {
+            Some(rng) => rng.gen(),
+            None => calculate_hash(&self.gs.state) as u128,
+        };
+        cell.save_with_sub_checksums(
+            frame,
+            Some(self.gs),
+            Some(frame_checksum ^ state_checksum),
+            &[("frame", frame_checksum), ("state", state_checksum)],
+        );
+    }
+}
+
 /// A single-player game stub for tests that use `with_num_players(1)`.
 /// The `advance_frame` logic only reads `inputs[0]`.
 pub struct GameStub1P {
@@ -149,6 +226,7 @@ impl GameStub1P {
             match request {
                 GgrsRequest::LoadGameState { cell, .. } => self.load_game_state(cell),
                 GgrsRequest::SaveGameState { cell, frame } => self.save_game_state(cell, frame),
+                GgrsRequest::ReportChecksum { cell, frame } => self.report_checksum(cell, frame),
                 GgrsRequest::AdvanceFrame { inputs } => self.advance_frame(inputs),
             }
         }
@@ -160,6 +238,12 @@ impl GameStub1P {
         cell.save(frame, Some(self.gs), Some(checksum as u128));
     }
 
+    fn report_checksum(&mut self, cell: GameStateCell<StateStub>, frame: Frame) {
+        assert_eq!(self.gs.frame, frame);
+        let checksum = calculate_hash(&self.gs);
+        cell.save(frame, None, Some(checksum as u128));
+    }
+
     fn load_game_state(&mut self, cell: GameStateCell<StateStub>) {
         self.gs = cell.load().unwrap();
     }
@@ -170,14 +254,19 @@ impl GameStub1P {
     }
 }
 
-#[derive(Default, Copy, Clone, Hash)]
+#[derive(Default, Copy, Clone, Hash, Serialize, Deserialize)]
 pub struct StateStub {
     pub frame: i32,
     pub state: i32,
 }
 
 impl StateStub {
-    fn advance_frame(&mut self, inputs: Vec<(StubInput, InputStatus)>) {
+    #[allow(dead_code)]
+    pub fn checksum(&self) -> u128 {
+        calculate_hash(self) as u128
+    }
+
+    pub fn advance_frame(&mut self, inputs: Vec<(StubInput, InputStatus)>) {
         let p0_inputs = inputs[0].0.inp;
         let p1_inputs = inputs[1].0.inp;
 
@@ -314,3 +403,38 @@ pub fn make_host_and_spectator(
 
     Ok((host_sess, spec_sess))
 }
+
+// ── Replay helpers ────────────────────────────────────────────────────────────
+
+/// An in-memory replay destination that stays readable after a session took ownership of it.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SharedBuffer {
+    /// Returns a copy of everything written so far.
+    #[allow(dead_code)]
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Reads back the replay recorded into this buffer.
+    #[allow(dead_code)]
+    pub fn read_replay(&self) -> (ReplayHeader, Vec<ReplayFrame<StubInput>>) {
+        let bytes = self.bytes();
+        let reader = ReplayReader::<StubInput, _>::new(bytes.as_slice()).unwrap();
+        let header = reader.header().clone();
+        let frames = reader.map(Result::unwrap).collect();
+        (header, frames)
+    }
+}
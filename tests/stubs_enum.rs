@@ -53,6 +53,7 @@ impl GameStubEnum {
             match request {
                 GgrsRequest::LoadGameState { cell, .. } => self.load_game_state(cell),
                 GgrsRequest::SaveGameState { cell, frame } => self.save_game_state(cell, frame),
+                GgrsRequest::ReportChecksum { cell, frame } => self.report_checksum(cell, frame),
                 GgrsRequest::AdvanceFrame { inputs } => self.advance_frame(inputs),
             }
         }
@@ -64,6 +65,12 @@ impl GameStubEnum {
         cell.save(frame, Some(self.gs), Some(checksum as u128));
     }
 
+    fn report_checksum(&mut self, cell: GameStateCell<StateStubEnum>, frame: Frame) {
+        assert_eq!(self.gs.frame, frame);
+        let checksum = calculate_hash(&self.gs);
+        cell.save(frame, None, Some(checksum as u128));
+    }
+
     fn load_game_state(&mut self, cell: GameStateCell<StateStubEnum>) {
         self.gs = cell.load().unwrap();
     }
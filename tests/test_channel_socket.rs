@@ -0,0 +1,108 @@
+mod stubs;
+
+use std::collections::HashMap;
+
+use ggrs::{
+    ChannelNetwork, GgrsError, GgrsRequest, P2PSession, PlayerType, SessionBuilder, SessionState,
+    SpectatorSession,
+};
+use stubs::{StubConfig, StubInput};
+
+const NUM_PLAYERS: usize = 4;
+
+fn handle_and_record(
+    stub: &mut stubs::GameStub,
+    history: &mut HashMap<i32, i32>,
+    requests: Vec<GgrsRequest<StubConfig>>,
+) {
+    for request in requests {
+        let advances = matches!(request, GgrsRequest::AdvanceFrame { .. });
+        stub.handle_requests(vec![request]);
+        if advances {
+            history.insert(stub.gs.frame, stub.gs.state);
+        }
+    }
+}
+
+/// Builds `NUM_PLAYERS` sessions with one local player each. The first one also hosts a spectator.
+fn make_sessions(
+    network: &ChannelNetwork<std::net::SocketAddr>,
+) -> Result<(Vec<P2PSession<StubConfig>>, SpectatorSession<StubConfig>), GgrsError> {
+    let addr = |handle: usize| stubs::localhost(9000 + handle as u16);
+    let spectator_addr = stubs::localhost(9100);
+
+    let mut sessions = Vec::new();
+    for local in 0..NUM_PLAYERS {
+        let mut builder = SessionBuilder::<StubConfig>::new().with_num_players(NUM_PLAYERS)?;
+        for handle in 0..NUM_PLAYERS {
+            let player_type = if handle == local {
+                PlayerType::Local
+            } else {
+                PlayerType::Remote(addr(handle))
+            };
+            builder = builder.add_player(player_type, handle)?;
+        }
+        if local == 0 {
+            builder = builder.add_player(PlayerType::Spectator(spectator_addr), NUM_PLAYERS)?;
+        }
+        sessions.push(builder.start_p2p_session(network.socket(addr(local)))?);
+    }
+
+    let spectator = SessionBuilder::<StubConfig>::new()
+        .with_num_players(NUM_PLAYERS)?
+        .start_spectator_session(addr(0), network.socket(spectator_addr));
+    Ok((sessions, spectator))
+}
+
+#[test]
+fn test_sessions_play_over_channel_sockets() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (mut sessions, mut spectator) = make_sessions(&network)?;
+
+    // messages arrive instantly, so synchronization only needs a few round trips
+    for _ in 0..50 {
+        for sess in &mut sessions {
+            sess.poll_remote_clients();
+        }
+        spectator.poll_remote_clients();
+    }
+    assert!(sessions
+        .iter()
+        .all(|sess| sess.current_state() == SessionState::Running));
+    assert_eq!(spectator.current_state(), SessionState::Running);
+
+    let mut stubs: Vec<_> = (0..NUM_PLAYERS).map(|_| stubs::GameStub::new()).collect();
+    let mut histories = vec![HashMap::new(); NUM_PLAYERS];
+    let mut spectator_stub = stubs::GameStub::new();
+    let mut spectator_history = HashMap::new();
+
+    for i in 0..120 {
+        for (handle, sess) in sessions.iter_mut().enumerate() {
+            sess.poll_remote_clients();
+            // keep the inputs constant at the end, so all predictions are correct
+            let inp = if i < 100 { (i + handle) as u32 } else { 0 };
+            sess.add_local_input(handle, StubInput { inp })?;
+            handle_and_record(
+                &mut stubs[handle],
+                &mut histories[handle],
+                sess.advance_frame()?,
+            );
+        }
+        if let Ok(requests) = spectator.advance_frame() {
+            handle_and_record(&mut spectator_stub, &mut spectator_history, requests);
+        }
+    }
+
+    for (stub, history) in stubs.iter().zip(&histories).skip(1) {
+        assert_eq!(stub.gs.frame, stubs[0].gs.frame);
+        assert_eq!(stub.gs.state, stubs[0].gs.state);
+        assert_eq!(history, &histories[0]);
+    }
+
+    assert!(spectator_stub.gs.frame > 100);
+    for (frame, state) in &spectator_history {
+        assert_eq!(histories[0][frame], *state);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,306 @@
+mod stubs;
+
+use std::collections::{BTreeSet, HashMap};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ggrs::{
+    ChannelNetwork, DesyncDetection, GgrsError, GgrsEvent, GgrsRequest, ManualClock,
+    NetworkConditions, NetworkSimulator, PlayerType, SessionBuilder, SessionState,
+};
+use stubs::{StubConfig, StubInput};
+
+fn handle_and_record(
+    stub: &mut stubs::GameStub,
+    history: &mut HashMap<i32, i32>,
+    requests: Vec<GgrsRequest<StubConfig>>,
+) {
+    for request in requests {
+        let advances = matches!(request, GgrsRequest::AdvanceFrame { .. });
+        stub.handle_requests(vec![request]);
+        if advances {
+            history.insert(stub.gs.frame, stub.gs.state);
+        }
+    }
+}
+
+#[test]
+fn test_sessions_stay_in_sync_over_bad_network() -> Result<(), GgrsError> {
+    let conditions = NetworkConditions {
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(15),
+        packet_loss: 0.1,
+        burst_loss: 0.02,
+        burst_length: 4,
+        duplication: 0.05,
+        reordering: 0.05,
+        reorder_delay: Duration::from_millis(30),
+        bandwidth: Some(64 * 1024),
+    };
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9200), stubs::localhost(9201));
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .start_p2p_session(NetworkSimulator::new(network.socket(addr1), conditions, 1))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(NetworkSimulator::new(network.socket(addr2), conditions, 2))?;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while sess1.current_state() != SessionState::Running
+        || sess2.current_state() != SessionState::Running
+    {
+        assert!(Instant::now() < deadline, "sessions did not synchronize");
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    let mut history1 = HashMap::new();
+    let mut history2 = HashMap::new();
+    for i in 0..150 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+
+        sess1.add_local_input(0, StubInput { inp: i })?;
+        sess2.add_local_input(1, StubInput { inp: i * 3 })?;
+        match sess1.advance_frame() {
+            Ok(requests) => handle_and_record(&mut stub1, &mut history1, requests),
+            Err(GgrsError::PredictionThreshold) => (),
+            Err(e) => return Err(e),
+        }
+        match sess2.advance_frame() {
+            Ok(requests) => handle_and_record(&mut stub2, &mut history2, requests),
+            Err(GgrsError::PredictionThreshold) => (),
+            Err(e) => return Err(e),
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    // despite losses, both peers agree on every frame both of them confirmed
+    let confirmed = sess1.confirmed_frame().min(sess2.confirmed_frame());
+    assert!(confirmed > 50, "only {confirmed} frames confirmed");
+    for frame in 1..=confirmed {
+        assert_eq!(
+            history1[&frame], history2[&frame],
+            "mismatch at frame {frame}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_user_messages_arrive_in_order_over_bad_network() -> Result<(), GgrsError> {
+    let conditions = NetworkConditions {
+        latency: Duration::from_millis(10),
+        jitter: Duration::from_millis(20),
+        packet_loss: 0.2,
+        duplication: 0.1,
+        ..Default::default()
+    };
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9210), stubs::localhost(9211));
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .start_p2p_session(NetworkSimulator::new(network.socket(addr1), conditions, 3))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(NetworkSimulator::new(network.socket(addr2), conditions, 4))?;
+
+    // messages sent before the peers are synchronized are delivered as well
+    for i in 0..20_u8 {
+        sess1.send_user_message(1, &[i; 3])?;
+    }
+
+    let mut received = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while received.len() < 20 {
+        assert!(
+            Instant::now() < deadline,
+            "only {} messages arrived",
+            received.len()
+        );
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        for event in sess2.events() {
+            if let GgrsEvent::UserMessage { addr, payload } = event {
+                assert_eq!(addr, addr1);
+                received.push(payload);
+            }
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(sess1.current_state(), SessionState::Running);
+
+    let expected: Vec<Vec<u8>> = (0..20_u8).map(|i| vec![i; 3]).collect();
+    assert_eq!(received, expected);
+    Ok(())
+}
+
+#[test]
+fn test_checksums_are_compared_over_lossy_network() -> Result<(), GgrsError> {
+    let conditions = NetworkConditions {
+        packet_loss: 0.2,
+        ..Default::default()
+    };
+    let desync_mode = DesyncDetection::On { interval: 10 };
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9230), stubs::localhost(9231));
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .with_desync_detection_mode(desync_mode)
+        .start_p2p_session(NetworkSimulator::new(network.socket(addr1), conditions, 5))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .with_desync_detection_mode(desync_mode)
+        .start_p2p_session(NetworkSimulator::new(network.socket(addr2), conditions, 6))?;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while sess1.current_state() != SessionState::Running
+        || sess2.current_state() != SessionState::Running
+    {
+        assert!(Instant::now() < deadline, "sessions did not synchronize");
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    let (mut detected1, mut detected2) = (BTreeSet::new(), BTreeSet::new());
+    for i in 0..400 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        // mess up state for peer 1, so every checksum from now on differs
+        if i >= 15 {
+            stub1.gs.state = 1234;
+        }
+        sess1.add_local_input(0, StubInput { inp: 0 })?;
+        sess2.add_local_input(1, StubInput { inp: 1 })?;
+        match sess1.advance_frame() {
+            Ok(requests) => stub1.handle_requests(requests),
+            Err(GgrsError::PredictionThreshold) => (),
+            Err(e) => return Err(e),
+        }
+        match sess2.advance_frame() {
+            Ok(requests) => stub2.handle_requests(requests),
+            Err(GgrsError::PredictionThreshold) => (),
+            Err(e) => return Err(e),
+        }
+        for (sess, detected) in [(&mut sess1, &mut detected1), (&mut sess2, &mut detected2)] {
+            for event in sess.events() {
+                if let GgrsEvent::DesyncDetected { frame, .. } = event {
+                    detected.insert(frame);
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    // lost reports are sent again, so both peers compared the same frames, apart from the last
+    // ones whose reports may still be on their way
+    detected1.retain(|&frame| frame <= 200);
+    detected2.retain(|&frame| frame <= 200);
+    assert!(detected1.len() > 10, "only {detected1:?} compared");
+    assert_eq!(detected1, detected2);
+    Ok(())
+}
+
+/// Plays a match over a bad network, stepping the sessions and the simulated network with the same
+/// `ManualClock`, and returns the confirmed game states of both peers.
+fn play_with_manual_clock() -> Result<[HashMap<i32, i32>; 2], GgrsError> {
+    let conditions = NetworkConditions {
+        latency: Duration::from_millis(30),
+        jitter: Duration::from_millis(20),
+        packet_loss: 0.1,
+        reordering: 0.05,
+        reorder_delay: Duration::from_millis(40),
+        ..Default::default()
+    };
+    let clock = ManualClock::new();
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9278), stubs::localhost(9279));
+    let socket1 =
+        NetworkSimulator::new(network.socket(addr1), conditions, 7).with_clock(clock.clone());
+    let socket2 =
+        NetworkSimulator::new(network.socket(addr2), conditions, 8).with_clock(clock.clone());
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .with_clock(clock.clone())
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .start_p2p_session(socket1)?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .with_clock(clock.clone())
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(socket2)?;
+
+    // delayed packets stay in flight while the clock stands still
+    for _ in 0..50 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+    }
+    assert_eq!(sess1.current_state(), SessionState::Synchronizing);
+
+    for _ in 0..1000 {
+        if sess1.current_state() == SessionState::Running
+            && sess2.current_state() == SessionState::Running
+        {
+            break;
+        }
+        clock.advance(Duration::from_millis(5));
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+    }
+    assert_eq!(sess1.current_state(), SessionState::Running);
+    assert_eq!(sess2.current_state(), SessionState::Running);
+
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    let mut history1 = HashMap::new();
+    let mut history2 = HashMap::new();
+    for i in 0..150 {
+        clock.advance(Duration::from_millis(16));
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+
+        sess1.add_local_input(0, StubInput { inp: i })?;
+        sess2.add_local_input(1, StubInput { inp: i * 3 })?;
+        match sess1.advance_frame() {
+            Ok(requests) => handle_and_record(&mut stub1, &mut history1, requests),
+            Err(GgrsError::PredictionThreshold) => (),
+            Err(e) => return Err(e),
+        }
+        match sess2.advance_frame() {
+            Ok(requests) => handle_and_record(&mut stub2, &mut history2, requests),
+            Err(GgrsError::PredictionThreshold) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let confirmed = sess1.confirmed_frame().min(sess2.confirmed_frame());
+    assert!(confirmed > 50, "only {confirmed} frames confirmed");
+    for frame in 1..=confirmed {
+        assert_eq!(
+            history1[&frame], history2[&frame],
+            "mismatch at frame {frame}"
+        );
+    }
+    Ok([history1, history2])
+}
+
+#[test]
+fn test_manual_clock_makes_bad_network_runs_reproducible() -> Result<(), GgrsError> {
+    // with the same seeds and clock steps, every packet meets the same fate in both runs
+    assert_eq!(play_with_manual_clock()?, play_with_manual_clock()?);
+    Ok(())
+}
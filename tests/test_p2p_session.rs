@@ -1,8 +1,9 @@
 mod stubs;
 
 use ggrs::{
-    DesyncDetection, GgrsError, GgrsEvent, GgrsRequest, InputStatus, PlayerType, SessionBuilder,
-    SessionState, UdpNonBlockingSocket,
+    AdaptiveInputDelay, ChannelNetwork, DesyncBundle, DesyncDetection, DesyncRecovery, GgrsError,
+    GgrsEvent, GgrsRequest, InputStatus, ManualClock, P2PSession, PacketSecurity, PlayerType,
+    ReplaySessionKind, SessionBuilder, SessionState, SpectatorSession, UdpNonBlockingSocket,
 };
 use instant::Duration;
 use serial_test::serial;
@@ -245,6 +246,8 @@ fn test_desyncs_detected() -> Result<(), GgrsError> {
         local_checksum: desync_local_checksum1,
         remote_checksum: desync_remote_checksum1,
         addr: desync_addr1,
+        inputs_match: inputs_match1,
+        mismatched_sub_checksums: ref sub_checksums1,
     } = sess1_events[0]
     else {
         panic!("no desync for peer 1");
@@ -258,6 +261,8 @@ fn test_desyncs_detected() -> Result<(), GgrsError> {
         local_checksum: desync_local_checksum2,
         remote_checksum: desync_remote_checksum2,
         addr: desync_addr2,
+        inputs_match: inputs_match2,
+        mismatched_sub_checksums: ref sub_checksums2,
     } = sess2_events[0]
     else {
         panic!("no desync for peer 2");
@@ -270,9 +275,699 @@ fn test_desyncs_detected() -> Result<(), GgrsError> {
     assert_eq!(desync_remote_checksum1, desync_local_checksum2);
     assert_eq!(desync_remote_checksum2, desync_local_checksum1);
 
+    // the states differ although both peers used the same inputs
+    assert_eq!(inputs_match1, Some(true));
+    assert_eq!(inputs_match2, Some(true));
+    // the states were saved without sub-checksums
+    assert!(sub_checksums1.is_empty());
+    assert!(sub_checksums2.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_desync_states_are_exchanged() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9370), stubs::localhost(9371));
+    let desync_mode = DesyncDetection::On { interval: 100 };
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .with_desync_detection_mode(desync_mode)
+        .with_desync_state_exchange()
+        .start_p2p_session(network.socket(addr1))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .with_desync_detection_mode(desync_mode)
+        .with_desync_state_exchange()
+        .start_p2p_session(network.socket(addr2))?;
+    stubs::sync_p2p_sessions(&mut sess1, &mut sess2);
+    let _ = sess1.events().count();
+    let _ = sess2.events().count();
+
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    for i in 0..210 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        // mess up state for peer 1 after the first checksum
+        if i >= 110 {
+            stub1.gs.state = 1234;
+        }
+        sess1.add_local_input(0, StubInput { inp: 0 })?;
+        sess2.add_local_input(1, StubInput { inp: 1 })?;
+        stub1.handle_requests(sess1.advance_frame()?);
+        stub2.handle_requests(sess2.advance_frame()?);
+    }
+    // deliver the states
+    for _ in 0..5 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+    }
+
+    let mut received = Vec::new();
+    for (sess, remote_addr) in [(&mut sess1, addr2), (&mut sess2, addr1)] {
+        let states: Vec<_> = sess
+            .events()
+            .filter_map(|event| match event {
+                GgrsEvent::DesyncStateReceived {
+                    frame,
+                    addr,
+                    local_state,
+                    remote_state,
+                } => Some((frame, addr, local_state, remote_state)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(states.len(), 1);
+        let (frame, addr, local_state, remote_state) = states[0].clone();
+        assert_eq!(frame, 200);
+        assert_eq!(addr, remote_addr);
+        received.push((local_state, remote_state));
+    }
+
+    // both peers got both states, and the states show the corrupted value
+    assert_eq!(received[0].0, received[1].1);
+    assert_eq!(received[0].1, received[1].0);
+    let corrupted: stubs::StateStub = bincode::deserialize(&received[0].0).unwrap();
+    let correct: stubs::StateStub = bincode::deserialize(&received[1].0).unwrap();
+    assert_eq!(corrupted.frame, 200);
+    assert_eq!(corrupted.state, 1234);
+    assert_ne!(correct.state, 1234);
+    Ok(())
+}
+
+#[test]
+fn test_desync_names_mismatched_sub_checksums() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9374), stubs::localhost(9375));
+    let desync_mode = DesyncDetection::On { interval: 10 };
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .with_desync_detection_mode(desync_mode)
+        .start_p2p_session(network.socket(addr1))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .with_desync_detection_mode(desync_mode)
+        .start_p2p_session(network.socket(addr2))?;
+    stubs::sync_p2p_sessions(&mut sess1, &mut sess2);
+    let _ = sess1.events().count();
+    let _ = sess2.events().count();
+
+    let mut stub1 = stubs::SubChecksumGameStub::new();
+    let mut stub2 = stubs::SubChecksumGameStub::new();
+    for i in 0..50 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        // mess up state for peer 1, the frame counter stays intact
+        if i >= 20 {
+            stub1.gs.state = 1234;
+        }
+        sess1.add_local_input(0, StubInput { inp: 0 })?;
+        sess2.add_local_input(1, StubInput { inp: 1 })?;
+        stub1.handle_requests(sess1.advance_frame()?);
+        stub2.handle_requests(sess2.advance_frame()?);
+    }
+
+    for sess in [&mut sess1, &mut sess2] {
+        let names: Vec<_> = sess
+            .events()
+            .filter_map(|event| match event {
+                GgrsEvent::DesyncDetected {
+                    mismatched_sub_checksums,
+                    ..
+                } => Some(mismatched_sub_checksums),
+                _ => None,
+            })
+            .collect();
+        assert!(!names.is_empty());
+        assert!(names.iter().all(|names| names == &["state".to_owned()]));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_desync_bundle_reproduces_desync() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9387), stubs::localhost(9388));
+    let desync_mode = DesyncDetection::On { interval: 10 };
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .with_desync_detection_mode(desync_mode)
+        .with_keyframe_interval(20)?
+        .with_desync_bundles()
+        .start_p2p_session(network.socket(addr1))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .with_desync_detection_mode(desync_mode)
+        .start_p2p_session(network.socket(addr2))?;
+    stubs::sync_p2p_sessions(&mut sess1, &mut sess2);
+
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    let mut desync_frame = None;
+    for i in 0..80 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        // mess up state for peer 1, so every checksum from now on differs
+        if i >= 35 {
+            stub1.gs.state = 1234;
+        }
+        sess1.add_local_input(0, StubInput { inp: 0 })?;
+        sess2.add_local_input(1, StubInput { inp: 1 })?;
+        stub1.handle_requests(sess1.advance_frame()?);
+        stub2.handle_requests(sess2.advance_frame()?);
+        for event in sess1.events() {
+            if let GgrsEvent::DesyncDetected { frame, .. } = event {
+                desync_frame.get_or_insert(frame);
+            }
+        }
+    }
+    let desync_frame = desync_frame.expect("desync not detected");
+    assert_eq!(desync_frame, 40);
+
+    let bundle = sess1.desync_bundle(desync_frame)?;
+    assert_eq!(bundle.header.session_kind, ReplaySessionKind::P2P);
+    assert_eq!(bundle.start_frame, 20);
+    let frames: Vec<_> = bundle.frames.iter().map(|f| f.frame).collect();
+    assert_eq!(frames, (20..=40).collect::<Vec<_>>());
+
+    let mut bytes = Vec::new();
+    bundle.write_to(&mut bytes).unwrap();
+    let bundle = DesyncBundle::<StubInput>::read_from(bytes.as_slice()).unwrap();
+
+    // re-simulating from the start state arrives at the state of the peer that did not desync
+    let mut state: stubs::StateStub = bincode::deserialize(&bundle.start_state.unwrap()).unwrap();
+    for frame in &bundle.frames {
+        // our states are messed up from frame 35 on
+        if frame.frame < 35 {
+            assert_eq!(Some(state.checksum()), frame.checksum);
+        }
+        if frame.frame == bundle.desync_frame {
+            break;
+        }
+        state.advance_frame(frame.input_statuses());
+    }
+    assert_eq!(state.checksum(), bundle.remote_checksum);
+    assert_ne!(state.checksum(), bundle.local_checksum);
+
+    // frames without desync cannot be bundled
+    assert!(sess1.desync_bundle(30).is_err());
+    assert!(sess2.desync_bundle(desync_frame).is_err());
+
+    // bundles need desync detection
+    let without_detection = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .with_desync_bundles()
+        .start_p2p_session(network.socket(stubs::localhost(9389)));
+    assert!(matches!(
+        without_detection,
+        Err(GgrsError::InvalidRequest { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_incompatible_peers_reject_each_other() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9390), stubs::localhost(9391));
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .with_build_hash(1)
+        .start_p2p_session(network.socket(addr1))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .with_build_hash(2)
+        .with_fps(30)?
+        .start_p2p_session(network.socket(addr2))?;
+    // the rejected peers count as disconnected, so the sessions stop synchronizing
+    stubs::sync_p2p_sessions(&mut sess1, &mut sess2);
+
+    for (sess, remote_addr) in [(&mut sess1, addr2), (&mut sess2, addr1)] {
+        let events: Vec<_> = sess
+            .events()
+            .filter(|event| !matches!(event, GgrsEvent::Synchronizing { .. }))
+            .collect();
+        assert_eq!(events.len(), 2);
+        let GgrsEvent::ConfigMismatch {
+            addr,
+            local,
+            remote,
+        } = &events[0]
+        else {
+            panic!("expected a config mismatch");
+        };
+        assert_eq!(*addr, remote_addr);
+        assert_eq!(local.mismatches(remote), vec!["build_hash", "fps"]);
+        assert!(matches!(events[1], GgrsEvent::Disconnected { addr } if addr == remote_addr));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_peers_with_the_same_key_play_together() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9252), stubs::localhost(9253));
+    let security = PacketSecurity::Encrypted { key: [42; 32] };
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .with_packet_security(security)
+        .start_p2p_session(network.socket(addr1))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .with_packet_security(security)
+        .start_p2p_session(network.socket(addr2))?;
+    stubs::sync_p2p_sessions(&mut sess1, &mut sess2);
+
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    for i in 0..20 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        sess1.add_local_input(0, StubInput { inp: i })?;
+        sess2.add_local_input(1, StubInput { inp: i })?;
+        stub1.handle_requests(sess1.advance_frame()?);
+        stub2.handle_requests(sess2.advance_frame()?);
+    }
+    for _ in 0..10 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+    }
+    assert_eq!(sess1.confirmed_frame(), 19);
+    assert_eq!(sess2.confirmed_frame(), 19);
+    Ok(())
+}
+
+#[test]
+fn test_peers_with_different_keys_never_synchronize() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (addr1, addr2, addr3) = (
+        stubs::localhost(9254),
+        stubs::localhost(9255),
+        stubs::localhost(9256),
+    );
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .with_num_players(3)?
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .add_player(PlayerType::Remote(addr3), 2)?
+        .with_packet_security(PacketSecurity::Authenticated { key: [1; 32] })
+        .start_p2p_session(network.socket(addr1))?;
+    // peers with another key or with the same key used for encryption cannot read each other
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .with_num_players(3)?
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .add_player(PlayerType::Remote(addr3), 2)?
+        .with_packet_security(PacketSecurity::Authenticated { key: [2; 32] })
+        .start_p2p_session(network.socket(addr2))?;
+    let mut sess3 = SessionBuilder::<StubConfig>::new()
+        .with_num_players(3)?
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .add_player(PlayerType::Local, 2)?
+        .with_packet_security(PacketSecurity::Encrypted { key: [1; 32] })
+        .start_p2p_session(network.socket(addr3))?;
+
+    for _ in 0..20 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        sess3.poll_remote_clients();
+    }
+    for sess in [&mut sess1, &mut sess2, &mut sess3] {
+        assert_eq!(sess.current_state(), SessionState::Synchronizing);
+        assert_eq!(sess.events().count(), 0);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_missing_checksums_are_reported_as_unverified() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9378), stubs::localhost(9379));
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .with_desync_detection_mode(DesyncDetection::On { interval: 1 })
+        .start_p2p_session(network.socket(addr1))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .with_desync_detection_mode(DesyncDetection::On { interval: 1 })
+        .start_p2p_session(network.socket(addr2))?;
+    stubs::sync_p2p_sessions(&mut sess1, &mut sess2);
+    let _ = sess1.events().count();
+
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    for i in 0..50 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        sess1.add_local_input(0, StubInput { inp: i })?;
+        sess2.add_local_input(1, StubInput { inp: i })?;
+        stub1.handle_requests(sess1.advance_frame()?);
+        // the second peer saves its states without checksums, so it never sends any
+        for request in sess2.advance_frame()? {
+            match request {
+                GgrsRequest::SaveGameState { cell, frame } => {
+                    cell.save(frame, Some(stub2.gs), None);
+                }
+                request => stub2.handle_requests(vec![request]),
+            }
+        }
+    }
+
+    let unverified: Vec<_> = sess1
+        .events()
+        .filter_map(|event| match event {
+            GgrsEvent::ChecksumUnverified { frame, addr } => Some((frame, addr)),
+            _ => None,
+        })
+        .collect();
+    // checksums are forgotten oldest first, once more than 32 are kept
+    assert!(!unverified.is_empty());
+    for (i, &(frame, addr)) in unverified.iter().enumerate() {
+        assert_eq!(frame, i as i32 + 1);
+        assert_eq!(addr, addr2);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_builder_desync_state_exchange_without_detection_errors() {
+    let network = ChannelNetwork::new();
+    let result = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)
+        .unwrap()
+        .add_player(PlayerType::Remote(stubs::localhost(9373)), 1)
+        .unwrap()
+        .with_desync_state_exchange()
+        .start_p2p_session(network.socket(stubs::localhost(9372)));
+    assert!(matches!(result, Err(GgrsError::InvalidRequest { .. })));
+}
+
+/// Creates three sessions on a channel network, each hosting the player with its index.
+fn make_three_sessions(
+    network: &ChannelNetwork<std::net::SocketAddr>,
+    port: u16,
+    outlier_disconnect: bool,
+) -> Result<Vec<P2PSession<StubConfig>>, GgrsError> {
+    let addrs: Vec<_> = (0..3).map(|i| stubs::localhost(port + i)).collect();
+    let mut sessions = Vec::new();
+    for (local, &addr) in addrs.iter().enumerate() {
+        let mut builder = SessionBuilder::<StubConfig>::new()
+            .with_num_players(3)?
+            .with_desync_detection_mode(DesyncDetection::On { interval: 10 })
+            .with_desync_outlier_disconnect(outlier_disconnect);
+        for (handle, &remote) in addrs.iter().enumerate() {
+            let player_type = if handle == local {
+                PlayerType::Local
+            } else {
+                PlayerType::Remote(remote)
+            };
+            builder = builder.add_player(player_type, handle)?;
+        }
+        sessions.push(builder.start_p2p_session(network.socket(addr))?);
+    }
+    for _ in 0..50 {
+        for sess in &mut sessions {
+            sess.poll_remote_clients();
+        }
+    }
+    assert!(sessions
+        .iter()
+        .all(|sess| sess.current_state() == SessionState::Running));
+    Ok(sessions)
+}
+
+/// Runs three sessions where the peer of player 2 messes up its state, and returns the events of
+/// every session.
+fn run_three_sessions_with_outlier(
+    mut sessions: Vec<P2PSession<StubConfig>>,
+) -> Result<Vec<Vec<GgrsEvent<StubConfig>>>, GgrsError> {
+    let mut stubs: Vec<_> = (0..3).map(|_| stubs::GameStub::new()).collect();
+    let mut events: Vec<_> = (0..3).map(|_| Vec::new()).collect();
+    for i in 0..100 {
+        if i >= 30 {
+            stubs[2].gs.state = 1234;
+        }
+        for (handle, sess) in sessions.iter_mut().enumerate() {
+            sess.poll_remote_clients();
+            sess.add_local_input(handle, StubInput { inp: i })?;
+            stubs[handle].handle_requests(sess.advance_frame()?);
+            events[handle].extend(sess.events());
+        }
+    }
+    Ok(events)
+}
+
+#[test]
+fn test_desync_attributed_to_minority() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let sessions = make_three_sessions(&network, 9390, false)?;
+    let events = run_three_sessions_with_outlier(sessions)?;
+
+    // every peer, including the one that diverged, names player 2
+    for session_events in &events {
+        let attributed: Vec<_> = session_events
+            .iter()
+            .filter_map(|event| match event {
+                GgrsEvent::DesyncAttributed {
+                    frame, outliers, ..
+                } => Some((*frame, outliers)),
+                _ => None,
+            })
+            .collect();
+        assert!(!attributed.is_empty());
+        for (frame, outliers) in attributed {
+            assert!(frame >= 30);
+            assert_eq!(outliers, &vec![2]);
+        }
+        assert!(!session_events
+            .iter()
+            .any(|event| matches!(event, GgrsEvent::Disconnected { .. })));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_desync_outliers_are_disconnected() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let sessions = make_three_sessions(&network, 9393, true)?;
+    let events = run_three_sessions_with_outlier(sessions)?;
+
+    for (handle, session_events) in events.iter().enumerate().take(2) {
+        let disconnected: Vec<_> = session_events
+            .iter()
+            .filter_map(|event| match event {
+                GgrsEvent::Disconnected { addr } => Some(*addr),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            disconnected,
+            vec![stubs::localhost(9395)],
+            "session of player {handle}"
+        );
+    }
+    Ok(())
+}
+
+/// Runs two peers where the peer hosting `corrupt_handle` messes up its state once, and checks
+/// that the other peer's state is taken over by the peer without the recovery authority.
+fn run_desync_recovery(
+    port: u16,
+    recovery: DesyncRecovery,
+    corrupt_handle: usize,
+    authority_handle: usize,
+) -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let addrs = [stubs::localhost(port), stubs::localhost(port + 1)];
+    let desync_mode = DesyncDetection::On { interval: 10 };
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addrs[1]), 1)?
+        .with_desync_detection_mode(desync_mode)
+        .with_desync_recovery(recovery)
+        .start_p2p_session(network.socket(addrs[0]))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Remote(addrs[0]), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .with_desync_detection_mode(desync_mode)
+        .with_desync_recovery(recovery)
+        .start_p2p_session(network.socket(addrs[1]))?;
+    stubs::sync_p2p_sessions(&mut sess1, &mut sess2);
+
+    let mut stubs = [stubs::GameStub::new(), stubs::GameStub::new()];
+    let mut histories = [
+        std::collections::HashMap::new(),
+        std::collections::HashMap::new(),
+    ];
+    let mut recoveries = [Vec::new(), Vec::new()];
+    for i in 0..200 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        if i == 50 {
+            stubs[corrupt_handle].gs.state += 1000;
+        }
+        sess1.add_local_input(0, StubInput { inp: i / 5 })?;
+        sess2.add_local_input(1, StubInput { inp: i / 7 })?;
+        let requests = [sess1.advance_frame()?, sess2.advance_frame()?];
+        for (handle, (sess, requests)) in [&mut sess1, &mut sess2]
+            .into_iter()
+            .zip(requests)
+            .enumerate()
+        {
+            handle_and_record(&mut stubs[handle], &mut histories[handle], requests);
+            for event in sess.events() {
+                if let GgrsEvent::DesyncRecovered { frame, addr } = event {
+                    recoveries[handle].push((frame, addr));
+                }
+            }
+        }
+    }
+
+    // only the peer without the authority loaded a state, and it got it from the authority
+    let recovering = 1 - authority_handle;
+    assert!(recoveries[authority_handle].is_empty());
+    assert!(!recoveries[recovering].is_empty());
+    let mut last_recovery = 0;
+    for &(frame, addr) in &recoveries[recovering] {
+        assert!(frame > 50);
+        assert_eq!(addr, addrs[authority_handle]);
+        last_recovery = frame;
+    }
+
+    // after the recovered frame, both peers agree on every confirmed frame
+    let confirmed = sess1.confirmed_frame().min(sess2.confirmed_frame());
+    assert!(confirmed > last_recovery + 50);
+    for frame in last_recovery + 1..=confirmed {
+        assert_eq!(
+            histories[0].get(&frame),
+            histories[1].get(&frame),
+            "mismatch at frame {frame}"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_desync_recovery_from_lowest_handle() -> Result<(), GgrsError> {
+    run_desync_recovery(9380, DesyncRecovery::LowestHandle, 1, 0)
+}
+
+#[test]
+fn test_desync_recovery_from_configured_authority() -> Result<(), GgrsError> {
+    // the authority's own state wins, even if it is the one that messed up
+    run_desync_recovery(9382, DesyncRecovery::Authority { player_handle: 1 }, 1, 1)
+}
+
+#[test]
+fn test_desync_recovery_falls_back_when_authority_disconnects() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let addrs = [
+        stubs::localhost(9280),
+        stubs::localhost(9281),
+        stubs::localhost(9282),
+    ];
+    let mut sessions = Vec::new();
+    for local in 0..3 {
+        let mut builder = SessionBuilder::<StubConfig>::new()
+            .with_num_players(3)?
+            .with_desync_detection_mode(DesyncDetection::On { interval: 10 })
+            .with_desync_recovery(DesyncRecovery::Authority { player_handle: 2 });
+        for (handle, addr) in addrs.iter().enumerate() {
+            let player_type = if handle == local {
+                PlayerType::Local
+            } else {
+                PlayerType::Remote(*addr)
+            };
+            builder = builder.add_player(player_type, handle)?;
+        }
+        sessions.push(builder.start_p2p_session(network.socket(addrs[local]))?);
+    }
+    for _ in 0..50 {
+        for sess in &mut sessions {
+            sess.poll_remote_clients();
+        }
+    }
+
+    let mut stubs = [
+        stubs::GameStub::new(),
+        stubs::GameStub::new(),
+        stubs::GameStub::new(),
+    ];
+    let mut recoveries = Vec::new();
+    for i in 0..200 {
+        if i == 20 {
+            // the authority leaves the match
+            sessions.pop();
+            for sess in &mut sessions {
+                sess.disconnect_player(2)?;
+            }
+        }
+        if i == 50 {
+            stubs[1].gs.state += 1000;
+        }
+        for (handle, sess) in sessions.iter_mut().enumerate() {
+            sess.poll_remote_clients();
+            sess.add_local_input(handle, StubInput { inp: i })?;
+            stubs[handle].handle_requests(sess.advance_frame()?);
+            for event in sess.events() {
+                if let GgrsEvent::DesyncRecovered { frame, addr } = event {
+                    recoveries.push((handle, frame, addr));
+                }
+            }
+        }
+    }
+
+    // the lowest connected handle took over as authority
+    assert!(!recoveries.is_empty());
+    for (handle, frame, addr) in recoveries {
+        assert_eq!(handle, 1);
+        assert!(frame > 50);
+        assert_eq!(addr, addrs[0]);
+    }
     Ok(())
 }
 
+#[test]
+fn test_builder_desync_recovery_checks() {
+    let network = ChannelNetwork::new();
+    let builder = || {
+        SessionBuilder::<StubConfig>::new()
+            .add_player(PlayerType::Local, 0)
+            .unwrap()
+            .add_player(PlayerType::Remote(stubs::localhost(9385)), 1)
+            .unwrap()
+    };
+    let without_detection = builder()
+        .with_desync_recovery(DesyncRecovery::LowestHandle)
+        .start_p2p_session(network.socket(stubs::localhost(9384)));
+    assert!(matches!(
+        without_detection,
+        Err(GgrsError::InvalidRequest { .. })
+    ));
+    let unknown_authority = builder()
+        .with_desync_detection_mode(DesyncDetection::On { interval: 10 })
+        .with_desync_recovery(DesyncRecovery::Authority { player_handle: 2 })
+        .start_p2p_session(network.socket(stubs::localhost(9386)));
+    assert!(matches!(
+        unknown_authority,
+        Err(GgrsError::InvalidRequest { .. })
+    ));
+}
+
 #[test]
 #[serial]
 fn test_desyncs_and_input_delay_no_panic() -> Result<(), GgrsError> {
@@ -1046,3 +1741,1001 @@ fn test_lockstep_recovers_after_repeated_stalls() -> Result<(), GgrsError> {
 
     Ok(())
 }
+
+// ── Replays ───────────────────────────────────────────────────────────────────
+
+#[test]
+fn test_lockstep_desyncs_detected() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9376), stubs::localhost(9377));
+    let desync_mode = DesyncDetection::On { interval: 10 };
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .with_max_prediction_window(0)
+        .with_input_delay(2)
+        .with_desync_detection_mode(desync_mode)
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .start_p2p_session(network.socket(addr1))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .with_max_prediction_window(0)
+        .with_input_delay(2)
+        .with_desync_detection_mode(desync_mode)
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(addr2))?;
+    stubs::sync_p2p_sessions(&mut sess1, &mut sess2);
+    let _ = sess1.events().count();
+    let _ = sess2.events().count();
+
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    let mut reported_frames = Vec::new();
+    for i in 0..100 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        // mess up state for peer 1 after a few checksums have been compared
+        if stub1.gs.frame >= 35 {
+            stub1.gs.state = 1234;
+        }
+        sess1.add_local_input(0, StubInput { inp: i })?;
+        sess2.add_local_input(1, StubInput { inp: i })?;
+        let requests1 = sess1.advance_frame()?;
+        for request in &requests1 {
+            assert!(!matches!(request, GgrsRequest::SaveGameState { .. }));
+            if let GgrsRequest::ReportChecksum { frame, .. } = request {
+                reported_frames.push(*frame);
+            }
+        }
+        stub1.handle_requests(requests1);
+        stub2.handle_requests(sess2.advance_frame()?);
+    }
+    assert!(reported_frames.len() >= 5);
+    assert!(reported_frames
+        .iter()
+        .enumerate()
+        .all(|(i, &frame)| frame == (i as i32 + 1) * 10));
+
+    for sess in [&mut sess1, &mut sess2] {
+        let frames: Vec<_> = sess
+            .events()
+            .filter_map(|event| match event {
+                GgrsEvent::DesyncDetected { frame, .. } => Some(frame),
+                _ => None,
+            })
+            .collect();
+        // the first desync is found at the first checksum after the state was messed up
+        assert_eq!(frames.first(), Some(&40));
+    }
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_replay_records_confirmed_frames_without_spectators() -> Result<(), GgrsError> {
+    let replays = [
+        stubs::SharedBuffer::default(),
+        stubs::SharedBuffer::default(),
+    ];
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .with_replay_recorder(replays[0].clone())
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(stubs::localhost(7763)), 1)?
+        .start_p2p_session(UdpNonBlockingSocket::bind_to_port(7762).unwrap())?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .with_replay_recorder(replays[1].clone())
+        .add_player(PlayerType::Remote(stubs::localhost(7762)), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(UdpNonBlockingSocket::bind_to_port(7763).unwrap())?;
+    stubs::sync_p2p_sessions(&mut sess1, &mut sess2);
+
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    for _ in 0..100 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+
+        let frame = sess1.current_frame() as u32;
+        sess1.add_local_input(0, StubInput { inp: frame })?;
+        stub1.handle_requests(sess1.advance_frame()?);
+        let frame = sess2.current_frame() as u32;
+        sess2.add_local_input(1, StubInput { inp: frame * 2 })?;
+        stub2.handle_requests(sess2.advance_frame()?);
+    }
+    drop(sess1);
+    drop(sess2);
+
+    let (header1, frames1) = replays[0].read_replay();
+    let (header2, frames2) = replays[1].read_replay();
+    assert_eq!(header1, header2);
+    assert_eq!(header1.session_kind, ReplaySessionKind::P2P);
+    assert_eq!(header1.num_players, 2);
+    assert!(
+        frames1.len() >= 50,
+        "only {} frames recorded",
+        frames1.len()
+    );
+
+    for (i, frame) in frames1.iter().enumerate() {
+        assert_eq!(frame.frame, i as i32);
+        assert_eq!(frame.inputs[0].inp, i as u32);
+        assert_eq!(frame.inputs[1].inp, i as u32 * 2);
+        assert_eq!(frame.disconnected, vec![false, false]);
+    }
+
+    // both peers record the same match, including the checksums of their states
+    let mut compared_checksums = 0;
+    for (frame1, frame2) in frames1.iter().zip(&frames2) {
+        assert_eq!(frame1.frame, frame2.frame);
+        assert!(frame1.inputs == frame2.inputs);
+        if let (Some(checksum1), Some(checksum2)) = (frame1.checksum, frame2.checksum) {
+            assert_eq!(checksum1, checksum2);
+            compared_checksums += 1;
+        }
+    }
+    assert!(compared_checksums > 0);
+
+    Ok(())
+}
+
+// ── Late join ─────────────────────────────────────────────────────────────────
+
+fn handle_and_record(
+    stub: &mut stubs::GameStub,
+    history: &mut std::collections::HashMap<i32, i32>,
+    requests: Vec<GgrsRequest<StubConfig>>,
+) {
+    for request in requests {
+        let advances = matches!(request, GgrsRequest::AdvanceFrame { .. });
+        stub.handle_requests(vec![request]);
+        if advances {
+            history.insert(stub.gs.frame, stub.gs.state);
+        }
+    }
+}
+
+#[test]
+fn test_builder_late_join_without_state_transfer_errors() {
+    let socket = UdpNonBlockingSocket::bind_to_port(7750).unwrap();
+    let result = SessionBuilder::<StubConfig>::new()
+        .with_late_join(true)
+        .add_player(PlayerType::Local, 1)
+        .unwrap()
+        .add_player(PlayerType::Remote(stubs::localhost(7751)), 0)
+        .unwrap()
+        .start_p2p_session(socket);
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_builder_late_join_slot_handle_checks() {
+    let builder = SessionBuilder::<StubConfig>::new()
+        .with_num_players(3)
+        .unwrap()
+        .add_late_join_slot(2)
+        .unwrap();
+    // slot handles must be valid player handles and cannot be registered twice
+    assert!(SessionBuilder::<StubConfig>::new()
+        .add_late_join_slot(2)
+        .is_err());
+    assert!(builder.add_player(PlayerType::Local, 2).is_err());
+}
+
+#[test]
+#[serial]
+fn test_late_join_player_catches_up() -> Result<(), GgrsError> {
+    let (port0, port1, port2) = (7752, 7753, 7754);
+
+    let mut sess0 = SessionBuilder::<StubConfig>::new()
+        .with_num_players(3)?
+        .with_state_transfer()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(stubs::localhost(port1)), 1)?
+        .add_late_join_slot(2)?
+        .start_p2p_session(UdpNonBlockingSocket::bind_to_port(port0).unwrap())?;
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .with_num_players(3)?
+        .with_state_transfer()
+        .add_player(PlayerType::Remote(stubs::localhost(port0)), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .add_late_join_slot(2)?
+        .start_p2p_session(UdpNonBlockingSocket::bind_to_port(port1).unwrap())?;
+    stubs::sync_p2p_sessions(&mut sess0, &mut sess1);
+
+    let mut stub0 = stubs::GameStub::new();
+    let mut stub1 = stubs::GameStub::new();
+    let mut history0 = std::collections::HashMap::new();
+    let mut history1 = std::collections::HashMap::new();
+
+    // play a while without the third player
+    for i in 0..40 {
+        sess0.poll_remote_clients();
+        sess1.poll_remote_clients();
+        sess0.add_local_input(0, StubInput { inp: i % 3 })?;
+        handle_and_record(&mut stub0, &mut history0, sess0.advance_frame()?);
+        sess1.add_local_input(1, StubInput { inp: i % 5 })?;
+        handle_and_record(&mut stub1, &mut history1, sess1.advance_frame()?);
+    }
+
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .with_num_players(3)?
+        .with_state_transfer()
+        .with_late_join(true)
+        .add_player(PlayerType::Remote(stubs::localhost(port0)), 0)?
+        .add_player(PlayerType::Remote(stubs::localhost(port1)), 1)?
+        .add_player(PlayerType::Local, 2)?
+        .start_p2p_session(UdpNonBlockingSocket::bind_to_port(port2).unwrap())?;
+    sess0.add_remote_player(2, stubs::localhost(port2))?;
+    sess1.add_remote_player(2, stubs::localhost(port2))?;
+
+    let mut stub2 = stubs::GameStub::new();
+    let mut history2 = std::collections::HashMap::new();
+    let mut join_frame = None;
+    let deadline = std::time::Instant::now() + stubs::SYNC_TIMEOUT * 2;
+
+    for i in 40.. {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "late joining player did not catch up"
+        );
+        sess0.poll_remote_clients();
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+
+        for event in sess2.events() {
+            if let GgrsEvent::PlayerJoined {
+                player_handle: 2,
+                frame,
+            } = event
+            {
+                join_frame = Some(frame);
+            }
+        }
+
+        sess0.add_local_input(0, StubInput { inp: i % 3 })?;
+        handle_and_record(&mut stub0, &mut history0, sess0.advance_frame()?);
+        sess1.add_local_input(1, StubInput { inp: i % 5 })?;
+        handle_and_record(&mut stub1, &mut history1, sess1.advance_frame()?);
+        if sess2.current_state() == SessionState::Running {
+            sess2.add_local_input(2, StubInput { inp: i % 7 })?;
+            handle_and_record(&mut stub2, &mut history2, sess2.advance_frame()?);
+        }
+
+        let confirmed = sess0
+            .confirmed_frame()
+            .min(sess1.confirmed_frame())
+            .min(sess2.confirmed_frame());
+        if let Some(join_frame) = join_frame {
+            if confirmed > join_frame + 20 {
+                // all sessions agree on every confirmed frame since the join
+                for frame in join_frame + 1..=confirmed {
+                    assert_eq!(history0.get(&frame), history1.get(&frame));
+                    assert_eq!(history0.get(&frame), history2.get(&frame));
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_unknown_player_joins_through_connection_request() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (addr0, addr1, addr2) = (
+        stubs::localhost(9257),
+        stubs::localhost(9258),
+        stubs::localhost(9259),
+    );
+
+    let mut sess0 = SessionBuilder::<StubConfig>::new()
+        .with_num_players(3)?
+        .with_state_transfer()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr1), 1)?
+        .add_late_join_slot(2)?
+        .start_p2p_session(network.socket(addr0))?;
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .with_num_players(3)?
+        .with_state_transfer()
+        .add_player(PlayerType::Remote(addr0), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .add_late_join_slot(2)?
+        .start_p2p_session(network.socket(addr1))?;
+    stubs::sync_p2p_sessions(&mut sess0, &mut sess1);
+
+    let mut stub0 = stubs::GameStub::new();
+    let mut stub1 = stubs::GameStub::new();
+    for i in 0..20 {
+        sess0.poll_remote_clients();
+        sess1.poll_remote_clients();
+        sess0.add_local_input(0, StubInput { inp: i })?;
+        stub0.handle_requests(sess0.advance_frame()?);
+        sess1.add_local_input(1, StubInput { inp: i })?;
+        stub1.handle_requests(sess1.advance_frame()?);
+    }
+
+    // the joining player is not registered anywhere, it only brings a token
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .with_num_players(3)?
+        .with_state_transfer()
+        .with_late_join(true)
+        .with_join_token(b"ticket".to_vec())?
+        .add_player(PlayerType::Remote(addr0), 0)?
+        .add_player(PlayerType::Remote(addr1), 1)?
+        .add_player(PlayerType::Local, 2)?
+        .start_p2p_session(network.socket(addr2))?;
+
+    let mut stub2 = stubs::GameStub::new();
+    let mut requests = 0;
+    let mut joined = false;
+    let deadline = std::time::Instant::now() + stubs::SYNC_TIMEOUT * 2;
+    for i in 20.. {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "unknown player did not join"
+        );
+        sess0.poll_remote_clients();
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+
+        for sess in [&mut sess0, &mut sess1] {
+            let events: Vec<_> = sess.events().collect();
+            for event in events {
+                if let GgrsEvent::ConnectionRequest { addr, token } = event {
+                    assert_eq!(addr, addr2);
+                    assert_eq!(token.as_deref(), Some(&b"ticket"[..]));
+                    sess.accept_connection_request(addr, 2)?;
+                    requests += 1;
+                }
+            }
+        }
+        joined |= sess2.events().any(|event| {
+            matches!(
+                event,
+                GgrsEvent::PlayerJoined {
+                    player_handle: 2,
+                    ..
+                }
+            )
+        });
+
+        sess0.add_local_input(0, StubInput { inp: i })?;
+        stub0.handle_requests(sess0.advance_frame()?);
+        sess1.add_local_input(1, StubInput { inp: i })?;
+        stub1.handle_requests(sess1.advance_frame()?);
+        if sess2.current_state() == SessionState::Running {
+            sess2.add_local_input(2, StubInput { inp: i })?;
+            stub2.handle_requests(sess2.advance_frame()?);
+        }
+        if joined {
+            break;
+        }
+    }
+
+    // every peer was asked exactly once
+    assert_eq!(requests, 2);
+    Ok(())
+}
+
+#[test]
+fn test_rejected_connection_requests_are_ignored() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, remote_addr, client_addr) = (
+        stubs::localhost(9263),
+        stubs::localhost(9264),
+        stubs::localhost(9265),
+    );
+    let mut host = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(remote_addr), 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    let mut client = SessionBuilder::<StubConfig>::new()
+        .with_join_token(b"wrong".to_vec())?
+        .start_spectator_session(host_addr, network.socket(client_addr));
+
+    // no connection request without a pending request
+    assert!(host.accept_connection_request(client_addr, 2).is_err());
+    assert!(host.reject_connection_request(client_addr).is_err());
+
+    let mut requests = Vec::new();
+    for _ in 0..100 {
+        client.poll_remote_clients();
+        host.poll_remote_clients();
+        let events: Vec<_> = host.events().collect();
+        for event in events {
+            if let GgrsEvent::ConnectionRequest { addr, token } = event {
+                requests.push(token);
+                host.reject_connection_request(addr)?;
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+
+    // the client asks repeatedly, but the request only shows up once
+    assert_eq!(requests, vec![Some(b"wrong".to_vec())]);
+    assert!(host.accept_connection_request(client_addr, 2).is_err());
+    assert_eq!(client.current_state(), SessionState::Synchronizing);
+    Ok(())
+}
+
+/// Polls the host and, if `client` is given, the client for `millis`, returning the addresses that
+/// asked to connect in the meantime.
+fn collect_connection_requests(
+    host: &mut P2PSession<StubConfig>,
+    client: Option<&mut SpectatorSession<StubConfig>>,
+    clock: &ManualClock,
+    millis: u64,
+) -> Vec<std::net::SocketAddr> {
+    let mut client = client;
+    let mut requests = Vec::new();
+    for _ in 0..millis / 10 {
+        if let Some(client) = client.as_mut() {
+            client.poll_remote_clients();
+        }
+        host.poll_remote_clients();
+        for event in host.events() {
+            if let GgrsEvent::ConnectionRequest { addr, .. } = event {
+                requests.push(addr);
+            }
+        }
+        clock.advance(Duration::from_millis(10));
+    }
+    requests
+}
+
+#[test]
+fn test_rejected_connections_are_forgotten() -> Result<(), GgrsError> {
+    let clock = ManualClock::new();
+    let network = ChannelNetwork::new();
+    let (host_addr, remote_addr, client_addr) = (
+        stubs::localhost(9283),
+        stubs::localhost(9284),
+        stubs::localhost(9285),
+    );
+    let mut host = SessionBuilder::<StubConfig>::new()
+        .with_clock(clock.clone())
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(remote_addr), 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    let mut client = SessionBuilder::<StubConfig>::new()
+        .with_clock(clock.clone())
+        .start_spectator_session(host_addr, network.socket(client_addr));
+
+    let requests = collect_connection_requests(&mut host, Some(&mut client), &clock, 500);
+    assert_eq!(requests, vec![client_addr]);
+    host.reject_connection_request(client_addr)?;
+
+    // the rejected client is ignored while it keeps asking
+    let requests = collect_connection_requests(&mut host, Some(&mut client), &clock, 5000);
+    assert!(requests.is_empty());
+
+    // once it has been silent for the disconnect timeout, its requests show up again
+    collect_connection_requests(&mut host, None, &clock, 3000);
+    let requests = collect_connection_requests(&mut host, Some(&mut client), &clock, 500);
+    assert_eq!(requests, vec![client_addr]);
+    host.reject_connection_request(client_addr)?;
+
+    // adding the address lifts the rejection
+    let handle = host.add_spectator(client_addr)?;
+    host.remove_spectator(handle)?;
+    let requests = collect_connection_requests(&mut host, Some(&mut client), &clock, 500);
+    assert_eq!(requests, vec![client_addr]);
+    Ok(())
+}
+
+#[test]
+fn test_builder_join_token_size_errors() {
+    let token = vec![0; ggrs::MAX_JOIN_TOKEN_SIZE + 1];
+    assert!(SessionBuilder::<StubConfig>::new()
+        .with_join_token(token)
+        .is_err());
+    assert!(SessionBuilder::<StubConfig>::new()
+        .with_join_token(vec![0; ggrs::MAX_JOIN_TOKEN_SIZE])
+        .is_ok());
+}
+
+// ── Reconnect ─────────────────────────────────────────────────────────────────
+
+#[test]
+fn test_builder_reconnect_without_state_transfer_errors() {
+    let socket = UdpNonBlockingSocket::bind_to_port(7755).unwrap();
+    let result = SessionBuilder::<StubConfig>::new()
+        .with_reconnect(true)
+        .add_player(PlayerType::Local, 0)
+        .unwrap()
+        .add_player(PlayerType::Remote(stubs::localhost(7756)), 1)
+        .unwrap()
+        .start_p2p_session(socket);
+    assert!(result.is_err());
+}
+
+/// Runs a session per port, drops the peer with handle `dropped` for a while and checks that
+/// `rejoining` rejoins the match and all peers agree on the game state afterwards.
+fn run_rejoin(
+    ports: &[u16],
+    dropped: usize,
+    rejoining: usize,
+    security: PacketSecurity,
+    changed_window: Option<usize>,
+) -> Result<(), GgrsError> {
+    let num_players = ports.len();
+    let mut sessions = Vec::new();
+    for local in 0..num_players {
+        let mut builder = SessionBuilder::<StubConfig>::new()
+            .with_num_players(num_players)?
+            .with_state_transfer()
+            .with_reconnect(true)
+            .with_packet_security(security)
+            .with_disconnect_timeout(Duration::from_millis(300))
+            .with_disconnect_notify_delay(Duration::from_millis(100));
+        for (handle, port) in ports.iter().enumerate() {
+            let player_type = if handle == local {
+                PlayerType::Local
+            } else {
+                PlayerType::Remote(stubs::localhost(*port))
+            };
+            builder = builder.add_player(player_type, handle)?;
+        }
+        sessions.push(
+            builder.start_p2p_session(UdpNonBlockingSocket::bind_to_port(ports[local]).unwrap())?,
+        );
+    }
+
+    let mut stubs: Vec<_> = (0..num_players).map(|_| stubs::GameStub::new()).collect();
+    let mut histories = vec![std::collections::HashMap::new(); num_players];
+    let mut rejoin_frames = vec![None; num_players];
+    let drop_start = std::time::Instant::now() + Duration::from_millis(300);
+    let drop_end = drop_start + Duration::from_millis(700);
+    let deadline = std::time::Instant::now() + stubs::SYNC_TIMEOUT * 3;
+
+    for i in 0u32.. {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "dropped player did not rejoin"
+        );
+        let now = std::time::Instant::now();
+        let is_dropped = now > drop_start && now < drop_end;
+
+        for (handle, sess) in sessions.iter_mut().enumerate() {
+            if handle == dropped && is_dropped {
+                continue;
+            }
+            sess.poll_remote_clients();
+            for event in sess.events() {
+                if let GgrsEvent::PlayerRejoined {
+                    player_handle,
+                    frame,
+                } = event
+                {
+                    assert_eq!(player_handle, rejoining);
+                    rejoin_frames[handle] = Some(frame);
+                }
+            }
+            if sess.current_state() != SessionState::Running {
+                continue;
+            }
+            // the peers that stay change their prediction window once the match is running
+            if let Some(window) = changed_window.filter(|_| handle != dropped) {
+                if sess.max_prediction() != window {
+                    sess.set_max_prediction(window)?;
+                }
+            }
+            sess.add_local_input(
+                handle,
+                StubInput {
+                    inp: i % (handle as u32 + 2),
+                },
+            )?;
+            match sess.advance_frame() {
+                Ok(requests) => {
+                    handle_and_record(&mut stubs[handle], &mut histories[handle], requests)
+                }
+                Err(GgrsError::PredictionThreshold) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        std::thread::sleep(Duration::from_millis(2));
+
+        if let Some(Some(rejoin_frame)) = rejoin_frames.first().copied() {
+            // everyone agrees on the rejoin frame
+            if rejoin_frames.iter().all(|f| *f == Some(rejoin_frame)) {
+                let confirmed = sessions.iter().map(|s| s.confirmed_frame()).min().unwrap();
+                if confirmed > rejoin_frame + 20 {
+                    for frame in rejoin_frame + 1..=confirmed {
+                        for history in &histories[1..] {
+                            assert_eq!(histories[0].get(&frame), history.get(&frame));
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_dropped_player_rejoins() -> Result<(), GgrsError> {
+    run_rejoin(&[7757, 7758, 7759], 0, 0, PacketSecurity::Off, None)
+}
+
+// If both players of a two player match lose each other, the lower handle continues the match.
+#[test]
+#[serial]
+fn test_two_isolated_players_reconnect() -> Result<(), GgrsError> {
+    run_rejoin(&[7760, 7761], 0, 1, PacketSecurity::Off, None)
+}
+
+// The replay history of a peer survives its rejoin, while its new connection is accepted.
+#[test]
+#[serial]
+fn test_dropped_player_rejoins_with_packet_security() -> Result<(), GgrsError> {
+    let security = PacketSecurity::Encrypted { key: [42; 32] };
+    run_rejoin(&[7764, 7765, 7766], 0, 0, security, None)
+}
+
+// Peers may use different prediction windows, so changing one does not keep others from rejoining.
+#[test]
+#[serial]
+fn test_player_rejoins_after_others_changed_prediction_window() -> Result<(), GgrsError> {
+    run_rejoin(&[7767, 7768, 7769], 0, 0, PacketSecurity::Off, Some(6))
+}
+
+#[test]
+fn test_network_interruption_with_manual_clock() -> Result<(), GgrsError> {
+    let clock = ManualClock::new();
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9300), stubs::localhost(9301));
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .with_clock(clock.clone())
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .start_p2p_session(network.socket(addr1))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .with_clock(clock.clone())
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(addr2))?;
+
+    for _ in 0..50 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+    }
+    assert_eq!(sess1.current_state(), SessionState::Running);
+    assert_eq!(sess2.current_state(), SessionState::Running);
+    sess1.events().for_each(drop);
+
+    // sess2 goes silent; no time passes, so nothing happens yet
+    sess1.poll_remote_clients();
+    assert_eq!(sess1.events().count(), 0);
+
+    // the default notify delay is 500ms, the default disconnect timeout 2000ms
+    clock.advance(Duration::from_millis(501));
+    sess1.poll_remote_clients();
+    let events: Vec<_> = sess1.events().collect();
+    assert!(matches!(
+        events[..],
+        [GgrsEvent::NetworkInterrupted { addr, disconnect_timeout: 1500 }] if addr == addr2
+    ));
+
+    clock.advance(Duration::from_millis(1500));
+    sess1.poll_remote_clients();
+    let events: Vec<_> = sess1.events().collect();
+    assert!(matches!(
+        events[..],
+        [GgrsEvent::Disconnected { addr }] if addr == addr2
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_adaptive_input_delay_follows_round_trip_time() -> Result<(), GgrsError> {
+    let clock = ManualClock::new();
+    let network = ChannelNetwork::new();
+    let (addr1, addr2) = (stubs::localhost(9310), stubs::localhost(9311));
+    let settings = AdaptiveInputDelay {
+        min_delay: 0,
+        max_delay: 4,
+        rollback_frames: 0,
+        hysteresis: 1,
+        interval: 10,
+    };
+    // a frame takes 100ms of the manual clock
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .with_clock(clock.clone())
+        .with_fps(10)?
+        .with_adaptive_input_delay(settings)?
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .start_p2p_session(network.socket(addr1))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .with_clock(clock.clone())
+        .with_fps(10)?
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(addr2))?;
+
+    for _ in 0..50 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+    }
+    assert_eq!(sess1.current_state(), SessionState::Running);
+    assert_eq!(sess2.current_state(), SessionState::Running);
+
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    let mut delays = Vec::new();
+    for i in 0..100 {
+        // advancing a frame polls the session; every message takes 50ms to arrive, for a round trip
+        // time of 100ms
+        sess1.add_local_input(0, StubInput { inp: i })?;
+        stub1.handle_requests(sess1.advance_frame()?);
+        clock.advance(Duration::from_millis(50));
+        sess2.add_local_input(1, StubInput { inp: i * 2 })?;
+        stub2.handle_requests(sess2.advance_frame()?);
+        clock.advance(Duration::from_millis(50));
+
+        for event in sess1.events() {
+            if let GgrsEvent::InputDelayChanged { delay } = event {
+                delays.push(delay);
+            }
+        }
+    }
+
+    // 100ms round trip time are half a frame one way at 10 FPS, which is rounded up; on top of
+    // that, sess1 always advances before sess2 and is a frame ahead
+    assert_eq!(sess1.frames_ahead(), 1);
+    assert_eq!(delays, vec![1, 2]);
+
+    // both sessions still agree on the game state, once all inputs have arrived
+    for i in 0..10 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        sess1.add_local_input(0, StubInput { inp: 100 + i })?;
+        sess2.add_local_input(1, StubInput { inp: 0 })?;
+        stub1.handle_requests(sess1.advance_frame()?);
+        stub2.handle_requests(sess2.advance_frame()?);
+    }
+    sess1.poll_remote_clients();
+    sess2.poll_remote_clients();
+    assert_eq!(sess1.confirmed_frame(), sess2.confirmed_frame());
+    assert_eq!(stub1.gs.frame, stub2.gs.frame);
+
+    Ok(())
+}
+
+#[test]
+fn test_builder_adaptive_input_delay_bounds_errors() {
+    let inverted = AdaptiveInputDelay {
+        min_delay: 3,
+        max_delay: 2,
+        ..Default::default()
+    };
+    assert!(SessionBuilder::<StubConfig>::new()
+        .with_adaptive_input_delay(inverted)
+        .is_err());
+
+    let no_interval = AdaptiveInputDelay {
+        interval: 0,
+        ..Default::default()
+    };
+    assert!(SessionBuilder::<StubConfig>::new()
+        .with_adaptive_input_delay(no_interval)
+        .is_err());
+}
+
+// ── Runtime prediction window ────────────────────────────────────────────────
+
+fn make_channel_sessions(
+    network: &ChannelNetwork<std::net::SocketAddr>,
+    port: u16,
+    sparse_saving: bool,
+) -> Result<(P2PSession<StubConfig>, P2PSession<StubConfig>), GgrsError> {
+    let (addr1, addr2) = (stubs::localhost(port), stubs::localhost(port + 1));
+    let mut sess1 = SessionBuilder::<StubConfig>::new()
+        .with_sparse_saving_mode(sparse_saving)
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(addr2), 1)?
+        .start_p2p_session(network.socket(addr1))?;
+    let mut sess2 = SessionBuilder::<StubConfig>::new()
+        .with_sparse_saving_mode(sparse_saving)
+        .add_player(PlayerType::Remote(addr1), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(addr2))?;
+    for _ in 0..50 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+    }
+    assert_eq!(sess1.current_state(), SessionState::Running);
+    assert_eq!(sess2.current_state(), SessionState::Running);
+    Ok((sess1, sess2))
+}
+
+fn assert_same_confirmed_history(
+    sess1: &P2PSession<StubConfig>,
+    sess2: &P2PSession<StubConfig>,
+    history1: &std::collections::HashMap<i32, i32>,
+    history2: &std::collections::HashMap<i32, i32>,
+) {
+    let confirmed = sess1.confirmed_frame().min(sess2.confirmed_frame());
+    assert!(confirmed > 0);
+    for frame in 1..=confirmed {
+        assert_eq!(
+            history1[&frame], history2[&frame],
+            "mismatch at frame {frame}"
+        );
+    }
+}
+
+#[test]
+fn test_grow_max_prediction_during_stall() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (mut sess1, mut sess2) = make_channel_sessions(&network, 9320, false)?;
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    let mut history1 = std::collections::HashMap::new();
+    let mut history2 = std::collections::HashMap::new();
+
+    // sess2 stops sending inputs, so sess1 predicts up to the default window of 8 frames
+    for i in 0..10 {
+        sess1.add_local_input(0, StubInput { inp: i })?;
+        handle_and_record(&mut stub1, &mut history1, sess1.advance_frame()?);
+    }
+    assert_eq!(sess1.current_frame(), 8);
+
+    // the predicted frames do not fit into a smaller window
+    assert!(sess1.set_max_prediction(4).is_err());
+    assert_eq!(sess1.max_prediction(), 8);
+
+    // a larger window lets sess1 keep predicting
+    sess1.set_max_prediction(12)?;
+    for i in 10..20 {
+        sess1.add_local_input(0, StubInput { inp: i })?;
+        handle_and_record(&mut stub1, &mut history1, sess1.advance_frame()?);
+    }
+    assert_eq!(sess1.current_frame(), 12);
+
+    // sess2 catches up, sess1 rolls back all the way
+    for i in 0..12 {
+        sess2.add_local_input(1, StubInput { inp: i * 3 })?;
+        handle_and_record(&mut stub2, &mut history2, sess2.advance_frame()?);
+    }
+    for i in 12..30 {
+        sess2.add_local_input(1, StubInput { inp: i * 3 })?;
+        handle_and_record(&mut stub2, &mut history2, sess2.advance_frame()?);
+        sess1.add_local_input(0, StubInput { inp: 20 + i })?;
+        handle_and_record(&mut stub1, &mut history1, sess1.advance_frame()?);
+    }
+    sess1.poll_remote_clients();
+    sess2.poll_remote_clients();
+
+    // once the inputs are confirmed, the window can shrink again
+    sess1.set_max_prediction(4)?;
+    for i in 0..10 {
+        sess1.add_local_input(0, StubInput { inp: i })?;
+        handle_and_record(&mut stub1, &mut history1, sess1.advance_frame()?);
+        sess2.add_local_input(1, StubInput { inp: i })?;
+        handle_and_record(&mut stub2, &mut history2, sess2.advance_frame()?);
+    }
+    sess1.poll_remote_clients();
+    sess2.poll_remote_clients();
+
+    assert_same_confirmed_history(&sess1, &sess2, &history1, &history2);
+    Ok(())
+}
+
+fn run_switch_to_lockstep_and_back(port: u16, sparse_saving: bool) -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (mut sess1, mut sess2) = make_channel_sessions(&network, port, sparse_saving)?;
+    let mut stub1 = stubs::GameStub::new();
+    let mut stub2 = stubs::GameStub::new();
+    let mut history1 = std::collections::HashMap::new();
+    let mut history2 = std::collections::HashMap::new();
+    let mut frame = 0;
+
+    // inputs change every few frames, like held buttons, so some predictions are wrong
+    let mut step = |sess1: &mut P2PSession<StubConfig>, sess2: &mut P2PSession<StubConfig>| {
+        sess1.add_local_input(0, StubInput { inp: frame / 5 })?;
+        handle_and_record(&mut stub1, &mut history1, sess1.advance_frame()?);
+        sess2.add_local_input(1, StubInput { inp: frame / 7 })?;
+        handle_and_record(&mut stub2, &mut history2, sess2.advance_frame()?);
+        frame += 1;
+        Ok::<_, GgrsError>(())
+    };
+
+    for _ in 0..20 {
+        step(&mut sess1, &mut sess2)?;
+    }
+    // sess1 has not received the input sess2 just sent, so the last frame is still predicted
+    assert!(sess1.set_max_prediction(0).is_err());
+
+    // switch each session once it has no more frames to correct
+    for _ in 0..20 {
+        sess1.poll_remote_clients();
+        sess2.poll_remote_clients();
+        for sess in [&mut sess1, &mut sess2] {
+            if !sess.in_lockstep_mode() {
+                let _ = sess.set_max_prediction(0);
+            }
+        }
+        if sess1.in_lockstep_mode() && sess2.in_lockstep_mode() {
+            break;
+        }
+        step(&mut sess1, &mut sess2)?;
+    }
+    assert!(sess1.in_lockstep_mode() && sess2.in_lockstep_mode());
+
+    let lockstep_start = sess1.current_frame();
+    for _ in 0..30 {
+        step(&mut sess1, &mut sess2)?;
+    }
+    assert!(sess1.current_frame() > lockstep_start + 10);
+
+    sess1.set_max_prediction(8)?;
+    sess2.set_max_prediction(8)?;
+    assert!(!sess1.in_lockstep_mode() && !sess2.in_lockstep_mode());
+    for _ in 0..30 {
+        step(&mut sess1, &mut sess2)?;
+    }
+    sess1.poll_remote_clients();
+    sess2.poll_remote_clients();
+
+    assert_same_confirmed_history(&sess1, &sess2, &history1, &history2);
+    Ok(())
+}
+
+#[test]
+fn test_switch_to_lockstep_and_back() -> Result<(), GgrsError> {
+    run_switch_to_lockstep_and_back(9330, false)
+}
+
+#[test]
+fn test_switch_to_lockstep_and_back_with_sparse_saving() -> Result<(), GgrsError> {
+    run_switch_to_lockstep_and_back(9340, true)
+}
+
+// ── User messages ─────────────────────────────────────────────────────────────
+
+#[test]
+fn test_send_user_message() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (mut sess1, mut sess2) = make_channel_sessions(&network, 9350, false)?;
+    let _ = sess2.events().count();
+
+    sess1.send_user_message(1, b"ready")?;
+    sess1.send_user_message(1, b"")?;
+    sess2.poll_remote_clients();
+    let payloads: Vec<Vec<u8>> = sess2
+        .events()
+        .filter_map(|event| match event {
+            GgrsEvent::UserMessage { addr, payload } => {
+                assert_eq!(addr, stubs::localhost(9350));
+                Some(payload)
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(payloads, vec![b"ready".to_vec(), Vec::new()]);
+    Ok(())
+}
+
+#[test]
+fn test_send_user_message_errors() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (mut sess1, _sess2) = make_channel_sessions(&network, 9360, false)?;
+
+    // local and unknown handles have no peer to send to
+    assert!(sess1.send_user_message(0, b"hi").is_err());
+    assert!(sess1.send_user_message(5, b"hi").is_err());
+    // the payload is too large
+    let oversized = vec![0; ggrs::MAX_USER_MESSAGE_SIZE + 1];
+    assert!(sess1.send_user_message(1, &oversized).is_err());
+    // the peer is disconnected
+    sess1.disconnect_player(1)?;
+    assert!(sess1.send_user_message(1, b"hi").is_err());
+    Ok(())
+}
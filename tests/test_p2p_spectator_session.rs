@@ -1,10 +1,12 @@
 mod stubs;
 
 use ggrs::{
-    GgrsError, GgrsRequest, PlayerType, SessionBuilder, SessionState, SpectatorSession,
+    ChannelNetwork, DesyncDetection, GgrsError, GgrsEvent, GgrsRequest, P2PSession, PacketSecurity,
+    PlayerType, ReplaySessionKind, SessionBuilder, SessionState, SpectatorSession,
     UdpNonBlockingSocket,
 };
 use serial_test::serial;
+use std::collections::HashMap;
 use std::thread;
 use std::time::{Duration, Instant};
 use stubs::{StubConfig, StubInput};
@@ -48,6 +50,7 @@ fn test_synchronize_with_host() -> Result<(), GgrsError> {
 
     let socket2 = UdpNonBlockingSocket::bind_to_port(7803).unwrap();
     let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .with_num_players(1)?
         .start_spectator_session(stubs::localhost(7802), socket2);
 
     assert_eq!(spec_sess.current_state(), SessionState::Synchronizing);
@@ -208,3 +211,693 @@ fn test_spectator_caps_catchup_speed_to_available_frames() -> Result<(), GgrsErr
 
     Ok(())
 }
+
+#[test]
+#[serial]
+fn test_spectator_records_replay() -> Result<(), GgrsError> {
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .with_num_players(1)?
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Spectator(stubs::localhost(7811)), 1)?
+        .start_p2p_session(UdpNonBlockingSocket::bind_to_port(7810).unwrap())?;
+
+    let replay = stubs::SharedBuffer::default();
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .with_num_players(1)?
+        .with_replay_recorder(replay.clone())
+        .start_spectator_session(
+            stubs::localhost(7810),
+            UdpNonBlockingSocket::bind_to_port(7811).unwrap(),
+        );
+    stubs::sync_host_and_spectator(&mut host_sess, &mut spec_sess);
+
+    let mut host_stub = stubs::GameStub1P::new();
+    let mut spec_stub = stubs::GameStub1P::new();
+    for i in 0..11 {
+        host_sess.add_local_input(0, StubInput { inp: i + 3 })?;
+        host_stub.handle_requests(host_sess.advance_frame()?);
+        if i > 0 {
+            spec_stub.handle_requests(advance_spectator_when_ready(&mut spec_sess)?);
+        }
+    }
+    drop(spec_sess);
+
+    let (header, frames) = replay.read_replay();
+    assert_eq!(header.session_kind, ReplaySessionKind::Spectator);
+    assert_eq!(header.num_players, 1);
+    assert_eq!(frames.len(), 10);
+    for (i, frame) in frames.iter().enumerate() {
+        assert_eq!(frame.frame, i as i32);
+        assert_eq!(frame.inputs[0].inp, i as u32 + 3);
+        assert_eq!(frame.disconnected, vec![false]);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_spectator_detects_desync_with_host() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, spec_addr) = (stubs::localhost(9220), stubs::localhost(9221));
+    let desync_mode = DesyncDetection::On { interval: 10 };
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .with_num_players(1)?
+        .with_desync_detection_mode(desync_mode)
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Spectator(spec_addr), 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .with_num_players(1)?
+        .with_desync_detection_mode(desync_mode)
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+    stubs::sync_host_and_spectator(&mut host_sess, &mut spec_sess);
+    let _ = spec_sess.events().count();
+
+    let mut host_stub = stubs::GameStub1P::new();
+    let mut spec_stub = stubs::GameStub1P::new();
+    let mut reported_frames = Vec::new();
+    for i in 0..100 {
+        host_sess.add_local_input(0, StubInput { inp: i })?;
+        host_stub.handle_requests(host_sess.advance_frame()?);
+        match spec_sess.advance_frame() {
+            Ok(requests) => {
+                for request in &requests {
+                    if let GgrsRequest::ReportChecksum { frame, .. } = request {
+                        reported_frames.push(*frame);
+                    }
+                }
+                spec_stub.handle_requests(requests);
+            }
+            Err(GgrsError::PredictionThreshold) => (),
+            Err(e) => return Err(e),
+        }
+        // the spectator shows the same game until it messes up its state
+        if spec_stub.gs.frame < 35 {
+            assert_eq!(spec_sess.events().count(), 0);
+        } else {
+            spec_stub.gs.state = 1234;
+        }
+    }
+    assert_eq!(&reported_frames[..5], &[10, 20, 30, 40, 50]);
+
+    let desyncs: Vec<_> = spec_sess
+        .events()
+        .filter_map(|event| match event {
+            GgrsEvent::DesyncDetected {
+                frame,
+                addr,
+                inputs_match,
+                ..
+            } => Some((frame, addr, inputs_match)),
+            _ => None,
+        })
+        .collect();
+    assert!(!desyncs.is_empty());
+    // the inputs are the same, only the state was messed up
+    assert_eq!(desyncs[0], (40, host_addr, Some(true)));
+    Ok(())
+}
+
+#[test]
+fn test_spectator_needs_matching_player_count() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, spec_addr) = (stubs::localhost(9240), stubs::localhost(9241));
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .with_num_players(1)?
+        .with_desync_detection_mode(DesyncDetection::On { interval: 10 })
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Spectator(spec_addr), 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    // spectators do not need to share settings like the prediction window or desync detection
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .with_num_players(1)?
+        .with_max_prediction_window(2)
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+    stubs::sync_host_and_spectator(&mut host_sess, &mut spec_sess);
+
+    // but they need to agree on the number of players
+    let (host_addr, spec_addr) = (stubs::localhost(9242), stubs::localhost(9243));
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .with_num_players(1)?
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Spectator(spec_addr), 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+    let mut mismatches = None;
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    while mismatches.is_none() {
+        assert!(Instant::now() < deadline, "spectator was not rejected");
+        host_sess.poll_remote_clients();
+        spec_sess.poll_remote_clients();
+        for event in spec_sess.events() {
+            if let GgrsEvent::ConfigMismatch { local, remote, .. } = event {
+                mismatches = Some(local.mismatches(&remote));
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    assert_eq!(mismatches, Some(vec!["num_players"]));
+    assert_eq!(spec_sess.current_state(), SessionState::Synchronizing);
+    Ok(())
+}
+
+#[test]
+fn test_spectator_follows_host_over_encrypted_packets() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, spec_addr) = (stubs::localhost(9250), stubs::localhost(9251));
+    let security = PacketSecurity::Encrypted { key: [3; 32] };
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .with_num_players(1)?
+        .with_packet_security(security)
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Spectator(spec_addr), 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .with_num_players(1)?
+        .with_packet_security(security)
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+    stubs::sync_host_and_spectator(&mut host_sess, &mut spec_sess);
+
+    let mut host_stub = stubs::GameStub1P::new();
+    let mut spec_stub = stubs::GameStub1P::new();
+    for i in 0..50 {
+        host_sess.add_local_input(0, StubInput { inp: i })?;
+        host_stub.handle_requests(host_sess.advance_frame()?);
+        match spec_sess.advance_frame() {
+            Ok(requests) => spec_stub.handle_requests(requests),
+            Err(GgrsError::PredictionThreshold) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    assert!(
+        spec_stub.gs.frame > 40,
+        "spectator only reached {}",
+        spec_stub.gs.frame
+    );
+    Ok(())
+}
+
+#[test]
+fn test_spectator_connects_through_connection_request() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, remote_addr, spec_addr) = (
+        stubs::localhost(9260),
+        stubs::localhost(9261),
+        stubs::localhost(9262),
+    );
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Remote(remote_addr), 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    // the host does not know the spectator in advance
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    let mut accepted = false;
+    while !accepted {
+        assert!(Instant::now() < deadline, "no connection request arrived");
+        spec_sess.poll_remote_clients();
+        host_sess.poll_remote_clients();
+        let events: Vec<_> = host_sess.events().collect();
+        for event in events {
+            if let GgrsEvent::ConnectionRequest { addr, token } = event {
+                assert_eq!(addr, spec_addr);
+                assert_eq!(token, None);
+                host_sess.accept_connection_request(addr, 2)?;
+                accepted = true;
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    assert_eq!(host_sess.spectator_handles(), vec![2]);
+
+    // the match only starts once the other player shows up
+    let mut remote_sess = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Remote(host_addr), 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(remote_addr))?;
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    while host_sess.current_state() != SessionState::Running
+        || remote_sess.current_state() != SessionState::Running
+        || spec_sess.current_state() != SessionState::Running
+    {
+        assert!(Instant::now() < deadline, "sessions did not synchronize");
+        host_sess.poll_remote_clients();
+        remote_sess.poll_remote_clients();
+        spec_sess.poll_remote_clients();
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    let mut host_stub = stubs::GameStub::new();
+    let mut remote_stub = stubs::GameStub::new();
+    let mut spec_stub = stubs::GameStub::new();
+    for i in 0..40 {
+        host_sess.poll_remote_clients();
+        remote_sess.poll_remote_clients();
+        host_sess.add_local_input(0, StubInput { inp: i })?;
+        host_stub.handle_requests(host_sess.advance_frame()?);
+        remote_sess.add_local_input(1, StubInput { inp: i })?;
+        remote_stub.handle_requests(remote_sess.advance_frame()?);
+        match spec_sess.advance_frame() {
+            Ok(requests) => spec_stub.handle_requests(requests),
+            Err(GgrsError::PredictionThreshold) => (),
+            Err(e) => return Err(e),
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    assert!(
+        spec_stub.gs.frame > 20,
+        "spectator only reached {}",
+        spec_stub.gs.frame
+    );
+
+    // spectators can also be accepted once the match is running
+    let late_addr = stubs::localhost(9266);
+    let mut late_sess = SessionBuilder::<StubConfig>::new()
+        .start_spectator_session(host_addr, network.socket(late_addr));
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    loop {
+        assert!(Instant::now() < deadline, "no connection request arrived");
+        late_sess.poll_remote_clients();
+        host_sess.poll_remote_clients();
+        let request = host_sess
+            .events()
+            .find(|event| matches!(event, GgrsEvent::ConnectionRequest { .. }));
+        if let Some(GgrsEvent::ConnectionRequest { addr, .. }) = request {
+            host_sess.accept_connection_request(addr, 3)?;
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    let mut handles = host_sess.spectator_handles();
+    handles.sort();
+    assert_eq!(handles, vec![2, 3]);
+    Ok(())
+}
+
+/// Polls the host and the spectator until the host reports that the spectator joined, returning
+/// the first frame the spectator receives inputs for.
+fn wait_until_spectator_joins(
+    host_sess: &mut P2PSession<StubConfig>,
+    spec_sess: &mut SpectatorSession<StubConfig>,
+    handle: usize,
+) -> i32 {
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    loop {
+        assert!(Instant::now() < deadline, "spectator did not join");
+        spec_sess.poll_remote_clients();
+        host_sess.poll_remote_clients();
+        let joined = host_sess.events().find_map(|event| match event {
+            GgrsEvent::SpectatorJoined {
+                player_handle,
+                frame,
+                ..
+            } if player_handle == handle => Some(frame),
+            _ => None,
+        });
+        if let Some(frame) = joined {
+            return frame;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[test]
+fn test_spectators_can_be_added_and_removed() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, spec_addr) = (stubs::localhost(9267), stubs::localhost(9268));
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+
+    let handle = host_sess.add_spectator(spec_addr)?;
+    assert_eq!(handle, 2);
+    assert!(host_sess.add_spectator(spec_addr).is_err());
+
+    assert_eq!(
+        wait_until_spectator_joins(&mut host_sess, &mut spec_sess, 2),
+        0
+    );
+
+    // the spectator follows the match from the first frame on
+    let mut host_stub = stubs::GameStub::new();
+    let mut spec_stub = stubs::GameStub::new();
+    for i in 0..21 {
+        host_sess.add_local_input(0, StubInput { inp: 0 })?;
+        host_sess.add_local_input(1, StubInput { inp: 1 })?;
+        host_stub.handle_requests(host_sess.advance_frame()?);
+        if i > 0 {
+            spec_stub.handle_requests(advance_spectator_when_ready(&mut spec_sess)?);
+        }
+    }
+    assert_eq!(spec_stub.gs.frame, 20);
+
+    host_sess.remove_spectator(handle)?;
+    assert_eq!(host_sess.num_spectators(), 0);
+    assert!(host_sess.remove_spectator(handle).is_err());
+    assert!(host_sess.events().any(|event| matches!(
+        event,
+        GgrsEvent::SpectatorLeft { addr, player_handle: 2 } if addr == spec_addr
+    )));
+
+    // the session keeps running without spectators, and the handle is free again
+    for _ in 0..10 {
+        host_sess.add_local_input(0, StubInput { inp: 0 })?;
+        host_sess.add_local_input(1, StubInput { inp: 1 })?;
+        host_stub.handle_requests(host_sess.advance_frame()?);
+    }
+    assert_eq!(host_sess.add_spectator(stubs::localhost(9269))?, 2);
+    Ok(())
+}
+
+#[test]
+fn test_spectator_added_mid_match_receives_later_frames() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, spec_addr) = (stubs::localhost(9270), stubs::localhost(9271));
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+
+    // the host plays on its own for much longer than it keeps inputs around
+    let mut host_stub = stubs::GameStub::new();
+    for _ in 0..200 {
+        host_sess.add_local_input(0, StubInput { inp: 0 })?;
+        host_sess.add_local_input(1, StubInput { inp: 1 })?;
+        host_stub.handle_requests(host_sess.advance_frame()?);
+    }
+
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+    let handle = host_sess.add_spectator(spec_addr)?;
+    // it receives the inputs that are confirmed after it joined
+    assert_eq!(
+        wait_until_spectator_joins(&mut host_sess, &mut spec_sess, handle),
+        199
+    );
+    Ok(())
+}
+
+fn advance_two_local_players(
+    sess: &mut P2PSession<StubConfig>,
+    stub: &mut stubs::GameStub,
+    i: u32,
+) -> Result<(), GgrsError> {
+    sess.add_local_input(0, StubInput { inp: i % 3 })?;
+    sess.add_local_input(1, StubInput { inp: i % 2 })?;
+    stub.handle_requests(sess.advance_frame()?);
+    Ok(())
+}
+
+#[test]
+fn test_spectator_joins_running_match_from_snapshot() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, spec_addr) = (stubs::localhost(9272), stubs::localhost(9273));
+    let desync_detection = DesyncDetection::On { interval: 10 };
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .with_desync_detection_mode(desync_detection)
+        .with_state_transfer()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+
+    // remember the state of the host at every frame
+    let mut host_stub = stubs::GameStub::new();
+    let mut host_states = HashMap::new();
+    for i in 0..200 {
+        advance_two_local_players(&mut host_sess, &mut host_stub, i)?;
+        host_states.insert(host_stub.gs.frame, host_stub.gs.state);
+    }
+
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .with_desync_detection_mode(desync_detection)
+        .with_state_transfer()
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+    host_sess.add_spectator(spec_addr)?;
+
+    let mut spec_stub = stubs::GameStub::new();
+    let mut loaded = false;
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    for i in 200..300 {
+        assert!(
+            Instant::now() < deadline,
+            "spectator did not follow the match"
+        );
+        advance_two_local_players(&mut host_sess, &mut host_stub, i)?;
+        host_states.insert(host_stub.gs.frame, host_stub.gs.state);
+        match spec_sess.advance_frame() {
+            Ok(requests) => {
+                loaded |= requests.iter().any(
+                    |r| matches!(r, GgrsRequest::LoadGameState { frame, .. } if *frame >= 200),
+                );
+                spec_stub.handle_requests(requests);
+                assert!(loaded, "spectator advanced without the state of the match");
+                assert_eq!(
+                    Some(&spec_stub.gs.state),
+                    host_states.get(&spec_stub.gs.frame)
+                );
+            }
+            Err(GgrsError::PredictionThreshold | GgrsError::NotSynchronized) => (),
+            Err(e) => return Err(e),
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    assert!(
+        spec_stub.gs.frame > 250,
+        "spectator only reached {}",
+        spec_stub.gs.frame
+    );
+    assert!(!spec_sess
+        .events()
+        .any(|event| matches!(event, GgrsEvent::DesyncDetected { .. })));
+    Ok(())
+}
+
+/// Lets the host play on its own while the spectator only polls, until the spectator is `frames`
+/// frames behind.
+fn stall_spectator(
+    host_sess: &mut P2PSession<StubConfig>,
+    host_stub: &mut stubs::GameStub,
+    spec_sess: &mut SpectatorSession<StubConfig>,
+    frames: u32,
+    host_states: &mut HashMap<i32, i32>,
+) -> Result<(), GgrsError> {
+    for _ in 0..frames {
+        let i = host_stub.gs.frame as u32;
+        advance_two_local_players(host_sess, host_stub, i)?;
+        host_states.insert(host_stub.gs.frame, host_stub.gs.state);
+        spec_sess.poll_remote_clients();
+        thread::sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_spectator_too_far_behind_without_state_transfer() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, spec_addr) = (stubs::localhost(9274), stubs::localhost(9275));
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+    let handle = host_sess.add_spectator(spec_addr)?;
+    assert_eq!(
+        wait_until_spectator_joins(&mut host_sess, &mut spec_sess, handle),
+        0
+    );
+
+    let mut host_stub = stubs::GameStub::new();
+    let mut host_states = HashMap::new();
+    stall_spectator(
+        &mut host_sess,
+        &mut host_stub,
+        &mut spec_sess,
+        100,
+        &mut host_states,
+    )?;
+    assert!(matches!(
+        spec_sess.advance_frame(),
+        Err(GgrsError::SpectatorTooFarBehind)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_spectator_too_far_behind_skips_ahead_with_snapshot() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, spec_addr) = (stubs::localhost(9276), stubs::localhost(9277));
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .with_state_transfer()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .with_state_transfer()
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+    let handle = host_sess.add_spectator(spec_addr)?;
+    assert_eq!(
+        wait_until_spectator_joins(&mut host_sess, &mut spec_sess, handle),
+        0
+    );
+
+    // the spectator follows for a while, then stops advancing
+    let mut host_stub = stubs::GameStub::new();
+    let mut spec_stub = stubs::GameStub::new();
+    let mut host_states = HashMap::new();
+    for i in 0..11 {
+        advance_two_local_players(&mut host_sess, &mut host_stub, i)?;
+        if i > 0 {
+            spec_stub.handle_requests(advance_spectator_when_ready(&mut spec_sess)?);
+        }
+    }
+    assert_eq!(spec_stub.gs.frame, 10);
+    stall_spectator(
+        &mut host_sess,
+        &mut host_stub,
+        &mut spec_sess,
+        100,
+        &mut host_states,
+    )?;
+
+    // instead of failing, the spectator skips ahead to a snapshot of the host
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    while spec_stub.gs.frame < 110 {
+        assert!(Instant::now() < deadline, "spectator did not recover");
+        let i = host_stub.gs.frame as u32;
+        advance_two_local_players(&mut host_sess, &mut host_stub, i)?;
+        host_states.insert(host_stub.gs.frame, host_stub.gs.state);
+        match spec_sess.advance_frame() {
+            Ok(requests) => {
+                spec_stub.handle_requests(requests);
+                assert_eq!(
+                    Some(&spec_stub.gs.state),
+                    host_states.get(&spec_stub.gs.frame)
+                );
+            }
+            Err(GgrsError::PredictionThreshold) => (),
+            Err(e) => return Err(e),
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    let skipped = spec_sess.events().find_map(|event| match event {
+        GgrsEvent::FramesSkipped { from, to } => Some((from, to)),
+        _ => None,
+    });
+    let (from, to) = skipped.expect("no FramesSkipped event");
+    assert_eq!(from, 10);
+    assert!(to >= 110, "spectator skipped to frame {to}");
+    Ok(())
+}
+
+#[test]
+fn test_spectator_with_state_transfer_joins_host_without_it() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, spec_addr) = (stubs::localhost(9286), stubs::localhost(9287));
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    let mut host_stub = stubs::GameStub::new();
+    for i in 0..200 {
+        advance_two_local_players(&mut host_sess, &mut host_stub, i)?;
+    }
+
+    // the host cannot send a snapshot, so the spectator must not wait for one
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .with_state_transfer()
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+    host_sess.add_spectator(spec_addr)?;
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    for i in 200.. {
+        assert!(
+            Instant::now() < deadline,
+            "spectator waited for a snapshot the host cannot send"
+        );
+        advance_two_local_players(&mut host_sess, &mut host_stub, i)?;
+        match spec_sess.advance_frame() {
+            Err(GgrsError::SpectatorTooFarBehind) => break,
+            Err(GgrsError::PredictionThreshold | GgrsError::NotSynchronized) => (),
+            Ok(_) => panic!("spectator advanced without the state of the match"),
+            Err(e) => return Err(e),
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_spectator_with_state_transfer_too_far_behind_host_without_it() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, spec_addr) = (stubs::localhost(9288), stubs::localhost(9289));
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .with_state_transfer()
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+    let handle = host_sess.add_spectator(spec_addr)?;
+    assert_eq!(
+        wait_until_spectator_joins(&mut host_sess, &mut spec_sess, handle),
+        0
+    );
+
+    let mut host_stub = stubs::GameStub::new();
+    let mut host_states = HashMap::new();
+    stall_spectator(
+        &mut host_sess,
+        &mut host_stub,
+        &mut spec_sess,
+        100,
+        &mut host_states,
+    )?;
+    assert!(matches!(
+        spec_sess.advance_frame(),
+        Err(GgrsError::SpectatorTooFarBehind)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_desync_bundles_do_not_enable_state_transfer() -> Result<(), GgrsError> {
+    let network = ChannelNetwork::new();
+    let (host_addr, spec_addr) = (stubs::localhost(9290), stubs::localhost(9291));
+    let mut host_sess = SessionBuilder::<StubConfig>::new()
+        .with_desync_detection_mode(DesyncDetection::On { interval: 10 })
+        .with_desync_bundles()
+        .add_player(PlayerType::Local, 0)?
+        .add_player(PlayerType::Local, 1)?
+        .start_p2p_session(network.socket(host_addr))?;
+    let mut spec_sess = SessionBuilder::<StubConfig>::new()
+        .with_desync_detection_mode(DesyncDetection::On { interval: 10 })
+        .with_state_transfer()
+        .start_spectator_session(host_addr, network.socket(spec_addr));
+    let handle = host_sess.add_spectator(spec_addr)?;
+    assert_eq!(
+        wait_until_spectator_joins(&mut host_sess, &mut spec_sess, handle),
+        0
+    );
+
+    // the host serializes states for the bundles, but does not send them to spectators
+    let mut host_stub = stubs::GameStub::new();
+    let mut host_states = HashMap::new();
+    stall_spectator(
+        &mut host_sess,
+        &mut host_stub,
+        &mut spec_sess,
+        100,
+        &mut host_states,
+    )?;
+    assert!(matches!(
+        spec_sess.advance_frame(),
+        Err(GgrsError::SpectatorTooFarBehind)
+    ));
+    Ok(())
+}
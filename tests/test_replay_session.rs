@@ -0,0 +1,148 @@
+mod stubs;
+
+use ggrs::{GgrsError, GgrsRequest, InputStatus, ReplaySession, SessionBuilder};
+use stubs::{StubConfig, StubInput};
+
+const RECORDED_FRAMES: u32 = 100;
+
+/// Records a sync test of `RECORDED_FRAMES` frames. Returns the replay and the state of the game
+/// at the beginning of every frame, including the final one.
+fn record_replay() -> (stubs::SharedBuffer, Vec<stubs::StateStub>) {
+    let replay = stubs::SharedBuffer::default();
+    let mut sess = SessionBuilder::<StubConfig>::new()
+        .with_replay_recorder(replay.clone())
+        .start_synctest_session()
+        .unwrap();
+    let mut stub = stubs::GameStub::new();
+    let mut states = vec![stub.gs];
+    for i in 0..RECORDED_FRAMES {
+        sess.add_local_input(0, StubInput { inp: i }).unwrap();
+        sess.add_local_input(1, StubInput { inp: i % 3 }).unwrap();
+        stub.handle_requests(sess.advance_frame().unwrap());
+        states.push(stub.gs);
+    }
+    (replay, states)
+}
+
+fn start_replay(replay: &stubs::SharedBuffer) -> ReplaySession<StubConfig> {
+    SessionBuilder::<StubConfig>::new()
+        .with_keyframe_interval(10)
+        .unwrap()
+        .start_replay_session(replay.bytes().as_slice())
+        .unwrap()
+}
+
+fn count_advances(requests: &[GgrsRequest<StubConfig>]) -> usize {
+    requests
+        .iter()
+        .filter(|r| matches!(r, GgrsRequest::AdvanceFrame { .. }))
+        .count()
+}
+
+#[test]
+fn test_replay_reproduces_recorded_match() {
+    let (replay, states) = record_replay();
+    let mut sess = start_replay(&replay);
+    assert_eq!(sess.num_players(), 2);
+    assert_eq!(sess.end_frame(), RECORDED_FRAMES as i32);
+
+    let mut stub = stubs::GameStub::new();
+    while !sess.is_finished() {
+        let requests = sess.advance_frame();
+        for request in &requests {
+            if let GgrsRequest::AdvanceFrame { inputs } = request {
+                assert!(inputs
+                    .iter()
+                    .all(|(_, status)| *status == InputStatus::Confirmed));
+            }
+        }
+        stub.handle_requests(requests);
+        assert_eq!(stub.gs.frame, sess.current_frame());
+        assert_eq!(stub.gs.state, states[stub.gs.frame as usize].state);
+    }
+
+    // nothing left to play
+    assert!(sess.advance_frame().is_empty());
+    assert!(sess.step().is_empty());
+}
+
+#[test]
+fn test_replay_pause_step_and_speed() -> Result<(), GgrsError> {
+    let (replay, _) = record_replay();
+    let mut sess = start_replay(&replay);
+
+    sess.pause();
+    assert!(sess.is_paused());
+    assert!(sess.advance_frame().is_empty());
+    assert_eq!(count_advances(&sess.step()), 1);
+    assert_eq!(sess.current_frame(), 1);
+    sess.resume();
+
+    sess.set_speed(3.0)?;
+    assert_eq!(count_advances(&sess.advance_frame()), 3);
+    assert_eq!(sess.current_frame(), 4);
+
+    sess.set_speed(0.5)?;
+    assert_eq!(count_advances(&sess.advance_frame()), 0);
+    assert_eq!(count_advances(&sess.advance_frame()), 1);
+    assert_eq!(sess.current_frame(), 5);
+
+    assert!(sess.set_speed(0.0).is_err());
+    assert!(sess.set_speed(f32::NAN).is_err());
+    assert_eq!(sess.speed(), 0.5);
+
+    Ok(())
+}
+
+#[test]
+fn test_replay_seek_loads_keyframes() -> Result<(), GgrsError> {
+    let (replay, states) = record_replay();
+    let mut sess = start_replay(&replay);
+    let mut stub = stubs::GameStub::new();
+
+    // seeking forward plays all frames in between and saves keyframes on the way
+    let requests = sess.seek(57)?;
+    assert_eq!(count_advances(&requests), 57);
+    let saves: Vec<_> = requests
+        .iter()
+        .filter_map(|r| match r {
+            GgrsRequest::SaveGameState { frame, .. } => Some(*frame),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(saves, vec![0, 10, 20, 30, 40, 50]);
+    stub.handle_requests(requests);
+    assert_eq!(stub.gs.state, states[57].state);
+
+    // seeking backwards loads the closest keyframe
+    let requests = sess.seek(35)?;
+    assert!(matches!(
+        requests[0],
+        GgrsRequest::LoadGameState { frame: 30, .. }
+    ));
+    assert_eq!(count_advances(&requests), 5);
+    stub.handle_requests(requests);
+    assert_eq!(sess.current_frame(), 35);
+    assert_eq!(stub.gs.frame, 35);
+    assert_eq!(stub.gs.state, states[35].state);
+
+    // back to the very beginning
+    stub.handle_requests(sess.seek(0)?);
+    assert_eq!(stub.gs.frame, 0);
+    assert_eq!(stub.gs.state, states[0].state);
+
+    assert!(sess.seek(-1).is_err());
+    assert!(sess.seek(RECORDED_FRAMES as i32 + 1).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_replay_session_rejects_invalid_input() {
+    assert!(SessionBuilder::<StubConfig>::new()
+        .with_keyframe_interval(0)
+        .is_err());
+    assert!(SessionBuilder::<StubConfig>::new()
+        .start_replay_session(&b"definitely not a replay"[..])
+        .is_err());
+}
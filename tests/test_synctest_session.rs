@@ -1,7 +1,7 @@
 mod stubs;
 mod stubs_enum;
 
-use ggrs::{GgrsError, GgrsRequest, SessionBuilder};
+use ggrs::{GgrsError, GgrsRequest, ReplaySessionKind, SessionBuilder};
 use stubs::{StubConfig, StubInput};
 
 #[test]
@@ -196,3 +196,64 @@ fn test_advance_frames_with_random_checksums() {
         assert_eq!(stub.gs.frame, i as i32 + 1);
     }
 }
+
+#[test]
+fn test_mismatched_sub_checksums_are_named() {
+    let mut stub = stubs::SubChecksumGameStub::random_state_checksum();
+    let mut sess = SessionBuilder::new()
+        .with_input_delay(2)
+        .start_synctest_session()
+        .unwrap();
+
+    for i in 0..200 {
+        sess.add_local_input(0, StubInput { inp: i }).unwrap();
+        sess.add_local_input(1, StubInput { inp: i }).unwrap();
+        match sess.advance_frame() {
+            Ok(requests) => stub.handle_requests(requests),
+            Err(GgrsError::MismatchedChecksum {
+                mismatched_sub_checksums,
+                ..
+            }) => {
+                // only the randomized component is named, the frame counter is deterministic
+                assert_eq!(mismatched_sub_checksums, vec!["state".to_owned()]);
+                return;
+            }
+            Err(e) => panic!("unexpected error: {e}"),
+        }
+    }
+    panic!("no mismatched checksum detected");
+}
+
+#[test]
+fn test_replay_records_inputs_and_checksums() -> Result<(), GgrsError> {
+    let replay = stubs::SharedBuffer::default();
+    let mut stub = stubs::GameStub::new();
+    let mut sess = SessionBuilder::new()
+        .with_input_delay(2)
+        .with_replay_recorder(replay.clone())
+        .start_synctest_session()?;
+
+    for i in 0..20 {
+        sess.add_local_input(0, StubInput { inp: i })?;
+        sess.add_local_input(1, StubInput { inp: i + 1 })?;
+        stub.handle_requests(sess.advance_frame()?);
+    }
+    drop(sess);
+
+    let (header, frames) = replay.read_replay();
+    assert_eq!(header.session_kind, ReplaySessionKind::SyncTest);
+    assert_eq!(header.input_delay, 2);
+    assert_eq!(frames.len(), 20);
+    for (i, frame) in frames.iter().enumerate() {
+        assert_eq!(frame.frame, i as i32);
+        // the input delay shifts the given inputs back by two frames
+        let expected = (i as u32).checked_sub(2);
+        assert_eq!(frame.inputs[0].inp, expected.unwrap_or(0));
+        assert_eq!(frame.inputs[1].inp, expected.map_or(0, |inp| inp + 1));
+    }
+    // the state of the last frame was never saved before the session ended
+    assert!(frames[..19].iter().all(|frame| frame.checksum.is_some()));
+    assert!(frames[19].checksum.is_none());
+
+    Ok(())
+}